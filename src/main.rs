@@ -1,47 +1,187 @@
 mod input_stream_editor {
-    use std::io;
-    use std::os::fd::AsRawFd;
-    use termios::*;
+    #[cfg(unix)]
+    pub use self::unix::*;
+    #[cfg(windows)]
+    pub use self::windows::*;
+
+
+    #[cfg(unix)]
+    mod unix {
+        use std::io;
+        use std::os::fd::AsRawFd;
+        use termios::*;
+
+
+        /// Mode of stdin before raw mode was activated.
+        pub type OriginalStdinMode = Termios;
+
+
+        /// Convert stdin from canonical to raw mode
+        pub fn activate_stdin_raw_mode() -> OriginalStdinMode {
+            let stdin_raw_fd = io::stdin().as_raw_fd();
+
+            // Can safely unwrap here since `Termios::from_fd` will only 
+            // fail if `raw_fd` is not an open file descriptor and stdin 
+            // is always open.
+            let termios_original = Termios::from_fd(stdin_raw_fd).unwrap();
+            let mut termios_new = termios_original;
 
+            termios_new.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+            termios_new.c_oflag &= !OPOST;
+            termios_new.c_cflag |= CS8;
+            termios_new.c_lflag &= !(ECHO | ICANON | ISIG | IEXTEN);
 
-    /// Convert stdin from canonical to raw mode
-    pub fn activate_stdin_raw_mode() -> Termios {
-        let stdin_raw_fd = io::stdin().as_raw_fd();
+            // Can safely unwrap here since `tscetattr` will only fail if 
+            // `raw_fd` is not an open file descriptor and stdin is always 
+            // open.
+            tcsetattr(stdin_raw_fd, TCSANOW, &termios_new).unwrap();
+            termios_original
+        }
+
+
+        /// Reset stdin to mode defined by `original_termios`.
+        pub fn recover_original_stdin_mode(original_termios: OriginalStdinMode) {
+            let raw_fd = io::stdin().as_raw_fd();
+            
+            // Should be able to safely unwrap here since tcsetattr should
+            // only return an error if it is unable to execute the update,
+            // but stdin is always open
+            tcsetattr(raw_fd, TCSANOW, &original_termios).unwrap();
+        }
 
-        // Can safely unwrap here since `Termios::from_fd` will only 
-        // fail if `raw_fd` is not an open file descriptor and stdin is
-        // always open.
-        let termios_original = Termios::from_fd(stdin_raw_fd).unwrap();
-        let mut termios_new = termios_original.clone();
 
-        termios_new.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
-        termios_new.c_oflag &= !OPOST;
-        termios_new.c_cflag |= CS8;
-        termios_new.c_lflag &= !(ECHO | ICANON | ISIG | IEXTEN);
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use std::io::IsTerminal;
 
-        // Can safely unwrap here since `tscetattr` will only fail if 
-        // `raw_fd` is not an open file descriptor and stdin is always 
-        // open.
-        tcsetattr(stdin_raw_fd, TCSANOW, &termios_new).unwrap();
-        termios_original
+            #[test]
+            fn raw_mode_round_trip() {
+                // Raw mode can only be toggled when the tests are run 
+                // from a terminal.
+                if !io::stdin().is_terminal() {
+                    return;
+                }
+                let stdin_raw_fd = io::stdin().as_raw_fd();
+
+                let original = activate_stdin_raw_mode();
+                let raw = Termios::from_fd(stdin_raw_fd).unwrap();
+                assert_eq!(raw.c_lflag & (ECHO | ICANON), 0);
+
+                recover_original_stdin_mode(original);
+                assert_eq!(Termios::from_fd(stdin_raw_fd).unwrap(), original);
+            }
+        }
     }
 
 
-    /// Reset stdin to mode defined by `original_termios`.
-    pub fn recover_original_stdin_mode(original_termios: Termios) {
-        let raw_fd = io::stdin().as_raw_fd();
-        
-        // Should be able to safely unwrap here since tcsetattr should
-        // only return an error if it is unable to execute the update,
-        // but stdin is always open
-        tcsetattr(raw_fd, TCSANOW, &original_termios).unwrap();
+    #[cfg(windows)]
+    mod windows {
+        use windows_sys::Win32::Foundation::HANDLE;
+        use windows_sys::Win32::System::Console::{
+            GetConsoleMode, GetStdHandle, SetConsoleMode, CONSOLE_MODE,
+            STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, ENABLE_ECHO_INPUT, 
+            ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT, 
+            ENABLE_VIRTUAL_TERMINAL_INPUT, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+            DISABLE_NEWLINE_AUTO_RETURN,
+        };
+
+
+        /// Modes of the console input and output buffers before raw mode
+        /// was activated.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct OriginalStdinMode {
+            input: CONSOLE_MODE,
+            output: CONSOLE_MODE,
+        }
+
+
+        fn get_console_mode(handle: HANDLE) -> Option<CONSOLE_MODE> {
+            let mut mode = 0;
+            if unsafe { GetConsoleMode(handle, &mut mode) } != 0 {
+                Some(mode)
+            } else {
+                None
+            }
+        }
+
+
+        /// Convert the console from line-buffered input to raw input, and
+        /// enable virtual terminal processing so that the escape
+        /// sequences used to draw the editor are interpreted.
+        pub fn activate_stdin_raw_mode() -> OriginalStdinMode {
+            let stdin = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+            let stdout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+
+            // Can safely unwrap here since `GetConsoleMode` will only 
+            // fail if the handles do not belong to a console, in which 
+            // case the editor cannot run.
+            let original = OriginalStdinMode {
+                input: get_console_mode(stdin).unwrap(),
+                output: get_console_mode(stdout).unwrap(),
+            };
+
+            let input = (original.input 
+                & !(ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT | ENABLE_PROCESSED_INPUT))
+                | ENABLE_VIRTUAL_TERMINAL_INPUT;
+            let output = original.output 
+                | ENABLE_VIRTUAL_TERMINAL_PROCESSING 
+                | DISABLE_NEWLINE_AUTO_RETURN;
+
+            unsafe {
+                SetConsoleMode(stdin, input);
+                SetConsoleMode(stdout, output);
+            }
+            original
+        }
+
+
+        /// Reset the console to the modes defined by `original`.
+        pub fn recover_original_stdin_mode(original: OriginalStdinMode) {
+            unsafe {
+                SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), original.input);
+                SetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), original.output);
+            }
+        }
+
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn raw_mode_round_trip() {
+                // Raw mode can only be toggled when the tests are run 
+                // from a console.
+                let stdin = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+                let stdout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+                if get_console_mode(stdin).is_none() || get_console_mode(stdout).is_none() {
+                    return;
+                }
+
+                let original = activate_stdin_raw_mode();
+                let raw_input = get_console_mode(stdin).unwrap();
+                assert_eq!(raw_input & (ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT), 0);
+
+                recover_original_stdin_mode(original);
+                assert_eq!(get_console_mode(stdin), Some(original.input));
+                assert_eq!(get_console_mode(stdout), Some(original.output));
+            }
+        }
     }
 }
 
 
 mod terminal {
+    #[cfg(unix)]
     use rustix::{termios::{tcgetwinsize, isatty}, fd::{RawFd, BorrowedFd, AsRawFd}};
-    use std::io;
+    use std::io::{ self, BufRead, IsTerminal, Write };
+    use std::time::{ Duration, Instant };
+
+
+    /// How long to wait for the terminal to reply to a query of the 
+    /// cursor position.
+    const CURSOR_POSITION_TIMEOUT: Duration = Duration::from_millis(500);
 
 
     pub struct WindowSize {
@@ -54,10 +194,11 @@ mod terminal {
     ///
     /// # Parameters
     /// * `fd` should be a raw file descriptor associated with the
-    /// terminal.
+    ///   terminal.
     ///
     /// # Errors
     /// *  Returns `None` if unable to determine terminal size from `fd`.
+    #[cfg(unix)]
     fn get_terminal_size_from_fd(fd: RawFd) -> Option<WindowSize> {
         let fd = unsafe { BorrowedFd::borrow_raw(fd) };
 
@@ -78,12 +219,139 @@ mod terminal {
     }
 
 
+    /// Attempt to get size of the visible window of the console 
+    /// attached to stdout.
+    ///
+    /// # Errors
+    /// *  Returns `None` if stdout is not attached to a console.
+    #[cfg(windows)]
+    fn get_terminal_size_from_console() -> Option<WindowSize> {
+        use windows_sys::Win32::System::Console::{
+            GetConsoleScreenBufferInfo, GetStdHandle, STD_OUTPUT_HANDLE,
+        };
+
+        let mut info = unsafe { std::mem::zeroed() };
+        let stdout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+        if unsafe { GetConsoleScreenBufferInfo(stdout, &mut info) } == 0 {
+            return None;
+        }
+
+        let rows = (info.srWindow.Bottom - info.srWindow.Top + 1).max(0) as usize;
+        let cols = (info.srWindow.Right - info.srWindow.Left + 1).max(0) as usize;
+
+        if rows > 0 && cols > 0 {
+            Some(WindowSize { cols, rows })
+        } else {
+            None
+        }
+    }
+
+
+    /// Parse the reply to a cursor position query, which has the form
+    /// `\x1b[{rows};{cols}R`.
+    ///
+    /// # Errors
+    /// *  Returns `None` if `reply` is not a well formed cursor position
+    ///    report or either dimension is zero.
+    fn parse_cursor_position_reply(reply: &[u8]) -> Option<WindowSize> {
+        let reply = reply.strip_prefix(b"\x1b[")?.strip_suffix(b"R")?;
+        let (rows, cols) = std::str::from_utf8(reply).ok()?.split_once(';')?;
+
+        let rows: usize = rows.parse().ok()?;
+        let cols: usize = cols.parse().ok()?;
+
+        if rows > 0 && cols > 0 {
+            Some(WindowSize { cols, rows })
+        } else {
+            None
+        }
+    }
+
+
+    /// Wait up to `timeout` for input on stdin and return whether there 
+    /// is any to read.
+    #[cfg(unix)]
+    fn poll_stdin(timeout: Duration) -> bool {
+        use rustix::event::{ poll, PollFd, PollFlags, Timespec };
+
+        let Ok(timeout) = Timespec::try_from(timeout) else {
+            return false;
+        };
+        let stdin = io::stdin();
+        let mut fds = [PollFd::new(&stdin, PollFlags::IN)];
+        poll(&mut fds, Some(&timeout)).is_ok_and(|n_ready| n_ready > 0)
+    }
+
+
+    /// Wait up to `timeout` for input on stdin and return whether there 
+    /// is any to read.
+    #[cfg(windows)]
+    fn poll_stdin(timeout: Duration) -> bool {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::Foundation::WAIT_OBJECT_0;
+        use windows_sys::Win32::System::Threading::WaitForSingleObject;
+
+        let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        // SAFETY: Stdin's handle stays open for the duration of the call.
+        unsafe { WaitForSingleObject(io::stdin().as_raw_handle(), millis) == WAIT_OBJECT_0 }
+    }
+
+
+    /// Attempt to get size of terminal by moving the cursor to the
+    /// bottom right of the screen and asking the terminal where it
+    /// ended up. Stdin should already be in raw mode so that the reply
+    /// can be read without waiting for a newline.
+    ///
+    /// # Errors
+    /// *  Returns `None` if stdin is not a tty, or if the query cannot
+    ///    be written or its reply cannot be read and parsed within 
+    ///    `CURSOR_POSITION_TIMEOUT`.
+    fn get_terminal_size_from_cursor_position() -> Option<WindowSize> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[999C\x1b[999B\x1b[6n").ok()?;
+        stdout.flush().ok()?;
+
+        // A well formed reply is never more than a few bytes long, so
+        // give up rather than blocking forever on a terminal that does
+        // not answer, or not in the expected format.
+        let deadline = Instant::now() + CURSOR_POSITION_TIMEOUT;
+        let mut stdin = io::stdin().lock();
+        let mut reply = Vec::new();
+        while reply.len() < 32 && !reply.ends_with(b"R") {
+            // Everything read from stdin so far has been taken into the
+            // reply, so there is only more to read once stdin is ready.
+            if !poll_stdin(deadline.saturating_duration_since(Instant::now())) {
+                return None;
+            }
+            let available = stdin.fill_buf().ok()?;
+            if available.is_empty() {
+                return None;
+            }
+            let n_bytes = available
+                .iter()
+                .position(|&byte| byte == b'R')
+                .map_or(available.len(), |i| i + 1)
+                .min(32 - reply.len());
+            reply.extend_from_slice(&available[..n_bytes]);
+            stdin.consume(n_bytes);
+        }
+
+        parse_cursor_position_reply(&reply)
+    }
+
+
     /// Attempt to get size of terminal from stdout, stderr, and stdin
-    /// in that order. Returns upon first success.
+    /// in that order, falling back to querying the cursor position if
+    /// none of them report a size. Returns upon first success.
     ///
     /// # Errors
     /// *  Returns `None` if unable to determine terminal size from 
-    /// stdout, stderr, or stdin.
+    ///    stdout, stderr, stdin, or the cursor position.
+    #[cfg(unix)]
     pub fn get_terminal_size() -> Option<WindowSize> {
         if let Some(size) = get_terminal_size_from_fd(io::stdout().as_raw_fd()) {
             Some(size)
@@ -92,143 +360,8787 @@ mod terminal {
         } else if let Some(size) = get_terminal_size_from_fd(io::stdin().as_raw_fd()) {
             Some(size)
         } else {
-            None
+            get_terminal_size_from_cursor_position()
+        }
+    }
+
+
+    /// Attempt to get size of terminal from the console attached to 
+    /// stdout, falling back to querying the cursor position.
+    ///
+    /// # Errors
+    /// *  Returns `None` if unable to determine terminal size from the
+    ///    console or the cursor position.
+    #[cfg(windows)]
+    pub fn get_terminal_size() -> Option<WindowSize> {
+        get_terminal_size_from_console().or_else(get_terminal_size_from_cursor_position)
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_cursor_position_reply_valid() {
+            let size = parse_cursor_position_reply(b"\x1b[24;80R").unwrap();
+            assert_eq!(size.rows, 24);
+            assert_eq!(size.cols, 80);
+        }
+
+        #[test]
+        fn parse_cursor_position_reply_malformed() {
+            assert!(parse_cursor_position_reply(b"24;80R").is_none());
+            assert!(parse_cursor_position_reply(b"\x1b[24;80").is_none());
+            assert!(parse_cursor_position_reply(b"\x1b[24R").is_none());
+            assert!(parse_cursor_position_reply(b"\x1b[0;80R").is_none());
         }
     }
 }
 
 
-mod editor {
-    use termios::Termios;
-    use std::io::{ self, Read, Write };
-    use crate::terminal;
+mod clipboard {
+    /// Replace the contents of the system clipboard with `text`. Returns
+    /// whether the clipboard was updated.
+    #[cfg(feature = "clipboard")]
+    pub fn set_text(text: &str) -> bool {
+        arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_text(text))
+            .is_ok()
+    }
 
-    pub enum EditorStatus{
-        RefershScreen,
-        TerminalExitSuccess,
-        FailedToBuild(EditorBuildError),
-        FailedToRefresh(io::Error),
-        FailedToProcessKeypress(io::Error),
+    /// Get the text contents of the system clipboard, if there is a 
+    /// clipboard available and it holds text.
+    #[cfg(feature = "clipboard")]
+    pub fn get_text() -> Option<String> {
+        arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.get_text())
+            .ok()
     }
-    
 
-    pub enum EditorBuildError {
-        UnableToGetWindowSize,
+    #[cfg(not(feature = "clipboard"))]
+    pub fn set_text(_text: &str) -> bool {
+        false
     }
-    
 
-    pub struct Editor {
-        pub status: EditorStatus,
-        pub screen_rows: usize,
-        pub screen_colums: usize,
+    #[cfg(not(feature = "clipboard"))]
+    pub fn get_text() -> Option<String> {
+        None
     }
+}
 
-    
-    impl Editor {
-        pub fn build() -> Result<Self, EditorBuildError> {
-            if let Some(size) = terminal::get_terminal_size() {
-                Ok(Self { 
-                    status: EditorStatus::RefershScreen,
-                    screen_rows: size.rows,
-                    screen_colums: size.cols,
-                })
-            } else {
-                Err(EditorBuildError::UnableToGetWindowSize)
+
+mod keys {
+    use std::io::{ self, BufRead, Read };
+    use std::time::Duration;
+    #[cfg(unix)]
+    use std::os::fd::AsFd;
+    #[cfg(windows)]
+    use std::os::windows::io::AsRawHandle;
+
+
+    /// How long to wait after an escape for the rest of an escape 
+    /// sequence before treating it as a lone `Key::Escape`.
+    const ESCAPE_SEQUENCE_TIMEOUT: Duration = Duration::from_millis(50);
+
+
+    /// A source of keypresses that can be waited on without blocking
+    /// indefinitely.
+    pub trait KeyInput: BufRead {
+        /// Wait up to `timeout` for input to become available and 
+        /// return whether there is input to read.
+        ///
+        /// # Errors
+        /// * Returns an error if unable to wait on the input.
+        fn poll_input(&mut self, timeout: Duration) -> io::Result<bool>;
+    }
+
+
+    /// In-memory input is always available, and reading past its end
+    /// returns end of file rather than blocking.
+    impl<T: AsRef<[u8]>> KeyInput for io::Cursor<T> {
+        fn poll_input(&mut self, _timeout: Duration) -> io::Result<bool> {
+            Ok(true)
+        }
+    }
+
+
+    impl<K: KeyInput + ?Sized> KeyInput for Box<K> {
+        fn poll_input(&mut self, timeout: Duration) -> io::Result<bool> {
+            (**self).poll_input(timeout)
+        }
+    }
+
+
+    #[cfg(unix)]
+    impl<R: Read + AsFd> KeyInput for io::BufReader<R> {
+        fn poll_input(&mut self, timeout: Duration) -> io::Result<bool> {
+            use rustix::event::{ poll, PollFd, PollFlags, Timespec };
+
+            if !self.buffer().is_empty() {
+                return Ok(true);
+            }
+            let timeout = Timespec::try_from(timeout)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            let mut fds = [PollFd::new(self.get_ref(), PollFlags::IN)];
+            match poll(&mut fds, Some(&timeout)) {
+                Ok(n_ready) => Ok(n_ready > 0),
+                Err(rustix::io::Errno::INTR) => Ok(false),
+                Err(err) => Err(err.into()),
             }
         }
     }
 
-    /// Processes the next keypress to stdin and updates `editor` as 
-    /// required.
+
+    #[cfg(windows)]
+    impl<R: Read + AsRawHandle> KeyInput for io::BufReader<R> {
+        fn poll_input(&mut self, timeout: Duration) -> io::Result<bool> {
+            use windows_sys::Win32::Foundation::{ WAIT_FAILED, WAIT_OBJECT_0 };
+            use windows_sys::Win32::System::Threading::WaitForSingleObject;
+
+            if !self.buffer().is_empty() {
+                return Ok(true);
+            }
+            let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+            // SAFETY: The handle is owned by the reader and stays open
+            // for the duration of the call.
+            match unsafe { WaitForSingleObject(self.get_ref().as_raw_handle(), millis) } {
+                WAIT_OBJECT_0 => Ok(true),
+                WAIT_FAILED => Err(io::Error::last_os_error()),
+                _ => Ok(false),
+            }
+        }
+    }
+
+
+    /// A single decoded keypress.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum Key {
+        Char(char),
+        Ctrl(char),
+        Enter,
+        Backspace,
+        ArrowLeft,
+        ArrowRight,
+        ArrowUp,
+        ArrowDown,
+        ShiftArrowLeft,
+        ShiftArrowRight,
+        ShiftArrowUp,
+        ShiftArrowDown,
+        CtrlArrowLeft,
+        CtrlArrowRight,
+        /// Shift-Tab.
+        BackTab,
+        Insert,
+        Home,
+        PageUp,
+        PageDown,
+        /// Start of text pasted into a terminal with bracketed paste
+        /// enabled. The text follows and is read with 
+        /// `read_bracketed_paste`.
+        PasteStart,
+        Escape,
+    }
+
+
+    impl Key {
+        /// The character the key types when it is inserted literally, 
+        /// with control keys giving their control character, or `None`
+        /// for keys that are not a single character.
+        pub fn literal(self) -> Option<char> {
+            match self {
+                Key::Char(c) => Some(c),
+                Key::Ctrl(c) => Some(char::from(c as u8 - b'a' + 1)),
+                Key::Enter => Some('\r'),
+                Key::Backspace => Some('\x7f'),
+                Key::Escape => Some('\x1b'),
+                _ => None,
+            }
+        }
+    }
+
+
+    /// Number of bytes in the UTF-8 sequence that starts with
+    /// `leading_byte`, or `None` if `leading_byte` cannot start a
+    /// sequence.
+    fn utf8_sequence_len(leading_byte: u8) -> Option<usize> {
+        match leading_byte {
+            0x00..=0x7f => Some(1),
+            0xc0..=0xdf => Some(2),
+            0xe0..=0xef => Some(3),
+            0xf0..=0xf7 => Some(4),
+            _ => None,
+        }
+    }
+
+
+    /// Read the rest of an escape sequence from `reader` after the 
+    /// leading `\x1b` has been read. Unrecognised sequences are read as
+    /// `Key::Escape`. If the next byte does not start a sequence it is
+    /// left unread so that it is returned as the next key, and if no
+    /// byte arrives shortly after the escape it is read as a lone 
+    /// `Key::Escape`.
     ///
     /// # Errors
-    /// * Returns an error if unable to read one u8 character from stdin
-    pub fn editor_process_keypress(editor: &mut Editor) -> io::Result<()> {
-        let mut buffer = [0;1];
-
-        io::stdin().read_exact(&mut buffer)?;
+    /// * Returns an error if unable to read from `reader`.
+    fn read_escape_sequence<R: KeyInput + ?Sized>(reader: &mut R) -> io::Result<Key> {
+        if !reader.poll_input(ESCAPE_SEQUENCE_TIMEOUT)? 
+            || reader.fill_buf()?.first() != Some(&b'[') 
+        {
+            return Ok(Key::Escape);
+        }
+        reader.consume(1);
 
-        match char::from(buffer[0]) {
-            'q' => editor.status = EditorStatus::TerminalExitSuccess,
-            _ => (),
+        // Parameter bytes such as the `1;2` of `\x1b[1;2D` come before
+        // the final byte that ends the sequence.
+        let mut parameters = Vec::new();
+        let mut buffer = [0; 1];
+        loop {
+            reader.read_exact(&mut buffer)?;
+            match buffer[0] {
+                0x40..=0x7e => break,
+                byte => parameters.push(byte),
+            }
         }
 
-        Ok(())
+        let key = match (parameters.as_slice(), buffer[0]) {
+            (b"", b'A') => Key::ArrowUp,
+            (b"", b'B') => Key::ArrowDown,
+            (b"", b'C') => Key::ArrowRight,
+            (b"", b'D') => Key::ArrowLeft,
+            (b"1;2", b'A') => Key::ShiftArrowUp,
+            (b"1;2", b'B') => Key::ShiftArrowDown,
+            (b"1;2", b'C') => Key::ShiftArrowRight,
+            (b"1;2", b'D') => Key::ShiftArrowLeft,
+            (b"1;5", b'C') => Key::CtrlArrowRight,
+            (b"1;5", b'D') => Key::CtrlArrowLeft,
+            (b"", b'Z') => Key::BackTab,
+            (b"", b'H') | (b"1", b'~') | (b"7", b'~') => Key::Home,
+            (b"2", b'~') => Key::Insert,
+            (b"5", b'~') => Key::PageUp,
+            (b"6", b'~') => Key::PageDown,
+            (b"200", b'~') => Key::PasteStart,
+            _ => Key::Escape,
+        };
+        Ok(key)
     }
 
 
-    fn editor_draw_rows(n_rows: usize) {
-        for _ in 0..n_rows {
-            print!("~\r\n");
+    /// Read the next key from `reader`, reading as many bytes as are
+    /// needed to decode a full UTF-8 sequence or escape sequence. 
+    /// Invalid sequences are skipped, up to the first byte that does not
+    /// continue them, which is read as the start of the next key.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read from `reader`.
+    pub fn read_key<R: KeyInput + ?Sized>(reader: &mut R) -> io::Result<Key> {
+        loop {
+            let mut buffer = [0; 4];
+            reader.read_exact(&mut buffer[..1])?;
+
+            match buffer[0] {
+                b'\x1b' => return read_escape_sequence(reader),
+                b'\r' => return Ok(Key::Enter),
+                0x7f => return Ok(Key::Backspace),
+                byte @ 0x01..=0x1a if byte != b'\t' => {
+                    return Ok(Key::Ctrl(char::from(b'a' + byte - 1)));
+                },
+                _ => (),
+            }
+
+            let Some(len) = utf8_sequence_len(buffer[0]) else {
+                continue;
+            };
+            // Only continuation bytes are consumed, so that a key cut 
+            // short by the next one does not swallow it.
+            let mut read = 1;
+            while read < len {
+                match reader.fill_buf()?.first() {
+                    Some(&byte) if byte & 0xc0 == 0x80 => {
+                        buffer[read] = byte;
+                        reader.consume(1);
+                        read += 1;
+                    },
+                    Some(_) => break,
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+            if read < len {
+                continue;
+            }
+
+            if let Some(c) = std::str::from_utf8(&buffer[..len])
+                .ok()
+                .and_then(|s| s.chars().next()) 
+            {
+                return Ok(Key::Char(c));
+            }
         }
     }
 
 
-    /// Draws the next frame by clearing the screen and redrawing the
-    /// contents of `editor`.
+    /// Read the text of a bracketed paste from `reader`, up to and 
+    /// including the `\x1b[201~` that ends it, after `Key::PasteStart`
+    /// has been read. Line breaks are converted to `\n`.
     ///
     /// # Errors
-    /// * Returns an error if the stdin.flush fails to write all bytes
-    /// to screen.
-    pub fn editor_refresh_screen(editor: & Editor) -> io::Result<()> {
-        // Clear contents of terminal
-        print!("\x1b[2J");
+    /// * Returns an error if unable to read from `reader`, or if it ends
+    ///   before the paste does.
+    pub fn read_bracketed_paste<R: BufRead + ?Sized>(reader: &mut R) -> io::Result<String> {
+        const PASTE_END: &[u8] = b"\x1b[201~";
 
-        // Move cursor to top 
-        print!("\x1b[H");
+        let mut bytes = Vec::new();
+        while !bytes.ends_with(PASTE_END) {
+            if reader.read_until(b'~', &mut bytes)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        bytes.truncate(bytes.len() - PASTE_END.len());
 
-        // Draw rows of tiles (like vim)
-        editor_draw_rows(editor.screen_rows);
+        let text = String::from_utf8_lossy(&bytes);
+        Ok(text.replace("\r\n", "\n").replace('\r', "\n"))
+    }
 
-        // Move cursor to top
-        print!("\x1b[H");
 
-        io::stdout().flush()
+    /// Read the next key from `reader`, or return `None` if no input 
+    /// arrives within `timeout`.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to wait on or read from `reader`.
+    pub fn read_key_timeout<R: KeyInput + ?Sized>(reader: &mut R, timeout: Duration) -> 
+        io::Result<Option<Key>> 
+    {
+        if !reader.poll_input(timeout)? {
+            return Ok(None);
+        }
+        read_key(reader).map(Some)
     }
 
-    /// Returns the terminal to the state defined by `original_termios`,
-    /// clears the terminal and kills the program.
-    pub fn kill_editor(original_termios: Termios, status: EditorStatus) -> ! {
-        crate::input_stream_editor::recover_original_stdin_mode(original_termios);
 
-        print!("\x1b[2J");
-        print!("\x1b[H");
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+        #[cfg(unix)]
+        use std::io::Write;
+        #[cfg(unix)]
+        use std::os::unix::net::UnixStream;
 
-        match io::stdout().flush() {
-            Ok(()) => {
-                std::process::exit(0);
-            },
+        fn read_key_from(bytes: &[u8]) -> Key {
+            read_key(&mut Cursor::new(bytes)).unwrap()
+        }
 
-            Err(err) => {
-                panic!("{}", err);
-            }
+        #[test]
+        fn read_key_ascii() {
+            assert_eq!(read_key_from(b"q"), Key::Char('q'));
+        }
+
+        #[test]
+        fn read_key_multibyte() {
+            assert_eq!(read_key_from("é".as_bytes()), Key::Char('é'));
+            assert_eq!(read_key_from("日".as_bytes()), Key::Char('日'));
+            assert_eq!(read_key_from("😀".as_bytes()), Key::Char('😀'));
+        }
+
+        #[test]
+        fn read_key_skips_invalid_sequences() {
+            // A stray continuation byte, then a leading byte followed by
+            // a byte that does not continue its sequence, which is kept.
+            assert_eq!(read_key_from(b"\x80\xc3\x28x"), Key::Char('('));
+            assert_eq!(read_key_from(b"\xe6\x97q"), Key::Char('q'));
+        }
+
+        #[test]
+        fn read_key_control_characters() {
+            assert_eq!(read_key_from(b"\r"), Key::Enter);
+            assert_eq!(read_key_from(b"\x7f"), Key::Backspace);
+            assert_eq!(read_key_from(b"\x11"), Key::Ctrl('q'));
+            assert_eq!(read_key_from(b"\x13"), Key::Ctrl('s'));
+            assert_eq!(read_key_from(b"\t"), Key::Char('\t'));
+        }
+
+        #[test]
+        fn read_key_arrows() {
+            assert_eq!(read_key_from(b"\x1b[A"), Key::ArrowUp);
+            assert_eq!(read_key_from(b"\x1b[B"), Key::ArrowDown);
+            assert_eq!(read_key_from(b"\x1b[C"), Key::ArrowRight);
+            assert_eq!(read_key_from(b"\x1b[D"), Key::ArrowLeft);
+        }
+
+        #[test]
+        fn read_key_shift_arrows() {
+            assert_eq!(read_key_from(b"\x1b[1;2A"), Key::ShiftArrowUp);
+            assert_eq!(read_key_from(b"\x1b[1;2D"), Key::ShiftArrowLeft);
+            assert_eq!(read_key_from(b"\x1b[1;5C"), Key::CtrlArrowRight);
+            assert_eq!(read_key_from(b"\x1b[1;5D"), Key::CtrlArrowLeft);
+            assert_eq!(read_key_from(b"\x1b[2~"), Key::Insert);
+            assert_eq!(read_key_from(b"\x1b[Z"), Key::BackTab);
+            assert_eq!(read_key_from(b"\x1b[H"), Key::Home);
+            assert_eq!(read_key_from(b"\x1b[1~"), Key::Home);
+            assert_eq!(read_key_from(b"\x1b[7~"), Key::Home);
+            assert_eq!(read_key_from(b"\x1b[5~"), Key::PageUp);
+            assert_eq!(read_key_from(b"\x1b[6~"), Key::PageDown);
+
+            // Unknown sequences are consumed whole
+            let mut reader = Cursor::new(b"\x1b[1;9Dx".as_slice());
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Escape);
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Char('x'));
+        }
+
+        #[test]
+        fn read_key_escape_leaves_next_key() {
+            let mut reader = Cursor::new(b"\x1bx".as_slice());
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Escape);
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Char('x'));
+            assert_eq!(read_key_from(b"\x1b"), Key::Escape);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn read_key_timeout_returns_none_without_input() {
+            let (mut sender, receiver) = UnixStream::pair().unwrap();
+            let mut reader = io::BufReader::new(receiver);
+            let timeout = Duration::from_millis(10);
+            assert_eq!(read_key_timeout(&mut reader, timeout).unwrap(), None);
+
+            sender.write_all(b"x").unwrap();
+            assert_eq!(read_key_timeout(&mut reader, timeout).unwrap(), Some(Key::Char('x')));
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn read_key_lone_escape_does_not_wait_for_next_key() {
+            let (mut sender, receiver) = UnixStream::pair().unwrap();
+            let mut reader = io::BufReader::new(receiver);
+            sender.write_all(b"\x1b").unwrap();
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Escape);
+        }
+
+        #[test]
+        fn read_bracketed_paste_reads_to_end_marker() {
+            let mut reader = Cursor::new(b"\x1b[200~a~b\r\ncd\x1b[201~x".as_slice());
+            assert_eq!(read_key(&mut reader).unwrap(), Key::PasteStart);
+            assert_eq!(read_bracketed_paste(&mut reader).unwrap(), "a~b\ncd");
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Char('x'));
+
+            let mut reader = Cursor::new(b"unterminated".as_slice());
+            assert!(read_bracketed_paste(&mut reader).is_err());
+        }
+
+        #[test]
+        fn read_key_consumes_whole_sequence() {
+            let mut reader = Cursor::new("日本".as_bytes());
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Char('日'));
+            assert_eq!(read_key(&mut reader).unwrap(), Key::Char('本'));
         }
     }
 }
 
-use editor::*;
 
-fn main() {
-    // Set up terminal and editor 
-    let original_termios = input_stream_editor::activate_stdin_raw_mode();
-    let mut editor = match Editor::build() {
-        Ok(editor) => editor,
-        Err(e) => kill_editor(original_termios, EditorStatus::FailedToBuild(e)),
-    };
+mod highlight {
+    use std::ops::Range;
 
-    loop {
-        if let Err(e) = editor_refresh_screen(&editor) {
-            editor.status = EditorStatus::FailedToRefresh(e);
+
+    /// A colour from the terminal's 256 colour palette.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Color(pub u8);
+
+    impl Color {
+        pub const RED: Color = Color(1);
+        pub const GREEN: Color = Color(2);
+        pub const YELLOW: Color = Color(3);
+        pub const MAGENTA: Color = Color(5);
+        pub const CYAN: Color = Color(6);
+
+        /// Escape sequence that sets the foreground colour to `self`.
+        pub fn escape(&self) -> String {
+            format!("\x1b[38;5;{}m", self.0)
+        }
+    }
+
+
+    /// Chooses colours for the text of each line drawn by the editor.
+    pub trait Highlighter {
+        /// Return the byte ranges of `text`, a single line without its
+        /// newline, to draw in colour. Ranges are in order and do not 
+        /// overlap.
+        fn highlight_line(&self, text: &str) -> Vec<(Range<usize>, Color)>;
+    }
+
+
+    /// A highlighter that colours numbers and double quoted strings.
+    pub struct BasicHighlighter;
+
+    impl BasicHighlighter {
+        pub const NUMBER: Color = Color::RED;
+        pub const STRING: Color = Color::MAGENTA;
+    }
+
+    impl Highlighter for BasicHighlighter {
+        fn highlight_line(&self, text: &str) -> Vec<(Range<usize>, Color)> {
+            let mut spans = Vec::new();
+            let mut chars = text.char_indices().peekable();
+            // Digits that continue an identifier such as `x1` are not 
+            // numbers.
+            let mut in_word = false;
+
+            while let Some((start, c)) = chars.next() {
+                if c == '"' {
+                    // An unterminated string runs to the end of the line
+                    let mut end = text.len();
+                    let mut escaped = false;
+                    for (i, c) in chars.by_ref() {
+                        match c {
+                            _ if escaped => escaped = false,
+                            '\\' => escaped = true,
+                            '"' => {
+                                end = i + 1;
+                                break;
+                            },
+                            _ => (),
+                        }
+                    }
+                    spans.push((start..end, Self::STRING));
+                    in_word = false;
+                } else if c.is_ascii_digit() && !in_word {
+                    let mut end = start + 1;
+                    while let Some((i, _)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                        end = i + 1;
+                    }
+                    spans.push((start..end, Self::NUMBER));
+                    in_word = true;
+                } else {
+                    in_word = c.is_alphanumeric() || c == '_';
+                }
+            }
+            spans
         }
+    }
+
 
-        if let Err(e) = editor_process_keypress(&mut editor) {
-            editor.status = EditorStatus::FailedToProcessKeypress(e);
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn basic_highlighter_colors_numbers_and_strings() {
+            let line = r#"let x1 = 42 + 3.5; print("a \"b\" 7")"#;
+            let spans = BasicHighlighter.highlight_line(line);
+            let highlighted: Vec<(&str, Color)> = spans
+                .into_iter()
+                .map(|(range, color)| (&line[range], color))
+                .collect();
+            assert_eq!(highlighted, [
+                ("42", BasicHighlighter::NUMBER),
+                ("3.5", BasicHighlighter::NUMBER),
+                (r#""a \"b\" 7""#, BasicHighlighter::STRING),
+            ]);
         }
 
-        if let EditorStatus::RefershScreen = editor.status {
-            continue;
-        } else {
-            kill_editor(original_termios, editor.status);
-        };
+        #[test]
+        fn basic_highlighter_unterminated_string_runs_to_end() {
+            let spans = BasicHighlighter.highlight_line(r#"x = "abc"#);
+            assert_eq!(spans, [(4..8, BasicHighlighter::STRING)]);
+        }
+    }
+}
+
+
+mod hooks {
+    /// Callbacks run by the editor after it changes the buffer or saves
+    /// it, for building integrations such as auto-save or language 
+    /// servers. Every callback does nothing by default.
+    pub trait EditorHook {
+        /// Called after `text` is inserted at byte offset `offset`.
+        fn on_insert(&mut self, _offset: usize, _text: &str) {}
+
+        /// Called after the bytes `start..end` are deleted, with offsets
+        /// as they were before the deletion.
+        fn on_delete(&mut self, _start: usize, _end: usize) {}
+
+        /// Called after the buffer is saved to `path`.
+        fn on_save(&mut self, _path: &str) {}
+
+        /// Called after the buffer is replaced by the contents of the 
+        /// file at `path`, discarding any unsaved changes.
+        fn on_reload(&mut self, _path: &str) {}
+    }
+}
+
+
+mod swap {
+    use std::io;
+    use std::path::{ Path, PathBuf };
+    use std::time::SystemTime;
+
+
+    /// First line of every swap file, identifying its format.
+    const SWAP_HEADER: &str = "text_editor swap 1\n";
+
+
+    /// Path of the swap file for the file at `path`: a hidden file named
+    /// `.<name>.swp` in the same directory.
+    pub fn swap_path(path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{}.swp", name))
+    }
+
+    /// Encode the contents of a buffer as a swap file. The length of 
+    /// the contents is recorded so that a swap file cut short by a crash
+    /// is not mistaken for a complete one.
+    pub fn serialize(contents: &str) -> String {
+        format!("{}{}\n{}", SWAP_HEADER, contents.len(), contents)
+    }
+
+    /// Decode the buffer contents of a swap file written by `serialize`,
+    /// or return `None` if `swap` is not a complete swap file.
+    pub fn deserialize(swap: &str) -> Option<&str> {
+        let rest = swap.strip_prefix(SWAP_HEADER)?;
+        let (len, contents) = rest.split_once('\n')?;
+        let len: usize = len.parse().ok()?;
+        (contents.len() == len).then_some(contents)
+    }
+
+    /// Whether a swap file modified at `swap_modified` holds changes 
+    /// that are newer than the file it belongs to, which was modified at
+    /// `file_modified` or does not exist if `None`.
+    pub fn is_newer(swap_modified: SystemTime, file_modified: Option<SystemTime>) -> bool {
+        file_modified.is_none_or(|file_modified| swap_modified > file_modified)
+    }
+
+    /// Write `contents` to the swap file at `swap_path`.
+    ///
+    /// # Errors
+    /// * Returns an error if the swap file cannot be written.
+    pub fn write(swap_path: &Path, contents: &str) -> io::Result<()> {
+        std::fs::write(swap_path, serialize(contents))
+    }
+
+    /// Return the contents saved in the swap file for the file at 
+    /// `path` if the swap file exists, is complete, and is newer than 
+    /// the file.
+    pub fn recoverable(path: &Path) -> Option<String> {
+        let swap_path = swap_path(path);
+        let swap_modified = std::fs::metadata(&swap_path).and_then(|m| m.modified()).ok()?;
+        let file_modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if !is_newer(swap_modified, file_modified) {
+            return None;
+        }
+        let swap = std::fs::read_to_string(&swap_path).ok()?;
+        deserialize(&swap).map(String::from)
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn swap_path_is_hidden_next_to_file() {
+            assert_eq!(swap_path(Path::new("dir/notes.txt")), Path::new("dir/.notes.txt.swp"));
+            assert_eq!(swap_path(Path::new("notes")), Path::new(".notes.swp"));
+        }
+
+        #[test]
+        fn serialize_round_trip() {
+            for contents in ["", "one\ntwo\n", "caf\u{e9}\n\n\t\u{1f600}"] {
+                assert_eq!(deserialize(&serialize(contents)), Some(contents));
+            }
+        }
+
+        #[test]
+        fn deserialize_rejects_incomplete_swap() {
+            let swap = serialize("one\ntwo\n");
+            assert_eq!(deserialize(&swap[..swap.len() - 1]), None);
+            assert_eq!(deserialize("one\ntwo\n"), None);
+            assert_eq!(deserialize(SWAP_HEADER), None);
+        }
+
+        #[test]
+        fn swap_is_newer_than_older_or_missing_file() {
+            let swap_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+            let older = SystemTime::UNIX_EPOCH + Duration::from_secs(50);
+            let newer = SystemTime::UNIX_EPOCH + Duration::from_secs(150);
+            assert!(is_newer(swap_modified, Some(older)));
+            assert!(!is_newer(swap_modified, Some(newer)));
+            assert!(!is_newer(swap_modified, Some(swap_modified)));
+            assert!(is_newer(swap_modified, None));
+        }
+
+        #[test]
+        fn recoverable_reads_newer_swap_file() {
+            let dir = std::env::temp_dir();
+            let path = dir.join(format!("editor_{}_swap.txt", std::process::id()));
+            std::fs::write(&path, "saved").unwrap();
+            assert_eq!(recoverable(&path), None);
+
+            write(&swap_path(&path), "unsaved").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+            let recovered = recoverable(&path);
+
+            std::fs::remove_file(swap_path(&path)).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(recovered.as_deref(), Some("unsaved"));
+        }
+    }
+}
+
+
+mod debug_log {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::{ self, BufRead, Read, Write };
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use crate::keys::{ Key, KeyInput };
+
+
+    /// Environment variable naming the file to write the debug log to.
+    pub const LOG_ENV_VAR: &str = "TEXT_EDITOR_LOG";
+
+
+    /// A log of every keypress, written to a file because the editor 
+    /// owns the terminal while it runs. Each line holds the bytes read 
+    /// for a key, the key they were decoded as, and where the cursor 
+    /// and scroll offsets were left after the key was handled.
+    pub struct DebugLog {
+        file: File,
+        /// Bytes read by the input given to `wrap_input` since the last
+        /// key was logged.
+        pending: Rc<RefCell<Vec<u8>>>,
+    }
+
+
+    impl DebugLog {
+        /// Create or truncate the log file at `path`.
+        ///
+        /// # Errors
+        /// * Returns an error if the file cannot be created.
+        pub fn create(path: &Path) -> io::Result<Self> {
+            Ok(Self { file: File::create(path)?, pending: Rc::default() })
+        }
+
+        /// Wrap `input` so that the bytes read from it are recorded for
+        /// the next call to `log_key`.
+        pub fn wrap_input(&self, input: Box<dyn KeyInput>) -> Box<dyn KeyInput> {
+            Box::new(LoggedInput { inner: input, pending: Rc::clone(&self.pending) })
+        }
+
+        /// Write a line for `key`. Errors are ignored, as there is 
+        /// nowhere to report them while the editor owns the terminal.
+        pub fn log_key(&mut self, key: Key, offset: usize, row_offset: usize, col_offset: usize) {
+            let bytes: Vec<String> = self.pending.take()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            let _ = writeln!(
+                self.file,
+                "bytes=[{}] key={:?} offset={} row_offset={} col_offset={}",
+                bytes.join(" "), key, offset, row_offset, col_offset
+            );
+        }
+    }
+
+
+    /// Input that records every byte read from it.
+    struct LoggedInput {
+        inner: Box<dyn KeyInput>,
+        pending: Rc<RefCell<Vec<u8>>>,
+    }
+
+
+    impl Read for LoggedInput {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n_bytes = self.inner.read(buf)?;
+            self.pending.borrow_mut().extend_from_slice(&buf[..n_bytes]);
+            Ok(n_bytes)
+        }
+    }
+
+
+    impl BufRead for LoggedInput {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.inner.fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            if let Ok(buf) = self.inner.fill_buf() {
+                let amt = amt.min(buf.len());
+                self.pending.borrow_mut().extend_from_slice(&buf[..amt]);
+            }
+            self.inner.consume(amt);
+        }
+    }
+
+
+    impl KeyInput for LoggedInput {
+        fn poll_input(&mut self, timeout: Duration) -> io::Result<bool> {
+            self.inner.poll_input(timeout)
+        }
+    }
+}
+
+
+mod keymap {
+    use std::collections::HashMap;
+    use crate::editor::{ Case, EditorMode };
+    use crate::keys::Key;
+
+
+    /// Prefix that Ctrl-V sets in insert mode, which makes the next key
+    /// be inserted literally rather than run.
+    pub const LITERAL_PREFIX: char = '\x16';
+
+
+    /// Something the editor can do in response to a key. Actions that
+    /// act on the selection or make a change act as they would for a 
+    /// key in the editor's current mode.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Action {
+        /// Quit, asking again first if there are unsaved changes.
+        Quit,
+        /// Save the buffer, prompting for a filename if it has none.
+        Save,
+        /// Prompt for text to search for.
+        Find,
+        /// Move to the next match of the last search.
+        FindNext,
+        /// Move to the previous match of the last search.
+        FindPrevious,
+        /// Prompt for text to search for and text to replace it with.
+        Replace,
+        /// Prompt for a line number to move the cursor to.
+        GoToLine,
+        /// Replace the buffer with the file it was opened from.
+        Reload,
+        /// Prompt for a command to run, as after `:`.
+        Command,
+        /// Delete from the cursor to the end of the line into the 
+        /// register.
+        KillToEndOfLine,
+        /// Swap the character before the cursor with the one under it.
+        TransposeChars,
+        /// Move the focus to the pane below, wrapping around to the top.
+        FocusNextPane,
+        /// Move the cursor one character left.
+        MoveLeft,
+        /// Move the cursor one character right.
+        MoveRight,
+        /// Move the cursor one line up.
+        MoveUp,
+        /// Move the cursor one line down.
+        MoveDown,
+        /// Move the cursor back to the start of the current or previous
+        /// word.
+        MoveWordLeft,
+        /// Move the cursor to the start of the next word.
+        MoveWordRight,
+        /// Move the cursor to the start of the line, or with smart home 
+        /// on, between the first non-whitespace character and the start.
+        MoveHome,
+        /// Move the cursor to the start of the first line.
+        GoToTop,
+        /// Move the cursor to the start of the last line.
+        GoToBottom,
+        /// Move the cursor left, extending the selection from where it
+        /// was, and starting one in visual mode if there is none.
+        SelectLeft,
+        /// Move the cursor right, extending the selection as 
+        /// `SelectLeft` does.
+        SelectRight,
+        /// Move the cursor up, extending the selection as `SelectLeft` 
+        /// does.
+        SelectUp,
+        /// Move the cursor down, extending the selection as 
+        /// `SelectLeft` does.
+        SelectDown,
+        /// Move the cursor and the view half a screen up.
+        ScrollHalfPageUp,
+        /// Move the cursor and the view half a screen down.
+        ScrollHalfPageDown,
+        /// Move the cursor and the view a screen up.
+        ScrollPageUp,
+        /// Move the cursor and the view a screen down.
+        ScrollPageDown,
+        /// Scroll so that the cursor is on the middle row of the screen.
+        CenterCursor,
+        /// Move the cursor back to the previous place in the jump list.
+        JumpBack,
+        /// Move the cursor forward to the next place in the jump list.
+        JumpForward,
+        /// Set the mark named by the character to the cursor.
+        SetMark(char),
+        /// Move the cursor to the mark named by the character.
+        JumpToMark(char),
+        /// Enter normal mode, ending the insertion that `.` repeats.
+        EnterNormalMode,
+        /// Enter insert mode.
+        EnterInsertMode,
+        /// Enter visual mode, selecting from the cursor.
+        EnterVisualMode,
+        /// Enter visual block mode, selecting a block from the cursor.
+        EnterVisualBlockMode,
+        /// Switch between insert and replace mode.
+        ToggleReplace,
+        /// Insert the character in insert mode, or replace the 
+        /// character under the cursor in replace mode.
+        InsertChar(char),
+        /// Split the line at the cursor, indenting the new line like the
+        /// cursor line.
+        InsertNewline,
+        /// Delete the character before the cursor.
+        DeleteBack,
+        /// Delete the character under the cursor.
+        DeleteChar,
+        /// Delete the cursor line into the register.
+        KillLine,
+        /// Copy the cursor line into the register.
+        CopyLine,
+        /// Join the next line onto the cursor line.
+        JoinLines,
+        /// Insert a copy of the cursor line below it.
+        DuplicateLine,
+        /// Insert the register or clipboard at the cursor, or in place 
+        /// of the selection.
+        Paste,
+        /// Indent the cursor line or selected lines.
+        Indent,
+        /// Remove a level of indentation from the cursor line or 
+        /// selected lines.
+        Dedent,
+        /// Comment the cursor line or selected lines, or uncomment them
+        /// if they are all commented.
+        ToggleComment,
+        /// Change the case of the character under the cursor or of the
+        /// selection.
+        ChangeCase(Case),
+        /// Delete the selection into the register.
+        DeleteSelection,
+        /// Copy the selection into the register.
+        CopySelection,
+        /// Prompt for text to insert on every line of the block 
+        /// selection.
+        InsertInBlock,
+        /// Complete the word before the cursor with a word from the 
+        /// buffer, or with the next such word if the previous key did.
+        CompleteWord,
+        /// Repeat the last change, as `.` does.
+        RepeatChange,
+        /// Undo the last change.
+        Undo,
+        /// Redo the last change undone.
+        Redo,
+        /// Start recording a macro into the register, or stop recording
+        /// if a macro is being recorded.
+        ToggleRecording,
+        /// Start recording a macro into the register named by the 
+        /// character.
+        StartRecording(char),
+        /// Play the macro recorded into the register named by the 
+        /// character.
+        PlayMacro(char),
+        /// Play the macro played most recently again.
+        PlayLastMacro,
+        /// Wait for the next key to complete a multi-key command that 
+        /// starts with the character, such as the first `d` of `dd`.
+        Prefix(char),
+    }
+
+    impl Action {
+        /// Whether the action edits the buffer, or enters a mode or 
+        /// starts a command that does, so is refused in read-only mode.
+        pub fn edits_buffer(self) -> bool {
+            matches!(
+                self, 
+                Action::Replace 
+                    | Action::KillToEndOfLine 
+                    | Action::TransposeChars
+                    | Action::EnterInsertMode
+                    | Action::ToggleReplace
+                    | Action::InsertChar(_)
+                    | Action::InsertNewline
+                    | Action::DeleteBack
+                    | Action::DeleteChar
+                    | Action::KillLine
+                    | Action::JoinLines
+                    | Action::DuplicateLine
+                    | Action::Paste
+                    | Action::Indent
+                    | Action::Dedent
+                    | Action::ToggleComment
+                    | Action::ChangeCase(_)
+                    | Action::DeleteSelection
+                    | Action::InsertInBlock
+                    | Action::CompleteWord
+                    | Action::RepeatChange
+                    | Action::Undo
+                    | Action::Redo
+                    | Action::Prefix('d')
+            )
+        }
+
+        /// Whether a count typed before the action runs it that many 
+        /// times.
+        pub fn takes_count(self) -> bool {
+            matches!(
+                self,
+                Action::MoveLeft
+                    | Action::MoveRight
+                    | Action::MoveUp
+                    | Action::MoveDown
+                    | Action::MoveWordLeft
+                    | Action::MoveWordRight
+                    | Action::SelectLeft
+                    | Action::SelectRight
+                    | Action::SelectUp
+                    | Action::SelectDown
+                    | Action::ScrollHalfPageUp
+                    | Action::ScrollHalfPageDown
+                    | Action::ScrollPageUp
+                    | Action::ScrollPageDown
+                    | Action::FindNext
+                    | Action::FindPrevious
+                    | Action::TransposeChars
+                    | Action::DeleteChar
+                    | Action::KillLine
+                    | Action::JoinLines
+                    | Action::DuplicateLine
+                    | Action::Paste
+                    | Action::Indent
+                    | Action::Dedent
+                    | Action::ChangeCase(_)
+                    | Action::RepeatChange
+                    | Action::Undo
+                    | Action::Redo
+                    | Action::PlayMacro(_)
+                    | Action::PlayLastMacro
+            )
+        }
+
+        /// Whether the action moves the cursor up or down lines, so 
+        /// keeps the column that vertical movement aims for.
+        pub fn moves_vertically(self) -> bool {
+            matches!(
+                self,
+                Action::MoveUp
+                    | Action::MoveDown
+                    | Action::SelectUp
+                    | Action::SelectDown
+                    | Action::ScrollHalfPageUp
+                    | Action::ScrollHalfPageDown
+                    | Action::ScrollPageUp
+                    | Action::ScrollPageDown
+            )
+        }
+    }
+
+
+    /// The action bound to each key. A key can be bound in every mode, 
+    /// or in a single mode where it takes precedence over a binding in 
+    /// every mode.
+    pub struct KeyMap {
+        global: HashMap<Key, Action>,
+        modes: HashMap<(EditorMode, Key), Action>,
+    }
+
+    impl KeyMap {
+        /// A keymap with no keys bound.
+        pub fn empty() -> Self {
+            Self { global: HashMap::new(), modes: HashMap::new() }
+        }
+
+        /// Bind `key` to `action` in every mode.
+        pub fn bind_global(&mut self, key: Key, action: Action) {
+            self.global.insert(key, action);
+        }
+
+        /// Bind `key` to `action` in `mode`.
+        pub fn bind(&mut self, mode: EditorMode, key: Key, action: Action) {
+            self.modes.insert((mode, key), action);
+        }
+
+        /// The action bound to `key` in `mode`, if any.
+        pub fn get(&self, mode: EditorMode, key: Key) -> Option<Action> {
+            self.modes.get(&(mode, key)).or_else(|| self.global.get(&key)).copied()
+        }
+
+        /// The action bound to `key` in every mode, if any.
+        pub fn get_global(&self, key: Key) -> Option<Action> {
+            self.global.get(&key).copied()
+        }
+    }
+
+    impl Default for KeyMap {
+        fn default() -> Self {
+            use EditorMode::{ Insert, Normal, Replace, Visual, VisualBlock };
+
+            let mut keymap = Self::empty();
+            for (key, action) in [
+                (Key::Ctrl('q'), Action::Quit),
+                (Key::Ctrl('s'), Action::Save),
+                (Key::Ctrl('f'), Action::Find),
+                (Key::Ctrl('e'), Action::Replace),
+                (Key::Ctrl('g'), Action::GoToLine),
+                (Key::Ctrl('r'), Action::Reload),
+                (Key::Ctrl('k'), Action::KillToEndOfLine),
+                (Key::Ctrl('t'), Action::TransposeChars),
+                (Key::Ctrl('w'), Action::FocusNextPane),
+                (Key::ArrowLeft, Action::MoveLeft),
+                (Key::ArrowRight, Action::MoveRight),
+                (Key::ArrowUp, Action::MoveUp),
+                (Key::ArrowDown, Action::MoveDown),
+                (Key::CtrlArrowLeft, Action::MoveWordLeft),
+                (Key::CtrlArrowRight, Action::MoveWordRight),
+                (Key::Home, Action::MoveHome),
+                (Key::PageUp, Action::ScrollPageUp),
+                (Key::PageDown, Action::ScrollPageDown),
+                (Key::ShiftArrowLeft, Action::SelectLeft),
+                (Key::ShiftArrowRight, Action::SelectRight),
+                (Key::ShiftArrowUp, Action::SelectUp),
+                (Key::ShiftArrowDown, Action::SelectDown),
+            ] {
+                keymap.bind_global(key, action);
+            }
+
+            for mode in [Normal, Visual, VisualBlock] {
+                for (key, action) in [
+                    (Key::Char('h'), Action::MoveLeft),
+                    (Key::Char('j'), Action::MoveDown),
+                    (Key::Char('k'), Action::MoveUp),
+                    (Key::Char('l'), Action::MoveRight),
+                    (Key::Escape, Action::EnterNormalMode),
+                    (Key::Ctrl('u'), Action::ScrollHalfPageUp),
+                    (Key::Ctrl('d'), Action::ScrollHalfPageDown),
+                    (Key::Char('G'), Action::GoToBottom),
+                ] {
+                    keymap.bind(mode, key, action);
+                }
+            }
+
+            for (key, action) in [
+                (Key::Char('i'), Action::EnterInsertMode),
+                (Key::Insert, Action::EnterInsertMode),
+                (Key::Char('v'), Action::EnterVisualMode),
+                (Key::Ctrl('v'), Action::EnterVisualBlockMode),
+                (Key::Char('x'), Action::DeleteChar),
+                (Key::Char('.'), Action::RepeatChange),
+                (Key::Char('u'), Action::Undo),
+                (Key::Char('U'), Action::Redo),
+                (Key::Char(':'), Action::Command),
+                (Key::Char('d'), Action::Prefix('d')),
+                (Key::Char('y'), Action::Prefix('y')),
+                (Key::Char('z'), Action::Prefix('z')),
+                (Key::Char('g'), Action::Prefix('g')),
+                (Key::Char('@'), Action::Prefix('@')),
+                (Key::Char('m'), Action::Prefix('m')),
+                (Key::Char('`'), Action::Prefix('`')),
+                (Key::Char('q'), Action::ToggleRecording),
+                (Key::Char('~'), Action::ChangeCase(Case::Toggle)),
+                (Key::Ctrl('o'), Action::JumpBack),
+                // Ctrl-I is the same byte as Tab, which indents, so 
+                // jumping forward is on Ctrl-N instead.
+                (Key::Ctrl('n'), Action::JumpForward),
+                (Key::Char('J'), Action::JoinLines),
+                (Key::Char('p'), Action::Paste),
+                (Key::Char('n'), Action::FindNext),
+                (Key::Char('N'), Action::FindPrevious),
+                (Key::Char('>'), Action::Indent),
+                (Key::Char('\t'), Action::Indent),
+                (Key::Char('<'), Action::Dedent),
+                (Key::BackTab, Action::Dedent),
+            ] {
+                keymap.bind(Normal, key, action);
+            }
+
+            for mode in [Insert, Replace] {
+                for (key, action) in [
+                    (Key::Escape, Action::EnterNormalMode),
+                    (Key::Insert, Action::ToggleReplace),
+                    (Key::Enter, Action::InsertNewline),
+                    (Key::Backspace, Action::DeleteBack),
+                    (Key::BackTab, Action::Dedent),
+                    (Key::Ctrl('u'), Action::Paste),
+                    (Key::Ctrl('d'), Action::DuplicateLine),
+                ] {
+                    keymap.bind(mode, key, action);
+                }
+            }
+            keymap.bind(Insert, Key::Ctrl('n'), Action::CompleteWord);
+            for mode in [Insert, Replace] {
+                keymap.bind(mode, Key::Ctrl('v'), Action::Prefix(LITERAL_PREFIX));
+            }
+
+            for (key, action) in [
+                (Key::Char('v'), Action::EnterNormalMode),
+                (Key::Char('d'), Action::DeleteSelection),
+                (Key::Char('x'), Action::DeleteSelection),
+                (Key::Char('y'), Action::CopySelection),
+                (Key::Char('U'), Action::ChangeCase(Case::Upper)),
+                (Key::Char('u'), Action::ChangeCase(Case::Lower)),
+                (Key::Char('~'), Action::ChangeCase(Case::Toggle)),
+                (Key::Char('>'), Action::Indent),
+                (Key::Char('\t'), Action::Indent),
+                (Key::Char('<'), Action::Dedent),
+                (Key::BackTab, Action::Dedent),
+                (Key::Char('g'), Action::Prefix('g')),
+                (Key::Char('p'), Action::Paste),
+            ] {
+                keymap.bind(Visual, key, action);
+            }
+
+            for (key, action) in [
+                (Key::Ctrl('v'), Action::EnterNormalMode),
+                (Key::Char('d'), Action::DeleteSelection),
+                (Key::Char('x'), Action::DeleteSelection),
+                (Key::Char('I'), Action::InsertInBlock),
+            ] {
+                keymap.bind(VisualBlock, key, action);
+            }
+            keymap
+        }
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mode_binding_takes_precedence() {
+            let mut keymap = KeyMap::default();
+            assert_eq!(keymap.get(EditorMode::Insert, Key::Char('h')), None);
+            assert_eq!(keymap.get(EditorMode::Normal, Key::Char('h')), Some(Action::MoveLeft));
+
+            keymap.bind(EditorMode::Insert, Key::Ctrl('s'), Action::Quit);
+            assert_eq!(keymap.get(EditorMode::Insert, Key::Ctrl('s')), Some(Action::Quit));
+            assert_eq!(keymap.get(EditorMode::Normal, Key::Ctrl('s')), Some(Action::Save));
+            assert_eq!(keymap.get_global(Key::Ctrl('s')), Some(Action::Save));
+        }
+    }
+}
+
+
+mod editor {
+    use std::cell::Cell;
+    #[cfg(feature = "regex")]
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::{ self, Write };
+    use std::ops::Range;
+    use std::panic::{ self, AssertUnwindSafe };
+    use std::path::{ Path, PathBuf };
+    use std::time::{ Duration, Instant };
+    use text_editor::piece_table::{ 
+        Edit, LineEnding, PieceTable, PieceTableError, Position, SearchOptions 
+    };
+    #[cfg(feature = "regex")]
+    use text_editor::piece_table::{ RegexMatches, SearchRegex };
+    use crate::terminal::{ self, WindowSize };
+    use crate::input_stream_editor::OriginalStdinMode;
+    use crate::keys::{ self, Key, KeyInput };
+    use crate::clipboard;
+    use crate::highlight::{ BasicHighlighter, Color, Highlighter };
+    use crate::hooks::EditorHook;
+    use crate::keymap::{ Action, KeyMap, LITERAL_PREFIX };
+    use crate::swap;
+    use crate::debug_log::DebugLog;
+    use unicode_width::{ UnicodeWidthChar, UnicodeWidthStr };
+
+    pub enum EditorStatus{
+        RefershScreen,
+        TerminalExitSuccess,
+        FailedToBuild(EditorBuildError),
+        FailedToRefresh(io::Error),
+        FailedToProcessKeypress(io::Error),
+        /// Input reached end of file while the buffer had unsaved 
+        /// changes, so there is no way to save or confirm quitting. The 
+        /// editor exits with status 1 so that scripts driving it through 
+        /// stdin can tell that the changes were lost.
+        InputClosedWithUnsavedChanges,
+    }
+    
+
+    pub enum EditorBuildError {
+        UnableToGetWindowSize,
+        UnableToOpenFile(PieceTableError),
+    }
+    
+
+    /// Settings that tune how the editor behaves.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct EditorConfig {
+        /// Number of columns between tab stops when rendering tabs.
+        pub tab_width: usize,
+        /// Draw a gutter containing the line number of each row.
+        pub show_line_numbers: bool,
+        /// Number the rows of the gutter by their distance from the 
+        /// cursor line, which shows its own line number, like vim's 
+        /// `relativenumber`. Draws the gutter even if 
+        /// `show_line_numbers` is off.
+        pub relative_line_numbers: bool,
+        /// Character drawn between the gutter and the text. It should 
+        /// take up a single column.
+        pub gutter_separator: char,
+        /// Number of additional times quit must be requested before
+        /// exiting with unsaved changes.
+        pub quit_confirmations: usize,
+        /// Number of lines kept visible above and below the cursor when
+        /// scrolling.
+        pub scroll_off: usize,
+        /// Insert `tab_width` spaces rather than a tab character when
+        /// indenting.
+        pub use_spaces_for_tabs: bool,
+        /// Move the cursor over whole grapheme clusters (such as a 
+        /// letter followed by a combining accent) rather than over 
+        /// single characters.
+        pub grapheme_movement: bool,
+        /// Leave spaces and tabs at the end of lines out of saved files.
+        pub trim_trailing_whitespace: bool,
+        /// End saved files with a newline if the buffer does not end
+        /// with one.
+        pub ensure_final_newline: bool,
+        /// Leave blank lines, and lines of only spaces and tabs, at the 
+        /// end of the buffer out of saved files, so that they end with a
+        /// single newline.
+        pub trim_final_newlines: bool,
+        /// Save a modified buffer that has a filename once no key has 
+        /// been pressed for this long, or never if `None`.
+        pub auto_save_interval: Option<Duration>,
+        /// Copy the file to `<file>.bak` before it is first overwritten
+        /// in a session.
+        pub make_backup: bool,
+        /// Wrap lines wider than the screen onto the following rows 
+        /// instead of scrolling horizontally.
+        pub wrap: bool,
+        /// Shape of the cursor in normal and visual modes.
+        pub normal_cursor: CursorShape,
+        /// Shape of the cursor in insert mode.
+        pub insert_cursor: CursorShape,
+        /// Shape of the cursor in replace mode.
+        pub replace_cursor: CursorShape,
+        /// Make the cursor blink.
+        pub cursor_blink: bool,
+        /// Move Home to the first non-whitespace character of the line,
+        /// or to the start of the line if already there, rather than 
+        /// always to the start.
+        pub smart_home: bool,
+        /// Copy to and paste from the system clipboard, when the editor
+        /// is built with the `clipboard` feature and one is available.
+        pub system_clipboard: bool,
+        /// Colour numbers and strings in every buffer, rather than only
+        /// in files of a known type other than plain text.
+        pub highlight_all: bool,
+    }
+
+
+    impl Default for EditorConfig {
+        fn default() -> Self {
+            Self {
+                tab_width: 8,
+                show_line_numbers: false,
+                relative_line_numbers: false,
+                gutter_separator: ' ',
+                quit_confirmations: 2,
+                scroll_off: 0,
+                use_spaces_for_tabs: false,
+                grapheme_movement: false,
+                trim_trailing_whitespace: false,
+                ensure_final_newline: false,
+                trim_final_newlines: false,
+                auto_save_interval: None,
+                make_backup: false,
+                wrap: false,
+                normal_cursor: CursorShape::Block,
+                insert_cursor: CursorShape::Bar,
+                replace_cursor: CursorShape::Underline,
+                cursor_blink: false,
+                smart_home: true,
+                system_clipboard: true,
+                highlight_all: false,
+            }
+        }
+    }
+
+
+    /// Shape the terminal draws the cursor in.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CursorShape {
+        Block,
+        Underline,
+        Bar,
+    }
+
+
+    impl CursorShape {
+        /// DECSCUSR escape sequence that sets the cursor to this shape.
+        pub fn escape(self, blink: bool) -> String {
+            let steady = match self {
+                CursorShape::Block => 2,
+                CursorShape::Underline => 4,
+                CursorShape::Bar => 6,
+            };
+            format!("\x1b[{} q", steady - usize::from(blink))
+        }
+    }
+
+
+    /// DECSCUSR escape sequence that restores the terminal's default 
+    /// cursor.
+    pub const DEFAULT_CURSOR_ESCAPE: &str = "\x1b[0 q";
+
+
+    /// The mode that determines how keys are interpreted.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum EditorMode {
+        /// Keys are commands that move the cursor or edit the buffer.
+        Normal,
+        /// Keys insert text into the buffer.
+        Insert,
+        /// Keys replace the text under the cursor.
+        Replace,
+        /// Keys move the cursor to select text, or act on the selection.
+        Visual,
+        /// Keys move the cursor to select a rectangle of columns across 
+        /// lines, or act on the rectangle.
+        VisualBlock,
+    }
+
+
+    /// A rectangular selection made in visual block mode.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Block {
+        /// Lines of the buffer the block spans.
+        pub rows: Range<usize>,
+        /// Render columns the block spans on each line.
+        pub cols: Range<usize>,
+    }
+
+
+    pub struct Editor {
+        pub status: EditorStatus,
+        pub screen_rows: usize,
+        pub screen_colums: usize,
+        pub config: EditorConfig,
+        pub buffer: PieceTable,
+        pub cursor_row: usize,
+        pub cursor_col: usize,
+        pub row_offset: usize,
+        pub col_offset: usize,
+        pub filename: Option<String>,
+        pub dirty: bool,
+        pub message: String,
+        pub message_time: Instant,
+        /// When the last key was read, for timing auto-saves.
+        pub keypress_time: Instant,
+        /// Crash recovery file the buffer is periodically written to, or
+        /// `None` if there is none.
+        pub swap_path: Option<PathBuf>,
+        /// When the swap file was last written.
+        pub swap_time: Instant,
+        /// Whether the file has been saved this session, so that a 
+        /// backup is no longer needed.
+        pub saved_once: bool,
+        pub mode: EditorMode,
+        pub quit_times: usize,
+        /// First key of a multi-key normal mode command, such as the 
+        /// first `d` of `dd`.
+        pub pending_key: Option<char>,
+        /// Count typed before a normal or visual mode command, such as 
+        /// the `3` of `3j`, which runs the command that many times.
+        pub pending_count: Option<usize>,
+        /// Text most recently killed or deleted, which can be pasted back
+        /// into the buffer.
+        pub register: String,
+        /// Contents of the system clipboard when the register was last 
+        /// set, so that paste can tell whether something else has been 
+        /// copied since.
+        pub clipboard_text: Option<String>,
+        /// Whether the previous key killed text, so that another kill 
+        /// should append to the register rather than replace it.
+        pub kill_appending: bool,
+        /// Column on screen, with tabs expanded, that moving up and down
+        /// lines aims for, kept while the cursor only moves vertically so
+        /// that it returns to the column after crossing shorter lines.
+        pub desired_col: Option<usize>,
+        /// Word completion in progress, kept while the completion key 
+        /// is pressed repeatedly to cycle through the candidates.
+        pub completion: Option<Completion>,
+        /// Byte offset the selection extends from to the cursor, set 
+        /// while in visual mode.
+        pub selection_anchor: Option<usize>,
+        /// Whether edits to the buffer and saving are refused.
+        pub read_only: bool,
+        /// Show the buffer as a dump of its bytes instead of as lines. 
+        /// Edits are refused while it is shown.
+        pub hex_view: bool,
+        /// Byte offset of the cursor in the hex view.
+        pub hex_cursor: usize,
+        /// The bytes the hex view shows when they differ from those of 
+        /// the buffer, as they do when the file has a byte order mark or
+        /// `\r\n` line endings that the buffer's text leaves out.
+        hex_bytes: Option<PieceTable>,
+        /// Colours the text of each line, or `None` to draw plain text.
+        pub highlighter: Option<Box<dyn Highlighter>>,
+        /// Keys recorded into each macro register by `q`.
+        pub macros: HashMap<char, Vec<Key>>,
+        /// Register being recorded into and the keys recorded so far.
+        pub recording: Option<(char, Vec<Key>)>,
+        /// Register of the last macro played, which `@@` plays again.
+        pub last_macro: Option<char>,
+        /// Registers of the macros being played, innermost last.
+        playing_macros: Vec<char>,
+        /// The last change made in normal mode and the count it was 
+        /// made with, which `.` repeats.
+        pub last_change: Option<(Change, usize)>,
+        /// Text typed since insert mode was entered from normal mode, or
+        /// `None` if the insertion can no longer be repeated.
+        insert_run: Option<String>,
+        /// Byte offsets set as marks by `m`.
+        pub marks: HashMap<char, usize>,
+        /// Byte offsets the cursor jumped away from, oldest first.
+        pub jumps: Vec<usize>,
+        /// Position in `jumps` that `Ctrl-O` and `Ctrl-N` move from, 
+        /// equal to its length unless moving through the list.
+        pub jump_index: usize,
+        /// Text of the last search, which `n` and `N` search for again.
+        pub last_search: Option<String>,
+        /// Search term whose matches are highlighted, or `None` if the
+        /// search has been dismissed.
+        pub search_highlight: Option<String>,
+        /// Treat search terms as regular expressions rather than as 
+        /// literal text.
+        pub regex_search: bool,
+        /// Whether searches ignore case and match only whole words.
+        pub search_options: SearchOptions,
+        /// The pattern last searched for as a regular expression and 
+        /// its compiled form, kept so that redrawing the matches does 
+        /// not compile it again.
+        #[cfg(feature = "regex")]
+        compiled_search: RefCell<Option<(String, SearchRegex)>>,
+        /// Run after each edit and save, in the order they were added.
+        pub hooks: Vec<Box<dyn EditorHook>>,
+        /// Action each key is bound to. Keys that are not bound are 
+        /// handled by the editor's built-in commands.
+        pub keymap: KeyMap,
+        /// Every open buffer. The active buffer's state lives in the
+        /// editor's own fields while it is active, so its entry here is
+        /// out of date until another buffer is switched to.
+        pub buffers: Vec<BufferState>,
+        /// Index into `buffers` of the buffer being edited.
+        pub active_buffer: usize,
+        /// The view shown in each pane, from the top of the screen 
+        /// down. The focused pane's view lives in the editor's own 
+        /// fields, so its entry here is out of date until the focus 
+        /// moves.
+        pub panes: Vec<View>,
+        /// Index into `panes` of the pane keys are sent to.
+        pub active_pane: usize,
+        /// Escape sequence of the cursor shape last sent to the 
+        /// terminal, so that it is only sent again when it changes.
+        drawn_cursor: Option<String>,
+        /// Rows of the last frame drawn to the terminal and the screen 
+        /// size they were drawn for, so that the next frame only needs
+        /// to draw the rows that changed.
+        drawn_rows: Vec<String>,
+        drawn_size: Option<(usize, usize)>,
+        /// Log of keypresses for debugging, if one was asked for.
+        pub debug_log: Option<DebugLog>,
+        pub input: Box<dyn KeyInput>,
+        pub output: Box<dyn Write>,
+    }
+
+
+    /// The state of an open buffer that is kept while another buffer is
+    /// being edited.
+    pub struct BufferState {
+        pub buffer: PieceTable,
+        pub filename: Option<String>,
+        pub dirty: bool,
+        pub cursor_row: usize,
+        pub cursor_col: usize,
+        pub row_offset: usize,
+        pub col_offset: usize,
+        pub saved_once: bool,
+        pub swap_path: Option<PathBuf>,
+        pub marks: HashMap<char, usize>,
+        pub jumps: Vec<usize>,
+        pub jump_index: usize,
+    }
+
+    /// The part of a buffer shown in a pane and the cursor within it.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct View {
+        /// Index into `Editor::buffers` of the buffer shown.
+        pub buffer_index: usize,
+        pub cursor_row: usize,
+        pub cursor_col: usize,
+        pub row_offset: usize,
+        pub col_offset: usize,
+    }
+
+
+    impl BufferState {
+        /// State for `buffer` backed by `filename`, with the cursor at 
+        /// the start of the buffer.
+        pub fn new(buffer: PieceTable, filename: Option<String>) -> Self {
+            Self {
+                buffer,
+                filename,
+                dirty: false,
+                cursor_row: 0,
+                cursor_col: 0,
+                row_offset: 0,
+                col_offset: 0,
+                saved_once: false,
+                swap_path: None,
+                marks: HashMap::new(),
+                jumps: Vec::new(),
+                jump_index: 0,
+            }
+        }
+    }
+
+
+    /// A change to the case of letters.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Case {
+        Upper,
+        Lower,
+        /// Upper case letters become lower case and lower case letters
+        /// become upper case.
+        Toggle,
+    }
+
+    impl Case {
+        /// Change the case of the letters in `text`. Unicode case 
+        /// mappings can change the length of the text, such as `ß` 
+        /// becoming `SS`.
+        pub fn apply(self, text: &str) -> String {
+            match self {
+                Case::Upper => text.to_uppercase(),
+                Case::Lower => text.to_lowercase(),
+                Case::Toggle => text.chars()
+                    .flat_map(|c| -> Box<dyn Iterator<Item = char>> {
+                        if c.is_uppercase() {
+                            Box::new(c.to_lowercase())
+                        } else {
+                            Box::new(c.to_uppercase())
+                        }
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+
+    /// Size of the buffer as counted by `:wc`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct WordCount {
+        pub lines: usize,
+        /// Number of runs of non-whitespace characters.
+        pub words: usize,
+        pub chars: usize,
+    }
+
+
+    /// A word completion started by `Action::CompleteWord`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Completion {
+        /// Byte offset of the start of the word being completed.
+        pub start: usize,
+        /// Words in the buffer that complete the word, in the order they
+        /// first appear.
+        pub candidates: Vec<String>,
+        /// Index into `candidates` of the word inserted.
+        pub index: usize,
+    }
+
+
+    /// Whether `c` can be part of a word for word completion.
+    fn is_word_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+
+    /// Where `offset` ends up once each range in `changes` has been 
+    /// replaced with text of the given length. The ranges are offsets 
+    /// before any of the replacements and must not overlap. An offset 
+    /// inside a replaced range is kept as far into the replacement as 
+    /// it fits.
+    fn offset_after_changes(
+        offset: usize, 
+        changes: impl IntoIterator<Item = (Range<usize>, usize)>
+    ) -> usize {
+        let mut shifted = offset;
+        for (range, new_len) in changes {
+            if range.end <= offset {
+                shifted = shifted + new_len - range.len();
+            } else if range.start < offset {
+                let into = offset - range.start;
+                shifted = shifted - into + into.min(new_len);
+            }
+        }
+        shifted
+    }
+
+
+    /// Colour of the bracket under the cursor and its match.
+    const MATCHED_BRACKET_COLOR: Color = Color::CYAN;
+
+    /// Colour of the bracket under the cursor when it has no match.
+    const UNMATCHED_BRACKET_COLOR: Color = Color::RED;
+
+    /// Colour of matches of the search term.
+    const SEARCH_MATCH_COLOR: Color = Color::YELLOW;
+
+    /// Colour of the match of the search term at the cursor.
+    const CURRENT_MATCH_COLOR: Color = Color::GREEN;
+
+    /// Shown a third of the way down the screen when the editor has an
+    /// empty buffer that has not been named.
+    const WELCOME_MESSAGE: &str = concat!("Text editor -- version ", env!("CARGO_PKG_VERSION"));
+
+    /// Shown in place of the editor when the screen is too small to draw
+    /// it on.
+    const TOO_SMALL_MESSAGE: &str = "Terminal too small";
+
+    /// Message shown when a hook panics and is removed.
+    const HOOK_PANICKED_MESSAGE: &str = "Disabled a hook that panicked";
+
+    thread_local! {
+        /// Whether a hook is being run on this thread.
+        static RUNNING_HOOK: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Install a panic hook that keeps quiet about hooks panicking, as 
+    /// they are reported on the message bar and writing to stderr in raw
+    /// mode would garble the screen. Any other panic is passed on to the
+    /// panic hook that was installed before.
+    fn silence_hook_panics() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            let previous_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !RUNNING_HOOK.with(Cell::get) {
+                    previous_hook(info);
+                }
+            }));
+        });
+    }
+
+    /// Message shown when an edit or save is refused in read-only mode.
+    const READ_ONLY_MESSAGE: &str = "File is read-only";
+
+    /// Message shown when quitting is refused because a buffer other 
+    /// than the active one has unsaved changes.
+    const OTHER_BUFFERS_DIRTY_MESSAGE: &str = 
+        "Another buffer has unsaved changes (add ! to override)";
+
+    /// How long a message stays on the message bar.
+    const MESSAGE_DURATION: Duration = Duration::from_secs(5);
+
+    /// Size in bytes above which files are mapped into memory rather 
+    /// than read into it.
+    const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+    /// Number of jumps remembered by the jump list.
+    const JUMP_LIST_LEN: usize = 100;
+
+    /// Minimum time between writes of the swap file.
+    const SWAP_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// How long to wait for a keypress before checking whether the 
+    /// screen needs redrawing anyway.
+    const KEYPRESS_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Largest count that can be typed before a command.
+    const MAX_COUNT: usize = 9999;
+
+    /// Number of bytes shown on each row of the hex view.
+    const HEX_BYTES_PER_ROW: usize = 16;
+
+    /// Number of bytes at the start of a buffer that are checked for 
+    /// NUL bytes to decide whether it looks like a binary file.
+    const BINARY_CHECK_LEN: usize = 8000;
+
+    
+    /// Load the file at `path` into a buffer, mapping it into memory if
+    /// it is larger than `MMAP_THRESHOLD`. A file that is not valid 
+    /// UTF-8 is loaded as bytes, to be shown read-only in the hex view.
+    ///
+    /// # Errors
+    /// * Returns an error if the file cannot be read.
+    fn load_file(path: &str) -> Result<PieceTable, PieceTableError> {
+        let size = std::fs::metadata(path).map_err(PieceTableError::IOError)?.len();
+        let loaded = if size > MMAP_THRESHOLD {
+            PieceTable::from_file_mmap(path)
+        } else {
+            PieceTable::from_file(path)
+        };
+        match loaded {
+            Err(PieceTableError::IOError(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                PieceTable::from_file_bytes(path)
+            },
+            loaded => loaded,
+        }
+    }
+
+
+    /// The highlighter for a buffer holding the file `path`, if there is
+    /// one and `config` asks for it to be highlighted.
+    fn default_highlighter(config: &EditorConfig, path: Option<&str>) -> Option<Box<dyn Highlighter>> {
+        let known_type = path.and_then(file_type).is_some_and(|file_type| file_type != "text");
+        if config.highlight_all || known_type {
+            Some(Box::new(BasicHighlighter))
+        } else {
+            None
+        }
+    }
+
+    
+    impl Editor {
+        /// Build an editor sized to the current terminal, containing the
+        /// contents of the file at `path` if one is given.
+        pub fn build(path: Option<&str>) -> Result<Self, EditorBuildError> {
+            let size = terminal::get_terminal_size()
+                .ok_or(EditorBuildError::UnableToGetWindowSize)?;
+            let buffer = match path {
+                Some(path) => load_file(path)
+                    .map_err(EditorBuildError::UnableToOpenFile)?,
+                None => PieceTable::from_str(""),
+            };
+            let mut editor = Self::new(size, EditorConfig::default(), buffer);
+            editor.filename = path.map(String::from);
+            editor.highlighter = default_highlighter(&editor.config, path);
+            Ok(editor)
+        }
+
+        /// Create an editor of `size` configured by `config` containing
+        /// `buffer`.
+        pub fn new(size: WindowSize, config: EditorConfig, buffer: PieceTable) -> Self {
+            Self { 
+                status: EditorStatus::RefershScreen,
+                quit_times: config.quit_confirmations,
+                pending_key: None,
+                pending_count: None,
+                register: String::new(),
+                clipboard_text: None,
+                kill_appending: false,
+                desired_col: None,
+                completion: None,
+                selection_anchor: None,
+                read_only: false,
+                hex_view: false,
+                hex_cursor: 0,
+                hex_bytes: None,
+                highlighter: None,
+                macros: HashMap::new(),
+                recording: None,
+                last_macro: None,
+                playing_macros: Vec::new(),
+                last_change: None,
+                insert_run: None,
+                marks: HashMap::new(),
+                jumps: Vec::new(),
+                jump_index: 0,
+                last_search: None,
+                search_highlight: None,
+                regex_search: false,
+                search_options: SearchOptions::default(),
+                #[cfg(feature = "regex")]
+                compiled_search: RefCell::new(None),
+                hooks: Vec::new(),
+                keymap: KeyMap::default(),
+                // Stands in for the active buffer, whose state is in the
+                // fields below.
+                buffers: vec![BufferState::new(PieceTable::from_str(""), None)],
+                active_buffer: 0,
+                panes: vec![View::default()],
+                active_pane: 0,
+                screen_rows: size.rows,
+                screen_colums: size.cols,
+                config,
+                buffer,
+                cursor_row: 0,
+                cursor_col: 0,
+                row_offset: 0,
+                col_offset: 0,
+                filename: None,
+                dirty: false,
+                message: String::new(),
+                message_time: Instant::now(),
+                keypress_time: Instant::now(),
+                swap_path: None,
+                swap_time: Instant::now(),
+                saved_once: false,
+                mode: EditorMode::Normal,
+                drawn_cursor: None,
+                drawn_rows: Vec::new(),
+                drawn_size: None,
+                debug_log: None,
+                input: Box::new(io::BufReader::new(io::stdin())),
+                output: Box::new(io::stdout()),
+            }
+        }
+
+        /// Escape sequence that sets the cursor to the shape configured
+        /// for the current mode.
+        pub fn cursor_escape(&self) -> String {
+            let shape = match self.mode {
+                EditorMode::Insert => self.config.insert_cursor,
+                EditorMode::Replace => self.config.replace_cursor,
+                EditorMode::Normal | EditorMode::Visual | EditorMode::VisualBlock => 
+                    self.config.normal_cursor,
+            };
+            shape.escape(self.config.cursor_blink)
+        }
+
+        /// Show `message` on the message bar.
+        pub fn set_message<S: Into<String>>(&mut self, message: S) {
+            self.message = message.into();
+            self.message_time = Instant::now();
+        }
+
+        /// Clear the message bar if its message has been shown for 
+        /// longer than `MESSAGE_DURATION`, returning whether it was 
+        /// cleared.
+        pub fn expire_message(&mut self) -> bool {
+            if self.message.is_empty() || self.message_time.elapsed() < MESSAGE_DURATION {
+                return false;
+            }
+            self.message.clear();
+            true
+        }
+
+        /// Run `f` on each hook, removing any hook that panics so that 
+        /// one broken integration cannot take down the editor. The panic
+        /// is reported on the message bar rather than on stderr.
+        fn run_hooks<F: FnMut(&mut dyn EditorHook)>(&mut self, mut f: F) {
+            silence_hook_panics();
+            let mut panic_message = None;
+            self.hooks.retain_mut(|hook| {
+                RUNNING_HOOK.with(|running| running.set(true));
+                let result = panic::catch_unwind(AssertUnwindSafe(|| f(hook.as_mut())));
+                RUNNING_HOOK.with(|running| running.set(false));
+                let Err(payload) = result else {
+                    return true;
+                };
+                panic_message = Some(payload.downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default());
+                false
+            });
+            match panic_message {
+                Some(message) if !message.is_empty() => {
+                    self.set_message(format!("{}: {}", HOOK_PANICKED_MESSAGE, message));
+                },
+                Some(_) => self.set_message(HOOK_PANICKED_MESSAGE),
+                None => (),
+            }
+        }
+
+        /// Insert `s` into the buffer at `offset`, running the hooks, and
+        /// return the number of bytes inserted.
+        fn buffer_insert(&mut self, offset: usize, s: &str) -> Result<usize, PieceTableError> {
+            let n_bytes = self.buffer.write_to_loc(offset, s)?;
+            self.run_hooks(|hook| hook.on_insert(offset, s));
+            Ok(n_bytes)
+        }
+
+        /// Delete `start..stop` from the buffer, running the hooks.
+        fn buffer_delete(&mut self, start: usize, stop: usize) -> Result<(), PieceTableError> {
+            self.buffer.delete(start, stop)?;
+            self.run_hooks(|hook| hook.on_delete(start, stop));
+            Ok(())
+        }
+
+        /// Replace `start..stop` in the buffer with `s`, running the 
+        /// hooks for the deletion and then the insertion, and return the
+        /// replaced text.
+        fn buffer_replace(&mut self, start: usize, stop: usize, s: &str) -> 
+            Result<String, PieceTableError> 
+        {
+            let replaced = self.buffer.replace_range(start, stop, s)?;
+            if start < stop {
+                self.run_hooks(|hook| hook.on_delete(start, stop));
+            }
+            if !s.is_empty() {
+                self.run_hooks(|hook| hook.on_insert(start, s));
+            }
+            Ok(replaced)
+        }
+
+        /// Number of rows available for drawing text in the focused 
+        /// pane once the message bar and the status line of each pane 
+        /// have been drawn.
+        pub fn text_rows(&self) -> usize {
+            self.pane_heights()[self.active_pane].saturating_sub(1)
+        }
+
+        /// Number of screen rows taken by each pane, including its status
+        /// line. The rows above the message bar are shared out evenly, 
+        /// with any left over going to the last pane.
+        pub fn pane_heights(&self) -> Vec<usize> {
+            let rows = self.screen_rows.saturating_sub(1);
+            let count = self.panes.len();
+            let mut heights = vec![rows / count; count];
+            heights[count - 1] += rows % count;
+            heights
+        }
+
+        /// Whether the screen is too small to draw the editor on, leaving
+        /// some pane without a row for text or no columns for text beside
+        /// the gutter.
+        pub fn screen_too_small(&self) -> bool {
+            self.text_cols() == 0 || self.pane_heights().iter().any(|&height| height <= 1)
+        }
+
+        /// Screen row that the pane at `index` starts on.
+        pub fn pane_top(&self, index: usize) -> usize {
+            self.pane_heights()[..index].iter().sum()
+        }
+
+        /// Insert `s` at the cursor and move the cursor to the end of
+        /// the inserted text.
+        pub fn insert_str(&mut self, s: &str) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let n_bytes = self.buffer_insert(offset, s)?;
+            self.dirty = true;
+            self.set_cursor_offset(offset + n_bytes);
+            Ok(())
+        }
+
+        /// Insert `c` at the cursor and move the cursor after it.
+        pub fn insert_char(&mut self, c: char) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let n_bytes = self.buffer.insert_char(offset, c)?;
+            self.run_hooks(|hook| hook.on_insert(offset, c.encode_utf8(&mut [0; 4])));
+            self.dirty = true;
+            self.set_cursor_offset(offset + n_bytes);
+            Ok(())
+        }
+
+        /// Split the cursor line at the cursor. The buffer always uses 
+        /// `\n` internally and the document's line ending is restored
+        /// when it is saved.
+        pub fn insert_newline(&mut self) -> Result<(), PieceTableError> {
+            self.insert_char('\n')
+        }
+
+        /// Split the cursor line at the cursor and indent the new line 
+        /// with the leading whitespace of the cursor line, converted to
+        /// spaces if `use_spaces_for_tabs` is set.
+        pub fn insert_newline_and_indent(&mut self) -> Result<(), PieceTableError> {
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            let before_cursor = &line[..self.cursor_col];
+            let indent_len = before_cursor.len() 
+                - before_cursor.trim_start_matches([' ', '\t']).len();
+            let indent = &before_cursor[..indent_len];
+
+            let indent = if self.config.use_spaces_for_tabs {
+                render_line(indent, self.config.tab_width)
+            } else {
+                indent.to_string()
+            };
+            self.insert_newline()?;
+            if !indent.is_empty() {
+                self.insert_str(&indent)?;
+            }
+            Ok(())
+        }
+
+        /// Delete the grapheme cluster or character before the cursor, 
+        /// joining the cursor line to the previous line at the start of
+        /// a line. If only spaces are before the cursor then they are 
+        /// deleted back to the previous indent level instead.
+        pub fn delete_char_before_cursor(&mut self) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let line_start = offset - self.cursor_col;
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            let before_cursor = &line[..self.cursor_col];
+
+            let start = if !before_cursor.is_empty() && before_cursor.bytes().all(|b| b == b' ') {
+                let indent_width = self.config.tab_width;
+                line_start + (before_cursor.len() - 1) / indent_width * indent_width
+            } else {
+                self.move_left();
+                self.cursor_offset()
+            };
+            if start < offset {
+                self.buffer_delete(start, offset)?;
+                self.dirty = true;
+            }
+            self.set_cursor_offset(start);
+            Ok(())
+        }
+
+        /// Delete from the cursor to the end of the cursor line, leaving
+        /// the newline in place, and return the deleted text.
+        pub fn delete_to_end_of_line(&mut self) -> Result<String, PieceTableError> {
+            let offset = self.cursor_offset();
+            let line_end = self.buffer
+                .line_to_offset(self.cursor_row + 1)
+                .map_or(self.buffer.len(), |next_line_start| next_line_start - 1);
+            let deleted = self.buffer_replace(offset, line_end, "")?;
+            if !deleted.is_empty() {
+                self.dirty = true;
+            }
+            Ok(deleted)
+        }
+
+        /// Delete the cursor line including its newline, move the cursor
+        /// to the start of the line that takes its place, and return the
+        /// deleted text. Deleting the only line leaves a single empty 
+        /// line.
+        pub fn delete_line(&mut self) -> Result<String, PieceTableError> {
+            // Can safely unwrap here since the cursor is always on a line
+            // in the buffer.
+            let mut start = self.buffer.line_to_offset(self.cursor_row).unwrap();
+            let stop = match self.buffer.line_to_offset(self.cursor_row + 1) {
+                Some(next_line_start) => next_line_start,
+                None => {
+                    // The last line has no newline of its own, so remove
+                    // the newline that ends the line before it instead.
+                    start = start.saturating_sub(1);
+                    self.buffer.len()
+                },
+            };
+
+            // Return the line as it would appear with its own newline
+            let mut deleted = self.buffer_replace(start, stop, "")?;
+            if deleted.starts_with('\n') {
+                deleted.remove(0);
+            }
+            if !deleted.ends_with('\n') {
+                deleted.push('\n');
+            }
+            self.dirty = true;
+
+            let row = self.cursor_row.min(self.buffer.line_count() - 1);
+            // Can safely unwrap here since `row` is always less than the
+            // number of lines.
+            let offset = self.buffer.line_to_offset(row).unwrap();
+            self.set_cursor_offset(offset);
+            Ok(deleted)
+        }
+
+        /// Join the cursor line with the line after it, replacing the 
+        /// newline and any whitespace around it with a single space. The
+        /// cursor is left where the lines were joined. Does nothing on 
+        /// the last line.
+        pub fn join_lines(&mut self) -> Result<(), PieceTableError> {
+            let Some(next_line) = self.buffer.line(self.cursor_row + 1) else {
+                return Ok(());
+            };
+            // Can safely unwrap here since the cursor is always on a line
+            // in the buffer.
+            let line = self.buffer.line(self.cursor_row).unwrap();
+            let line_start = self.buffer.line_to_offset(self.cursor_row).unwrap();
+            let next_stop = self.buffer
+                .line_to_offset(self.cursor_row + 2)
+                .map_or(self.buffer.len(), |offset| offset - 1);
+
+            let start = line_start + line.trim_end().len();
+            let stop = next_stop - next_line.trim_start().len();
+            // No space is needed when either side of the join is blank
+            let separator = if start == line_start || stop == next_stop { "" } else { " " };
+            self.buffer_replace(start, stop, separator)?;
+            self.dirty = true;
+            self.set_cursor_offset(start);
+            Ok(())
+        }
+
+        /// Swap the character before the cursor with the one under it 
+        /// and move the cursor past them both. At the end of a line the
+        /// two characters before the cursor are swapped instead. Does 
+        /// nothing when there are not two characters to swap.
+        pub fn transpose_chars(&mut self) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let line_start = offset - self.cursor_col;
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            let col = self.cursor_col;
+
+            let mut before = line[..col].chars().rev();
+            let (start, stop) = match (before.next(), line[col..].chars().next()) {
+                (Some(prev), Some(under)) => (col - prev.len_utf8(), col + under.len_utf8()),
+                (Some(prev), None) => match before.next() {
+                    Some(prev_prev) => (col - prev.len_utf8() - prev_prev.len_utf8(), col),
+                    None => return Ok(()),
+                },
+                (None, _) => return Ok(()),
+            };
+
+            let mut chars = line[start..stop].chars();
+            // Can safely unwrap here since the range holds two characters
+            let (first, second) = (chars.next().unwrap(), chars.next().unwrap());
+            let swapped = format!("{}{}", second, first);
+            self.buffer_replace(line_start + start, line_start + stop, &swapped)?;
+            self.dirty = true;
+            self.cursor_col = stop;
+            Ok(())
+        }
+
+        /// Insert a copy of the cursor line below it and move the cursor
+        /// to the copy, keeping its column.
+        pub fn duplicate_line(&mut self) -> Result<(), PieceTableError> {
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default().into_owned();
+            let (loc, copy) = match self.buffer.line_to_offset(self.cursor_row + 1) {
+                Some(next_start) => (next_start, format!("{}\n", line)),
+                // The last line has no newline of its own, so the copy
+                // goes after a new one at the end of the buffer.
+                None => (self.buffer.len(), format!("\n{}", line)),
+            };
+            self.buffer_insert(loc, &copy)?;
+            self.dirty = true;
+            self.cursor_row += 1;
+            Ok(())
+        }
+
+        /// Replace the register with `text`, also copying it to the 
+        /// system clipboard when one is available.
+        pub fn set_register(&mut self, text: String) {
+            self.clipboard_text = if self.config.system_clipboard && clipboard::set_text(&text) {
+                Some(text.clone())
+            } else {
+                self.system_clipboard_text()
+            };
+            self.register = text;
+        }
+
+        /// Text on the system clipboard, if it is used and holds text.
+        fn system_clipboard_text(&self) -> Option<String> {
+            if self.config.system_clipboard {
+                clipboard::get_text()
+            } else {
+                None
+            }
+        }
+
+        /// Delete from the cursor to the end of the cursor line, adding 
+        /// the deleted text to the register. Consecutive kills append to
+        /// the register so that they can be pasted back together.
+        pub fn kill_to_end_of_line(&mut self) -> Result<(), PieceTableError> {
+            let killed = self.delete_to_end_of_line()?;
+            let text = if self.kill_appending {
+                format!("{}{}", self.register, killed)
+            } else {
+                killed
+            };
+            self.set_register(text);
+            self.kill_appending = true;
+            Ok(())
+        }
+
+        /// Complete the word before the cursor with the first word in the
+        /// buffer that starts with it, or if the previous key completed 
+        /// a word, replace that completion with the next candidate, 
+        /// cycling back to the first after the last.
+        pub fn complete_word(&mut self) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let completion = match self.completion.take() {
+                Some(mut completion) => {
+                    completion.index = (completion.index + 1) % completion.candidates.len();
+                    completion
+                },
+                None => {
+                    let start = self.buffer
+                        .char_indices_before(offset)
+                        .take_while(|&(_, c)| is_word_char(c))
+                        .last()
+                        .map_or(offset, |(i, _)| i);
+                    // Can safely unwrap here since both ends of the range
+                    // are character boundaries within the buffer.
+                    let prefix = self.buffer.get_text_range(start..offset).unwrap().into_owned();
+                    if prefix.is_empty() {
+                        return Ok(());
+                    }
+
+                    let mut candidates: Vec<String> = Vec::new();
+                    let mut word = String::new();
+                    for c in self.buffer.snapshot().chars().chain(std::iter::once(' ')) {
+                        if is_word_char(c) {
+                            word.push(c);
+                            continue;
+                        }
+                        if word.len() > prefix.len() 
+                            && word.starts_with(&prefix) 
+                            && !candidates.contains(&word) 
+                        {
+                            candidates.push(word.clone());
+                        }
+                        word.clear();
+                    }
+                    if candidates.is_empty() {
+                        self.set_message(format!("No completions for {}", prefix));
+                        return Ok(());
+                    }
+                    Completion { start, candidates, index: 0 }
+                },
+            };
+
+            let candidate = &completion.candidates[completion.index];
+            self.buffer_replace(completion.start, offset, candidate)?;
+            self.dirty = true;
+            self.set_cursor_offset(completion.start + candidate.len());
+            self.set_message(format!(
+                "Match {} of {}", completion.index + 1, completion.candidates.len()
+            ));
+            self.completion = Some(completion);
+            Ok(())
+        }
+
+        /// Delete the cursor line, replacing the register with it, or 
+        /// appending to the register if `kill_appending` is set, as it is
+        /// for each line after the first of a counted `dd`.
+        pub fn kill_line(&mut self) -> Result<(), PieceTableError> {
+            let line = self.delete_line()?;
+            let text = if self.kill_appending {
+                format!("{}{}", self.register, line)
+            } else {
+                line
+            };
+            self.set_register(text);
+            Ok(())
+        }
+
+        /// Copy the cursor line, including its newline, to the register.
+        pub fn copy_line(&mut self) {
+            let mut line = self.buffer.line(self.cursor_row).unwrap_or_default().into_owned();
+            line.push('\n');
+            self.set_register(line);
+        }
+
+        /// Undo the last group of changes to the buffer, or redo the last
+        /// group undone if `redo`, running the hooks for each change and
+        /// moving the cursor to where the last one was made.
+        pub fn undo(&mut self, redo: bool) -> Result<(), PieceTableError> {
+            let edits = if redo { self.buffer.redo()? } else { self.buffer.undo()? };
+            if edits.is_empty() {
+                self.set_message(if redo { "Already at newest change" } else { "Already at oldest change" });
+                return Ok(());
+            }
+            let mut cursor = 0;
+            for edit in &edits {
+                match edit {
+                    Edit::Insert { loc, text } => {
+                        self.run_hooks(|hook| hook.on_insert(*loc, text));
+                        cursor = *loc;
+                    },
+                    Edit::Delete { loc, text } => {
+                        self.run_hooks(|hook| hook.on_delete(*loc, loc + text.len()));
+                        cursor = *loc;
+                    },
+                }
+            }
+            self.set_cursor_offset(cursor);
+            self.dirty = true;
+            Ok(())
+        }
+
+        /// Insert text at the cursor, or in place of the selection if 
+        /// there is one. The text is taken from the system clipboard if 
+        /// something has been copied to it since the register was last 
+        /// set, and from the register otherwise.
+        pub fn paste(&mut self) -> Result<(), PieceTableError> {
+            let text = match self.system_clipboard_text() {
+                Some(text) if self.clipboard_text.as_ref() != Some(&text) => text,
+                _ => self.register.clone(),
+            };
+            if text.is_empty() {
+                return Ok(());
+            }
+            if self.selection().is_some() {
+                self.replace_selection(&text)
+            } else {
+                self.insert_str(&text)
+            }
+        }
+
+        /// Delete the grapheme cluster or character under the cursor. 
+        /// Does nothing at the end of a line.
+        pub fn delete_char_under_cursor(&mut self) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            let Some(next_offset) = self.char_under_cursor_end() else {
+                return Ok(());
+            };
+            if self.config.grapheme_movement {
+                self.buffer_delete(offset, next_offset)?;
+            } else {
+                let n_bytes = self.buffer.delete_char_at(offset)?;
+                self.run_hooks(|hook| hook.on_delete(offset, offset + n_bytes));
+            }
+            self.dirty = true;
+            Ok(())
+        }
+
+        /// Replace the grapheme cluster or character under the cursor 
+        /// with `c` and move the cursor past it. At the end of a line `c`
+        /// is inserted instead.
+        pub fn overwrite_char(&mut self, c: char) -> Result<(), PieceTableError> {
+            let Some(next_offset) = self.char_under_cursor_end() else {
+                return self.insert_char(c);
+            };
+            let offset = self.cursor_offset();
+            self.buffer_replace(offset, next_offset, c.encode_utf8(&mut [0; 4]))?;
+            self.dirty = true;
+            self.set_cursor_offset(offset + c.len_utf8());
+            Ok(())
+        }
+
+        /// Byte offset of the end of the grapheme cluster or character 
+        /// under the cursor, or `None` at the end of a line.
+        fn char_under_cursor_end(&self) -> Option<usize> {
+            let offset = self.cursor_offset();
+            let (_, c) = self.buffer.char_indices_from(offset).next()?;
+            if c == '\n' {
+                return None;
+            }
+            if self.config.grapheme_movement {
+                self.buffer.next_grapheme_boundary(offset)
+            } else {
+                Some(offset + c.len_utf8())
+            }
+        }
+
+        /// Switch to `mode`, clearing the message bar. The mode is shown
+        /// on the status line.
+        pub fn set_mode(&mut self, mode: EditorMode) {
+            self.mode = mode;
+            self.buffer.push_undo_boundary();
+            self.selection_anchor = match mode {
+                EditorMode::Visual | EditorMode::VisualBlock => Some(self.cursor_offset()),
+                _ => None,
+            };
+            self.set_message("");
+        }
+
+        /// Count the lines, words and characters of the buffer.
+        pub fn word_count(&self) -> WordCount {
+            let contents = self.buffer.write_contents_to_string();
+            let mut words = 0;
+            let mut in_word = false;
+            for c in contents.chars() {
+                if c.is_whitespace() {
+                    in_word = false;
+                } else if !in_word {
+                    in_word = true;
+                    words += 1;
+                }
+            }
+            WordCount {
+                lines: self.buffer.line_count(),
+                words,
+                chars: contents.chars().count(),
+            }
+        }
+
+        /// If the cursor is on a bracket, return its byte offset and the
+        /// byte offset of its matching bracket, if it has one.
+        pub fn bracket_match(&self) -> Option<(usize, Option<usize>)> {
+            let offset = self.cursor_offset();
+            let (_, c) = self.buffer.char_indices_from(offset).next()?;
+            bracket_pair(c)?;
+            let after = self.buffer.char_indices_from(offset);
+            let before = self.buffer.char_indices_before(offset + c.len_utf8());
+            Some((offset, matching_bracket(c, after, before)))
+        }
+
+        /// Rows covered by the selection, or the cursor row if there is 
+        /// no selection.
+        pub fn selected_rows(&self) -> Range<usize> {
+            let Some(selection) = self.selection() else {
+                return self.cursor_row..self.cursor_row + 1;
+            };
+            // Can safely unwrap here since the selection is within the
+            // buffer.
+            let first_row = self.buffer.offset_to_line(selection.start).unwrap();
+            let last_row = self.buffer.offset_to_line(selection.end).unwrap();
+            first_row..last_row + 1
+        }
+
+        /// Add one level of indentation to the start of each line in 
+        /// `rows`, using a tab or `tab_width` spaces as set by 
+        /// `use_spaces_for_tabs`. Empty lines are left alone.
+        pub fn indent_rows(&mut self, rows: Range<usize>) -> Result<(), PieceTableError> {
+            let indent = if self.config.use_spaces_for_tabs {
+                " ".repeat(self.config.tab_width)
+            } else {
+                String::from("\t")
+            };
+            let is_empty: Vec<bool> = self.buffer
+                .lines_from(rows.start)
+                .into_iter()
+                .flatten()
+                .take(rows.len())
+                .map(|line| line.is_empty())
+                .collect();
+
+            self.undo_group(|editor| {
+                for row in rows.clone().rev() {
+                    if is_empty.get(row - rows.start).is_none_or(|&empty| empty) {
+                        continue;
+                    }
+                    // Can safely unwrap here since `row` is a line of the 
+                    // buffer.
+                    let line_start = editor.buffer.line_to_offset(row).unwrap();
+                    editor.buffer_insert(line_start, &indent)?;
+                    editor.dirty = true;
+                    if row == editor.cursor_row {
+                        editor.cursor_col += indent.len();
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        /// Remove up to one level of indentation, a tab or up to 
+        /// `tab_width` spaces, from the start of each line in `rows`.
+        pub fn dedent_rows(&mut self, rows: Range<usize>) -> Result<(), PieceTableError> {
+            let tab_width = self.config.tab_width;
+            let n_removed: Vec<usize> = self.buffer
+                .lines_from(rows.start)
+                .into_iter()
+                .flatten()
+                .take(rows.len())
+                .map(|line| if line.starts_with('\t') {
+                    1
+                } else {
+                    line.bytes()
+                        .take(tab_width)
+                        .take_while(|&b| b == b' ')
+                        .count()
+                })
+                .collect();
+
+            self.undo_group(|editor| {
+                for (row, &n_removed) in rows.zip(&n_removed).rev() {
+                    if n_removed == 0 {
+                        continue;
+                    }
+                    // Can safely unwrap here since `row` is a line of the 
+                    // buffer.
+                    let line_start = editor.buffer.line_to_offset(row).unwrap();
+                    editor.buffer_delete(line_start, line_start + n_removed)?;
+                    editor.dirty = true;
+                    if row == editor.cursor_row {
+                        editor.cursor_col = editor.cursor_col.saturating_sub(n_removed);
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        /// Comment out each line in `rows` by putting the comment prefix 
+        /// for the file type after its indentation, or uncomment them 
+        /// all if every one is already commented. Blank lines are left 
+        /// alone. The lines are rewritten with a single replace. Shows a
+        /// message if the file type has no known comment prefix.
+        pub fn toggle_comment_rows(&mut self, rows: Range<usize>) -> Result<(), PieceTableError> {
+            let Some(prefix) = self.filename.as_deref()
+                .and_then(file_type)
+                .and_then(comment_prefix) 
+            else {
+                self.set_message("No comment prefix for this file type");
+                return Ok(());
+            };
+            let rows = rows.start..rows.end.min(self.buffer.line_count());
+            if rows.is_empty() {
+                return Ok(());
+            }
+            // Can safely unwrap here since `rows` are lines of the 
+            // buffer.
+            let lines: Vec<String> = self.buffer
+                .lines_from(rows.start)
+                .unwrap()
+                .take(rows.len())
+                .map(|line| line.into_owned())
+                .collect();
+            let indent_len = |line: &str| line.len() - line.trim_start().len();
+            let is_blank = |line: &str| line.trim().is_empty();
+            if lines.iter().all(|line| is_blank(line)) {
+                return Ok(());
+            }
+            let uncomment = lines.iter()
+                .filter(|line| !is_blank(line))
+                .all(|line| line.trim_start().starts_with(prefix));
+
+            let mut cursor_shift = 0;
+            let toggled: Vec<String> = lines.iter().zip(rows.clone()).map(|(line, row)| {
+                if is_blank(line) {
+                    return line.to_string();
+                }
+                let (indent, rest) = line.split_at(indent_len(line));
+                let toggled = if uncomment {
+                    let rest = &rest[prefix.len()..];
+                    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+                    format!("{indent}{rest}")
+                } else {
+                    format!("{indent}{prefix} {rest}")
+                };
+                if row == self.cursor_row && self.cursor_col >= indent.len() {
+                    cursor_shift = toggled.len() as isize - line.len() as isize;
+                }
+                toggled
+            }).collect();
+
+            // Can safely unwrap here since `rows` are lines of the 
+            // buffer.
+            let start = self.buffer.line_to_offset(rows.start).unwrap();
+            let stop = start + lines.join("\n").len();
+            self.buffer_replace(start, stop, &toggled.join("\n"))?;
+            self.dirty = true;
+            if rows.contains(&self.cursor_row) {
+                let line_len = toggled[self.cursor_row - rows.start].len();
+                self.cursor_col = self.cursor_col
+                    .saturating_add_signed(cursor_shift)
+                    .min(line_len);
+            }
+            Ok(())
+        }
+
+        /// Byte range `[start, end)` of the buffer between the selection
+        /// anchor and the cursor, if there is a selection.
+        pub fn selection(&self) -> Option<Range<usize>> {
+            if self.mode == EditorMode::VisualBlock {
+                return None;
+            }
+            let anchor = self.selection_anchor?;
+            let cursor = self.cursor_offset();
+            Some(anchor.min(cursor)..anchor.max(cursor))
+        }
+
+        /// The rectangle from the selection anchor to the cursor, 
+        /// including the characters under both, while in visual block
+        /// mode.
+        pub fn block_selection(&self) -> Option<Block> {
+            if self.mode != EditorMode::VisualBlock {
+                return None;
+            }
+            let anchor = self.selection_anchor?;
+            let anchor_row = self.buffer.offset_to_line(anchor)?;
+            let anchor_col = anchor - self.buffer.line_to_offset(anchor_row)?;
+
+            let tab_width = self.config.tab_width;
+            // Render columns covered by the character at `col` of `row`
+            let cell = |row: usize, col: usize| {
+                let line = self.buffer.line(row).unwrap_or_default();
+                let start = buffer_col_to_render_col(&line, col, tab_width);
+                let width = line[col..].chars()
+                    .next()
+                    .map_or(1, |c| char_render_width(c, start, tab_width).max(1));
+                start..start + width
+            };
+            let anchor_cell = cell(anchor_row, anchor_col);
+            let cursor_cell = cell(self.cursor_row, self.cursor_col);
+            Some(Block {
+                rows: anchor_row.min(self.cursor_row)..anchor_row.max(self.cursor_row) + 1,
+                cols: anchor_cell.start.min(cursor_cell.start)..anchor_cell.end.max(cursor_cell.end),
+            })
+        }
+
+        /// Byte range of `line` that lies within the render columns 
+        /// `cols`, including any wide character that straddles an edge,
+        /// or `None` if the line ends before `cols` begins.
+        fn block_line_range(&self, line: &str, cols: &Range<usize>) -> Option<Range<usize>> {
+            let tab_width = self.config.tab_width;
+            let start = render_col_to_buffer_col(line, cols.start, None, tab_width);
+            if start == line.len() {
+                return None;
+            }
+            let last = render_col_to_buffer_col(line, cols.end - 1, None, tab_width);
+            let end = line[last..].chars().next().map_or(last, |c| last + c.len_utf8());
+            Some(start..end.max(start))
+        }
+
+        /// Byte offsets into the buffer of the part of each line of 
+        /// `block` within its columns, from the last line up so that 
+        /// editing one range does not move the ones still to come. Lines
+        /// that end before the block's columns begin are left out.
+        fn block_ranges(&self, block: &Block) -> Vec<Range<usize>> {
+            let Some(lines) = self.buffer.lines_from(block.rows.start) else {
+                return Vec::new();
+            };
+            // Can safely unwrap here since the block starts on a line of
+            // the buffer.
+            let mut line_start = self.buffer.line_to_offset(block.rows.start).unwrap();
+            let mut ranges = Vec::new();
+            for line in lines.take(block.rows.len()) {
+                if let Some(range) = self.block_line_range(&line, &block.cols) {
+                    ranges.push(line_start + range.start..line_start + range.end);
+                }
+                line_start += line.len() + 1;
+            }
+            ranges.reverse();
+            ranges
+        }
+
+        /// Delete the text of the block selection, moving it to the 
+        /// register one line per row, and return to normal mode with the
+        /// cursor at the top left of the block.
+        pub fn delete_block(&mut self) -> Result<(), PieceTableError> {
+            let Some(block) = self.block_selection() else {
+                return Ok(());
+            };
+            let ranges = self.block_ranges(&block);
+            let mut deleted = Vec::new();
+            self.undo_group(|editor| {
+                for range in ranges {
+                    deleted.push(editor.buffer_replace(range.start, range.end, "")?);
+                }
+                Ok(())
+            })?;
+            if !deleted.is_empty() {
+                deleted.reverse();
+                self.dirty = true;
+                self.set_register(deleted.join("\n"));
+            }
+            self.move_to_block_start(&block);
+            self.set_mode(EditorMode::Normal);
+            Ok(())
+        }
+
+        /// Insert `text` at the left edge of the block selection on each
+        /// of its lines, and return to normal mode with the cursor at the
+        /// top left of the block. Lines that end before the block begins
+        /// are left alone.
+        pub fn insert_in_block(&mut self, text: &str) -> Result<(), PieceTableError> {
+            let Some(block) = self.block_selection() else {
+                return Ok(());
+            };
+            let ranges = self.block_ranges(&block);
+            self.undo_group(|editor| {
+                for range in ranges {
+                    editor.buffer_insert(range.start, text)?;
+                    editor.dirty = true;
+                }
+                Ok(())
+            })?;
+            self.move_to_block_start(&block);
+            self.set_mode(EditorMode::Normal);
+            Ok(())
+        }
+
+        /// Move the cursor to the top left of `block`, or as near as the
+        /// first line of the block allows.
+        fn move_to_block_start(&mut self, block: &Block) {
+            let line = self.buffer.line(block.rows.start).unwrap_or_default();
+            let cursor_col = self.block_line_range(&line, &block.cols)
+                .map_or(line.len(), |range| range.start);
+            self.cursor_row = block.rows.start;
+            self.cursor_col = cursor_col;
+        }
+
+        /// Delete the selected text, moving it to the register, and 
+        /// return to normal mode.
+        pub fn delete_selection(&mut self) -> Result<(), PieceTableError> {
+            let Some(selection) = self.selection() else {
+                return Ok(());
+            };
+            let deleted = self.buffer_replace(selection.start, selection.end, "")?;
+            if !deleted.is_empty() {
+                self.dirty = true;
+                self.set_register(deleted);
+            }
+            self.set_cursor_offset(selection.start);
+            self.set_mode(EditorMode::Normal);
+            Ok(())
+        }
+
+        /// Replace the selected text with `text` in a single edit, 
+        /// leaving the cursor after it. The replaced text is not kept in
+        /// the register.
+        pub fn replace_selection(&mut self, text: &str) -> Result<(), PieceTableError> {
+            let Some(selection) = self.selection() else {
+                return Ok(());
+            };
+            self.buffer_replace(selection.start, selection.end, text)?;
+            self.dirty = true;
+            self.set_cursor_offset(selection.start + text.len());
+            Ok(())
+        }
+
+        /// Change the case of the text in `range`, returning the offset 
+        /// of the end of the changed text.
+        fn change_case_of_range(&mut self, range: Range<usize>, case: Case) -> 
+            Result<usize, PieceTableError> 
+        {
+            let text = self.buffer
+                .get_text_range(range.clone())
+                .ok_or(PieceTableError::GotBadLoc)?
+                .into_owned();
+            let changed = case.apply(&text);
+            if changed != text {
+                self.buffer_replace(range.start, range.end, &changed)?;
+                self.dirty = true;
+            }
+            Ok(range.start + changed.len())
+        }
+
+        /// Change the case of the selected text, moving the cursor to 
+        /// its start, and return to normal mode.
+        pub fn change_selection_case(&mut self, case: Case) -> Result<(), PieceTableError> {
+            let Some(selection) = self.selection() else {
+                return Ok(());
+            };
+            let start = selection.start;
+            self.change_case_of_range(selection, case)?;
+            self.set_cursor_offset(start);
+            self.set_mode(EditorMode::Normal);
+            Ok(())
+        }
+
+        /// Change the case of the character under the cursor and move 
+        /// the cursor past it. Does nothing at the end of a line.
+        pub fn change_char_case(&mut self, case: Case) -> Result<(), PieceTableError> {
+            let offset = self.cursor_offset();
+            if let Some(next_offset) = self.char_under_cursor_end() {
+                let end = self.change_case_of_range(offset..next_offset, case)?;
+                self.set_cursor_offset(end);
+            }
+            Ok(())
+        }
+
+        /// Copy the selected text to the register and return to normal
+        /// mode.
+        pub fn copy_selection(&mut self) {
+            if let Some(text) = self.selection().and_then(|range| self.buffer.get_text_range(range)) {
+                self.set_register(text.into_owned());
+            }
+            self.set_mode(EditorMode::Normal);
+        }
+
+        /// Return the range of every match of the search term `term` 
+        /// that lies within the byte offsets `range`, treating `term` as
+        /// a regular expression if regex search is on.
+        ///
+        /// # Errors
+        /// * Returns a message for the message bar if `term` is not a 
+        ///   valid regular expression.
+        pub fn search_matches(&self, term: &str, range: Range<usize>) -> 
+            Result<Vec<Range<usize>>, String> 
+        {
+            if !self.regex_search {
+                return Ok(self.buffer.find_all_in(term, range, self.search_options));
+            }
+            #[cfg(feature = "regex")]
+            return self.search_regex(term, range.start).map(|matches| matches
+                .take_while(|found| found.start <= range.end)
+                .filter(|found| found.end <= range.end)
+                .collect());
+            #[cfg(not(feature = "regex"))]
+            Err(String::from("Regex search needs the regex feature"))
+        }
+
+        /// Return the matches of the search term `term` as a regular 
+        /// expression, with the search options applied, that start at or
+        /// after `from`. The expression is only compiled again when the
+        /// pattern has changed since the last search.
+        ///
+        /// # Errors
+        /// * As for `search_matches`.
+        #[cfg(feature = "regex")]
+        fn search_regex(&self, term: &str, from: usize) -> Result<RegexMatches<'_>, String> {
+            let pattern = format!(
+                "{}{}",
+                if self.search_options.case_insensitive { "(?i)" } else { "" },
+                if self.search_options.whole_word { 
+                    format!(r"\b(?:{})\b", term) 
+                } else { 
+                    term.to_string() 
+                }
+            );
+            let mut compiled = self.compiled_search.borrow_mut();
+            let regex = match &*compiled {
+                Some((compiled_pattern, regex)) if *compiled_pattern == pattern => regex.clone(),
+                _ => {
+                    let regex = SearchRegex::new(&pattern)
+                        .map_err(|_| format!("Invalid pattern: {}", term))?;
+                    *compiled = Some((pattern, regex.clone()));
+                    regex
+                },
+            };
+            Ok(self.buffer.search_regex_from(&regex, from))
+        }
+
+        /// Return the start of the first match of the search term `term`
+        /// at or after `from`, or of the last match that starts before 
+        /// `from` if not `forward`, wrapping around the buffer if there 
+        /// is none, along with whether it wrapped. The buffer is searched
+        /// outwards from `from` so that a match near it is found without
+        /// reading the rest of the buffer, except when ignoring case or 
+        /// matching whole words without regex search.
+        ///
+        /// # Errors
+        /// * As for `search_matches`.
+        pub fn find_match(&self, term: &str, from: usize, forward: bool) -> 
+            Result<Option<(usize, bool)>, String> 
+        {
+            #[cfg(feature = "regex")]
+            if self.regex_search {
+                let search = |from: usize| -> Result<Option<usize>, String> {
+                    Ok(if forward {
+                        self.search_regex(term, from)?.next().map(|found| found.start)
+                    } else {
+                        self.search_regex(term, 0)?
+                            .map(|found| found.start)
+                            .take_while(|&start| start < from)
+                            .last()
+                    })
+                };
+                return Ok(match search(from)? {
+                    Some(found) => Some((found, false)),
+                    None => search(if forward { 0 } else { usize::MAX })?.map(|found| (found, true)),
+                });
+            }
+            if !self.regex_search && self.search_options == SearchOptions::default() {
+                let search = |from: usize| {
+                    let mut from = from.min(self.buffer.len());
+                    while self.buffer.byte_offset(Position::Byte(from)).is_err() {
+                        from += 1;
+                    }
+                    let found = if forward {
+                        self.buffer.find(term, Position::Byte(from))
+                    } else {
+                        self.buffer.rfind(term, Position::Byte(from))
+                    };
+                    found.and_then(|found| self.buffer.byte_offset(found).ok())
+                };
+                return Ok(match search(from) {
+                    Some(found) => Some((found, false)),
+                    None => search(if forward { 0 } else { usize::MAX }).map(|found| (found, true)),
+                });
+            }
+            let matches = self.search_matches(term, 0..usize::MAX)?;
+            Ok(if forward { next_match(&matches, from) } else { previous_match(&matches, from) })
+        }
+
+        /// Run `f`, recording every change it makes to the buffer as a 
+        /// single undo group.
+        fn undo_group<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+            self.buffer.begin_undo_group();
+            let result = f(self);
+            self.buffer.end_undo_group();
+            result
+        }
+
+        /// Replace every occurrence of `query` with `replacement` and 
+        /// return the number of occurrences replaced.
+        pub fn replace_all(&mut self, query: &str, replacement: &str) -> 
+            Result<usize, PieceTableError> 
+        {
+            let cursor_offset = self.cursor_offset();
+            let matches = self.buffer.find_all(query, SearchOptions::default());
+
+            // Replace from the back so that the offsets of the earlier
+            // matches are not moved by the replacements.
+            self.undo_group(|editor| {
+                for range in matches.iter().rev() {
+                    editor.buffer_replace(range.start, range.end, replacement)?;
+                }
+                Ok(())
+            })?;
+
+            if !matches.is_empty() {
+                self.dirty = true;
+                self.set_cursor_offset(cursor_offset);
+            }
+            Ok(matches.len())
+        }
+
+        /// Replace every tab with the spaces it is drawn as, up to the 
+        /// next multiple of `tab_width`, and return the number of tabs 
+        /// replaced.
+        pub fn expand_tabs(&mut self) -> Result<usize, PieceTableError> {
+            let cursor_offset = self.cursor_offset();
+            let contents = self.buffer.write_contents_to_string();
+            let tabs = self.buffer.find_all("\t", SearchOptions::default());
+            let tab_width = self.config.tab_width;
+
+            let changes: Vec<_> = tabs.iter()
+                .map(|range| {
+                    let line_start = contents[..range.start].rfind('\n').map_or(0, |i| i + 1);
+                    let line = &contents[line_start..];
+                    let render_col = buffer_col_to_render_col(line, range.start - line_start, tab_width);
+                    (range.clone(), " ".repeat(char_render_width('\t', render_col, tab_width)))
+                })
+                .collect();
+
+            // Replace from the back so that the offsets of the earlier 
+            // tabs are not moved by the replacements.
+            self.undo_group(|editor| {
+                for (range, spaces) in changes.iter().rev() {
+                    editor.buffer_replace(range.start, range.end, spaces)?;
+                }
+                Ok(())
+            })?;
+
+            if !tabs.is_empty() {
+                self.dirty = true;
+                let changes = changes.iter().map(|(range, spaces)| (range.clone(), spaces.len()));
+                self.set_cursor_offset(offset_after_changes(cursor_offset, changes));
+            }
+            Ok(tabs.len())
+        }
+
+        /// Replace the leading whitespace of each line with as many tabs
+        /// as fit followed by spaces, leaving whitespace after the first
+        /// non-whitespace character of a line alone. Returns the number
+        /// of lines changed.
+        pub fn unexpand_tabs(&mut self) -> Result<usize, PieceTableError> {
+            let cursor_offset = self.cursor_offset();
+            let contents = self.buffer.write_contents_to_string();
+            // Tabs are drawn at least one column wide
+            let tab_width = self.config.tab_width.max(1);
+
+            let mut changes = Vec::new();
+            let mut line_start = 0;
+            for line in contents.split('\n') {
+                let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+                let indent = &line[..indent_len];
+                let width = render_line(indent, tab_width).len();
+                let new_indent = format!(
+                    "{}{}", 
+                    "\t".repeat(width / tab_width), 
+                    " ".repeat(width % tab_width)
+                );
+                if new_indent != indent {
+                    changes.push((line_start..line_start + indent_len, new_indent));
+                }
+                line_start += line.len() + 1;
+            }
+
+            self.undo_group(|editor| {
+                for (range, new_indent) in changes.iter().rev() {
+                    editor.buffer_replace(range.start, range.end, new_indent)?;
+                }
+                Ok(())
+            })?;
+
+            if !changes.is_empty() {
+                self.dirty = true;
+                let changes = changes.iter().map(|(range, new_indent)| (range.clone(), new_indent.len()));
+                self.set_cursor_offset(offset_after_changes(cursor_offset, changes));
+            }
+            Ok(changes.len())
+        }
+
+        /// Replace the buffer with the contents of `self.filename`, 
+        /// discarding any unsaved changes. The cursor stays on its line
+        /// if the line still exists. Does nothing if the buffer has no
+        /// filename.
+        pub fn reload(&mut self) -> Result<(), PieceTableError> {
+            let Some(filename) = self.filename.clone() else {
+                return Ok(());
+            };
+            self.buffer = load_file(&filename)?;
+            self.dirty = false;
+            self.selection_anchor = None;
+            self.cursor_row = self.cursor_row.min(self.buffer.line_count() - 1);
+            self.cursor_col = 0;
+            self.row_offset = 0;
+            self.col_offset = 0;
+            // The swap file holds the changes that were discarded
+            if let Some(swap_path) = &self.swap_path {
+                let _ = std::fs::remove_file(swap_path);
+            }
+            self.run_hooks(|hook| hook.on_reload(&filename));
+            Ok(())
+        }
+
+        /// Name the buffer `filename`, moving its swap file to the swap 
+        /// path of the new name. A buffer that had no name is given a 
+        /// swap path unless it is read-only.
+        pub fn set_filename(&mut self, filename: String) {
+            let swap_path = swap::swap_path(Path::new(&filename));
+            if let Some(old_swap_path) = self.swap_path.take() {
+                // The swap file is only there once it has been written
+                if old_swap_path.exists() {
+                    let _ = std::fs::rename(&old_swap_path, &swap_path);
+                }
+            }
+            if !self.is_read_only() {
+                self.swap_path = Some(swap_path);
+            }
+            self.filename = Some(filename);
+        }
+
+        /// Whether the buffer should be auto-saved at `now`: auto-saving 
+        /// is enabled, the buffer has unsaved changes and a filename, and
+        /// no key has been pressed for `config.auto_save_interval`.
+        pub fn auto_save_due(&self, now: Instant) -> bool {
+            let Some(interval) = self.config.auto_save_interval else {
+                return false;
+            };
+            self.dirty 
+                && !self.is_read_only()
+                && self.filename.is_some()
+                && now.saturating_duration_since(self.keypress_time) >= interval
+        }
+
+        /// Add `key` to the macro being recorded, if any. Keys played 
+        /// from a macro are already recorded as the `@` that played them.
+        fn record_key(&mut self, key: Key) {
+            if self.playing_macros.is_empty() {
+                if let Some((_, keys)) = &mut self.recording {
+                    keys.push(key);
+                }
+            }
+        }
+
+        /// Whether edits to the buffer and saving are refused, because 
+        /// the editor is read-only, the hex view is shown or the buffer
+        /// is not valid UTF-8.
+        pub fn is_read_only(&self) -> bool {
+            self.read_only || self.hex_view || self.buffer.is_binary()
+        }
+
+        /// Show or hide the hex view, keeping the cursor on the same 
+        /// byte, or on the start of the character it falls within when 
+        /// returning to the text.
+        pub fn set_hex_view(&mut self, hex_view: bool) {
+            if hex_view == self.hex_view {
+                return;
+            }
+            if hex_view {
+                self.set_mode(EditorMode::Normal);
+                self.hex_bytes = (self.buffer.has_bom() || self.buffer.line_ending() != LineEnding::LF)
+                    .then(|| PieceTable::from_bytes(self.buffer.to_stored_bytes()));
+                self.hex_cursor = self.buffer.stored_offset(self.cursor_offset());
+            } else {
+                // `set_cursor_offset` moves back to the start of the 
+                // character the byte is in.
+                self.set_cursor_offset(self.buffer.offset_from_stored(self.hex_cursor));
+                self.hex_bytes = None;
+            }
+            self.hex_view = hex_view;
+        }
+
+        /// The bytes shown by the hex view: those of the file the buffer
+        /// is saved to.
+        pub fn hex_buffer(&self) -> &PieceTable {
+            self.hex_bytes.as_ref().unwrap_or(&self.buffer)
+        }
+
+        /// Write the buffer to the swap file, if there is one.
+        ///
+        /// # Errors
+        /// * Returns an error if the swap file cannot be written.
+        pub fn write_swap(&mut self) -> io::Result<()> {
+            if let Some(swap_path) = &self.swap_path {
+                swap::write(swap_path, &self.buffer.write_contents_to_string())?;
+            }
+            Ok(())
+        }
+
+        /// Delete the swap file of every buffer that has one.
+        pub fn remove_swap(&mut self) {
+            let inactive = self.buffers.iter_mut()
+                .enumerate()
+                .filter(|(index, _)| *index != self.active_buffer)
+                .map(|(_, state)| &mut state.swap_path);
+            for swap_path in std::iter::once(&mut self.swap_path).chain(inactive) {
+                if let Some(swap_path) = swap_path.take() {
+                    // The swap file may already be gone, and there is
+                    // nothing to do about it on the way out anyway.
+                    let _ = std::fs::remove_file(swap_path);
+                }
+            }
+        }
+
+        /// Exchange the state of the active buffer held in the editor's
+        /// fields with `self.buffers[index]`.
+        fn exchange_buffer_state(&mut self, index: usize) {
+            let state = &mut self.buffers[index];
+            std::mem::swap(&mut self.buffer, &mut state.buffer);
+            std::mem::swap(&mut self.filename, &mut state.filename);
+            std::mem::swap(&mut self.dirty, &mut state.dirty);
+            std::mem::swap(&mut self.cursor_row, &mut state.cursor_row);
+            std::mem::swap(&mut self.cursor_col, &mut state.cursor_col);
+            std::mem::swap(&mut self.row_offset, &mut state.row_offset);
+            std::mem::swap(&mut self.col_offset, &mut state.col_offset);
+            std::mem::swap(&mut self.saved_once, &mut state.saved_once);
+            std::mem::swap(&mut self.swap_path, &mut state.swap_path);
+            std::mem::swap(&mut self.marks, &mut state.marks);
+            std::mem::swap(&mut self.jumps, &mut state.jumps);
+            std::mem::swap(&mut self.jump_index, &mut state.jump_index);
+        }
+
+        /// Make `self.buffers[index]` the buffer being edited, returning
+        /// to normal mode. Does nothing if there is no such buffer.
+        pub fn switch_buffer(&mut self, index: usize) {
+            if index == self.active_buffer || index >= self.buffers.len() {
+                return;
+            }
+            // Store the active buffer in its stale entry, then take the
+            // target's state, leaving its entry stale in turn.
+            self.exchange_buffer_state(self.active_buffer);
+            self.exchange_buffer_state(index);
+            self.active_buffer = index;
+            self.leave_view();
+        }
+
+        /// Forget state tied to the view being left, returning to normal
+        /// mode.
+        fn leave_view(&mut self) {
+            self.mode = EditorMode::Normal;
+            self.selection_anchor = None;
+            self.pending_key = None;
+            self.pending_count = None;
+            self.insert_run = None;
+            self.completion = None;
+            self.desired_col = None;
+            self.hex_view = false;
+            self.hex_bytes = None;
+        }
+
+        /// The view of the focused pane.
+        pub fn view(&self) -> View {
+            View {
+                buffer_index: self.active_buffer,
+                cursor_row: self.cursor_row,
+                cursor_col: self.cursor_col,
+                row_offset: self.row_offset,
+                col_offset: self.col_offset,
+            }
+        }
+
+        /// Show `view` in the focused pane, without leaving the current
+        /// mode. The cursor is kept within the buffer, which may have 
+        /// been edited in another pane since the view was stored.
+        fn load_view(&mut self, view: View) {
+            if view.buffer_index != self.active_buffer {
+                self.exchange_buffer_state(self.active_buffer);
+                self.exchange_buffer_state(view.buffer_index);
+                self.active_buffer = view.buffer_index;
+            }
+            self.row_offset = view.row_offset;
+            self.col_offset = view.col_offset;
+            self.cursor_row = view.cursor_row;
+            self.cursor_col = view.cursor_col;
+            let line_count = self.buffer.line_count();
+            if self.cursor_row >= line_count {
+                self.cursor_row = line_count - 1;
+                self.cursor_col = 0;
+            }
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            self.cursor_col = self.cursor_col.min(line.len());
+            while !line.is_char_boundary(self.cursor_col) {
+                self.cursor_col -= 1;
+            }
+        }
+
+        /// Move the focus to the pane at `index` without leaving the 
+        /// current mode, as when drawing each pane in turn.
+        fn enter_pane(&mut self, index: usize) {
+            self.panes[self.active_pane] = self.view();
+            self.active_pane = index;
+            self.load_view(self.panes[index]);
+        }
+
+        /// Move the focus to the pane at `index`, returning to normal 
+        /// mode. Does nothing if there is no such pane.
+        pub fn focus_pane(&mut self, index: usize) {
+            if index == self.active_pane || index >= self.panes.len() {
+                return;
+            }
+            self.enter_pane(index);
+            self.leave_view();
+        }
+
+        /// Move the focus to the pane below, wrapping around to the top.
+        pub fn focus_next_pane(&mut self) {
+            self.focus_pane((self.active_pane + 1) % self.panes.len());
+        }
+
+        /// Split the focused pane in two, showing the same view in the 
+        /// new pane below it, which takes the focus.
+        pub fn split_pane(&mut self) {
+            self.panes.insert(self.active_pane + 1, self.view());
+            self.focus_pane(self.active_pane + 1);
+        }
+
+        /// Close the focused pane, moving the focus to the pane that 
+        /// takes its place. The last pane cannot be closed.
+        pub fn close_pane(&mut self) -> bool {
+            if self.panes.len() == 1 {
+                return false;
+            }
+            self.panes.remove(self.active_pane);
+            self.active_pane = self.active_pane.min(self.panes.len() - 1);
+            self.load_view(self.panes[self.active_pane]);
+            self.leave_view();
+            true
+        }
+
+        /// Open `buffer`, backed by `filename`, as a new buffer and 
+        /// switch to it.
+        pub fn open_buffer(&mut self, buffer: PieceTable, filename: Option<String>) {
+            self.buffers.push(BufferState::new(buffer, filename));
+            self.switch_buffer(self.buffers.len() - 1);
+        }
+
+        /// Index of the open buffer backed by `filename`, if any.
+        pub fn find_buffer(&self, filename: &str) -> Option<usize> {
+            (0..self.buffers.len()).find(|&index| {
+                self.buffer_filename(index) == Some(filename)
+            })
+        }
+
+        /// The filename of `self.buffers[index]`, reading the editor's
+        /// own field for the active buffer.
+        fn buffer_filename(&self, index: usize) -> Option<&str> {
+            if index == self.active_buffer {
+                self.filename.as_deref()
+            } else {
+                self.buffers[index].filename.as_deref()
+            }
+        }
+
+        /// Whether any open buffer has unsaved changes.
+        pub fn any_dirty(&self) -> bool {
+            self.dirty || self.buffers.iter()
+                .enumerate()
+                .any(|(index, state)| index != self.active_buffer && state.dirty)
+        }
+
+        /// One line listing each open buffer's number, counting from 1,
+        /// and filename. The active buffer is marked with `%` and 
+        /// buffers with unsaved changes with `+`.
+        pub fn buffer_list(&self) -> String {
+            (0..self.buffers.len())
+                .map(|index| {
+                    let dirty = if index == self.active_buffer {
+                        self.dirty
+                    } else {
+                        self.buffers[index].dirty
+                    };
+                    format!(
+                        "{}{}{} \"{}\"",
+                        index + 1,
+                        if index == self.active_buffer { "%" } else { "" },
+                        if dirty { "+" } else { "" },
+                        self.buffer_filename(index).unwrap_or("[No Name]"),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("  ")
+        }
+
+        /// Write the buffer to `self.filename` and return the number of
+        /// bytes written. Does nothing if the buffer has no filename. If
+        /// `config.make_backup` is set then the first save of the session
+        /// copies the existing file to `<file>.bak` beforehand.
+        pub fn save(&mut self) -> Result<usize, PieceTableError> {
+            let Some(filename) = self.filename.clone() else {
+                return Ok(0);
+            };
+            if self.config.make_backup && !self.saved_once && Path::new(&filename).exists() {
+                std::fs::copy(&filename, format!("{}.bak", filename))
+                    .map_err(PieceTableError::IOError)?;
+            }
+            let n_bytes = self.save_to(&filename)?;
+            self.saved_once = true;
+            self.dirty = false;
+            Ok(n_bytes)
+        }
+
+        /// Write the buffer to `path`, as configured by `self.config`,
+        /// and return the number of bytes written. Edits made after the
+        /// save start a new undo group.
+        pub fn save_to(&mut self, path: &str) -> Result<usize, PieceTableError> {
+            self.buffer.push_undo_boundary();
+            self.buffer.set_trim_trailing_whitespace(self.config.trim_trailing_whitespace);
+            self.buffer.set_ensure_final_newline(self.config.ensure_final_newline);
+            self.buffer.set_trim_final_newlines(self.config.trim_final_newlines);
+            let n_bytes = self.buffer.save_to_file(path)?;
+            self.run_hooks(|hook| hook.on_save(path));
+            Ok(n_bytes)
+        }
+
+        /// Number of columns taken up by the line number gutter, or 0 if
+        /// line numbers are not shown.
+        pub fn gutter_width(&self) -> usize {
+            if !self.config.show_line_numbers && !self.config.relative_line_numbers {
+                return 0;
+            }
+            self.buffer.line_count().to_string().len() + 1
+        }
+
+        /// Byte offset into the buffer of the cursor.
+        pub fn cursor_offset(&self) -> usize {
+            let row_start = self.buffer
+                .line_to_offset(self.cursor_row)
+                .unwrap_or_else(|| self.buffer.len() + 1);
+            row_start + self.cursor_col
+        }
+
+        /// Move the cursor to `offset`, a byte offset into the buffer.
+        /// If `offset` is past the end of the buffer or within a 
+        /// character the cursor is moved to the last valid position 
+        /// before it.
+        pub fn set_cursor_offset(&mut self, offset: usize) {
+            let mut offset = offset.min(self.buffer.len());
+            while self.buffer.byte_offset(Position::Byte(offset)).is_err() {
+                offset -= 1;
+            }
+            // Can safely unwrap here since `offset` is within the buffer.
+            self.cursor_row = self.buffer.offset_to_line(offset).unwrap();
+            self.cursor_col = offset - self.buffer.line_to_offset(self.cursor_row).unwrap();
+        }
+
+        /// Move the cursor one grapheme cluster or character to the left,
+        /// wrapping to the end of the previous line.
+        pub fn move_left(&mut self) {
+            let offset = self.cursor_offset();
+            let new_offset = if self.config.grapheme_movement {
+                self.buffer.prev_grapheme_boundary(offset)
+            } else {
+                self.buffer.char_indices_before(offset).next().map(|(i, _)| i)
+            };
+            if let Some(new_offset) = new_offset {
+                self.set_cursor_offset(new_offset);
+            }
+        }
+
+        /// Move the cursor one grapheme cluster or character to the 
+        /// right, wrapping to the start of the next line.
+        pub fn move_right(&mut self) {
+            let offset = self.cursor_offset();
+            let new_offset = if self.config.grapheme_movement {
+                self.buffer.next_grapheme_boundary(offset)
+            } else {
+                self.buffer.char_indices_from(offset).next().map(|(i, c)| i + c.len_utf8())
+            };
+            if let Some(new_offset) = new_offset {
+                self.set_cursor_offset(new_offset);
+            }
+        }
+
+        /// Move the cursor forward to the start of the next word, where a
+        /// word is a run of alphanumeric characters. Whitespace, 
+        /// punctuation and line breaks between words are skipped.
+        pub fn move_word_right(&mut self) {
+            let offset = self.cursor_offset();
+            let mut chars = self.buffer.char_indices_from(offset).peekable();
+            while chars.next_if(|(_, c)| c.is_alphanumeric()).is_some() {}
+            while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+            let new_offset = chars.peek().map_or(self.buffer.len(), |&(i, _)| i);
+            drop(chars);
+            self.set_cursor_offset(new_offset);
+        }
+
+        /// Move the cursor back to the start of the current word, or of 
+        /// the previous word if already at the start of a word.
+        pub fn move_word_left(&mut self) {
+            let offset = self.cursor_offset();
+            let mut chars = self.buffer.char_indices_before(offset).peekable();
+            let mut new_offset = offset;
+            while let Some((i, _)) = chars.next_if(|(_, c)| !c.is_alphanumeric()) {
+                new_offset = i;
+            }
+            while let Some((i, _)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+                new_offset = i;
+            }
+            drop(chars);
+            self.set_cursor_offset(new_offset);
+        }
+
+        /// Move the cursor to the start of the line. With `smart_home` 
+        /// on, move it to the first non-whitespace character instead, 
+        /// unless it is already there.
+        pub fn move_home(&mut self) {
+            let first_non_blank = if self.config.smart_home {
+                self.buffer
+                    .line(self.cursor_row)
+                    .map_or(0, |line| line.len() - line.trim_start().len())
+            } else {
+                0
+            };
+            self.cursor_col = if self.cursor_col == first_non_blank { 0 } else { first_non_blank };
+        }
+
+        /// Move the cursor up or down to `row`, keeping the column it is
+        /// drawn at where possible. The column is remembered in 
+        /// `desired_col` so that it is kept across shorter lines.
+        fn move_to_row(&mut self, row: usize) {
+            let Some(line) = self.buffer.line(row) else {
+                return;
+            };
+            let tab_width = self.config.tab_width;
+            let desired_col = match self.desired_col {
+                Some(desired_col) => desired_col,
+                None => {
+                    let current = self.buffer.line(self.cursor_row).unwrap_or_default();
+                    *self.desired_col.insert(buffer_col_to_render_col(&current, self.cursor_col, tab_width))
+                },
+            };
+            self.cursor_row = row;
+            self.cursor_col = render_col_to_buffer_col(&line, desired_col, None, tab_width);
+        }
+
+        /// Record `offset` in the jump list as a place the cursor jumped 
+        /// away from, forgetting any newer jumps that had been moved back
+        /// over. Edits made after the jump start a new undo group.
+        pub fn push_jump(&mut self, offset: usize) {
+            self.buffer.push_undo_boundary();
+            self.jumps.truncate(self.jump_index);
+            self.jumps.push(offset);
+            if self.jumps.len() > JUMP_LIST_LEN {
+                self.jumps.remove(0);
+            }
+            self.jump_index = self.jumps.len();
+        }
+
+        /// Move the cursor back to the previous place in the jump list.
+        pub fn jump_back(&mut self) {
+            if self.jump_index == 0 {
+                return;
+            }
+            // Remember where the cursor is so that it can be jumped 
+            // forward to again.
+            if self.jump_index == self.jumps.len() {
+                self.jumps.push(self.cursor_offset());
+            }
+            self.jump_index -= 1;
+            self.buffer.push_undo_boundary();
+            self.set_cursor_offset(self.jumps[self.jump_index]);
+        }
+
+        /// Move the cursor forward to the next place in the jump list.
+        pub fn jump_forward(&mut self) {
+            if self.jump_index + 1 >= self.jumps.len() {
+                return;
+            }
+            self.jump_index += 1;
+            self.buffer.push_undo_boundary();
+            self.set_cursor_offset(self.jumps[self.jump_index]);
+        }
+
+        /// Move the cursor to the mark `name`, clamped to the buffer if 
+        /// the buffer has shrunk since the mark was set. Does nothing if
+        /// the mark is not set.
+        pub fn jump_to_mark(&mut self, name: char) {
+            if let Some(&offset) = self.marks.get(&name) {
+                self.push_jump(self.cursor_offset());
+                self.set_cursor_offset(offset);
+            }
+        }
+
+        /// Scroll so that the cursor is on the middle row of the screen.
+        pub fn center_cursor(&mut self) {
+            self.row_offset = self.cursor_row.saturating_sub(self.text_rows() / 2);
+        }
+
+        /// Move the cursor and the view half a screen down, stopping at
+        /// the end of the buffer.
+        pub fn scroll_half_page_down(&mut self) {
+            self.scroll_down(self.text_rows().div_ceil(2).max(1));
+        }
+
+        /// Move the cursor and the view half a screen up, stopping at the
+        /// start of the buffer.
+        pub fn scroll_half_page_up(&mut self) {
+            self.scroll_up(self.text_rows().div_ceil(2).max(1));
+        }
+
+        /// Move the cursor and the view a screen down, stopping at the 
+        /// end of the buffer.
+        pub fn scroll_page_down(&mut self) {
+            self.scroll_down(self.text_rows().max(1));
+        }
+
+        /// Move the cursor and the view a screen up, stopping at the 
+        /// start of the buffer.
+        pub fn scroll_page_up(&mut self) {
+            self.scroll_up(self.text_rows().max(1));
+        }
+
+        /// Move the cursor and the view `rows` lines down, stopping at 
+        /// the end of the buffer.
+        fn scroll_down(&mut self, rows: usize) {
+            let last_row = self.buffer.line_count() - 1;
+            let max_row_offset = (last_row + 1).saturating_sub(self.text_rows());
+            self.row_offset = (self.row_offset + rows).min(max_row_offset.max(self.row_offset));
+            self.move_to_row((self.cursor_row + rows).min(last_row));
+        }
+
+        /// Move the cursor and the view `rows` lines up, stopping at the
+        /// start of the buffer.
+        fn scroll_up(&mut self, rows: usize) {
+            self.row_offset = self.row_offset.saturating_sub(rows);
+            self.move_to_row(self.cursor_row.saturating_sub(rows));
+        }
+
+        /// Move the cursor up one line, or up one screen row if lines 
+        /// are wrapped.
+        pub fn move_up(&mut self) {
+            if self.config.wrap {
+                self.move_screen_row(false);
+            } else if self.cursor_row > 0 {
+                self.move_to_row(self.cursor_row - 1);
+            }
+        }
+
+        /// Move the cursor down one line, or down one screen row if 
+        /// lines are wrapped.
+        pub fn move_down(&mut self) {
+            if self.config.wrap {
+                self.move_screen_row(true);
+            } else {
+                self.move_to_row(self.cursor_row + 1);
+            }
+        }
+
+        /// Move the cursor to the wrapped screen row below it if `down`
+        /// is set, or above it otherwise, keeping its distance from the
+        /// start of the screen row where possible.
+        fn move_screen_row(&mut self, down: bool) {
+            let line = |row: usize| self.buffer.line(row).unwrap_or_default();
+            let tab_width = self.config.tab_width;
+            let cursor_line = line(self.cursor_row);
+            let starts = self.wrap_line(&cursor_line);
+            let render_col = buffer_col_to_render_col(&cursor_line, self.cursor_col, tab_width);
+            let screen_row = wrapped_row_of(&starts, render_col);
+            let col_in_row = render_col - starts[screen_row];
+
+            let (row, target_starts, target_screen_row) = if down {
+                if screen_row + 1 < starts.len() {
+                    (self.cursor_row, starts, screen_row + 1)
+                } else if self.cursor_row + 1 < self.buffer.line_count() {
+                    (self.cursor_row + 1, self.wrap_line(&line(self.cursor_row + 1)), 0)
+                } else {
+                    return;
+                }
+            } else if screen_row > 0 {
+                (self.cursor_row, starts, screen_row - 1)
+            } else if self.cursor_row > 0 {
+                let starts = self.wrap_line(&line(self.cursor_row - 1));
+                let last = starts.len() - 1;
+                (self.cursor_row - 1, starts, last)
+            } else {
+                return;
+            };
+
+            self.cursor_col = render_col_to_buffer_col(
+                &line(row),
+                target_starts[target_screen_row] + col_in_row,
+                target_starts.get(target_screen_row + 1).copied(),
+                tab_width,
+            );
+            self.cursor_row = row;
+        }
+
+        /// Render columns at which each screen row of `line` starts when
+        /// lines are wrapped to the width of the screen.
+        pub fn wrap_line(&self, line: &str) -> Vec<usize> {
+            wrap_line(line, self.text_cols(), self.config.tab_width)
+        }
+
+        /// Number of screen rows `line` is drawn on.
+        pub fn line_height(&self, line: &str) -> usize {
+            if self.config.wrap {
+                self.wrap_line(line).len()
+            } else {
+                1
+            }
+        }
+
+        /// Column of the cursor line that the cursor is drawn at, after 
+        /// tabs and wide characters on the cursor line have been 
+        /// expanded.
+        pub fn cursor_render_col(&self) -> usize {
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            buffer_col_to_render_col(&line, self.cursor_col, self.config.tab_width)
+        }
+
+        /// Column of the screen that the cursor is drawn at, accounting
+        /// for the gutter and horizontal scrolling or wrapping.
+        pub fn cursor_screen_col(&self) -> usize {
+            if self.hex_view {
+                return hex_dump_col(self.hex_cursor % HEX_BYTES_PER_ROW);
+            }
+            let render_col = self.cursor_render_col();
+            if self.config.wrap {
+                let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+                let starts = self.wrap_line(&line);
+                self.gutter_width() + render_col - starts[wrapped_row_of(&starts, render_col)]
+            } else {
+                self.gutter_width() + render_col - self.col_offset
+            }
+        }
+
+        /// Row of the screen that the cursor is drawn on, counting the
+        /// screen rows taken by wrapped lines above it.
+        pub fn cursor_screen_row(&self) -> usize {
+            if self.hex_view {
+                return self.hex_cursor / HEX_BYTES_PER_ROW - self.row_offset;
+            }
+            if !self.config.wrap {
+                return self.cursor_row - self.row_offset;
+            }
+            let rows_above: usize = self.buffer
+                .lines_from(self.row_offset)
+                .into_iter()
+                .flatten()
+                .take(self.cursor_row - self.row_offset)
+                .map(|line| self.line_height(&line))
+                .sum();
+            let line = self.buffer.line(self.cursor_row).unwrap_or_default();
+            let render_col = buffer_col_to_render_col(&line, self.cursor_col, self.config.tab_width);
+            rows_above + wrapped_row_of(&self.wrap_line(&line), render_col)
+        }
+
+        /// How far through the buffer the cursor line is, like vim's 
+        /// ruler: `Top` on the first line, `Bot` on the last line, and a
+        /// percentage of the lines otherwise.
+        pub fn position_percentage(&self) -> String {
+            let line_count = self.buffer.line_count();
+            if self.cursor_row == 0 {
+                String::from("Top")
+            } else if self.cursor_row + 1 >= line_count {
+                String::from("Bot")
+            } else {
+                format!("{}%", (self.cursor_row + 1) * 100 / line_count)
+            }
+        }
+
+        /// Number of columns available for drawing text once the gutter
+        /// has been drawn.
+        pub fn text_cols(&self) -> usize {
+            self.screen_colums.saturating_sub(self.gutter_width())
+        }
+    }
+
+
+    /// The opening and closing brackets of the pair `c` belongs to, and
+    /// whether `c` is the opening bracket.
+    fn bracket_pair(c: char) -> Option<(char, char, bool)> {
+        match c {
+            '(' => Some(('(', ')', true)),
+            ')' => Some(('(', ')', false)),
+            '[' => Some(('[', ']', true)),
+            ']' => Some(('[', ']', false)),
+            '{' => Some(('{', '}', true)),
+            '}' => Some(('{', '}', false)),
+            _ => None,
+        }
+    }
+
+
+    /// Byte offset of the bracket that matches the bracket `c`, given 
+    /// the characters from `c` to the end of the text in `after` and 
+    /// from `c` back to the start in `before`, with their offsets. 
+    /// Returns `None` if `c` is not a bracket or it is unmatched.
+    fn matching_bracket(
+        c: char,
+        mut after: impl Iterator<Item = (usize, char)>,
+        mut before: impl Iterator<Item = (usize, char)>,
+    ) -> Option<usize> {
+        let (open, close, is_open) = bracket_pair(c)?;
+
+        let mut depth = 0;
+        let step = |(i, c): (usize, char)| {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+            }
+            (depth == 0).then_some(i)
+        };
+
+        if is_open {
+            after.find_map(step)
+        } else {
+            before.find_map(step)
+        }
+    }
+
+
+    /// Number of cells `c` occupies on screen when drawn at `render_col`.
+    /// Tabs extend to the next multiple of `tab_width`, other control 
+    /// characters are drawn in two cells as `^X`, and CJK and emoji
+    /// characters are two cells wide. A `tab_width` of 0 is taken as 1.
+    pub fn char_render_width(c: char, render_col: usize, tab_width: usize) -> usize {
+        if c == '\t' {
+            let tab_width = tab_width.max(1);
+            tab_width - (render_col % tab_width)
+        } else if c.is_ascii_control() {
+            2
+        } else {
+            c.width().unwrap_or(0)
+        }
+    }
+
+
+    /// Expand each tab in `line` with spaces up to the next multiple of
+    /// `tab_width`, and draw other control characters in caret notation,
+    /// such as `^[` for escape.
+    pub fn render_line(line: &str, tab_width: usize) -> String {
+        let mut rendered = String::with_capacity(line.len());
+        let mut render_col = 0;
+        for c in line.chars() {
+            let width = char_render_width(c, render_col, tab_width);
+            if c == '\t' {
+                rendered.extend(std::iter::repeat_n(' ', width));
+            } else if c.is_ascii_control() {
+                rendered.push('^');
+                rendered.push(char::from(c as u8 ^ 0x40));
+            } else {
+                rendered.push(c);
+            }
+            render_col += width;
+        }
+        rendered
+    }
+
+
+    /// Convert `col`, a byte offset into `line`, to the column it is
+    /// drawn at once tabs have been expanded to `tab_width` and wide
+    /// characters take up two columns.
+    pub fn buffer_col_to_render_col(line: &str, col: usize, tab_width: usize) -> usize {
+        let mut render_col = 0;
+        for (i, c) in line.char_indices() {
+            if i >= col {
+                break;
+            }
+            render_col += char_render_width(c, render_col, tab_width);
+        }
+        render_col
+    }
+
+
+    /// Convert `render_col`, a column `line` is drawn at, to the byte 
+    /// offset of the character drawn there. If the line is wrapped and
+    /// the screen row ends at `row_end` then the column is kept on that
+    /// row.
+    pub fn render_col_to_buffer_col(
+        line: &str, 
+        render_col: usize, 
+        row_end: Option<usize>, 
+        tab_width: usize
+    ) -> usize {
+        let mut col = 0;
+        let mut prev_col = 0;
+        for (i, c) in line.char_indices() {
+            if row_end.is_some_and(|row_end| col >= row_end) {
+                return prev_col;
+            }
+            let width = char_render_width(c, col, tab_width);
+            if col + width > render_col {
+                return i;
+            }
+            prev_col = i;
+            col += width;
+        }
+        line.len()
+    }
+
+
+    /// Render columns at which each screen row of `line` starts when it
+    /// is wrapped to `width` columns. A character is never split across
+    /// rows, and a line that exactly fills its last row gets an empty 
+    /// row after it for the cursor to sit on at the end of the line.
+    pub fn wrap_line(line: &str, width: usize, tab_width: usize) -> Vec<usize> {
+        let width = width.max(1);
+        let mut starts = vec![0];
+        let mut row_start = 0;
+        let mut render_col = 0;
+        for c in line.chars() {
+            let char_width = char_render_width(c, render_col, tab_width);
+            if render_col + char_width > row_start + width && render_col > row_start {
+                row_start = render_col;
+                starts.push(row_start);
+            }
+            render_col += char_width;
+        }
+        if render_col >= row_start + width {
+            starts.push(render_col);
+        }
+        starts
+    }
+
+
+    /// Index of the screen row that `render_col` is drawn on, given the
+    /// columns `starts` that each row of a wrapped line starts at.
+    fn wrapped_row_of(starts: &[usize], render_col: usize) -> usize {
+        starts.partition_point(|&start| start <= render_col) - 1
+    }
+
+
+    /// Return the cells of `rendered`, a line with its tabs already 
+    /// expanded, from column `start` up to `start + width`. A wide 
+    /// character that straddles either edge is drawn as spaces.
+    pub fn clip_rendered_line(rendered: &str, start: usize, width: usize) -> String {
+        let end = start + width;
+        let mut clipped = String::new();
+        let mut render_col = 0;
+        for c in rendered.chars() {
+            if render_col > end {
+                break;
+            }
+            let char_width = c.width().unwrap_or(0);
+            if render_col >= start && render_col + char_width <= end {
+                clipped.push(c);
+            } else if render_col < end && render_col + char_width > start {
+                let visible = (render_col + char_width).min(end) - render_col.max(start);
+                clipped.extend(std::iter::repeat_n(' ', visible));
+            }
+            render_col += char_width;
+        }
+        clipped
+    }
+
+
+    /// Update the row and column offsets so that the cursor is on 
+    /// screen, with `scroll_off` lines visible above and below it 
+    /// unless it is near the start or end of the buffer.
+    pub fn editor_scroll(editor: &mut Editor) {
+        if editor.hex_view {
+            editor_scroll_hex(editor);
+            return;
+        }
+        if editor.config.wrap {
+            editor_scroll_wrapped(editor);
+            return;
+        }
+        let text_rows = editor.text_rows();
+        // Keep the margins small enough that the cursor can still move
+        // on small screens.
+        let scroll_off = editor.config.scroll_off.min(text_rows.saturating_sub(1) / 2);
+        let top_row = editor.cursor_row.saturating_sub(scroll_off);
+        let bottom_row = (editor.cursor_row + scroll_off)
+            .min(editor.buffer.line_count() - 1)
+            .max(editor.cursor_row);
+
+        if top_row < editor.row_offset {
+            editor.row_offset = top_row;
+        }
+        if bottom_row >= editor.row_offset + text_rows {
+            editor.row_offset = (bottom_row + 1).saturating_sub(text_rows);
+        }
+
+        let line = editor.buffer.line(editor.cursor_row).unwrap_or_default();
+        let render_col = buffer_col_to_render_col(&line, editor.cursor_col, editor.config.tab_width);
+        let cursor_width = line[editor.cursor_col..]
+            .chars()
+            .next()
+            .map_or(1, |c| char_render_width(c, render_col, editor.config.tab_width).max(1));
+        let text_cols = editor.text_cols();
+
+        if render_col < editor.col_offset {
+            editor.col_offset = render_col;
+        }
+        if render_col + cursor_width > editor.col_offset + text_cols {
+            editor.col_offset = (render_col + cursor_width).saturating_sub(text_cols);
+        }
+    }
+
+
+    /// Behaves as `editor_scroll` in the hex view, scrolling by rows of
+    /// the dump so that the cursor's byte is visible.
+    fn editor_scroll_hex(editor: &mut Editor) {
+        let row = editor.hex_cursor / HEX_BYTES_PER_ROW;
+        let text_rows = editor.text_rows();
+        if row < editor.row_offset {
+            editor.row_offset = row;
+        }
+        if row >= editor.row_offset + text_rows {
+            editor.row_offset = (row + 1).saturating_sub(text_rows);
+        }
+        editor.col_offset = 0;
+    }
+
+
+    /// Behaves as `editor_scroll` when lines are wrapped, so that there
+    /// is no horizontal scrolling and the lines on screen are measured 
+    /// in the screen rows they take up.
+    fn editor_scroll_wrapped(editor: &mut Editor) {
+        editor.col_offset = 0;
+        let scroll_off = editor.config.scroll_off.min(editor.text_rows().saturating_sub(1) / 2);
+        let top_row = editor.cursor_row.saturating_sub(scroll_off);
+        if top_row < editor.row_offset {
+            editor.row_offset = top_row;
+        }
+
+        let bottom_row = (editor.cursor_row + scroll_off).min(editor.buffer.line_count() - 1);
+        let rows_below: usize = editor.buffer
+            .lines_from(editor.cursor_row + 1)
+            .into_iter()
+            .flatten()
+            .take(bottom_row.saturating_sub(editor.cursor_row))
+            .map(|line| editor.line_height(&line))
+            .sum();
+        let mut rows_needed = editor.cursor_screen_row() + 1 + rows_below;
+        while rows_needed > editor.text_rows() && editor.row_offset < editor.cursor_row {
+            let line = editor.buffer.line(editor.row_offset).unwrap_or_default();
+            rows_needed -= editor.line_height(&line);
+            editor.row_offset += 1;
+        }
+    }
+
+
+    /// Processes the next keypress to stdin and updates `editor` as 
+    /// required. If no key is pressed before `KEYPRESS_TIMEOUT` then
+    /// expired messages are cleared instead. Returns whether the screen
+    /// needs to be redrawn.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key from stdin
+    pub fn editor_process_keypress(editor: &mut Editor) -> io::Result<bool> {
+        let result = match keys::read_key_timeout(&mut editor.input, KEYPRESS_TIMEOUT) {
+            Ok(Some(key)) => {
+                editor.keypress_time = Instant::now();
+                let result = editor_process_key(editor, key).map(|()| true);
+                if let Some(mut log) = editor.debug_log.take() {
+                    log.log_key(key, editor.cursor_offset(), editor.row_offset, editor.col_offset);
+                    editor.debug_log = Some(log);
+                }
+                result
+            },
+            Ok(None) => {
+                let saved = editor_auto_save(editor, Instant::now());
+                Ok(editor.expire_message() || saved)
+            },
+            Err(err) => Err(err),
+        };
+        let result = result.map(|redraw| editor_update_swap(editor, Instant::now()) || redraw);
+
+        // Input closing, even partway through a prompt, is a request to
+        // quit rather than a failure.
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                editor_input_closed(editor);
+                Ok(false)
+            },
+            result => result,
+        }
+    }
+
+
+    /// Start writing a debug log of keypresses to `path`, reporting on 
+    /// the message bar if the log file cannot be created.
+    pub fn editor_start_debug_log(editor: &mut Editor, path: &Path) {
+        match DebugLog::create(path) {
+            Ok(log) => {
+                let input = std::mem::replace(&mut editor.input, Box::new(io::Cursor::new(Vec::new())));
+                editor.input = log.wrap_input(input);
+                editor.debug_log = Some(log);
+            },
+            Err(err) => editor.set_message(format!("Unable to open debug log: {}", err)),
+        }
+    }
+
+
+    /// Quit the editor because its input has closed. No more keys can
+    /// be read, so unsaved changes cannot be confirmed and are reported
+    /// instead, as a failure.
+    fn editor_input_closed(editor: &mut Editor) {
+        editor.status = if editor.any_dirty() {
+            EditorStatus::InputClosedWithUnsavedChanges
+        } else {
+            EditorStatus::TerminalExitSuccess
+        };
+    }
+
+
+    /// Save the buffer, first prompting for a filename if it does not
+    /// have one, and report the outcome on the message bar.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting. Failing to write the file is reported on the
+    ///   message bar instead.
+    pub fn editor_save(editor: &mut Editor) -> io::Result<()> {
+        if editor.is_read_only() {
+            editor.set_message(READ_ONLY_MESSAGE);
+            return Ok(());
+        }
+        if editor.filename.is_none() {
+            match editor_prompt(editor, "Save as: ")? {
+                Some(filename) if !filename.is_empty() => editor.set_filename(filename),
+                _ => {
+                    editor.set_message("Save aborted");
+                    return Ok(());
+                },
+            }
+        }
+
+        let result = match editor.save() {
+            Err(PieceTableError::FileChangedOnDisk) => {
+                if !editor_confirm(editor, "File changed on disk. Overwrite it? (y/n) ")? {
+                    editor.set_message("Save aborted");
+                    return Ok(());
+                }
+                editor.buffer.forget_file_modified();
+                editor.save()
+            },
+            result => result,
+        };
+        match result {
+            Ok(n_bytes) => editor.set_message(format!("{} bytes written to disk", n_bytes)),
+            Err(err) => editor.set_message(format!("Can't save! I/O error: {}", err)),
+        }
+        Ok(())
+    }
+
+
+    /// Reload the buffer from its file, first asking for confirmation if
+    /// there are unsaved changes, and report the outcome on the message
+    /// bar.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting. Failing to read the file is reported on the 
+    ///   message bar instead.
+    pub fn editor_reload(editor: &mut Editor) -> io::Result<()> {
+        let Some(filename) = editor.filename.clone() else {
+            editor.set_message("No file to reload");
+            return Ok(());
+        };
+        if editor.dirty 
+            && !editor_confirm(editor, "Discard unsaved changes and reload? (y/n) ")? 
+        {
+            editor.set_message("Reload aborted");
+            return Ok(());
+        }
+        match editor.reload() {
+            Ok(()) => editor.set_message(format!("Reloaded {}", filename)),
+            Err(err) => editor.set_message(format!("Can't reload! I/O error: {}", err)),
+        }
+        Ok(())
+    }
+
+
+    /// Save the buffer if an auto-save is due at `now`, returning 
+    /// whether the screen needs redrawing. Failing to write the file is
+    /// reported on the message bar.
+    pub fn editor_auto_save(editor: &mut Editor, now: Instant) -> bool {
+        if !editor.auto_save_due(now) {
+            return false;
+        }
+        if let Err(err) = editor.save() {
+            editor.set_message(format!("Can't auto-save! I/O error: {}", err));
+            // Wait for another interval rather than retrying every
+            // keypress timeout.
+            editor.keypress_time = now;
+        }
+        true
+    }
+
+
+    /// Offer to show the buffer in the hex view if it looks like the 
+    /// contents of a binary file, or show it there without asking if it
+    /// is not valid UTF-8.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting.
+    pub fn editor_offer_hex_view(editor: &mut Editor) -> io::Result<()> {
+        // A buffer that is not valid UTF-8 can only be shown as bytes.
+        if editor.buffer.is_binary() {
+            editor.set_hex_view(true);
+            editor.set_message(format!(
+                "{} is not valid UTF-8, so is shown read-only in hex view",
+                editor.filename.as_deref().unwrap_or("The buffer")
+            ));
+            return Ok(());
+        }
+        // Only the start of the buffer is checked, so only the chunks 
+        // that hold it are read.
+        let start: Vec<u8> = editor.buffer
+            .chunks()
+            .flat_map(str::bytes)
+            .take(BINARY_CHECK_LEN)
+            .collect();
+        if !looks_binary(&start) {
+            return Ok(());
+        }
+        let prompt = format!(
+            "{} looks like a binary file. Open it in hex view? (y/n) ",
+            editor.filename.as_deref().unwrap_or("The buffer")
+        );
+        if editor_confirm(editor, &prompt)? {
+            editor.set_hex_view(true);
+        }
+        Ok(())
+    }
+
+
+    /// Set up the swap file for the buffer's file, first offering to 
+    /// recover the contents of an existing swap file that is newer than
+    /// the file. Buffers without a filename or that are read-only have
+    /// no swap file. The swap file is not written until the buffer has
+    /// unsaved changes, by `editor_update_swap`.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting.
+    pub fn editor_open_swap(editor: &mut Editor) -> io::Result<()> {
+        let Some(filename) = editor.filename.clone() else {
+            return Ok(());
+        };
+        if editor.is_read_only() {
+            return Ok(());
+        }
+        let path = Path::new(&filename);
+
+        if let Some(contents) = swap::recoverable(path) {
+            let prompt = format!("Found a newer swap file for {}. Recover it? (y/n) ", filename);
+            if editor_confirm(editor, &prompt)? {
+                let mut buffer = PieceTable::from_string(contents);
+                buffer.set_line_ending(editor.buffer.line_ending());
+                buffer.set_has_bom(editor.buffer.has_bom());
+                editor.buffer = buffer;
+                editor.dirty = true;
+                editor.set_message("Recovered from swap file");
+            }
+        }
+
+        editor.swap_path = Some(swap::swap_path(path));
+        editor.swap_time = Instant::now();
+        Ok(())
+    }
+
+
+    /// Write the swap file if the buffer has unsaved changes made since
+    /// it was last written and at least `SWAP_INTERVAL` has passed by 
+    /// `now`, returning whether the screen needs redrawing. Failing to
+    /// write the swap file is reported on the message bar.
+    pub fn editor_update_swap(editor: &mut Editor, now: Instant) -> bool {
+        if editor.swap_path.is_none()
+            || !editor.dirty
+            || editor.keypress_time < editor.swap_time
+            || now.saturating_duration_since(editor.swap_time) < SWAP_INTERVAL
+        {
+            return false;
+        }
+        editor.swap_time = now;
+        match editor.write_swap() {
+            Ok(()) => false,
+            Err(err) => {
+                editor.set_message(format!("Can't write swap file! I/O error: {}", err));
+                true
+            },
+        }
+    }
+
+
+    /// Prompt for a search string and its replacement, replace every
+    /// occurrence in the buffer, and report how many were replaced on
+    /// the message bar.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting, or the buffer cannot be edited.
+    pub fn editor_replace(editor: &mut Editor) -> io::Result<()> {
+        let query = match editor_prompt(editor, "Replace: ")? {
+            Some(query) if !query.is_empty() => query,
+            _ => {
+                editor.set_message("Replace aborted");
+                return Ok(());
+            },
+        };
+        let Some(replacement) = editor_prompt(editor, &format!("Replace {} with: ", query))? 
+        else {
+            editor.set_message("Replace aborted");
+            return Ok(());
+        };
+
+        let n_replaced = editor.replace_all(&query, &replacement)?;
+        editor.set_message(format!("Replaced {} occurrences", n_replaced));
+        Ok(())
+    }
+
+
+    /// Show `prompt` on the message bar followed by the text typed so 
+    /// far, redrawing the screen after each key. Returns the text once
+    /// Enter is pressed, or `None` if the prompt is cancelled with ESC.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen.
+    pub fn editor_prompt(editor: &mut Editor, prompt: &str) -> io::Result<Option<String>> {
+        editor_prompt_with_callback(editor, prompt, |_, _, _| ())
+    }
+
+
+    /// Ask a yes or no question with `prompt`, returning whether it was
+    /// answered yes.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen.
+    pub fn editor_confirm(editor: &mut Editor, prompt: &str) -> io::Result<bool> {
+        let answer = editor_prompt(editor, prompt)?;
+        Ok(answer.is_some_and(|answer| answer.starts_with(['y', 'Y'])))
+    }
+
+
+    /// Behaves as `editor_prompt`, but calls `callback` with the text 
+    /// typed so far after every key, including the Enter or ESC that
+    /// ends the prompt.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen.
+    pub fn editor_prompt_with_callback<F>(
+        editor: &mut Editor, 
+        prompt: &str, 
+        callback: F
+    ) -> io::Result<Option<String>> 
+    where
+        F: FnMut(&mut Editor, &str, Key),
+    {
+        editor_prompt_with_label(editor, |_| prompt.to_string(), callback)
+    }
+
+
+    /// Behaves as `editor_prompt_with_callback`, but the prompt is 
+    /// rebuilt by `label` before every key so that it can show state 
+    /// changed by `callback`.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen.
+    pub fn editor_prompt_with_label<L, F>(
+        editor: &mut Editor, 
+        label: L, 
+        mut callback: F
+    ) -> io::Result<Option<String>> 
+    where
+        L: Fn(&Editor) -> String,
+        F: FnMut(&mut Editor, &str, Key),
+    {
+        let mut input = String::new();
+
+        loop {
+            editor.set_message(format!("{}{}", label(editor), input));
+            editor_refresh_screen(editor)?;
+
+            let key = keys::read_key(&mut editor.input)?;
+            match key {
+                Key::Enter => {
+                    editor.set_message("");
+                    callback(editor, &input, key);
+                    return Ok(Some(input));
+                },
+                Key::Escape => {
+                    editor.set_message("");
+                    callback(editor, &input, key);
+                    return Ok(None);
+                },
+                Key::Backspace | Key::Ctrl('h') => {
+                    input.pop();
+                },
+                Key::Char(c) if !c.is_control() => input.push(c),
+                _ => (),
+            }
+            callback(editor, &input, key);
+        }
+    }
+
+
+    /// Return the start of the first match in `matches` at or after 
+    /// `from`, wrapping around to the first match, along with whether
+    /// it wrapped.
+    fn next_match(matches: &[Range<usize>], from: usize) -> Option<(usize, bool)> {
+        matches.iter()
+            .find(|found| found.start >= from)
+            .map(|found| (found.start, false))
+            .or_else(|| matches.first().map(|found| (found.start, true)))
+    }
+
+
+    /// Return the start of the last match in `matches` before `before`,
+    /// wrapping around to the last match, along with whether it wrapped.
+    fn previous_match(matches: &[Range<usize>], before: usize) -> Option<(usize, bool)> {
+        matches.iter()
+            .rfind(|found| found.start < before)
+            .map(|found| (found.start, false))
+            .or_else(|| matches.last().map(|found| (found.start, true)))
+    }
+
+
+    /// Search the buffer for the text typed into a prompt, moving the
+    /// cursor to the first match at or after the cursor as each key is
+    /// typed. The arrow keys move to the next or previous match, Ctrl-R
+    /// toggles regex search, Ctrl-C toggles ignoring case, Ctrl-W 
+    /// toggles whole-word matching, and the cursor is returned to where
+    /// it started if the search is cancelled.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen.
+    pub fn editor_find(editor: &mut Editor) -> io::Result<()> {
+        let original_offset = editor.cursor_offset();
+        let original_row_offset = editor.row_offset;
+        let original_col_offset = editor.col_offset;
+        let mut last_match: Option<usize> = None;
+
+        let label = |editor: &Editor| {
+            let toggles: Vec<&str> = [
+                (editor.regex_search, "regex"),
+                (editor.search_options.case_insensitive, "ignore case"),
+                (editor.search_options.whole_word, "whole word"),
+            ].into_iter()
+                .filter_map(|(on, name)| on.then_some(name))
+                .collect();
+            if toggles.is_empty() {
+                String::from("Search (Use ESC/Arrows/Enter): ")
+            } else {
+                format!("Search [{}] (Use ESC/Arrows/Enter): ", toggles.join(", "))
+            }
+        };
+        let query = editor_prompt_with_label(editor, label, |editor, query, key| {
+            editor.search_highlight = Some(query.to_string()).filter(|query| !query.is_empty());
+            if key == Key::Escape {
+                editor.search_highlight = None;
+                editor.set_cursor_offset(original_offset);
+                editor.row_offset = original_row_offset;
+                editor.col_offset = original_col_offset;
+                return;
+            }
+            match key {
+                #[cfg(feature = "regex")]
+                Key::Ctrl('r') => editor.regex_search = !editor.regex_search,
+                Key::Ctrl('c') => editor.search_options.case_insensitive ^= true,
+                Key::Ctrl('w') => editor.search_options.whole_word ^= true,
+                _ => (),
+            }
+            if query.is_empty() {
+                return;
+            }
+
+            let (from, forward) = match (key, last_match) {
+                _ if key == Key::Enter => (original_offset, true),
+                (Key::ArrowRight | Key::ArrowDown, Some(last)) => (last + 1, true),
+                (Key::ArrowLeft | Key::ArrowUp, Some(last)) => (last, false),
+                _ => (original_offset, true),
+            };
+            let found = match editor.find_match(query, from, forward) {
+                Ok(found) => found.map(|(offset, _)| offset),
+                Err(message) => {
+                    // The prompt hides messages until it is finished
+                    if key == Key::Enter {
+                        editor.set_message(message);
+                    }
+                    return;
+                },
+            };
+            if key == Key::Enter {
+                return;
+            }
+
+            last_match = found;
+            if let Some(offset) = found {
+                editor.set_cursor_offset(offset);
+            }
+        })?;
+
+        if let Some(query) = query.filter(|query| !query.is_empty()) {
+            editor.search_highlight = Some(query.clone());
+            editor.last_search = Some(query);
+        }
+        if editor.cursor_offset() != original_offset {
+            editor.push_jump(original_offset);
+        }
+        Ok(())
+    }
+
+
+    /// Move the cursor to the next match of the last search after the
+    /// cursor, or the previous match before it if `forward` is false, 
+    /// wrapping around the buffer with a message if there is none.
+    pub fn editor_find_next(editor: &mut Editor, forward: bool) {
+        let Some(query) = editor.last_search.clone() else {
+            editor.set_message("No previous search");
+            return;
+        };
+        let offset = editor.cursor_offset();
+        let from = if forward { offset + 1 } else { offset };
+        let found = match editor.find_match(&query, from, forward) {
+            Ok(found) => found,
+            Err(message) => {
+                editor.set_message(message);
+                return;
+            },
+        };
+
+        let Some((found, wrapped)) = found else {
+            editor.set_message(format!("Pattern not found: {}", query));
+            return;
+        };
+        editor.search_highlight = Some(query);
+        if wrapped {
+            editor.set_message(if forward {
+                "Search hit BOTTOM, continuing at TOP"
+            } else {
+                "Search hit TOP, continuing at BOTTOM"
+            });
+        }
+        if found != offset {
+            editor.push_jump(offset);
+            editor.set_cursor_offset(found);
+        }
+    }
+
+
+    /// A change to the buffer that can be repeated with `.`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Change {
+        /// Delete the character under the cursor.
+        DeleteChar,
+        /// Delete the cursor line into the register.
+        KillLine,
+        /// Join the cursor line with the next.
+        JoinLines,
+        /// Paste at the cursor.
+        Paste,
+        /// Indent the cursor line.
+        Indent,
+        /// Dedent the cursor line.
+        Dedent,
+        /// Toggle the comment on the cursor line.
+        ToggleComment,
+        /// Change the case of the character under the cursor.
+        Case(Case),
+        /// Insert text typed in insert mode.
+        Insert(String),
+    }
+
+
+    /// Make `change` to the buffer at the cursor.
+    ///
+    /// # Errors
+    /// * Returns an error if the buffer cannot be edited.
+    pub fn editor_apply_change(editor: &mut Editor, change: &Change) -> io::Result<()> {
+        match change {
+            Change::DeleteChar => editor.delete_char_under_cursor()?,
+            Change::KillLine => editor.kill_line()?,
+            Change::JoinLines => editor.join_lines()?,
+            Change::Paste => editor.paste()?,
+            Change::Indent => editor.indent_rows(editor.selected_rows())?,
+            Change::Dedent => editor.dedent_rows(editor.selected_rows())?,
+            Change::ToggleComment => editor.toggle_comment_rows(editor.selected_rows())?,
+            Change::Case(case) => editor.change_char_case(*case)?,
+            Change::Insert(text) => editor.insert_str(text)?,
+        }
+        Ok(())
+    }
+
+
+    /// Make `change` and remember it as the change for `.` to repeat.
+    ///
+    /// # Errors
+    /// * Returns an error if the buffer cannot be edited.
+    fn editor_make_change(editor: &mut Editor, change: Change) -> io::Result<()> {
+        editor_apply_change(editor, &change)?;
+        editor.last_change = Some((change, 1));
+        Ok(())
+    }
+
+
+    /// Make `change` `count` times as a single undo group, as `.` 
+    /// repeats a change made with a count.
+    ///
+    /// # Errors
+    /// * Returns an error if the buffer cannot be edited.
+    fn editor_repeat_change(editor: &mut Editor, change: &Change, count: usize) -> io::Result<()> {
+        editor.undo_group(|editor| {
+            for i in 0..count {
+                // As for a counted `dd`, the lines go to the register 
+                // together
+                editor.kill_appending = *change == Change::KillLine && i > 0;
+                editor_apply_change(editor, change)?;
+            }
+            Ok(())
+        })
+    }
+
+
+    /// A command entered on the command line after `:`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Command {
+        /// Write the buffer to its file, or to the given file without
+        /// changing the buffer's filename.
+        Write(Option<String>),
+        /// Quit, unless there are unsaved changes.
+        Quit,
+        /// Quit, discarding any unsaved changes.
+        ForceQuit,
+        /// Write the buffer to its file, or to the given file which 
+        /// becomes the buffer's filename, and then quit.
+        WriteQuit(Option<String>),
+        /// Move the cursor to the start of a line, counting from 1.
+        GoToLine(usize),
+        /// Move the cursor to the start of the line the given 
+        /// percentage of the way through the buffer.
+        GoToPercent(usize),
+        /// Show the number of lines, words and characters in the buffer.
+        WordCount,
+        /// Replace every tab with spaces.
+        ExpandTabs,
+        /// Replace the spaces indenting each line with tabs.
+        UnexpandTabs,
+        /// Discard unsaved changes and load the file again.
+        Reload,
+        /// Switch to the buffer for the given file, opening it in a new
+        /// buffer if it is not already open.
+        Edit(String),
+        /// Switch to the next buffer, wrapping around to the first.
+        NextBuffer,
+        /// Switch to the previous buffer, wrapping around to the last.
+        PrevBuffer,
+        /// Show the open buffers.
+        ListBuffers,
+        /// Split the focused pane in two, showing the given file in the
+        /// new pane if there is one.
+        Split(Option<String>),
+        /// Close the focused pane.
+        ClosePane,
+        /// Stop highlighting matches of the last search.
+        NoHighlight,
+        /// Show the buffer in the hex view, or return to the text if it
+        /// is shown.
+        HexView,
+    }
+
+
+    /// Parse `command`, the text entered after `:`, into a `Command`.
+    ///
+    /// # Errors
+    /// * Returns `None` if `command` is not a known command or is given
+    ///   an argument it does not take.
+    pub fn parse_command(command: &str) -> Option<Command> {
+        let command = command.trim();
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_string())),
+            None => (command, None),
+        };
+
+        match (name, arg) {
+            ("w", arg) => Some(Command::Write(arg)),
+            ("wq" | "x", arg) => Some(Command::WriteQuit(arg)),
+            ("q", None) => Some(Command::Quit),
+            ("q!", None) => Some(Command::ForceQuit),
+            ("wc", None) => Some(Command::WordCount),
+            ("expandtabs", None) => Some(Command::ExpandTabs),
+            ("unexpandtabs", None) => Some(Command::UnexpandTabs),
+            ("e!", None) => Some(Command::Reload),
+            ("e", Some(path)) if !path.is_empty() => Some(Command::Edit(path)),
+            ("bn", None) => Some(Command::NextBuffer),
+            ("bp", None) => Some(Command::PrevBuffer),
+            ("ls", None) => Some(Command::ListBuffers),
+            ("sp" | "split", arg) => Some(Command::Split(arg)),
+            ("close", None) => Some(Command::ClosePane),
+            ("noh" | "nohlsearch", None) => Some(Command::NoHighlight),
+            ("hex", None) => Some(Command::HexView),
+            (line, None) if line.ends_with('%') => {
+                line[..line.len() - 1].parse().ok().map(Command::GoToPercent)
+            },
+            (line, None) => line.parse().ok().map(Command::GoToLine),
+            _ => None,
+        }
+    }
+
+
+    /// Prompt for a command and run it.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting.
+    pub fn editor_command(editor: &mut Editor) -> io::Result<()> {
+        let Some(input) = editor_prompt(editor, ":")? else {
+            return Ok(());
+        };
+        if input.trim().is_empty() {
+            return Ok(());
+        }
+
+        match parse_command(&input) {
+            Some(command) => editor_run_command(editor, command),
+            None => {
+                editor.set_message(format!("Not an editor command: {}", input.trim()));
+                Ok(())
+            },
+        }
+    }
+
+
+    /// Run `command` on `editor`.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting for a filename.
+    pub fn editor_run_command(editor: &mut Editor, command: Command) -> io::Result<()> {
+        match command {
+            Command::Write(_) 
+            | Command::WriteQuit(_) 
+            | Command::ExpandTabs 
+            | Command::UnexpandTabs if editor.is_read_only() => {
+                editor.set_message(READ_ONLY_MESSAGE);
+            },
+            Command::Write(None) => editor_save(editor)?,
+            Command::Write(Some(path)) => match editor.save_to(&path) {
+                Ok(n_bytes) => editor.set_message(format!("{} bytes written to {}", n_bytes, path)),
+                Err(err) => editor.set_message(format!("Can't save! I/O error: {}", err)),
+            },
+            Command::WriteQuit(path) => {
+                if let Some(path) = path {
+                    editor.set_filename(path);
+                }
+                editor_save(editor)?;
+                if editor.any_dirty() {
+                    if !editor.dirty {
+                        editor.set_message(OTHER_BUFFERS_DIRTY_MESSAGE);
+                    }
+                } else {
+                    editor.status = EditorStatus::TerminalExitSuccess;
+                }
+            },
+            Command::Quit => {
+                if editor.dirty {
+                    editor.set_message("No write since last change (add ! to override)");
+                } else if editor.any_dirty() {
+                    editor.set_message(OTHER_BUFFERS_DIRTY_MESSAGE);
+                } else {
+                    editor.status = EditorStatus::TerminalExitSuccess;
+                }
+            },
+            Command::ForceQuit => editor.status = EditorStatus::TerminalExitSuccess,
+            Command::GoToLine(line) => editor_go_to_line(editor, line),
+            Command::GoToPercent(percent) => editor_go_to_percent(editor, percent),
+            Command::Reload => editor_reload(editor)?,
+            Command::Edit(path) => editor_edit(editor, &path)?,
+            Command::NextBuffer => {
+                editor.switch_buffer((editor.active_buffer + 1) % editor.buffers.len());
+                editor.set_message(editor.buffer_list());
+            },
+            Command::PrevBuffer => {
+                let count = editor.buffers.len();
+                editor.switch_buffer((editor.active_buffer + count - 1) % count);
+                editor.set_message(editor.buffer_list());
+            },
+            Command::ListBuffers => editor.set_message(editor.buffer_list()),
+            Command::Split(path) => {
+                editor.split_pane();
+                if let Some(path) = path {
+                    editor_edit(editor, &path)?;
+                }
+            },
+            Command::NoHighlight => editor.search_highlight = None,
+            Command::HexView if editor.hex_view && editor.buffer.is_binary() => {
+                editor.set_message("Can't show the buffer as text: it is not valid UTF-8");
+            },
+            Command::HexView => editor.set_hex_view(!editor.hex_view),
+            Command::ClosePane => {
+                if !editor.close_pane() {
+                    editor.set_message("Can't close the last pane");
+                }
+            },
+            Command::WordCount => {
+                let count = editor.word_count();
+                editor.set_message(format!(
+                    "{} lines, {} words, {} characters", 
+                    count.lines, count.words, count.chars
+                ));
+            },
+            Command::ExpandTabs => {
+                let n_tabs = editor.expand_tabs()?;
+                editor.set_message(format!("Expanded {} tabs", n_tabs));
+            },
+            Command::UnexpandTabs => {
+                let n_lines = editor.unexpand_tabs()?;
+                editor.set_message(format!("Indented {} lines with tabs", n_lines));
+            },
+        }
+        Ok(())
+    }
+
+
+    /// Switch to the buffer for the file at `path`, opening it in a new
+    /// buffer if it is not open. A file that does not exist opens as an
+    /// empty buffer that saving creates.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting to recover a swap file. Failing to read the 
+    ///   file is reported on the message bar instead.
+    pub fn editor_edit(editor: &mut Editor, path: &str) -> io::Result<()> {
+        if let Some(index) = editor.find_buffer(path) {
+            editor.switch_buffer(index);
+            return Ok(());
+        }
+        let buffer = match load_file(path) {
+            Ok(buffer) => buffer,
+            Err(PieceTableError::IOError(err)) if err.kind() == io::ErrorKind::NotFound => {
+                PieceTable::from_str("")
+            },
+            Err(err) => {
+                editor.set_message(format!("Can't open {}! {}", path, err));
+                return Ok(());
+            },
+        };
+        editor.open_buffer(buffer, Some(path.to_string()));
+        editor.set_message(format!("\"{}\" {} lines", path, editor.buffer.line_count()));
+        editor_offer_hex_view(editor)?;
+        editor_open_swap(editor)
+    }
+
+
+    /// Move the cursor to the start of `line`, counting from 1, and 
+    /// scroll so that it is visible. Lines past the end of the buffer 
+    /// move the cursor to the last line.
+    pub fn editor_go_to_line(editor: &mut Editor, line: usize) {
+        let line_count = editor.buffer.line_count();
+        let row = if line > line_count {
+            editor.set_message(format!("Line {} out of range, moved to line {}", line, line_count));
+            line_count - 1
+        } else {
+            line.saturating_sub(1)
+        };
+
+        // Can safely unwrap here since `row` is always less than 
+        // `line_count`.
+        let offset = editor.buffer.line_to_offset(row).unwrap();
+        editor.push_jump(editor.cursor_offset());
+        editor.set_cursor_offset(offset);
+        editor_scroll(editor);
+    }
+
+
+    /// Move the cursor to the start of the line `percent` of the way 
+    /// through the buffer, rounding up as vim does. Percentages over 100
+    /// move to the last line.
+    pub fn editor_go_to_percent(editor: &mut Editor, percent: usize) {
+        let line_count = editor.buffer.line_count();
+        let line = (percent.min(100) * line_count).div_ceil(100);
+        editor_go_to_line(editor, line);
+    }
+
+
+    /// Prompt for a line number and move the cursor to it.
+    ///
+    /// # Errors
+    /// * Returns an error if unable to read a key or refresh the screen
+    ///   while prompting.
+    pub fn editor_prompt_go_to_line(editor: &mut Editor) -> io::Result<()> {
+        let Some(input) = editor_prompt(editor, "Go to line: ")? else {
+            return Ok(());
+        };
+        match input.trim().parse() {
+            Ok(line) => editor_go_to_line(editor, line),
+            Err(_) => editor.set_message(format!("Not a line number: {}", input.trim())),
+        }
+        Ok(())
+    }
+
+
+    /// Quit the editor, unless the buffer has unsaved changes and quit
+    /// has not yet been requested `quit_confirmations` more times in a 
+    /// row.
+    pub fn editor_quit(editor: &mut Editor) {
+        if editor.any_dirty() && editor.quit_times > 0 {
+            editor.set_message(format!(
+                "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                editor.quit_times
+            ));
+            editor.quit_times -= 1;
+        } else {
+            editor.status = EditorStatus::TerminalExitSuccess;
+        }
+    }
+
+
+    /// Apply `action` to `editor`, as pressing a key bound to it would.
+    /// Changes made in normal mode are remembered for `.` to repeat.
+    ///
+    /// # Errors
+    /// * Returns an error if the buffer cannot be edited or saved, or if
+    ///   unable to read a key or refresh the screen while prompting.
+    pub fn editor_apply_action(editor: &mut Editor, action: Action) -> io::Result<()> {
+        if editor.hex_view && editor_move_hex_cursor(editor, action) {
+            return Ok(());
+        }
+        if editor.is_read_only() && action.edits_buffer() {
+            editor.set_message(READ_ONLY_MESSAGE);
+            return Ok(());
+        }
+        let normal = editor.mode == EditorMode::Normal;
+        match action {
+            Action::Quit => editor_quit(editor),
+            Action::Save => editor_save(editor)?,
+            Action::Find => editor_find(editor)?,
+            Action::FindNext => editor_find_next(editor, true),
+            Action::FindPrevious => editor_find_next(editor, false),
+            Action::Replace => editor_replace(editor)?,
+            Action::GoToLine => editor_prompt_go_to_line(editor)?,
+            Action::Reload => editor_reload(editor)?,
+            Action::Command => editor_command(editor)?,
+            Action::KillToEndOfLine => editor.kill_to_end_of_line()?,
+            Action::TransposeChars => editor.transpose_chars()?,
+            Action::FocusNextPane => editor.focus_next_pane(),
+            Action::MoveLeft => editor.move_left(),
+            Action::MoveRight => editor.move_right(),
+            Action::MoveUp => editor.move_up(),
+            Action::MoveDown => editor.move_down(),
+            Action::MoveWordLeft => editor.move_word_left(),
+            Action::MoveWordRight => editor.move_word_right(),
+            Action::MoveHome => editor.move_home(),
+            Action::GoToTop => editor_go_to_line(editor, 1),
+            Action::GoToBottom => editor_go_to_line(editor, editor.buffer.line_count()),
+            Action::SelectLeft | Action::SelectRight | Action::SelectUp | Action::SelectDown => {
+                if editor.mode != EditorMode::Visual {
+                    editor.set_mode(EditorMode::Visual);
+                }
+                match action {
+                    Action::SelectLeft => editor.move_left(),
+                    Action::SelectRight => editor.move_right(),
+                    Action::SelectUp => editor.move_up(),
+                    _ => editor.move_down(),
+                }
+            },
+            Action::ScrollHalfPageUp => editor.scroll_half_page_up(),
+            Action::ScrollHalfPageDown => editor.scroll_half_page_down(),
+            Action::ScrollPageUp => editor.scroll_page_up(),
+            Action::ScrollPageDown => editor.scroll_page_down(),
+            Action::CenterCursor => editor.center_cursor(),
+            Action::JumpBack => editor.jump_back(),
+            Action::JumpForward => editor.jump_forward(),
+            Action::SetMark(c) => {
+                editor.marks.insert(c, editor.cursor_offset());
+            },
+            Action::JumpToMark(c) if editor.marks.contains_key(&c) => editor.jump_to_mark(c),
+            Action::JumpToMark(c) => editor.set_message(format!("Mark {} not set", c)),
+            Action::EnterNormalMode => {
+                if let Some(run) = editor.insert_run.take() {
+                    if !run.is_empty() && editor.mode == EditorMode::Insert {
+                        editor.last_change = Some((Change::Insert(run), 1));
+                    }
+                }
+                editor.set_mode(EditorMode::Normal);
+            },
+            Action::EnterInsertMode => {
+                editor.set_mode(EditorMode::Insert);
+                editor.insert_run = Some(String::new());
+            },
+            Action::EnterVisualMode => editor.set_mode(EditorMode::Visual),
+            Action::EnterVisualBlockMode => editor.set_mode(EditorMode::VisualBlock),
+            Action::ToggleReplace if editor.mode == EditorMode::Insert => {
+                editor.set_mode(EditorMode::Replace);
+            },
+            Action::ToggleReplace => editor.set_mode(EditorMode::Insert),
+            Action::InsertChar(c) if editor.mode == EditorMode::Replace => {
+                editor.overwrite_char(c)?;
+            },
+            Action::InsertChar(c) if editor.mode == EditorMode::Visual => {
+                editor.replace_selection(c.encode_utf8(&mut [0; 4]))?;
+                editor.set_mode(EditorMode::Insert);
+            },
+            Action::InsertChar(c) => {
+                editor.insert_char(c)?;
+                if let Some(run) = &mut editor.insert_run {
+                    run.push(c);
+                }
+            },
+            Action::InsertNewline => {
+                editor.insert_newline_and_indent()?;
+                if let Some(run) = &mut editor.insert_run {
+                    // Include the indent so that repeating the insertion
+                    // gives the same text.
+                    let line = editor.buffer.line(editor.cursor_row).unwrap_or_default();
+                    run.push('\n');
+                    run.push_str(&line[..editor.cursor_col]);
+                }
+            },
+            Action::DeleteBack => {
+                let offset = editor.cursor_offset();
+                editor.delete_char_before_cursor()?;
+                let n_deleted = offset - editor.cursor_offset();
+                // Deleting text from before the insertion began cannot 
+                // be repeated.
+                editor.insert_run = editor.insert_run.take()
+                    .filter(|run| run.len() >= n_deleted)
+                    .map(|mut run| {
+                        run.truncate(run.len() - n_deleted);
+                        run
+                    });
+            },
+            Action::DeleteChar => editor_make_change(editor, Change::DeleteChar)?,
+            Action::KillLine => editor_make_change(editor, Change::KillLine)?,
+            Action::CopyLine => editor.copy_line(),
+            Action::JoinLines => editor_make_change(editor, Change::JoinLines)?,
+            Action::DuplicateLine => editor.duplicate_line()?,
+            Action::Paste if normal => editor_make_change(editor, Change::Paste)?,
+            Action::Paste => {
+                editor.paste()?;
+                if editor.mode == EditorMode::Visual {
+                    editor.set_mode(EditorMode::Normal);
+                }
+            },
+            Action::Indent if normal => editor_make_change(editor, Change::Indent)?,
+            Action::Dedent if normal => editor_make_change(editor, Change::Dedent)?,
+            Action::Indent | Action::Dedent => {
+                let rows = editor.selected_rows();
+                if action == Action::Indent {
+                    editor.indent_rows(rows)?;
+                } else {
+                    editor.dedent_rows(rows)?;
+                }
+                if editor.mode == EditorMode::Visual {
+                    editor.set_mode(EditorMode::Normal);
+                }
+            },
+            Action::ToggleComment if normal => editor_make_change(editor, Change::ToggleComment)?,
+            Action::ToggleComment => {
+                editor.toggle_comment_rows(editor.selected_rows())?;
+                editor.set_mode(EditorMode::Normal);
+            },
+            Action::ChangeCase(case) if normal => editor_make_change(editor, Change::Case(case))?,
+            Action::ChangeCase(case) => editor.change_selection_case(case)?,
+            Action::DeleteSelection if editor.mode == EditorMode::VisualBlock => {
+                editor.delete_block()?;
+            },
+            Action::DeleteSelection => editor.delete_selection()?,
+            Action::CopySelection => editor.copy_selection(),
+            Action::CompleteWord => editor.complete_word()?,
+            Action::InsertInBlock => {
+                if let Some(text) = editor_prompt(editor, "Insert: ")? {
+                    editor.insert_in_block(&text)?;
+                }
+            },
+            Action::RepeatChange => {
+                if let Some((change, count)) = editor.last_change.clone() {
+                    editor_repeat_change(editor, &change, count)?;
+                }
+            },
+            Action::Undo => editor.undo(false)?,
+            Action::Redo => editor.undo(true)?,
+            Action::ToggleRecording => match editor.recording.take() {
+                Some((register, mut keys)) => {
+                    // Leave out the key that stopped the recording
+                    keys.pop();
+                    editor.macros.insert(register, keys);
+                    editor.set_message("");
+                },
+                None => editor.pending_key = Some('q'),
+            },
+            Action::StartRecording(c) => {
+                editor.recording = Some((c, Vec::new()));
+                editor.set_message(format!("recording @{}", c));
+            },
+            Action::PlayMacro(c) => editor_play_macro(editor, c)?,
+            Action::PlayLastMacro => {
+                if let Some(register) = editor.last_macro {
+                    editor_play_macro(editor, register)?;
+                }
+            },
+            Action::Prefix(c) => editor.pending_key = Some(c),
+        }
+        Ok(())
+    }
+
+
+    /// Move the cursor of the hex view by bytes for the movement 
+    /// `action`, returning whether `action` was a movement.
+    fn editor_move_hex_cursor(editor: &mut Editor, action: Action) -> bool {
+        let last = editor.hex_buffer().len().saturating_sub(1);
+        let cursor = editor.hex_cursor;
+        editor.hex_cursor = match action {
+            Action::MoveLeft => cursor.saturating_sub(1),
+            Action::MoveRight => (cursor + 1).min(last),
+            Action::MoveUp => cursor.checked_sub(HEX_BYTES_PER_ROW).unwrap_or(cursor),
+            Action::MoveDown if cursor + HEX_BYTES_PER_ROW <= last => cursor + HEX_BYTES_PER_ROW,
+            Action::MoveDown => cursor,
+            Action::MoveHome => cursor - cursor % HEX_BYTES_PER_ROW,
+            Action::GoToTop => 0,
+            Action::GoToBottom => last - last % HEX_BYTES_PER_ROW,
+            _ => return false,
+        };
+        true
+    }
+
+
+    /// The action of `key` following `pending_key`, the first key of a
+    /// multi-key command. Keys bound in every mode keep their binding.
+    fn pending_key_action(editor: &Editor, pending_key: char, key: Key) -> Option<Action> {
+        let action = match (pending_key, key) {
+            ('d', Key::Char('d')) => Action::KillLine,
+            ('y', Key::Char('y')) => Action::CopyLine,
+            ('z', Key::Char('z')) => Action::CenterCursor,
+            ('g', Key::Char('U')) => Action::ChangeCase(Case::Upper),
+            ('g', Key::Char('u')) => Action::ChangeCase(Case::Lower),
+            ('g', Key::Char('c')) => Action::ToggleComment,
+            ('g', Key::Char('g')) => Action::GoToTop,
+            ('q', Key::Char(c)) if c.is_ascii_alphanumeric() => Action::StartRecording(c),
+            ('@', Key::Char('@')) => Action::PlayLastMacro,
+            ('@', Key::Char(c)) => Action::PlayMacro(c),
+            ('m', Key::Char(c)) if c.is_ascii_lowercase() => Action::SetMark(c),
+            ('`', Key::Char(c)) => Action::JumpToMark(c),
+            (LITERAL_PREFIX, key) => Action::InsertChar(key.literal()?),
+            _ => return editor.keymap.get_global(key),
+        };
+        Some(action)
+    }
+
+
+    /// Updates `editor` in response to `key`, applying the action bound
+    /// to it in `editor.keymap`. Characters that are not bound are 
+    /// typed in insert and replace mode, and replace the selection in
+    /// visual mode.
+    ///
+    /// # Errors
+    /// * Returns an error if the buffer cannot be edited or saved.
+    pub fn editor_process_key(editor: &mut Editor, key: Key) -> io::Result<()> {
+        // Digits before a normal or visual mode command are its count. 
+        // A leading 0 is not a count.
+        if let Key::Char(c @ '0'..='9') = key {
+            let counting = editor.pending_key.is_none()
+                && matches!(
+                    editor.mode, 
+                    EditorMode::Normal | EditorMode::Visual | EditorMode::VisualBlock
+                )
+                && (c != '0' || editor.pending_count.is_some());
+            if counting {
+                editor.record_key(key);
+                // Can safely unwrap here since `c` is a decimal digit.
+                let digit = c.to_digit(10).unwrap() as usize;
+                let count = editor.pending_count.unwrap_or(0)
+                    .saturating_mul(10)
+                    .saturating_add(digit);
+                editor.pending_count = Some(count.min(MAX_COUNT));
+                return Ok(());
+            }
+        }
+
+        let action = match editor.pending_key {
+            Some(pending_key) => pending_key_action(editor, pending_key, key),
+            None => editor.keymap.get(editor.mode, key).or(match (editor.mode, key) {
+                (EditorMode::Insert | EditorMode::Replace | EditorMode::Visual, Key::Char(c)) => {
+                    Some(Action::InsertChar(c))
+                },
+                _ => None,
+            }),
+        };
+        if action == Some(Action::Quit) {
+            editor_quit(editor);
+            return Ok(());
+        }
+        editor.quit_times = editor.config.quit_confirmations;
+        editor.pending_key = None;
+        let kill_appending = std::mem::take(&mut editor.kill_appending);
+        let completion = editor.completion.take();
+        let desired_col = editor.desired_col.take();
+        let count = editor.pending_count.take();
+        editor.record_key(key);
+
+        // Only text that was typed can be repeated, so anything else
+        // typed in insert mode ends the insertion that `.` repeats.
+        if key != Key::PasteStart && !matches!(
+            action, 
+            Some(
+                Action::InsertChar(_) 
+                    | Action::InsertNewline 
+                    | Action::DeleteBack 
+                    | Action::EnterNormalMode
+            )
+        ) {
+            editor.insert_run = None;
+        }
+
+        if key == Key::PasteStart {
+            // The paste is read even when it is refused so that its text
+            // is not run as keys.
+            let text = keys::read_bracketed_paste(&mut editor.input)?;
+            if editor.is_read_only() {
+                editor.set_message(READ_ONLY_MESSAGE);
+            } else if editor.mode == EditorMode::Visual {
+                editor.replace_selection(&text)?;
+                editor.set_mode(EditorMode::Normal);
+            } else {
+                editor.insert_str(&text)?;
+                if let Some(run) = &mut editor.insert_run {
+                    run.push_str(&text);
+                }
+            }
+            return Ok(());
+        }
+
+        let Some(action) = action else {
+            return Ok(());
+        };
+        if action == Action::KillToEndOfLine {
+            editor.kill_appending = kill_appending;
+        }
+        if action == Action::CompleteWord {
+            editor.completion = completion;
+        }
+        if action.moves_vertically() {
+            editor.desired_col = desired_col;
+        }
+        match (action, count) {
+            (Action::Prefix(_), count) => {
+                // Keep the count for the command the prefix starts
+                editor.pending_count = count;
+                editor_apply_action(editor, action)?;
+            },
+            (Action::GoToTop | Action::GoToBottom, Some(line)) => {
+                editor_go_to_line(editor, line);
+            },
+            // A count given to `.` replaces the count of the change
+            (Action::RepeatChange, Some(count)) => {
+                if let Some((change, _)) = editor.last_change.clone() {
+                    editor_repeat_change(editor, &change, count)?;
+                    editor.last_change = Some((change, count));
+                }
+            },
+            (action, Some(count)) if action.takes_count() => {
+                let last_change = editor.last_change.take();
+                editor.undo_group(|editor| {
+                    for i in 0..count {
+                        // Lines killed by a counted `dd` go to the 
+                        // register together
+                        editor.kill_appending = action == Action::KillLine && i > 0;
+                        editor_apply_action(editor, action)?;
+                    }
+                    io::Result::Ok(())
+                })?;
+                // A change made by the action is repeated with its count
+                editor.last_change = match editor.last_change.take() {
+                    Some((change, _)) => Some((change, count)),
+                    None => last_change,
+                };
+            },
+            (action, _) => editor_apply_action(editor, action)?,
+        }
+        Ok(())
+    }
+
+
+    /// Process each key recorded into the macro `register`. A macro 
+    /// that tries to play itself, directly or through another macro, 
+    /// stops there instead of recursing forever.
+    ///
+    /// # Errors
+    /// * Returns an error if a key of the macro cannot be processed.
+    pub fn editor_play_macro(editor: &mut Editor, register: char) -> io::Result<()> {
+        if editor.playing_macros.contains(&register) {
+            editor.set_message(format!("Recursive macro @{}", register));
+            return Ok(());
+        }
+        let Some(keys) = editor.macros.get(&register).cloned() else {
+            return Ok(());
+        };
+        editor.last_macro = Some(register);
+        editor.playing_macros.push(register);
+        let result = keys.into_iter().try_for_each(|key| editor_process_key(editor, key));
+        editor.playing_macros.pop();
+        result
+    }
+
+
+    /// How the cells of a line are drawn.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct CellStyle {
+        color: Option<Color>,
+        inverse: bool,
+    }
+
+    impl CellStyle {
+        /// Escape sequence that switches from the default style to 
+        /// `self`.
+        fn escape(&self) -> String {
+            let mut escape = String::new();
+            if self.inverse {
+                escape.push_str("\x1b[7m");
+            }
+            if let Some(color) = self.color {
+                escape.push_str(&color.escape());
+            }
+            escape
+        }
+    }
+
+
+    /// Append the cells of `rendered` from column `start` up to 
+    /// `start + width` to `frame`, drawing the columns covered by 
+    /// `colors` in their colour and the columns in `selection` in 
+    /// inverse video.
+    fn draw_rendered_line(
+        frame: &mut String, 
+        rendered: &str, 
+        start: usize, 
+        width: usize, 
+        colors: &[(Range<usize>, Color)],
+        selection: Option<Range<usize>>,
+    ) {
+        let end = start + width;
+
+        // Columns at which the style of the line can change
+        let mut boundaries: Vec<usize> = colors.iter()
+            .map(|(range, _)| range)
+            .chain(selection.as_ref())
+            .flat_map(|range| [range.start, range.end])
+            .map(|col| col.clamp(start, end))
+            .chain([start, end])
+            .collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut current_style = CellStyle::default();
+        for segment in boundaries.windows(2) {
+            let (segment_start, segment_end) = (segment[0], segment[1]);
+            let style = CellStyle {
+                color: colors.iter()
+                    .find(|(range, _)| range.contains(&segment_start))
+                    .map(|(_, color)| *color),
+                inverse: selection.as_ref().is_some_and(|range| range.contains(&segment_start)),
+            };
+            if style != current_style {
+                if current_style != CellStyle::default() {
+                    frame.push_str("\x1b[m");
+                }
+                frame.push_str(&style.escape());
+                current_style = style;
+            }
+            frame.push_str(&clip_rendered_line(
+                rendered, 
+                segment_start, 
+                segment_end - segment_start
+            ));
+        }
+        if current_style != CellStyle::default() {
+            frame.push_str("\x1b[m");
+        }
+    }
+
+
+    /// Append each row of the screen to `frame`, drawing lines of the
+    /// buffer with tabs expanded and clipped to the screen width, and a
+    /// tilde (like vim) on rows past the end of the buffer. An empty 
+    /// buffer that has not been named or edited shows a welcome message
+    /// instead.
+    fn editor_draw_rows(editor: &Editor, frame: &mut String) {
+        if editor.hex_view {
+            editor_draw_hex_rows(editor, frame);
+            return;
+        }
+        let text_rows = editor.text_rows();
+        let mut lines = editor.buffer
+            .lines_from(editor.row_offset)
+            .into_iter()
+            .flatten()
+            .take(text_rows);
+        let gutter_width = editor.gutter_width();
+        let selection = editor.selection();
+        let block = editor.block_selection();
+        let brackets: Vec<(usize, Color)> = match editor.bracket_match() {
+            Some((cursor, Some(other))) => vec![
+                (cursor, MATCHED_BRACKET_COLOR), 
+                (other, MATCHED_BRACKET_COLOR),
+            ],
+            Some((cursor, None)) => vec![(cursor, UNMATCHED_BRACKET_COLOR)],
+            None => vec![],
+        };
+        let mut line_start = editor.buffer
+            .line_to_offset(editor.row_offset)
+            .unwrap_or(editor.buffer.len());
+        let mut line_number = editor.row_offset;
+        let show_welcome = editor.filename.is_none() && !editor.dirty && editor.buffer.is_empty();
+
+        // Matches of the search term on the lines that can be seen
+        let cursor_offset = editor.cursor_offset();
+        let search_matches: Vec<(Range<usize>, Color)> = editor.search_highlight
+            .as_ref()
+            .map(|term| {
+                let visible_end = editor.buffer
+                    .line_to_offset(editor.row_offset + text_rows)
+                    .unwrap_or(editor.buffer.len());
+                editor.search_matches(term, line_start..visible_end)
+                    .unwrap_or_default()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|range| {
+                let color = if range.start == cursor_offset {
+                    CURRENT_MATCH_COLOR
+                } else {
+                    SEARCH_MATCH_COLOR
+                };
+                (range, color)
+            })
+            .collect();
+
+        let mut row = 0;
+        while row < text_rows {
+            match lines.next() {
+                Some(line) => {
+                    let line = line.as_ref();
+                    line_number += 1;
+                    let tab_width = editor.config.tab_width;
+                    let rendered = render_line(line, tab_width);
+                    let to_render_cols = |range: Range<usize>| {
+                        buffer_col_to_render_col(line, range.start, tab_width)
+                            ..buffer_col_to_render_col(line, range.end, tab_width)
+                    };
+
+                    let line_end = line_start + line.len();
+
+                    // Brackets come first so that they are drawn over
+                    // search matches, which are drawn over the
+                    // highlighter's colours.
+                    let colors: Vec<(Range<usize>, Color)> = brackets.iter()
+                        .filter(|(offset, _)| (line_start..line_end).contains(offset))
+                        .map(|(offset, color)| {
+                            let col = offset - line_start;
+                            (col..col + 1, *color)
+                        })
+                        .chain(search_matches.iter()
+                            .filter(|(range, _)| range.start < line_end && range.end > line_start)
+                            .map(|(range, color)| (
+                                range.start.max(line_start) - line_start
+                                    ..range.end.min(line_end) - line_start,
+                                *color,
+                            )))
+                        .chain(editor.highlighter
+                            .as_ref()
+                            .map(|highlighter| highlighter.highlight_line(line))
+                            .unwrap_or_default())
+                        .map(|(range, color)| (to_render_cols(range), color))
+                        .collect();
+
+                    // Columns of the line covered by the selection
+                    let line_selection = selection.as_ref()
+                        .filter(|selection| {
+                            selection.start < line_end && selection.end > line_start
+                        })
+                        .map(|selection| to_render_cols(
+                            selection.start.max(line_start) - line_start
+                                ..selection.end.min(line_end) - line_start
+                        ))
+                        .or_else(|| block.as_ref()
+                            .filter(|block| block.rows.contains(&(line_number - 1)))
+                            .map(|block| block.cols.clone()));
+
+                    // The columns drawn on each screen row of the line
+                    let segments: Vec<(usize, usize)> = if editor.config.wrap {
+                        let starts = editor.wrap_line(line);
+                        let ends = starts.iter().skip(1).copied().map(Some).chain([None]);
+                        starts.iter()
+                            .zip(ends)
+                            .map(|(&start, end)| {
+                                (start, end.map_or(editor.text_cols(), |end| end - start))
+                            })
+                            .collect()
+                    } else {
+                        vec![(editor.col_offset, editor.text_cols())]
+                    };
+
+                    for (i, (start, width)) in segments.into_iter().enumerate() {
+                        if row == text_rows {
+                            break;
+                        }
+                        if gutter_width > 0 {
+                            let number = if editor.config.relative_line_numbers 
+                                && line_number - 1 != editor.cursor_row 
+                            {
+                                (line_number - 1).abs_diff(editor.cursor_row)
+                            } else {
+                                line_number
+                            };
+                            let mut gutter = if i == 0 {
+                                format!("{:>1$}", number, gutter_width - 1)
+                            } else {
+                                " ".repeat(gutter_width - 1)
+                            };
+                            gutter.push(editor.config.gutter_separator);
+                            frame.extend(gutter.chars().take(editor.screen_colums));
+                        }
+
+                        // Without wrapping, mark lines that go on past 
+                        // either edge of the screen
+                        let (mut start, mut width) = (start, width);
+                        let scrolled_left = !editor.config.wrap 
+                            && start > 0 
+                            && rendered.width() > 0;
+                        let scrolled_right = !editor.config.wrap 
+                            && rendered.width() > start + width;
+                        if scrolled_left && width > 0 {
+                            frame.push('<');
+                            start += 1;
+                            width -= 1;
+                        }
+                        let mark_right = scrolled_right && width > 0;
+                        if mark_right {
+                            width -= 1;
+                        }
+                        draw_rendered_line(
+                            frame,
+                            &rendered, 
+                            start, 
+                            width,
+                            &colors,
+                            line_selection.clone(),
+                        );
+                        if mark_right {
+                            frame.push('>');
+                        }
+                        end_row(frame, false);
+                        row += 1;
+                    }
+                    line_start = line_end + 1;
+                },
+                None if show_welcome && row == text_rows / 3 => {
+                    let width = editor.screen_colums;
+                    let welcome = clip_rendered_line(WELCOME_MESSAGE, 0, width);
+                    let padding = (width - welcome.width()) / 2;
+                    if padding > 0 {
+                        frame.push('~');
+                        frame.push_str(&" ".repeat(padding - 1));
+                    }
+                    frame.push_str(&welcome);
+                    end_row(frame, false);
+                    row += 1;
+                },
+                None => {
+                    frame.push('~');
+                    end_row(frame, false);
+                    row += 1;
+                },
+            }
+        }
+    }
+
+
+    /// Behaves as `editor_draw_rows` in the hex view, drawing a row of 
+    /// the dump for every `HEX_BYTES_PER_ROW` bytes of the buffer.
+    fn editor_draw_hex_rows(editor: &Editor, frame: &mut String) {
+        // Only the bytes of the rows that can be seen are copied out of
+        // the buffer.
+        let start = editor.row_offset * HEX_BYTES_PER_ROW;
+        let stop = start + editor.text_rows() * HEX_BYTES_PER_ROW;
+        let buffer = editor.hex_buffer();
+        let len = buffer.len();
+        // Can safely unwrap here since the range is clamped to the buffer
+        let visible = buffer.get_bytes_range(start.min(len)..stop.min(len)).unwrap();
+
+        let mut rows = visible.chunks(HEX_BYTES_PER_ROW).enumerate();
+        for _ in 0..editor.text_rows() {
+            match rows.next() {
+                Some((row, bytes)) => {
+                    let dump = hex_dump_row((editor.row_offset + row) * HEX_BYTES_PER_ROW, bytes);
+                    frame.push_str(&clip_rendered_line(&dump, 0, editor.screen_colums));
+                },
+                None => frame.push('~'),
+            }
+            end_row(frame, false);
+        }
+    }
+
+
+    /// A row of a hex dump of `bytes`, which start at `offset`, in the 
+    /// layout of `hexdump -C`: the offset, the bytes in hex with a gap
+    /// after the eighth, then the bytes as ASCII with `.` standing in 
+    /// for anything unprintable.
+    pub fn hex_dump_row(offset: usize, bytes: &[u8]) -> String {
+        let mut row = format!("{:08x}  ", offset);
+        for i in 0..HEX_BYTES_PER_ROW {
+            match bytes.get(i) {
+                Some(byte) => row.push_str(&format!("{:02x} ", byte)),
+                None => row.push_str("   "),
+            }
+            if i == HEX_BYTES_PER_ROW / 2 - 1 {
+                row.push(' ');
+            }
+        }
+        row.push_str(" |");
+        row.extend(bytes.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' { char::from(byte) } else { '.' }
+        }));
+        row.push('|');
+        row
+    }
+
+
+    /// Column of a hex dump row that the byte at `index` within the row
+    /// is drawn from.
+    fn hex_dump_col(index: usize) -> usize {
+        10 + 3 * index + usize::from(index >= HEX_BYTES_PER_ROW / 2)
+    }
+
+
+    /// Whether `bytes` look like the contents of a binary file rather 
+    /// than text, going by whether there is a NUL byte near the start.
+    pub fn looks_binary(bytes: &[u8]) -> bool {
+        bytes.iter().take(BINARY_CHECK_LEN).any(|&byte| byte == 0)
+    }
+
+
+    /// Append the message bar to `frame` if the current message has not
+    /// expired. The message bar is the bottom row of the screen.
+    fn editor_draw_message_bar(editor: &Editor, frame: &mut String) {
+        if editor.message_time.elapsed() < MESSAGE_DURATION {
+            frame.push_str(&clip_rendered_line(&editor.message, 0, editor.screen_colums));
+        }
+        end_row(frame, true);
+    }
+
+
+    /// Finish a row drawn to `frame`, moving on to the start of the next
+    /// one unless it `is_last_row` of the screen, where a newline would
+    /// scroll the terminal up a row.
+    fn end_row(frame: &mut String, is_last_row: bool) {
+        if !is_last_row {
+            frame.push_str("\r\n");
+        }
+    }
+
+
+    /// Name of the type of file `filename` is, going by its extension,
+    /// or `None` if the extension is not recognised.
+    pub fn file_type(filename: &str) -> Option<&'static str> {
+        let extension = Path::new(filename).extension()?.to_str()?;
+        let file_type = match extension.to_ascii_lowercase().as_str() {
+            "rs" => "rust",
+            "c" | "h" => "c",
+            "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+            "py" => "python",
+            "js" => "javascript",
+            "ts" => "typescript",
+            "go" => "go",
+            "java" => "java",
+            "sh" => "sh",
+            "sql" => "sql",
+            "html" | "htm" => "html",
+            "css" => "css",
+            "json" => "json",
+            "toml" => "toml",
+            "yaml" | "yml" => "yaml",
+            "md" => "markdown",
+            "txt" => "text",
+            _ => return None,
+        };
+        Some(file_type)
+    }
+
+
+    /// The prefix that starts a line comment in files of `file_type`, 
+    /// as returned by `file_type`.
+    pub fn comment_prefix(file_type: &str) -> Option<&'static str> {
+        let prefix = match file_type {
+            "rust" | "c" | "cpp" | "javascript" | "typescript" | "go" | "java" => "//",
+            "python" | "sh" | "toml" | "yaml" => "#",
+            "sql" => "--",
+            _ => return None,
+        };
+        Some(prefix)
+    }
+
+
+    /// Append the status line of the focused pane to `frame`. The 
+    /// filename, whether it has unsaved changes and, in the pane keys are
+    /// sent to, the mode unless it is normal mode are on the left, and
+    /// the file type, the cursor's line and how far through the file it
+    /// is are on the right. The status line of the pane keys are sent to
+    /// is drawn in inverse video.
+    fn editor_draw_status_line(editor: &Editor, focused: bool, frame: &mut String) {
+        let mode = match editor.mode {
+            _ if !focused => "",
+            EditorMode::Normal => "",
+            EditorMode::Insert => " -- INSERT --",
+            EditorMode::Replace => " -- REPLACE --",
+            EditorMode::Visual => " -- VISUAL --",
+            EditorMode::VisualBlock => " -- VISUAL BLOCK --",
+        };
+        let left = format!(
+            "{}{}{}",
+            editor.filename.as_deref().unwrap_or("[No Name]"),
+            if editor.dirty { " [+]" } else { "" },
+            mode,
+        );
+        let file_type = editor.filename.as_deref().and_then(file_type);
+        let right = format!(
+            "{}{}/{} {}",
+            file_type.map(|file_type| format!("{} | ", file_type)).unwrap_or_default(),
+            editor.cursor_row + 1,
+            editor.buffer.line_count(),
+            editor.position_percentage(),
+        );
+        let render_width = |s: &str| -> usize { s.chars().map(|c| c.width().unwrap_or(0)).sum() };
+
+        // The right side is kept whole as long as it fits, cutting the 
+        // filename short instead.
+        let right = clip_rendered_line(&right, 0, editor.screen_colums);
+        let right_width = render_width(&right);
+        let left = clip_rendered_line(
+            &left, 
+            0, 
+            editor.screen_colums.saturating_sub(right_width + 1)
+        );
+        let padding = editor.screen_colums.saturating_sub(render_width(&left) + right_width);
+        if focused {
+            frame.push_str("\x1b[7m");
+        }
+        frame.push_str(&left);
+        frame.extend(std::iter::repeat_n(' ', padding));
+        frame.push_str(&right);
+        if focused {
+            frame.push_str("\x1b[m");
+        }
+        end_row(frame, false);
+    }
+
+
+    /// Append every pane to `frame`, each followed by its status line.
+    /// Each pane is focused in turn while it is drawn, and only the 
+    /// focused pane shows the selection.
+    fn editor_draw_panes(editor: &mut Editor, frame: &mut String) {
+        let focused = editor.active_pane;
+        let selection_anchor = editor.selection_anchor.take();
+        for index in 0..editor.panes.len() {
+            editor.enter_pane(index);
+            if index == focused {
+                editor.selection_anchor = selection_anchor;
+            }
+            editor_scroll(editor);
+            editor_draw_rows(editor, frame);
+            editor_draw_status_line(editor, index == focused, frame);
+            editor.selection_anchor = None;
+        }
+        editor.enter_pane(focused);
+        editor.selection_anchor = selection_anchor;
+    }
+
+
+    /// Draws the next frame showing the contents of `editor`. Only the
+    /// rows that differ from the last frame are drawn, unless the 
+    /// screen has changed size, when it is cleared and drawn in full.
+    ///
+    /// # Errors
+    /// * Returns an error if the stdin.flush fails to write all bytes
+    ///   to screen.
+    pub fn editor_refresh_screen(editor: &mut Editor) -> io::Result<()> {
+        if editor.screen_too_small() {
+            return editor_draw_too_small(editor);
+        }
+        editor_scroll(editor);
+
+        let mut screen = String::new();
+        editor_draw_panes(editor, &mut screen);
+        editor_draw_message_bar(editor, &mut screen);
+        let rows: Vec<String> = screen.split("\r\n").map(String::from).collect();
+
+        let mut frame = String::new();
+        let size = (editor.screen_rows, editor.screen_colums);
+        if editor.drawn_size != Some(size) || editor.drawn_rows.len() != rows.len() {
+            // Clear contents of terminal and move cursor to top 
+            frame.push_str("\x1b[2J");
+            frame.push_str("\x1b[H");
+            frame.push_str(&screen);
+        } else {
+            // Redraw each changed row, clearing what was left after it
+            for (i, (row, drawn)) in rows.iter().zip(&editor.drawn_rows).enumerate() {
+                if row != drawn {
+                    frame.push_str(&format!("\x1b[{};1H{}\x1b[K", i + 1, row));
+                }
+            }
+        }
+        editor.drawn_rows = rows;
+        editor.drawn_size = Some(size);
+
+        // Move cursor to its position in the buffer
+        frame.push_str(&format!(
+            "\x1b[{};{}H", 
+            editor.pane_top(editor.active_pane) + editor.cursor_screen_row() + 1, 
+            editor.cursor_screen_col() + 1
+        ));
+
+        // Change the cursor's shape on the first draw and when the mode
+        // changes
+        let cursor_escape = editor.cursor_escape();
+        if editor.drawn_cursor.as_ref() != Some(&cursor_escape) {
+            frame.push_str(&cursor_escape);
+            editor.drawn_cursor = Some(cursor_escape);
+        }
+
+        editor.output.write_all(frame.as_bytes())?;
+        editor.output.flush()
+    }
+
+    /// Clears the screen and draws `TOO_SMALL_MESSAGE` in place of the
+    /// editor, forgetting the last frame so that the editor is drawn in
+    /// full once the screen is big enough again.
+    ///
+    /// # Errors
+    /// * Returns an error if the message fails to be written to screen.
+    fn editor_draw_too_small(editor: &mut Editor) -> io::Result<()> {
+        editor.drawn_rows.clear();
+        editor.drawn_size = None;
+        let message = clip_rendered_line(TOO_SMALL_MESSAGE, 0, editor.screen_colums);
+        write!(editor.output, "\x1b[2J\x1b[H{}\x1b[H", message)?;
+        editor.output.flush()
+    }
+
+    /// Returns the terminal to the state defined by `original_termios`,
+    /// clears the terminal and kills the program. If `status` describes
+    /// a failure then it is reported on stderr and the program exits
+    /// with a non-zero exit code.
+    pub fn kill_editor(original_termios: OriginalStdinMode, status: EditorStatus) -> ! {
+        crate::input_stream_editor::recover_original_stdin_mode(original_termios);
+
+        // Disable bracketed paste and restore the default cursor
+        print!("\x1b[?2004l");
+        print!("{}", DEFAULT_CURSOR_ESCAPE);
+        print!("\x1b[2J");
+        print!("\x1b[H");
+
+        if let Err(err) = io::stdout().flush() {
+            panic!("{}", err);
+        }
+
+        match status {
+            EditorStatus::RefershScreen | EditorStatus::TerminalExitSuccess => {
+                std::process::exit(0);
+            },
+
+            EditorStatus::FailedToBuild(EditorBuildError::UnableToGetWindowSize) => {
+                eprintln!("Failed to build editor: unable to get window size");
+            },
+
+            EditorStatus::FailedToBuild(EditorBuildError::UnableToOpenFile(err)) => {
+                eprintln!("Failed to build editor: unable to open file: {}", err);
+            },
+
+            EditorStatus::FailedToRefresh(err) => {
+                eprintln!("Failed to refresh screen: {}", err);
+            },
+
+            EditorStatus::FailedToProcessKeypress(err) => {
+                eprintln!("Failed to process keypress: {}", err);
+            },
+
+            EditorStatus::InputClosedWithUnsavedChanges => {
+                eprintln!("Input closed with unsaved changes, changes were not saved");
+            },
+        }
+
+        std::process::exit(1);
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        fn editor_from_str(s: &str) -> Editor {
+            let size = WindowSize { rows: 6, cols: 20 };
+            Editor::new(size, EditorConfig::default(), PieceTable::from_str(s))
+        }
+
+        #[test]
+        fn render_line_expands_tabs_to_next_stop() {
+            assert_eq!(render_line("\tab", 4), "    ab");
+            assert_eq!(render_line("a\tb", 4), "a   b");
+            assert_eq!(render_line("abc\td", 4), "abc d");
+            assert_eq!(render_line("abcd\te", 4), "abcd    e");
+            assert_eq!(render_line("a\t\tb", 4), "a       b");
+            // A tab is always at least one column wide
+            assert_eq!(render_line("a\t\tb", 0), "a  b");
+        }
+
+        #[test]
+        fn buffer_col_to_render_col_with_tabs() {
+            let line = "a\tbc\td";
+            assert_eq!(buffer_col_to_render_col(line, 0, 4), 0);
+            assert_eq!(buffer_col_to_render_col(line, 1, 4), 1);
+            assert_eq!(buffer_col_to_render_col(line, 2, 4), 4);
+            assert_eq!(buffer_col_to_render_col(line, 4, 4), 6);
+            assert_eq!(buffer_col_to_render_col(line, 5, 4), 8);
+        }
+
+        #[test]
+        fn editor_draw_rows_aligns_tabbed_rows() {
+            let mut editor = editor_from_str("\tx\nab\ty\nabcd\tz");
+            editor.config.tab_width = 4;
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[0], "    x");
+            assert_eq!(rows[1], "ab  y");
+            assert_eq!(rows[2], "abcd    z");
+            assert_eq!(rows[3], "~");
+        }
+
+        #[test]
+        fn render_width_of_wide_characters() {
+            let line = "a\u{65e5}\u{672c}b";
+            assert_eq!(render_line(line, 4), line);
+            assert_eq!(buffer_col_to_render_col(line, 1, 4), 1);
+            assert_eq!(buffer_col_to_render_col(line, 4, 4), 3);
+            assert_eq!(buffer_col_to_render_col(line, 7, 4), 5);
+            assert_eq!(buffer_col_to_render_col(line, 8, 4), 6);
+            // Tab stops are measured in cells, not characters
+            assert_eq!(render_line("\u{65e5}\tx", 4), "\u{65e5}  x");
+        }
+
+        #[test]
+        fn wrap_line_keeps_wide_characters_and_tabs_whole() {
+            assert_eq!(wrap_line("", 4, 8), [0]);
+            assert_eq!(wrap_line("abc", 4, 8), [0]);
+            // A line that fills its last row gets a row for the cursor
+            assert_eq!(wrap_line("abcd", 4, 8), [0, 4]);
+            assert_eq!(wrap_line("abc\u{65e5}d", 4, 8), [0, 3]);
+            assert_eq!(wrap_line("ab\tc", 4, 4), [0, 4]);
+            assert_eq!(wrap_line("a\tbcdefghi", 6, 4), [0, 6, 12]);
+        }
+
+        #[test]
+        fn wrap_long_line() {
+            let size = WindowSize { rows: 10, cols: 80 };
+            let config = EditorConfig { wrap: true, ..Default::default() };
+            let line = "0123456789".repeat(20);
+            let contents = format!("{}\nshort", line);
+            let mut editor = Editor::new(size, config, PieceTable::from_str(&contents));
+
+            assert_eq!(editor.line_height(&line), 3);
+            let drawn = editor_draw_rows_to_string(&editor);
+            let rows: Vec<&str> = drawn.split("\r\n").collect();
+            assert_eq!(rows[0], &line[..80]);
+            assert_eq!(rows[1], &line[80..160]);
+            assert_eq!(rows[2], &line[160..]);
+            assert_eq!(rows[3], "short");
+            assert_eq!(rows[4], "~");
+
+            editor.cursor_col = 170;
+            assert_eq!((editor.cursor_screen_row(), editor.cursor_screen_col()), (2, 10));
+            editor.move_up();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 90));
+            assert_eq!((editor.cursor_screen_row(), editor.cursor_screen_col()), (1, 10));
+            editor.move_down();
+            editor.move_down();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 5));
+            assert_eq!(editor.cursor_screen_row(), 3);
+            editor.move_up();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 165));
+        }
+
+        #[test]
+        fn wrap_scrolls_by_screen_rows() {
+            let size = WindowSize { rows: 6, cols: 10 };
+            let config = EditorConfig { wrap: true, ..Default::default() };
+            let contents = format!("{}\n{}\nend", "a".repeat(25), "b".repeat(15));
+            let mut editor = Editor::new(size, config, PieceTable::from_str(&contents));
+
+            // The first line takes three rows, so the second row of the
+            // second line only fits once the first has scrolled off.
+            editor.move_to_row(1);
+            editor_scroll(&mut editor);
+            assert_eq!(editor.row_offset, 0);
+            editor.move_down();
+            editor_scroll(&mut editor);
+            assert_eq!(editor.row_offset, 1);
+            assert_eq!(editor.cursor_screen_row(), 1);
+            assert_eq!(editor.col_offset, 0);
+        }
+
+        #[test]
+        fn clip_rendered_line_splits_wide_characters() {
+            let rendered = "a\u{65e5}\u{672c}b";
+            assert_eq!(clip_rendered_line(rendered, 0, 6), rendered);
+            assert_eq!(clip_rendered_line(rendered, 2, 4), " \u{672c}b");
+            assert_eq!(clip_rendered_line(rendered, 0, 2), "a ");
+        }
+
+        #[test]
+        fn horizontal_scroll_keeps_wide_cursor_cell_visible() {
+            let size = WindowSize { rows: 3, cols: 4 };
+            let line = "ab\u{65e5}\u{672c}\u{8a9e}";
+            let mut editor = Editor::new(size, EditorConfig::default(), PieceTable::from_str(line));
+            editor.cursor_col = 8;
+            editor_scroll(&mut editor);
+            assert_eq!(editor.cursor_render_col(), 6);
+            assert_eq!(editor.col_offset, 4);
+            assert_eq!(editor.cursor_screen_col(), 2);
+
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            // The scroll marker covers half of the first character, so
+            // it is drawn as a space
+            assert_eq!(frame.split("\r\n").next().unwrap(), "< \u{8a9e}");
+        }
+
+        #[test]
+        fn editor_draw_rows_marks_lines_scrolled_past_edges() {
+            let mut editor = editor_from_str("0123456789abcdefghijklmnopqrstuvwxyz\nshort\n");
+            editor.col_offset = 10;
+            let frame = editor_draw_rows_to_string(&editor);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[0], "<bcdefghijklmnopqrs>");
+            // Lines entirely to the left are still marked, but empty 
+            // lines have nothing to scroll past
+            assert_eq!(rows[1], "<");
+            assert_eq!(rows[2], "");
+
+            editor.col_offset = 0;
+            let frame = editor_draw_rows_to_string(&editor);
+            assert_eq!(frame.split("\r\n").next(), Some("0123456789abcdefghi>"));
+
+            // Wrapped lines are never scrolled
+            editor.config.wrap = true;
+            let frame = editor_draw_rows_to_string(&editor);
+            assert_eq!(frame.split("\r\n").next(), Some("0123456789abcdefghij"));
+        }
+
+        #[test]
+        fn cursor_render_col_follows_tab_width() {
+            let mut editor = editor_from_str("abc\n\tdef");
+            editor.cursor_row = 1;
+            editor.cursor_col = 2;
+            assert_eq!(editor.cursor_render_col(), 9);
+            editor.config.tab_width = 4;
+            assert_eq!(editor.cursor_render_col(), 5);
+        }
+
+        #[test]
+        fn move_right_over_combining_character() {
+            let mut editor = editor_from_str("e\u{301}x");
+            editor.config.grapheme_movement = true;
+            editor.move_right();
+            assert_eq!(editor.cursor_col, 3);
+            editor.move_left();
+            assert_eq!(editor.cursor_col, 0);
+
+            editor.config.grapheme_movement = false;
+            editor.move_right();
+            assert_eq!(editor.cursor_col, 1);
+        }
+
+        #[test]
+        fn move_right_over_flag_emoji() {
+            let mut editor = editor_from_str("\u{1f1ec}\u{1f1e7}!");
+            editor.config.grapheme_movement = true;
+            editor.move_right();
+            assert_eq!(editor.cursor_col, 8);
+            editor.move_left();
+            assert_eq!(editor.cursor_col, 0);
+
+            editor.config.grapheme_movement = false;
+            editor.move_right();
+            assert_eq!(editor.cursor_col, 4);
+        }
+
+        #[test]
+        fn move_left_and_right_wrap_lines() {
+            let mut editor = editor_from_str("ab\ncd");
+            editor.cursor_col = 2;
+            editor.move_right();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+            editor.move_left();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 2));
+        }
+
+        #[test]
+        fn move_down_clamps_column() {
+            let mut editor = editor_from_str("abcd\n\u{e9}\nxyz");
+            editor.cursor_col = 4;
+            editor.move_down();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+            // The column moved down from is kept past the shorter line
+            editor.move_down();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 3));
+            editor.move_down();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 3));
+        }
+
+        #[test]
+        fn enter_in_crlf_document_saves_crlf() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_crlf", std::process::id()));
+            std::fs::write(&path, "first\r\nsecond\r\n").unwrap();
+
+            let size = WindowSize { rows: 4, cols: 20 };
+            let buffer = PieceTable::from_file(&path).unwrap();
+            let mut editor = Editor::new(size, EditorConfig::default(), buffer);
+            editor.filename = Some(path.to_string_lossy().into_owned());
+
+            editor.cursor_col = 3;
+            editor.mode = EditorMode::Insert;
+            editor_process_key(&mut editor, Key::Enter).unwrap();
+            editor_process_key(&mut editor, Key::Char('!')).unwrap();
+            assert!(editor.dirty);
+            editor_process_key(&mut editor, Key::Ctrl('s')).unwrap();
+            assert!(!editor.dirty);
+
+            let saved = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(saved, "fir\r\n!st\r\nsecond\r\n");
+            assert!(saved.split_inclusive('\n').all(|line| line.ends_with("\r\n")));
+        }
+
+        fn editor_with_input(s: &str, input: &[u8]) -> Editor {
+            let mut editor = editor_from_str(s);
+            editor.input = Box::new(io::Cursor::new(input.to_vec()));
+            editor.output = Box::new(io::sink());
+            editor
+        }
+
+        #[test]
+        fn open_swap_offers_recovery() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_recover.txt", std::process::id()));
+            let swap_path = swap::swap_path(&path);
+            std::fs::write(&path, "saved").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(std::time::SystemTime::now() - Duration::from_secs(60)).unwrap();
+            swap::write(&swap_path, "unsaved").unwrap();
+
+            let mut editor = editor_with_input("saved", b"y\r");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor_open_swap(&mut editor).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "unsaved");
+            assert!(editor.dirty);
+            assert_eq!(editor.swap_path.as_deref(), Some(swap_path.as_path()));
+
+            editor.remove_swap();
+            assert!(!swap_path.exists());
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn swap_is_only_written_once_buffer_is_dirty() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_swap_dirty.txt", std::process::id()));
+            let swap_path = swap::swap_path(&path);
+            std::fs::write(&path, "saved").unwrap();
+
+            let mut editor = editor_with_input("saved", b"");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor_open_swap(&mut editor).unwrap();
+            assert_eq!(editor.swap_path.as_deref(), Some(swap_path.as_path()));
+            assert!(!swap_path.exists());
+
+            let later = editor.swap_time + SWAP_INTERVAL;
+            editor_update_swap(&mut editor, later);
+            assert!(!swap_path.exists());
+
+            process_input(&mut editor, b"x");
+            editor.keypress_time = later;
+            editor_update_swap(&mut editor, later);
+            assert!(swap_path.exists());
+
+            editor.remove_swap();
+            assert!(!swap_path.exists());
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn save_as_moves_swap_file() {
+            let dir = std::env::temp_dir();
+            let old_path = dir.join(format!("editor_{}_save_as_old.txt", std::process::id()));
+            let new_path = dir.join(format!("editor_{}_save_as_new.txt", std::process::id()));
+            std::fs::write(&old_path, "saved").unwrap();
+            let mut editor = editor_with_input("saved", b"");
+            editor.filename = Some(old_path.to_string_lossy().into_owned());
+            editor_open_swap(&mut editor).unwrap();
+            process_input(&mut editor, b"x");
+            editor.write_swap().unwrap();
+
+            let command = format!("wq {}", new_path.to_string_lossy());
+            editor_run_command(&mut editor, parse_command(&command).unwrap()).unwrap();
+            assert!(!swap::swap_path(&old_path).exists());
+            assert_eq!(editor.swap_path, Some(swap::swap_path(&new_path)));
+            assert_eq!(swap::recoverable(&new_path), None);
+
+            editor.remove_swap();
+            assert!(!swap::swap_path(&new_path).exists());
+            std::fs::remove_file(&old_path).unwrap();
+            std::fs::remove_file(&new_path).unwrap();
+
+            // An unnamed buffer is given a swap path when it is named
+            let mut editor = editor_with_input("", b"");
+            editor.set_filename(new_path.to_string_lossy().into_owned());
+            assert_eq!(editor.swap_path, Some(swap::swap_path(&new_path)));
+        }
+
+        #[test]
+        fn save_asks_before_overwriting_changed_file() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_conflict.txt", std::process::id()));
+            std::fs::write(&path, "one").unwrap();
+            let size = WindowSize { rows: 5, cols: 40 };
+            let buffer = PieceTable::from_file(&path).unwrap();
+            let mut editor = Editor::new(size, EditorConfig::default(), buffer);
+            editor.input = Box::new(io::Cursor::new(b"n\ry\r".to_vec()));
+            editor.output = Box::new(io::sink());
+            editor.filename = Some(path.to_string_lossy().into_owned());
+
+            editor.insert_str("two ").unwrap();
+            std::fs::write(&path, "changed").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60)).unwrap();
+
+            editor_save(&mut editor).unwrap();
+            assert_eq!(editor.message, "Save aborted");
+            assert!(editor.dirty);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "changed");
+
+            editor_save(&mut editor).unwrap();
+            let saved = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(!editor.dirty);
+            assert_eq!(saved, "two one");
+        }
+
+        #[test]
+        fn reload_discards_changes() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_reload.txt", std::process::id()));
+            std::fs::write(&path, "one\ntwo\nthree").unwrap();
+            let mut editor = editor_with_input("", b"y\r");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor.reload().unwrap();
+
+            editor.cursor_row = 2;
+            editor.insert_str("new ").unwrap();
+            editor.delete_line().unwrap();
+            assert!(editor.dirty);
+            editor_process_key(&mut editor, Key::Ctrl('r')).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo\nthree");
+            assert!(!editor.dirty);
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+        }
+
+        #[test]
+        fn reload_removes_swap_file() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_reload_swap.txt", std::process::id()));
+            let swap_path = swap::swap_path(&path);
+            std::fs::write(&path, "saved").unwrap();
+            let mut editor = editor_with_input("saved", b"");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor_open_swap(&mut editor).unwrap();
+            process_input(&mut editor, b"x");
+            editor.write_swap().unwrap();
+            assert!(swap_path.exists());
+
+            editor.reload().unwrap();
+            assert!(!swap_path.exists());
+            assert!(swap::recoverable(&path).is_none());
+            assert_eq!(editor.swap_path.as_deref(), Some(swap_path.as_path()));
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn reload_keeps_changes_unless_confirmed() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_reload_abort.txt", std::process::id()));
+            std::fs::write(&path, "one").unwrap();
+            let mut editor = editor_with_input("", b"n\r");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor.reload().unwrap();
+
+            editor.insert_str("new ").unwrap();
+            editor_reload(&mut editor).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(editor.message, "Reload aborted");
+            assert_eq!(editor.buffer.write_contents_to_string(), "new one");
+        }
+
+        #[test]
+        fn editor_prompt_returns_input_on_enter() {
+            let mut editor = editor_with_input("", b"foo\r");
+            let input = editor_prompt(&mut editor, "Name: ").unwrap();
+            assert_eq!(input.as_deref(), Some("foo"));
+        }
+
+        #[test]
+        fn editor_prompt_handles_backspace() {
+            let mut editor = editor_with_input("", b"fooo\x7f\x7fo\r");
+            let input = editor_prompt(&mut editor, "Name: ").unwrap();
+            assert_eq!(input.as_deref(), Some("foo"));
+        }
+
+        #[test]
+        fn editor_prompt_cancelled_by_escape() {
+            let mut editor = editor_with_input("", b"foo\x1b\x1b");
+            let input = editor_prompt(&mut editor, "Name: ").unwrap();
+            assert_eq!(input, None);
+        }
+
+        #[test]
+        fn editor_prompt_draws_message_bar() {
+            let mut editor = editor_from_str("");
+            editor.set_message("Name: fo");
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            editor_draw_message_bar(&editor, &mut frame);
+            assert!(frame.ends_with("\r\nName: fo"));
+        }
+
+        #[test]
+        fn editor_save_prompts_for_filename() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_save_as", std::process::id()));
+            let mut input = path.to_string_lossy().into_owned().into_bytes();
+            input.push(b'\r');
+
+            let mut editor = editor_with_input("", &input);
+            editor.mode = EditorMode::Insert;
+            editor_process_key(&mut editor, Key::Char('x')).unwrap();
+            editor_process_key(&mut editor, Key::Ctrl('s')).unwrap();
+
+            let saved = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(saved, "x");
+            assert_eq!(editor.filename, Some(path.to_string_lossy().into_owned()));
+            assert!(!editor.dirty);
+            assert_eq!(editor.message, "1 bytes written to disk");
+        }
+
+        #[test]
+        fn save_starts_a_new_undo_group() {
+            fn fixed_clock() -> Instant {
+                static NOW: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+                *NOW.get_or_init(Instant::now)
+            }
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_save_undo", std::process::id()));
+            let mut editor = editor_with_input("", b"2\r");
+            editor.buffer.set_clock(fixed_clock);
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            process_input(&mut editor, b"ione\x13 two\x1bu");
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one");
+
+            // A jump also starts a new group, even when it lands where the
+            // last character typed ended
+            process_input(&mut editor, b"i\x1b[H\r\x07?\x1bu");
+            assert_eq!(editor.buffer.write_contents_to_string(), "\none");
+        }
+
+        #[test]
+        fn editor_save_aborted_keeps_dirty() {
+            let mut editor = editor_with_input("", b"name\x1b");
+            editor.mode = EditorMode::Insert;
+            editor_process_key(&mut editor, Key::Char('x')).unwrap();
+            editor_process_key(&mut editor, Key::Ctrl('s')).unwrap();
+
+            assert_eq!(editor.filename, None);
+            assert!(editor.dirty);
+            assert_eq!(editor.message, "Save aborted");
+        }
+
+        #[test]
+        fn editor_find_moves_cursor_to_match() {
+            let mut editor = editor_with_input("one two\nthree two", b"two\r");
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 4);
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 4));
+        }
+
+        #[test]
+        fn editor_find_cycles_with_arrows() {
+            let mut editor = editor_with_input("one two\nthree two", b"two\x1b[B\r");
+            editor_find(&mut editor).unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 6));
+
+            let mut editor = editor_with_input("one two\nthree two", b"two\x1b[B\x1b[B\r");
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 4);
+
+            let mut editor = editor_with_input("one two\nthree two", b"two\x1b[A\r");
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 14);
+        }
+
+        #[test]
+        fn find_next_repeats_last_search_and_wraps() {
+            let mut editor = editor_with_input("one foo\nfoo two foo", b"foo\r");
+            editor_process_key(&mut editor, Key::Ctrl('f')).unwrap();
+            assert_eq!(editor.last_search.as_deref(), Some("foo"));
+            assert_eq!(editor.cursor_offset(), 4);
+
+            process_input(&mut editor, b"n");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+            process_input(&mut editor, b"n");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 8));
+            process_input(&mut editor, b"n");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 4));
+            assert_eq!(editor.message, "Search hit BOTTOM, continuing at TOP");
+
+            process_input(&mut editor, b"N");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 8));
+            assert_eq!(editor.message, "Search hit TOP, continuing at BOTTOM");
+            process_input(&mut editor, b"N");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+        }
+
+        #[test]
+        fn find_match_searches_out_from_offset() {
+            let mut editor = editor_from_str("\u{e9}fo");
+            editor.buffer.write_to_loc(4, "o \u{e9} foo").unwrap();
+            assert_eq!(editor.find_match("foo", 0, true), Ok(Some((2, false))));
+            assert_eq!(editor.find_match("foo", 3, true), Ok(Some((9, false))));
+            assert_eq!(editor.find_match("foo", 10, true), Ok(Some((2, true))));
+            assert_eq!(editor.find_match("foo", 2, false), Ok(Some((9, true))));
+            assert_eq!(editor.find_match("\u{e9}", 1, true), Ok(Some((6, false))));
+            assert_eq!(editor.find_match("\u{e9}", 6, false), Ok(Some((0, false))));
+            assert_eq!(editor.find_match("bar", 0, true), Ok(None));
+
+            editor.search_options.case_insensitive = true;
+            assert_eq!(editor.find_match("FOO", 3, true), Ok(Some((9, false))));
+            #[cfg(feature = "regex")]
+            {
+                editor.regex_search = true;
+                assert_eq!(editor.find_match("F.O", 9, false), Ok(Some((2, false))));
+                assert_eq!(editor.find_match("F.O", 2, false), Ok(Some((9, true))));
+            }
+        }
+
+        #[test]
+        fn find_next_without_search() {
+            let mut editor = editor_from_str("abc");
+            process_input(&mut editor, b"n");
+            assert_eq!(editor.message, "No previous search");
+            editor.last_search = Some(String::from("xyz"));
+            process_input(&mut editor, b"N");
+            assert_eq!(editor.message, "Pattern not found: xyz");
+        }
+
+        #[cfg(feature = "regex")]
+        #[test]
+        fn editor_find_regex() {
+            let mut editor = editor_with_input("a1 b22\nc333", b"\x12\\d{3}\r");
+            editor_find(&mut editor).unwrap();
+            assert!(editor.regex_search);
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+
+            process_input(&mut editor, b"n");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+            editor.last_search = Some(String::from(r"(?m)\d+$"));
+            process_input(&mut editor, b"N");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 4));
+        }
+
+        #[cfg(feature = "regex")]
+        #[test]
+        fn search_matches_compiles_pattern_once() {
+            let mut editor = editor_from_str("ab ab\nab");
+            editor.regex_search = true;
+            let compiled = |editor: &Editor| editor.compiled_search
+                .borrow()
+                .as_ref()
+                .map(|(pattern, _)| pattern.clone());
+
+            assert_eq!(editor.search_matches("a.", 3..8), Ok(vec![3..5, 6..8]));
+            assert_eq!(compiled(&editor).as_deref(), Some("a."));
+            // A match is only found if it starts in the range
+            assert_eq!(editor.search_matches("a.", 1..8), Ok(vec![3..5, 6..8]));
+            editor.search_options.case_insensitive = true;
+            assert_eq!(editor.search_matches("A.", 0..5), Ok(vec![0..2, 3..5]));
+            assert_eq!(compiled(&editor).as_deref(), Some("(?i)A."));
+        }
+
+        #[test]
+        fn editor_find_ignoring_case() {
+            let mut editor = editor_with_input("foo Foo", b"\x03Foo\r");
+            editor.cursor_col = 1;
+            editor_find(&mut editor).unwrap();
+            assert!(editor.search_options.case_insensitive);
+            assert_eq!(editor.cursor_offset(), 4);
+            process_input(&mut editor, b"n");
+            assert_eq!(editor.cursor_offset(), 0);
+        }
+
+        #[test]
+        fn editor_find_whole_word() {
+            let mut editor = editor_with_input("category cat", b"cat\x17\r");
+            editor_find(&mut editor).unwrap();
+            assert!(editor.search_options.whole_word);
+            assert_eq!(editor.cursor_offset(), 9);
+            process_input(&mut editor, b"n");
+            assert_eq!(editor.cursor_offset(), 9);
+            assert_eq!(editor.message, "Search hit BOTTOM, continuing at TOP");
+        }
+
+        #[test]
+        fn editor_find_shows_toggles_in_prompt() {
+            let mut editor = editor_with_input("abc", b"\x03\x17");
+            let _ = editor_find(&mut editor);
+            assert_eq!(editor.message, "Search [ignore case, whole word] (Use ESC/Arrows/Enter): ");
+        }
+
+        #[test]
+        fn editor_find_invalid_regex_shows_message() {
+            let mut editor = editor_with_input("a(b", b"(\r");
+            editor.regex_search = true;
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 0);
+            #[cfg(feature = "regex")]
+            assert_eq!(editor.message, "Invalid pattern: (");
+            #[cfg(not(feature = "regex"))]
+            assert_eq!(editor.message, "Regex search needs the regex feature");
+        }
+
+        #[test]
+        fn editor_find_starts_at_cursor() {
+            let mut editor = editor_with_input("two one two", b"two\r");
+            editor.cursor_col = 1;
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 8);
+        }
+
+        #[test]
+        fn editor_find_cancel_restores_cursor() {
+            let mut editor = editor_with_input("one two\nthree two", b"thr\x1b\x1b");
+            editor.cursor_col = 2;
+            editor_find(&mut editor).unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 2));
+        }
+
+        #[test]
+        fn editor_replace_reports_count() {
+            let mut editor = editor_with_input("foo bar foo\nfoofoo", b"foo\rbarbar\r");
+            editor_process_key(&mut editor, Key::Ctrl('e')).unwrap();
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "barbar bar barbar\nbarbarbarbar"
+            );
+            assert_eq!(editor.message, "Replaced 4 occurrences");
+            assert!(editor.dirty);
+        }
+
+        #[test]
+        fn editor_replace_all_returns_count() {
+            let mut editor = editor_from_str("foo\nfoo");
+            editor.cursor_row = 1;
+            editor.cursor_col = 3;
+            assert_eq!(editor.replace_all("foo", "barbar").unwrap(), 2);
+            assert_eq!(editor.buffer.write_contents_to_string(), "barbar\nbarbar");
+            assert_eq!(editor.replace_all("baz", "x").unwrap(), 0);
+
+            // The whole replace is undone in one step
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "foo\nfoo");
+        }
+
+        fn process_input(editor: &mut Editor, input: &[u8]) {
+            let mut reader = io::Cursor::new(input);
+            while let Ok(key) = keys::read_key(&mut reader) {
+                editor_process_key(editor, key).unwrap();
+            }
+        }
+
+        #[test]
+        fn editor_modal_insert_then_delete() {
+            let mut editor = editor_from_str("ab\ncd");
+            assert_eq!(editor.mode, EditorMode::Normal);
+
+            process_input(&mut editor, b"li");
+            assert_eq!(editor.mode, EditorMode::Insert);
+            process_input(&mut editor, b"xyz");
+            assert_eq!(editor.buffer.write_contents_to_string(), "axyzb\ncd");
+
+            process_input(&mut editor, b"\x1b");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            process_input(&mut editor, b"hx");
+            assert_eq!(editor.buffer.write_contents_to_string(), "axyb\ncd");
+            assert_eq!(editor.mode, EditorMode::Normal);
+        }
+
+        #[test]
+        fn editor_normal_mode_movement() {
+            let mut editor = editor_from_str("ab\ncd");
+            process_input(&mut editor, b"lj");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+            process_input(&mut editor, b"hk");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab\ncd");
+        }
+
+        #[test]
+        fn editor_delete_char_keeps_newline() {
+            let mut editor = editor_from_str("a\nb");
+            editor.cursor_col = 1;
+            process_input(&mut editor, b"x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\nb");
+            editor.cursor_col = 0;
+            process_input(&mut editor, b"x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "\nb");
+        }
+
+        #[test]
+        fn parse_command_variants() {
+            assert_eq!(parse_command("w"), Some(Command::Write(None)));
+            assert_eq!(
+                parse_command("w out.txt"), 
+                Some(Command::Write(Some(String::from("out.txt"))))
+            );
+            assert_eq!(parse_command("q"), Some(Command::Quit));
+            assert_eq!(parse_command(" q! "), Some(Command::ForceQuit));
+            assert_eq!(parse_command("wc"), Some(Command::WordCount));
+            assert_eq!(parse_command("expandtabs"), Some(Command::ExpandTabs));
+            assert_eq!(parse_command("unexpandtabs"), Some(Command::UnexpandTabs));
+            assert_eq!(parse_command("e!"), Some(Command::Reload));
+            assert_eq!(parse_command("e b.txt"), Some(Command::Edit(String::from("b.txt"))));
+            assert_eq!(parse_command("e"), None);
+            assert_eq!(parse_command("bn"), Some(Command::NextBuffer));
+            assert_eq!(parse_command("bp"), Some(Command::PrevBuffer));
+            assert_eq!(parse_command("ls"), Some(Command::ListBuffers));
+            assert_eq!(parse_command("sp"), Some(Command::Split(None)));
+            assert_eq!(
+                parse_command("split b.txt"), 
+                Some(Command::Split(Some(String::from("b.txt"))))
+            );
+            assert_eq!(parse_command("close"), Some(Command::ClosePane));
+            assert_eq!(parse_command("noh"), Some(Command::NoHighlight));
+            assert_eq!(parse_command("hex"), Some(Command::HexView));
+            assert_eq!(parse_command("wq"), Some(Command::WriteQuit(None)));
+            assert_eq!(
+                parse_command("wq out.txt"), 
+                Some(Command::WriteQuit(Some(String::from("out.txt"))))
+            );
+            assert_eq!(parse_command("50"), Some(Command::GoToLine(50)));
+            assert_eq!(parse_command("50%"), Some(Command::GoToPercent(50)));
+            assert_eq!(parse_command("%"), None);
+            assert_eq!(parse_command("q file"), None);
+            assert_eq!(parse_command("nope"), None);
+        }
+
+        #[test]
+        fn command_quit_respects_unsaved_changes() {
+            let mut editor = editor_from_str("abc");
+            editor.dirty = true;
+            editor_run_command(&mut editor, Command::Quit).unwrap();
+            assert!(matches!(editor.status, EditorStatus::RefershScreen));
+
+            editor_run_command(&mut editor, Command::ForceQuit).unwrap();
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+
+            let mut editor = editor_from_str("abc");
+            editor_run_command(&mut editor, Command::Quit).unwrap();
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        #[test]
+        fn buffers_keep_independent_state() {
+            let dir = std::env::temp_dir();
+            let first = dir.join(format!("editor_{}_buffer_a.txt", std::process::id()));
+            let second = dir.join(format!("editor_{}_buffer_b.txt", std::process::id()));
+            std::fs::write(&first, "first\nfile").unwrap();
+            std::fs::write(&second, "second").unwrap();
+            let first = first.to_string_lossy().into_owned();
+            let second = second.to_string_lossy().into_owned();
+
+            let mut editor = editor_from_str("");
+            editor_run_command(&mut editor, Command::Edit(first.clone())).unwrap();
+            assert_eq!(editor.buffers.len(), 2);
+            editor.cursor_row = 1;
+            editor.insert_str("my ").unwrap();
+
+            editor_run_command(&mut editor, Command::Edit(second.clone())).unwrap();
+            assert_eq!(editor.active_buffer, 2);
+            assert_eq!(editor.buffer.write_contents_to_string(), "second");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+            assert!(!editor.dirty);
+            editor.cursor_col = 6;
+            editor.insert_str("!").unwrap();
+
+            editor_run_command(&mut editor, Command::PrevBuffer).unwrap();
+            assert_eq!(editor.filename.as_deref(), Some(first.as_str()));
+            assert_eq!(editor.buffer.write_contents_to_string(), "first\nmy file");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 3));
+
+            editor_run_command(&mut editor, Command::NextBuffer).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "second!");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 7));
+            editor_run_command(&mut editor, Command::NextBuffer).unwrap();
+            assert_eq!(editor.active_buffer, 0);
+            assert_eq!(editor.buffer.write_contents_to_string(), "");
+
+            // Opening a file that is already open switches to its buffer.
+            editor_run_command(&mut editor, Command::Edit(first.clone())).unwrap();
+            assert_eq!(editor.active_buffer, 1);
+            assert_eq!(editor.buffers.len(), 3);
+            assert_eq!(
+                editor.buffer_list(), 
+                format!("1 \"[No Name]\"  2%+ \"{}\"  3+ \"{}\"", first, second)
+            );
+
+            editor.remove_swap();
+            std::fs::remove_file(&first).unwrap();
+            std::fs::remove_file(&second).unwrap();
+        }
+
+        #[test]
+        fn quit_checks_every_buffer_for_unsaved_changes() {
+            let mut editor = editor_from_str("abc");
+            editor.open_buffer(PieceTable::from_str("def"), None);
+            editor.insert_str("x").unwrap();
+            editor.switch_buffer(0);
+            assert!(!editor.dirty);
+
+            editor_run_command(&mut editor, Command::Quit).unwrap();
+            assert!(matches!(editor.status, EditorStatus::RefershScreen));
+            assert_eq!(editor.message, OTHER_BUFFERS_DIRTY_MESSAGE);
+            editor_run_command(&mut editor, Command::ForceQuit).unwrap();
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        #[test]
+        fn command_write_to_file_keeps_filename() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_write_to", std::process::id()));
+            let path = path.to_string_lossy().into_owned();
+
+            let mut editor = editor_from_str("abc");
+            editor.filename = Some(String::from("original.txt"));
+            editor.dirty = true;
+            editor_run_command(&mut editor, Command::Write(Some(path.clone()))).unwrap();
+            let saved = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(saved, "abc");
+            assert_eq!(editor.filename.as_deref(), Some("original.txt"));
+
+            editor_run_command(&mut editor, Command::WriteQuit(Some(path.clone()))).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(editor.filename, Some(path));
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        #[test]
+        fn command_line_runs_parsed_command() {
+            let mut editor = editor_with_input("abc", b":q!\r");
+            editor.dirty = true;
+            let key = keys::read_key(&mut editor.input).unwrap();
+            editor_process_key(&mut editor, key).unwrap();
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        #[test]
+        fn ctrl_q_requires_confirmation_when_dirty() {
+            let mut editor = editor_from_str("abc");
+            editor.dirty = true;
+            for _ in 0..editor.config.quit_confirmations {
+                editor_process_key(&mut editor, Key::Ctrl('q')).unwrap();
+                assert!(matches!(editor.status, EditorStatus::RefershScreen));
+            }
+            editor_process_key(&mut editor, Key::Ctrl('q')).unwrap();
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        fn editor_with_lines(n_lines: usize, rows: usize) -> Editor {
+            let contents: Vec<String> = (1..=n_lines).map(|i| format!("line {}", i)).collect();
+            let size = WindowSize { rows, cols: 20 };
+            let buffer = PieceTable::from_string(contents.join("\n"));
+            Editor::new(size, EditorConfig::default(), buffer)
+        }
+
+        #[test]
+        fn split_panes_draw_their_own_rows() {
+            let mut editor = editor_with_lines(10, 8);
+            editor.screen_colums = 30;
+            editor_run_command(&mut editor, Command::Split(None)).unwrap();
+            assert_eq!((editor.panes.len(), editor.active_pane), (2, 1));
+            assert_eq!(editor.pane_heights(), vec![3, 4]);
+            assert_eq!(editor.text_rows(), 3);
+            editor.cursor_row = 9;
+
+            let mut frame = String::new();
+            editor_draw_panes(&mut editor, &mut frame);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(&rows[..2], ["line 1", "line 2"]);
+            assert_eq!(rows[2], format!("[No Name]{}1/10 Top", " ".repeat(13)));
+            assert_eq!(&rows[3..6], ["line 8", "line 9", "line 10"]);
+            assert_eq!(rows[6], format!("\x1b[7m[No Name]{}10/10 Bot\x1b[m", " ".repeat(12)));
+            // Drawing leaves the focus where it was.
+            assert_eq!((editor.active_pane, editor.cursor_row), (1, 9));
+            assert_eq!(editor.pane_top(editor.active_pane), 3);
+        }
+
+        #[test]
+        fn position_percentage_like_vim_ruler() {
+            let mut editor = editor_with_lines(8, 5);
+            assert_eq!(editor.position_percentage(), "Top");
+            editor.cursor_row = 3;
+            assert_eq!(editor.position_percentage(), "50%");
+            editor.cursor_row = 7;
+            assert_eq!(editor.position_percentage(), "Bot");
+        }
+
+        #[test]
+        fn highlights_only_known_file_types_by_default() {
+            let config = EditorConfig::default();
+            assert!(default_highlighter(&config, Some("src/main.rs")).is_some());
+            assert!(default_highlighter(&config, Some("notes.txt")).is_none());
+            assert!(default_highlighter(&config, Some("Makefile")).is_none());
+            assert!(default_highlighter(&config, None).is_none());
+
+            let config = EditorConfig { highlight_all: true, ..Default::default() };
+            assert!(default_highlighter(&config, Some("notes.txt")).is_some());
+            assert!(default_highlighter(&config, None).is_some());
+        }
+
+        #[test]
+        fn status_line_shows_file_type() {
+            assert_eq!(file_type("src/main.rs"), Some("rust"));
+            assert_eq!(file_type("notes.MD"), Some("markdown"));
+            assert_eq!(file_type("Makefile"), None);
+            assert_eq!(file_type("archive.xyz"), None);
+
+            let mut editor = editor_with_lines(3, 5);
+            editor.filename = Some(String::from("a_long_name.rs"));
+            editor.dirty = true;
+            editor.cursor_row = 1;
+            let mut frame = String::new();
+            editor_draw_status_line(&editor, false, &mut frame);
+            // The filename gives way to the right side on narrow screens.
+            assert_eq!(frame, "a_lon rust | 2/3 66%\r\n");
+
+            // The focused pane shows the mode
+            editor.screen_colums = 50;
+            editor.mode = EditorMode::Replace;
+            frame.clear();
+            editor_draw_status_line(&editor, true, &mut frame);
+            assert_eq!(frame, "\x1b[7ma_long_name.rs [+] -- REPLACE --    rust | 2/3 66%\x1b[m\r\n");
+            frame.clear();
+            editor_draw_status_line(&editor, false, &mut frame);
+            assert!(frame.starts_with("a_long_name.rs [+]  "));
+
+            // Only on the status line, not on the message bar as well
+            editor.set_mode(EditorMode::Visual);
+            frame.clear();
+            editor_draw_status_line(&editor, true, &mut frame);
+            assert!(frame.starts_with("\x1b[7ma_long_name.rs [+] -- VISUAL --  "));
+            assert_eq!(editor.message, "");
+        }
+
+        #[test]
+        fn apply_action_without_keys() {
+            let mut editor = editor_from_str("one\ntwo");
+            editor_apply_action(&mut editor, Action::MoveDown).unwrap();
+            editor_apply_action(&mut editor, Action::MoveRight).unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+
+            editor_apply_action(&mut editor, Action::EnterInsertMode).unwrap();
+            editor_apply_action(&mut editor, Action::InsertChar('x')).unwrap();
+            editor_apply_action(&mut editor, Action::InsertChar('y')).unwrap();
+            editor_apply_action(&mut editor, Action::DeleteBack).unwrap();
+            editor_apply_action(&mut editor, Action::EnterNormalMode).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntxwo");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert_eq!(editor.last_change, Some((Change::Insert(String::from("x")), 1)));
+
+            editor_apply_action(&mut editor, Action::KillLine).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one");
+            assert_eq!(editor.register, "txwo\n");
+            editor_apply_action(&mut editor, Action::ChangeCase(Case::Upper)).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "One");
+        }
+
+        #[test]
+        fn apply_action_acts_on_selection_in_visual_mode() {
+            let mut editor = editor_from_str("one two");
+            editor_apply_action(&mut editor, Action::SelectRight).unwrap();
+            editor_apply_action(&mut editor, Action::SelectRight).unwrap();
+            assert_eq!(editor.mode, EditorMode::Visual);
+            editor_apply_action(&mut editor, Action::ChangeCase(Case::Upper)).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "ONe two");
+            assert_eq!(editor.mode, EditorMode::Normal);
+
+            editor_apply_action(&mut editor, Action::SelectRight).unwrap();
+            editor_apply_action(&mut editor, Action::DeleteSelection).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "Ne two");
+            // Changes to a selection are not repeated by `.`
+            assert_eq!(editor.last_change, None);
+        }
+
+        #[test]
+        fn rebound_keys_apply_their_action() {
+            let mut editor = editor_from_str("abc\ndef");
+            editor.keymap.bind_global(Key::Ctrl('x'), Action::Quit);
+            editor.keymap.bind(EditorMode::Normal, Key::Char('n'), Action::MoveDown);
+            editor.keymap.bind(EditorMode::Normal, Key::Char('h'), Action::MoveRight);
+
+            process_input(&mut editor, b"nh");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+            // Bindings in one mode leave the others alone.
+            process_input(&mut editor, b"in");
+            assert_eq!(editor.buffer.write_contents_to_string(), "abc\ndnef");
+
+            // Quitting with unsaved changes asks for confirmation as it
+            // would on Ctrl-Q.
+            editor_process_key(&mut editor, Key::Escape).unwrap();
+            editor_process_key(&mut editor, Key::Ctrl('x')).unwrap();
+            assert!(editor.message.starts_with("WARNING!!!"));
+        }
+
+        #[test]
+        fn bound_keys_complete_pending_commands() {
+            let mut editor = editor_from_str("abc\ndef");
+            process_input(&mut editor, b"jmhk`h");
+            assert_eq!(editor.cursor_row, 1);
+        }
+
+        #[test]
+        fn ctrl_w_sends_keys_to_the_other_pane() {
+            let mut editor = editor_with_lines(3, 8);
+            editor.split_pane();
+            editor.cursor_row = 2;
+            editor_process_key(&mut editor, Key::Ctrl('w')).unwrap();
+            assert_eq!((editor.active_pane, editor.cursor_row), (0, 0));
+            process_input(&mut editor, b"x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ine 1\nline 2\nline 3");
+
+            editor_process_key(&mut editor, Key::Ctrl('w')).unwrap();
+            assert_eq!((editor.active_pane, editor.cursor_row), (1, 2));
+            process_input(&mut editor, b"x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ine 1\nline 2\nine 3");
+
+            // Panes can show different buffers.
+            editor.open_buffer(PieceTable::from_str("other"), None);
+            editor_process_key(&mut editor, Key::Ctrl('w')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "ine 1\nline 2\nine 3");
+            editor_process_key(&mut editor, Key::Ctrl('w')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "other");
+
+            editor_run_command(&mut editor, Command::ClosePane).unwrap();
+            assert_eq!((editor.panes.len(), editor.active_pane), (1, 0));
+            assert_eq!(editor.text_rows(), 6);
+            editor_run_command(&mut editor, Command::ClosePane).unwrap();
+            assert_eq!(editor.message, "Can't close the last pane");
+        }
+
+        #[test]
+        fn scroll_off_keeps_context_around_cursor() {
+            let mut editor = editor_with_lines(100, 12);
+            editor.config.scroll_off = 3;
+            for _ in 0..20 {
+                editor.move_down();
+                editor_scroll(&mut editor);
+                let rows_below = editor.row_offset + editor.text_rows() - 1 - editor.cursor_row;
+                assert!(rows_below >= 3);
+            }
+            assert_eq!(editor.cursor_row, 20);
+            assert_eq!(editor.row_offset, 14);
+
+            for _ in 0..10 {
+                editor.move_up();
+                editor_scroll(&mut editor);
+            }
+            assert_eq!(editor.cursor_row, 10);
+            assert_eq!(editor.row_offset, 7);
+
+            // At the end of the buffer the cursor reaches the last row
+            editor_go_to_line(&mut editor, 100);
+            assert_eq!(editor.row_offset, 90);
+            assert_eq!(editor.cursor_row - editor.row_offset, 9);
+        }
+
+        #[test]
+        fn scroll_off_is_clamped_on_small_screens() {
+            let mut editor = editor_with_lines(100, 4);
+            editor.config.scroll_off = 5;
+            editor.move_down();
+            editor.move_down();
+            editor_scroll(&mut editor);
+            assert_eq!(editor.row_offset, 1);
+            editor.move_down();
+            editor_scroll(&mut editor);
+            assert_eq!(editor.row_offset, 2);
+        }
+
+        #[test]
+        fn center_cursor_puts_cursor_mid_screen() {
+            let mut editor = editor_with_lines(100, 12);
+            editor_go_to_line(&mut editor, 60);
+            assert_eq!(editor.row_offset, 50);
+            process_input(&mut editor, b"zz");
+            assert_eq!(editor.row_offset, 54);
+            editor_scroll(&mut editor);
+            assert_eq!(editor.row_offset, 54);
+            assert_eq!(editor.cursor_row - editor.row_offset, editor.text_rows() / 2);
+
+            // Near the top the view cannot scroll further up
+            editor_go_to_line(&mut editor, 2);
+            process_input(&mut editor, b"zz");
+            assert_eq!(editor.row_offset, 0);
+        }
+
+        #[test]
+        fn half_page_scroll_moves_cursor_and_view() {
+            let mut editor = editor_with_lines(30, 12);
+            editor.cursor_col = 3;
+            process_input(&mut editor, b"\x04");
+            assert_eq!((editor.cursor_row, editor.row_offset), (5, 5));
+            assert_eq!(editor.cursor_col, 3);
+            process_input(&mut editor, b"\x04\x04\x04");
+            assert_eq!((editor.cursor_row, editor.row_offset), (20, 20));
+
+            // The view stops at the end of the buffer and the cursor at
+            // the last line.
+            process_input(&mut editor, b"\x04\x04");
+            assert_eq!((editor.cursor_row, editor.row_offset), (29, 20));
+
+            process_input(&mut editor, b"\x15");
+            assert_eq!((editor.cursor_row, editor.row_offset), (24, 15));
+            process_input(&mut editor, b"\x15\x15\x15\x15\x15");
+            assert_eq!((editor.cursor_row, editor.row_offset), (0, 0));
+        }
+
+        #[test]
+        fn go_to_line_scrolls_to_target() {
+            let mut editor = editor_with_lines(100, 12);
+            editor.cursor_col = 3;
+            editor_run_command(&mut editor, Command::GoToLine(50)).unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (49, 0));
+            assert_eq!(editor.row_offset, 40);
+        }
+
+        #[test]
+        fn go_to_line_clamps_to_last_line() {
+            let mut editor = editor_with_lines(100, 12);
+            editor_go_to_line(&mut editor, 500);
+            assert_eq!(editor.cursor_row, 99);
+            assert_eq!(editor.row_offset, 90);
+            assert_eq!(editor.message, "Line 500 out of range, moved to line 100");
+        }
+
+        #[test]
+        fn go_to_top_bottom_and_percentage() {
+            let mut editor = editor_with_lines(100, 12);
+            editor.cursor_row = 20;
+            editor.cursor_col = 3;
+            process_input(&mut editor, b"G");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (99, 0));
+            assert_eq!(editor.row_offset, 90);
+
+            editor.cursor_col = 2;
+            process_input(&mut editor, b"gg");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+            assert_eq!(editor.row_offset, 0);
+
+            editor_run_command(&mut editor, Command::GoToPercent(50)).unwrap();
+            assert_eq!(editor.cursor_row, 49);
+            editor_run_command(&mut editor, Command::GoToPercent(1)).unwrap();
+            assert_eq!(editor.cursor_row, 0);
+            editor_run_command(&mut editor, Command::GoToPercent(250)).unwrap();
+            assert_eq!(editor.cursor_row, 99);
+        }
+
+        #[test]
+        fn vertical_movement_keeps_desired_column() {
+            let mut editor = editor_from_str("a long line\nab\n\nxyz\nanother long line");
+            editor.cursor_col = 7;
+            process_input(&mut editor, b"jj");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 0));
+            process_input(&mut editor, b"j");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (3, 3));
+            process_input(&mut editor, b"\x1b[B");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (4, 7));
+            process_input(&mut editor, b"kkkk");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 7));
+
+            // Any other movement sets a new column
+            process_input(&mut editor, b"hjkk");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 6));
+        }
+
+        #[test]
+        fn page_scrolls_keep_desired_column() {
+            let mut editor = editor_with_lines(20, 5);
+            let contents: Vec<&str> = ["a long line"; 2].into_iter()
+                .chain(["x"; 16])
+                .chain(["a long line"; 2])
+                .collect();
+            editor.buffer = PieceTable::from_string(contents.join("\n"));
+            editor.cursor_col = 8;
+
+            process_input(&mut editor, b"\x04");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 1));
+            for _ in 0..7 {
+                process_input(&mut editor, b"\x04");
+            }
+            assert_eq!((editor.cursor_row, editor.cursor_col), (16, 1));
+            process_input(&mut editor, b"\x04");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (18, 8));
+
+            for _ in 0..9 {
+                process_input(&mut editor, b"\x15");
+            }
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 8));
+
+            // Page Down and Page Up move a screen at a time
+            process_input(&mut editor, b"\x1b[6~");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (3, 1));
+            for _ in 0..5 {
+                process_input(&mut editor, b"\x1b[6~");
+            }
+            assert_eq!((editor.cursor_row, editor.cursor_col), (18, 8));
+            for _ in 0..3 {
+                process_input(&mut editor, b"\x1b[5~");
+            }
+            assert_eq!((editor.cursor_row, editor.cursor_col), (9, 1));
+            for _ in 0..3 {
+                process_input(&mut editor, b"\x1b[5~");
+            }
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 8));
+        }
+
+        #[test]
+        fn vertical_movement_keeps_screen_column_across_tabs() {
+            let mut editor = editor_from_str("\tab\nabcdefgh\n\tx");
+            editor.config.tab_width = 4;
+            editor.cursor_col = 2;
+            process_input(&mut editor, b"j");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 5));
+            process_input(&mut editor, b"j");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 2));
+            // A column within a tab moves to the tab
+            process_input(&mut editor, b"kk0llj");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 0));
+        }
+
+        #[test]
+        fn count_prefix_repeats_commands() {
+            let mut editor = editor_from_str("abcdefgh\none\ntwo\nthree\nfour");
+            process_input(&mut editor, b"3l");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 3));
+            process_input(&mut editor, b"2x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "abcfgh\none\ntwo\nthree\nfour");
+            assert_eq!(editor.pending_count, None);
+
+            process_input(&mut editor, b"j2dd");
+            assert_eq!(editor.buffer.write_contents_to_string(), "abcfgh\nthree\nfour");
+            assert_eq!(editor.register, "one\ntwo\n");
+            assert_eq!(editor.cursor_row, 1);
+
+            // A separate dd replaces the register
+            process_input(&mut editor, b"dd");
+            assert_eq!(editor.register, "three\n");
+
+            // A counted change is undone in one step and repeated with 
+            // its count, unless `.` is given a count of its own
+            let mut editor = editor_from_str("abcdefghij");
+            process_input(&mut editor, b"3x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "defghij");
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.buffer.write_contents_to_string(), "abcdefghij");
+            process_input(&mut editor, b"3x.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ghij");
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.buffer.write_contents_to_string(), "defghij");
+            process_input(&mut editor, b"2.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "fghij");
+            assert_eq!(editor.last_change, Some((Change::DeleteChar, 2)));
+            process_input(&mut editor, b"3l.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "fgh");
+
+            // Counts of several digits, and 0 once a count is started
+            let mut editor = editor_with_lines(30, 5);
+            process_input(&mut editor, b"12j");
+            assert_eq!(editor.cursor_row, 12);
+            process_input(&mut editor, b"10k");
+            assert_eq!(editor.cursor_row, 2);
+            process_input(&mut editor, b"20G");
+            assert_eq!(editor.cursor_row, 19);
+            process_input(&mut editor, b"3gg");
+            assert_eq!(editor.cursor_row, 2);
+
+            // Huge counts saturate rather than overflow
+            process_input(&mut editor, b"99999999999999999999999j");
+            assert_eq!(editor.cursor_row, 29);
+            assert_eq!(editor.pending_count, None);
+        }
+
+        #[test]
+        fn undo_and_redo_changes() {
+            fn fixed_clock() -> Instant {
+                static NOW: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+                *NOW.get_or_init(Instant::now)
+            }
+            let mut editor = editor_from_str("one\ntwo");
+            editor.buffer.set_clock(fixed_clock);
+            process_input(&mut editor, b"jiab\x1bx");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nabwo");
+
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nabtwo");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.message, "Already at oldest change");
+
+            process_input(&mut editor, b"2U");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nabwo");
+            process_input(&mut editor, b"U");
+            assert_eq!(editor.message, "Already at newest change");
+        }
+
+        #[test]
+        fn go_to_line_prompt() {
+            let mut editor = editor_with_lines(100, 12);
+            editor.input = Box::new(io::Cursor::new(b"7\r".to_vec()));
+            editor.output = Box::new(io::sink());
+            editor_process_key(&mut editor, Key::Ctrl('g')).unwrap();
+            assert_eq!(editor.cursor_row, 6);
+        }
+
+        #[test]
+        fn ctrl_k_deletes_to_end_of_line() {
+            let mut editor = editor_from_str("one two\nthree");
+            editor.cursor_col = 3;
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nthree");
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nthree");
+
+            editor.cursor_row = 1;
+            editor.cursor_col = 2;
+            assert_eq!(editor.delete_to_end_of_line().unwrap(), "ree");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nth");
+        }
+
+        #[test]
+        fn dd_deletes_whole_line() {
+            let mut editor = editor_from_str("one\ntwo\nthree");
+            editor.cursor_row = 1;
+            editor.cursor_col = 2;
+            process_input(&mut editor, b"dd");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nthree");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 0));
+
+            process_input(&mut editor, b"dd");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+
+            process_input(&mut editor, b"dd");
+            assert_eq!(editor.buffer.write_contents_to_string(), "");
+            assert_eq!(editor.buffer.line_count(), 1);
+        }
+
+        #[test]
+        fn delete_line_returns_line_with_newline() {
+            let mut editor = editor_from_str("one\ntwo");
+            assert_eq!(editor.delete_line().unwrap(), "one\n");
+            assert_eq!(editor.delete_line().unwrap(), "two\n");
+        }
+
+        #[test]
+        fn d_then_other_key_does_nothing() {
+            let mut editor = editor_from_str("one\ntwo");
+            process_input(&mut editor, b"dj");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo");
+            assert_eq!(editor.cursor_row, 0);
+        }
+
+        #[test]
+        fn word_movement_stops_at_word_starts() {
+            let mut editor = editor_from_str("foo  bar.baz");
+            let mut stops = Vec::new();
+            for _ in 0..4 {
+                process_input(&mut editor, b"\x1b[1;5C");
+                stops.push(editor.cursor_col);
+            }
+            assert_eq!(stops, [5, 9, 12, 12]);
+
+            let mut stops = Vec::new();
+            for _ in 0..4 {
+                process_input(&mut editor, b"\x1b[1;5D");
+                stops.push(editor.cursor_col);
+            }
+            assert_eq!(stops, [9, 5, 0, 0]);
+        }
+
+        #[test]
+        fn word_movement_crosses_lines() {
+            let mut editor = editor_from_str("foo.\n  bar\nbaz");
+            editor.cursor_col = 1;
+            editor.move_word_right();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+            editor.move_word_right();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 0));
+            editor.move_word_left();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+            editor.move_word_left();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+        }
+
+        #[test]
+        fn smart_home_toggles_between_indent_and_line_start() {
+            let mut editor = editor_from_str("    let x = 1;\nfoo");
+            editor.cursor_col = 8;
+            process_input(&mut editor, b"\x1b[H");
+            assert_eq!(editor.cursor_col, 4);
+            process_input(&mut editor, b"\x1b[H");
+            assert_eq!(editor.cursor_col, 0);
+            process_input(&mut editor, b"\x1b[1~");
+            assert_eq!(editor.cursor_col, 4);
+
+            editor.config.smart_home = false;
+            process_input(&mut editor, b"\x1b[H");
+            assert_eq!(editor.cursor_col, 0);
+            process_input(&mut editor, b"\x1b[H");
+            assert_eq!(editor.cursor_col, 0);
+        }
+
+        #[test]
+        fn typing_replaces_selection() {
+            let mut editor = editor_from_str("one two three");
+            editor.cursor_col = 4;
+            process_input(&mut editor, b"vllllX");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one Xthree");
+            assert_eq!(editor.mode, EditorMode::Insert);
+            assert_eq!(editor.cursor_col, 5);
+            assert_eq!(editor.selection(), None);
+
+            // Typing carries on after the replaced selection
+            process_input(&mut editor, b"Y ");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one XY three");
+
+            // Keys bound in visual mode keep their binding
+            process_input(&mut editor, b"\x1bvhhd");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one Xthree");
+        }
+
+        #[test]
+        fn paste_replaces_selection() {
+            let mut editor = editor_from_str("one two three");
+            editor.set_register(String::from("2"));
+            editor.cursor_col = 4;
+            process_input(&mut editor, b"vlllp");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one 2 three");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            // The replaced text is not kept in the register
+            assert_eq!(editor.register, "2");
+
+            // Text pasted into the terminal replaces it too
+            editor.input = Box::new(io::Cursor::new(b"vlll\x1b[200~four \x1b[201~".to_vec()));
+            for _ in 0..5 {
+                editor_process_keypress(&mut editor).unwrap();
+            }
+            assert_eq!(editor.buffer.write_contents_to_string(), "one 2four ree");
+            assert_eq!(editor.mode, EditorMode::Normal);
+        }
+
+        #[test]
+        fn ctrl_v_inserts_next_key_literally() {
+            let mut editor = editor_with_input("ab", b"i\x16\tx\x16\x1b\x16\x13\x1b");
+            for _ in 0..9 {
+                editor_process_keypress(&mut editor).unwrap();
+            }
+            assert_eq!(editor.buffer.write_contents_to_string(), "\tx\x1b\x13ab");
+            assert_eq!(editor.mode, EditorMode::Normal);
+
+            // Control characters are drawn in caret notation
+            editor.config.tab_width = 4;
+            assert!(editor_draw_rows_to_string(&editor).starts_with("    x^[^Sab\r\n"));
+            assert_eq!(buffer_col_to_render_col("\tx\x1b\x13ab", 4, 4), 9);
+            assert_eq!(render_line("\x7f", 4), "^?");
+        }
+
+        #[test]
+        fn complete_word_cycles_through_buffer_words() {
+            let mut editor = editor_from_str("function functional fun\n");
+            editor.cursor_row = 1;
+            process_input(&mut editor, b"ifunc\x0e");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "function functional fun\nfunction"
+            );
+            assert_eq!(editor.cursor_col, 8);
+            assert_eq!(editor.message, "Match 1 of 2");
+
+            process_input(&mut editor, b"\x0e");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "function functional fun\nfunctional"
+            );
+            assert_eq!(editor.cursor_col, 10);
+            process_input(&mut editor, b"\x0e");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "function functional fun\nfunction"
+            );
+
+            // Any other key ends the completion, so the next one starts
+            // from the new word
+            process_input(&mut editor, b"\x0e");
+            process_input(&mut editor, b"a \x0e");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "function functional fun\nfunctionala "
+            );
+            process_input(&mut editor, b"\x7f\x7f\x0e");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "function functional fun\nfunctional"
+            );
+            assert_eq!(editor.message, "No completions for functional");
+        }
+
+        #[test]
+        fn selection_spans_anchor_to_cursor() {
+            let mut editor = editor_from_str("one two\nthree");
+            editor.cursor_col = 4;
+            assert_eq!(editor.selection(), None);
+
+            process_input(&mut editor, b"vjh");
+            assert_eq!(editor.mode, EditorMode::Visual);
+            assert_eq!(editor.selection(), Some(4..11));
+
+            // The anchor can be after the cursor
+            process_input(&mut editor, b"kkhh");
+            assert_eq!(editor.selection(), Some(1..4));
+        }
+
+        #[test]
+        fn delete_selection_removes_exactly_the_selection() {
+            let mut editor = editor_from_str("one two\nthree");
+            editor.cursor_col = 4;
+            process_input(&mut editor, b"vjh");
+            let selection = editor.selection().unwrap();
+            let mut expected = editor.buffer.write_contents_to_string();
+            expected.replace_range(selection, "");
+
+            process_input(&mut editor, b"d");
+            assert_eq!(editor.buffer.write_contents_to_string(), expected);
+            assert_eq!(editor.buffer.write_contents_to_string(), "one ee");
+            assert_eq!(editor.register, "two\nthr");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert_eq!(editor.selection(), None);
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 4));
+        }
+
+        #[test]
+        fn shift_arrows_start_a_selection() {
+            let mut editor = editor_from_str("one two");
+            process_input(&mut editor, b"\x1b[1;2C\x1b[1;2Cy");
+            assert_eq!(editor.register, "on");
+            assert_eq!(editor.mode, EditorMode::Normal);
+        }
+
+        #[test]
+        fn editor_draw_rows_highlights_selection() {
+            let mut editor = editor_from_str("one\ntwo");
+            editor.cursor_col = 1;
+            process_input(&mut editor, b"vj");
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[0], "o\x1b[7mne\x1b[m");
+            assert_eq!(rows[1], "\x1b[7mt\x1b[mwo");
+        }
+
+        #[test]
+        fn block_insert_prefixes_each_line() {
+            let mut editor = editor_with_input("one\ntwo\n\nfour\nfive", b"- \r");
+            process_input(&mut editor, b"\x16jjj");
+            assert_eq!(editor.mode, EditorMode::VisualBlock);
+            assert_eq!(editor.block_selection(), Some(Block { rows: 0..4, cols: 0..1 }));
+            editor_process_key(&mut editor, Key::Char('I')).unwrap();
+
+            // The empty line ends before the block, so is left alone.
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "- one\n- two\n\n- four\nfive"
+            );
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 0));
+
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo\n\nfour\nfive");
+        }
+
+        #[test]
+        fn block_delete_removes_rectangle() {
+            let mut editor = editor_from_str("abcdef\nab\na\tbc\nabcdef");
+            editor.config.tab_width = 4;
+            editor.cursor_col = 1;
+            process_input(&mut editor, b"\x16jjjl");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (3, 2));
+            assert_eq!(editor.block_selection(), Some(Block { rows: 0..4, cols: 1..3 }));
+
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[1], "a\x1b[7mb\x1b[m");
+
+            process_input(&mut editor, b"d");
+            // The short line loses what it has of the block, and the tab
+            // straddling the block's right edge is deleted whole.
+            assert_eq!(editor.buffer.write_contents_to_string(), "adef\na\nabc\nadef");
+            assert_eq!(editor.register, "bc\nb\n\t\nbc");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 1));
+
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "abcdef\nab\na\tbc\nabcdef");
+        }
+
+        #[test]
+        fn editor_draw_rows_colors_highlighted_spans() {
+            let mut editor = editor_from_str("x = \"hi\" 12");
+            assert!(!editor_draw_rows_to_string(&editor).contains("\x1b[38;5;"));
+
+            editor.highlighter = Some(Box::new(BasicHighlighter));
+            let string = BasicHighlighter::STRING.escape();
+            let number = BasicHighlighter::NUMBER.escape();
+            assert_eq!(
+                editor_draw_rows_to_string(&editor).split("\r\n").next().unwrap(),
+                format!("x = {string}\"hi\"\x1b[m {number}12\x1b[m")
+            );
+
+            // Colours are kept inside the selection
+            editor.cursor_col = 5;
+            process_input(&mut editor, b"vlllll");
+            assert_eq!(
+                editor_draw_rows_to_string(&editor).split("\r\n").next().unwrap(),
+                format!(
+                    "x = {string}\"\x1b[m\x1b[7m{string}hi\"\x1b[m\x1b[7m \x1b[m\x1b[7m{number}1\x1b[m{number}2\x1b[m"
+                )
+            );
+        }
+
+        #[test]
+        fn bracket_match_handles_nesting() {
+            let bracket_match_at = |text: &str, offset: usize| {
+                let mut editor = editor_from_str(text);
+                editor.set_cursor_offset(offset);
+                editor.bracket_match().and_then(|(_, matched)| matched)
+            };
+            let text = "(a[b]c)";
+            assert_eq!(bracket_match_at(text, 0), Some(6));
+            assert_eq!(bracket_match_at(text, 6), Some(0));
+            assert_eq!(bracket_match_at(text, 2), Some(4));
+            assert_eq!(bracket_match_at(text, 4), Some(2));
+            assert_eq!(bracket_match_at(text, 1), None);
+            assert_eq!(bracket_match_at("((a)", 0), None);
+            assert_eq!(bracket_match_at("(é\n{})", 0), Some(6));
+
+            let mut editor = editor_from_str("(a)");
+            editor.buffer.write_to_loc(2, "[b]").unwrap();
+            editor.set_cursor_offset(0);
+            assert_eq!(editor.bracket_match(), Some((0, Some(5))));
+            editor.set_cursor_offset(5);
+            assert_eq!(editor.bracket_match(), Some((5, Some(0))));
+        }
+
+        #[test]
+        fn editor_draw_rows_colors_brackets() {
+            let matched = MATCHED_BRACKET_COLOR.escape();
+            let unmatched = UNMATCHED_BRACKET_COLOR.escape();
+
+            let mut editor = editor_from_str("f(a[b])");
+            editor.cursor_col = 3;
+            assert_eq!(editor.bracket_match(), Some((3, Some(5))));
+            assert_eq!(
+                editor_draw_rows_to_string(&editor).split("\r\n").next().unwrap(),
+                format!("f(a{matched}[\x1b[mb{matched}]\x1b[m)")
+            );
+
+            let mut editor = editor_from_str("f(a");
+            editor.cursor_col = 1;
+            assert_eq!(editor.bracket_match(), Some((1, None)));
+            assert_eq!(
+                editor_draw_rows_to_string(&editor).split("\r\n").next().unwrap(),
+                format!("f{unmatched}(\x1b[ma")
+            );
+        }
+
+        #[test]
+        fn editor_draw_rows_centers_welcome_on_empty_buffer() {
+            let mut editor = editor_from_str("");
+            editor.screen_rows = 11;
+            editor.screen_colums = 40;
+            let frame = editor_draw_rows_to_string(&editor);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            // A third of the way down the nine text rows
+            let padding = (40 - WELCOME_MESSAGE.len()) / 2;
+            assert_eq!(rows[3], format!("~{}{}", " ".repeat(padding - 1), WELCOME_MESSAGE));
+            assert_eq!(rows[2], "~");
+            assert_eq!(rows[4], "~");
+
+            // Clipped when the screen is narrower than the message
+            editor.screen_colums = 10;
+            let frame = editor_draw_rows_to_string(&editor);
+            assert_eq!(frame.split("\r\n").nth(3), Some(&WELCOME_MESSAGE[..10]));
+
+            // Gone once something is typed
+            editor.insert_char('a').unwrap();
+            assert!(!editor_draw_rows_to_string(&editor).contains(WELCOME_MESSAGE));
+            editor.delete_char_before_cursor().unwrap();
+            assert!(!editor_draw_rows_to_string(&editor).contains(WELCOME_MESSAGE));
+        }
+
+        #[test]
+        fn debug_log_records_keypresses() {
+            let path = std::env::temp_dir().join(format!("editor_{}_debug.log", std::process::id()));
+            let mut editor = editor_with_input("abc\ndef", b"l\x1b[Bx");
+            editor_start_debug_log(&mut editor, &path);
+            for _ in 0..3 {
+                editor_process_keypress(&mut editor).unwrap();
+            }
+            let log = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let lines: Vec<&str> = log.lines().collect();
+            assert_eq!(lines, [
+                "bytes=[6c] key=Char('l') offset=1 row_offset=0 col_offset=0",
+                "bytes=[1b 5b 42] key=ArrowDown offset=5 row_offset=0 col_offset=0",
+                "bytes=[78] key=Char('x') offset=5 row_offset=0 col_offset=0",
+            ]);
+            assert_eq!(editor.buffer.write_contents_to_string(), "abc\ndf");
+        }
+
+        /// Output that can still be read after it is given to an editor.
+        #[derive(Clone, Default)]
+        struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+        impl SharedOutput {
+            /// Take everything written so far.
+            fn take(&self) -> String {
+                String::from_utf8(self.0.take()).unwrap()
+            }
+        }
+
+        impl Write for SharedOutput {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn refresh_sets_cursor_shape_for_mode() {
+            let mut editor = editor_from_str("abc");
+            let output = SharedOutput::default();
+            editor.output = Box::new(output.clone());
+
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().ends_with("\x1b[2 q"));
+            // The shape is only sent again when it changes
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(!output.take().contains(" q"));
+
+            editor.set_mode(EditorMode::Insert);
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().ends_with("\x1b[6 q"));
+            editor.set_mode(EditorMode::Replace);
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().ends_with("\x1b[4 q"));
+
+            editor.config.cursor_blink = true;
+            editor.set_mode(EditorMode::Normal);
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().ends_with("\x1b[1 q"));
+        }
+
+        #[test]
+        fn refresh_only_draws_changed_rows() {
+            let mut editor = editor_from_str("abc\ndef");
+            editor.screen_colums = 40;
+            editor.set_mode(EditorMode::Insert);
+            let output = SharedOutput::default();
+            editor.output = Box::new(output.clone());
+
+            editor_refresh_screen(&mut editor).unwrap();
+            let first = output.take();
+            assert!(first.starts_with(
+                "\x1b[2J\x1b[Habc\r\ndef\r\n~\r\n~\r\n\
+                 \x1b[7m[No Name] -- INSERT --           1/2 Top\x1b[m\r\n"
+            ));
+
+            // The status line shows the unsaved change
+            editor_process_key(&mut editor, Key::Char('x')).unwrap();
+            editor_refresh_screen(&mut editor).unwrap();
+            assert_eq!(
+                output.take(), 
+                "\x1b[1;1Hxabc\x1b[K\
+                 \x1b[5;1H\x1b[7m[No Name] [+] -- INSERT --       1/2 Top\x1b[m\x1b[K\x1b[1;2H"
+            );
+
+            // Nothing changed, so only the cursor is placed
+            editor_refresh_screen(&mut editor).unwrap();
+            assert_eq!(output.take(), "\x1b[1;2H");
+
+            // A new screen size is drawn in full
+            editor.screen_rows = 4;
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().starts_with("\x1b[2J\x1b[Hxabc\r\ndef\r\n\x1b[7m[No Name] [+]"));
+        }
+
+        #[test]
+        fn refresh_ends_bottom_row_without_newline() {
+            let mut editor = editor_from_str("abc");
+            editor.set_message("hi");
+            let output = SharedOutput::default();
+            editor.output = Box::new(output.clone());
+            editor_refresh_screen(&mut editor).unwrap();
+            let frame = output.take();
+            let (screen, _) = frame.rsplit_once("\x1b[1;1H").unwrap();
+            assert!(screen.ends_with("~\r\n\x1b[7m[No Name]    1/1 Top\x1b[m\r\nhi"));
+            assert_eq!(screen.matches("\r\n").count(), editor.screen_rows - 1);
+
+            // Each pane ends with its status line, above the message bar
+            editor.split_pane();
+            let mut screen = String::new();
+            editor_draw_panes(&mut editor, &mut screen);
+            editor_draw_message_bar(&editor, &mut screen);
+            assert!(screen.ends_with("\r\nhi"));
+            assert_eq!(screen.matches("\r\n").count(), editor.screen_rows - 1);
+        }
+
+        #[test]
+        fn refresh_shows_fallback_when_screen_too_small() {
+            let size = WindowSize { rows: 1, cols: 1 };
+            let mut editor = Editor::new(size, EditorConfig::default(), PieceTable::from_str("abc"));
+            let output = SharedOutput::default();
+            editor.output = Box::new(output.clone());
+            assert!(editor.screen_too_small());
+            editor_refresh_screen(&mut editor).unwrap();
+            assert_eq!(output.take(), "\x1b[2J\x1b[HT\x1b[H");
+
+            // A pane needs a text row as well as its status line
+            editor.screen_colums = 20;
+            editor.screen_rows = 2;
+            assert!(editor.screen_too_small());
+
+            editor.screen_rows = 5;
+            editor.screen_colums = 20;
+            editor_refresh_screen(&mut editor).unwrap();
+            assert!(output.take().starts_with("\x1b[2J\x1b[Habc\r\n~\r\n"));
+
+            // A split needs a text row and a status line for each pane
+            editor.split_pane();
+            editor.screen_rows = 4;
+            assert!(editor.screen_too_small());
+            editor.screen_rows = 5;
+            assert!(!editor.screen_too_small());
+        }
+
+        #[test]
+        fn editor_draw_rows_colors_search_matches() {
+            let found = SEARCH_MATCH_COLOR.escape();
+            let current = CURRENT_MATCH_COLOR.escape();
+
+            let mut editor = editor_with_input("ab ab\nxab\nab", b"ab\r");
+            editor.screen_rows = 4;
+            editor.cursor_col = 1;
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.cursor_offset(), 3);
+            let frame = editor_draw_rows_to_string(&editor);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[0], format!("{found}ab\x1b[m {current}ab\x1b[m"));
+            assert_eq!(rows[1], format!("x{found}ab\x1b[m"));
+            // The last line is off screen
+            assert_eq!(rows.len(), 3);
+
+            editor_run_command(&mut editor, Command::NoHighlight).unwrap();
+            let frame = editor_draw_rows_to_string(&editor);
+            assert!(frame.starts_with("ab ab\r\nxab\r\n"));
+        }
+
+        #[test]
+        fn escape_dismisses_search_highlight() {
+            let mut editor = editor_with_input("ab ab", b"ab\x1b");
+            editor_find(&mut editor).unwrap();
+            assert_eq!(editor.search_highlight, None);
+            assert!(editor_draw_rows_to_string(&editor).starts_with("ab ab\r\n"));
+        }
+
+        #[test]
+        fn relative_line_numbers_count_from_cursor_line() {
+            let mut editor = editor_with_lines(12, 7);
+            editor.config.relative_line_numbers = true;
+            editor.cursor_row = 2;
+            let rows: Vec<String> = editor_draw_rows_to_string(&editor)
+                .split("\r\n")
+                .map(|row| row[..3].to_string())
+                .take(5)
+                .collect();
+            assert_eq!(rows, [" 2 ", " 1 ", " 3 ", " 1 ", " 2 "]);
+
+            // Scrolled down, with a separator and absolute numbers too
+            editor.config.show_line_numbers = true;
+            editor.config.gutter_separator = '|';
+            editor.cursor_row = 10;
+            editor.row_offset = 8;
+            let rows: Vec<String> = editor_draw_rows_to_string(&editor)
+                .split("\r\n")
+                .map(|row| row[..3].to_string())
+                .take(4)
+                .collect();
+            assert_eq!(rows, [" 2|", " 1|", "11|", " 1|"]);
+
+            // Plain line numbers are drawn with the separator too
+            editor.config.relative_line_numbers = false;
+            assert!(editor_draw_rows_to_string(&editor).starts_with(" 9|"));
+        }
+
+        #[test]
+        fn hex_dump_rows_of_known_bytes() {
+            let bytes: Vec<u8> = b"Hello, world!\n\x00\x01\x7f\xff abc".to_vec();
+            assert_eq!(
+                hex_dump_row(0, &bytes[..16]),
+                "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|"
+            );
+            assert_eq!(
+                hex_dump_row(16, &bytes[16..]),
+                "00000010  7f ff 20 61 62 63                                 |.. abc|"
+            );
+            assert!(looks_binary(&bytes));
+            assert!(!looks_binary(b"plain text\n"));
+        }
+
+        #[test]
+        fn hex_view_draws_dump_and_moves_by_bytes() {
+            let mut editor = editor_from_str("ab\0cdefghijklmnopqrstu");
+            editor.screen_colums = 80;
+            editor.cursor_col = 1;
+            editor_run_command(&mut editor, Command::HexView).unwrap();
+            assert!(editor.hex_view);
+            assert_eq!(editor.hex_cursor, 1);
+            assert_eq!(
+                editor_draw_rows_to_string(&editor),
+                "00000000  61 62 00 63 64 65 66 67  68 69 6a 6b 6c 6d 6e 6f  |ab.cdefghijklmno|\r\n\
+                 00000010  70 71 72 73 74 75                                 |pqrstu|\r\n\
+                 ~\r\n~\r\n"
+            );
+            assert_eq!((editor.cursor_screen_row(), editor.cursor_screen_col()), (0, 13));
+            editor.row_offset = 1;
+            assert_eq!(
+                editor_draw_rows_to_string(&editor),
+                "00000010  70 71 72 73 74 75                                 |pqrstu|\r\n\
+                 ~\r\n~\r\n~\r\n"
+            );
+            editor.row_offset = 0;
+
+            // Movement is by bytes and rows of the dump
+            process_input(&mut editor, b"jl");
+            assert_eq!(editor.hex_cursor, 18);
+            assert_eq!((editor.cursor_screen_row(), editor.cursor_screen_col()), (1, 16));
+            process_input(&mut editor, b"jkhhh");
+            assert_eq!(editor.hex_cursor, 0);
+            process_input(&mut editor, b"G");
+            assert_eq!(editor.hex_cursor, 16);
+
+            // Edits are refused
+            process_input(&mut editor, b"x");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab\0cdefghijklmnopqrstu");
+            assert_eq!(editor.message, "File is read-only");
+
+            editor_run_command(&mut editor, Command::HexView).unwrap();
+            assert!(!editor.hex_view);
+            assert_eq!(editor.cursor_offset(), 16);
+        }
+
+        #[test]
+        fn binary_file_offers_hex_view() {
+            let mut editor = editor_with_input("\0\x01", b"y\r");
+            editor_offer_hex_view(&mut editor).unwrap();
+            assert!(editor.hex_view);
+
+            let mut editor = editor_with_input("text", b"y\r");
+            editor_offer_hex_view(&mut editor).unwrap();
+            assert!(!editor.hex_view);
+        }
+
+        #[test]
+        fn invalid_utf8_file_opens_read_only_in_hex_view() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_invalid_utf8.bin", std::process::id()));
+            std::fs::write(&path, b"a\xff\r\n").unwrap();
+
+            let mut editor = editor_with_input("", b"");
+            editor.screen_colums = 80;
+            editor.buffer = load_file(&path.to_string_lossy()).unwrap();
+            editor_offer_hex_view(&mut editor).unwrap();
+            assert!(editor.hex_view);
+            assert!(editor.is_read_only());
+            assert_eq!(
+                editor_draw_rows_to_string(&editor),
+                "00000000  61 ff 0d 0a                                       |a...|\r\n\
+                 ~\r\n~\r\n~\r\n"
+            );
+
+            editor_run_command(&mut editor, Command::HexView).unwrap();
+            assert!(editor.hex_view);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn hex_view_shows_stored_bom_and_line_endings() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_hex_crlf.txt", std::process::id()));
+            std::fs::write(&path, b"\xef\xbb\xbfab\r\ncd\r\n").unwrap();
+
+            let mut editor = editor_with_input("", b"");
+            editor.screen_colums = 80;
+            editor.buffer = load_file(&path.to_string_lossy()).unwrap();
+            editor.cursor_row = 1;
+            editor_run_command(&mut editor, Command::HexView).unwrap();
+            assert_eq!(editor.hex_cursor, 7);
+            assert_eq!(
+                editor_draw_rows_to_string(&editor),
+                "00000000  ef bb bf 61 62 0d 0a 63  64 0d 0a                 |...ab..cd..|\r\n\
+                 ~\r\n~\r\n~\r\n"
+            );
+            process_input(&mut editor, b"llll");
+            assert_eq!(editor.hex_cursor, 10);
+
+            editor_run_command(&mut editor, Command::HexView).unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab\ncd\n");
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        fn editor_draw_rows_to_string(editor: &Editor) -> String {
+            let mut frame = String::new();
+            editor_draw_rows(editor, &mut frame);
+            frame
+        }
+
+        #[test]
+        fn bracketed_paste_inserts_text_without_running_keys() {
+            let mut editor = editor_with_input("ab", b"l\x1b[200~dd:q\rx\x1b[201~l");
+            for _ in 0..3 {
+                editor_process_keypress(&mut editor).unwrap();
+            }
+            assert_eq!(editor.buffer.write_contents_to_string(), "add:q\nxb");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert!(matches!(editor.status, EditorStatus::RefershScreen));
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+
+            // The lines were inserted at once, so one undo removes them all
+            process_input(&mut editor, b"u");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab");
+        }
+
+        #[test]
+        fn end_of_input_quits_gracefully() {
+            let mut editor = editor_with_input("abc", b"");
+            assert!(editor_process_keypress(&mut editor).is_ok());
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+
+            // Input closing partway through a prompt also quits
+            let mut editor = editor_with_input("abc", b":w");
+            assert!(editor_process_keypress(&mut editor).is_ok());
+            assert!(editor_process_keypress(&mut editor).is_ok());
+            assert!(matches!(editor.status, EditorStatus::TerminalExitSuccess));
+        }
+
+        #[test]
+        fn end_of_input_reports_unsaved_changes() {
+            let mut editor = editor_with_input("abc", b"");
+            editor.dirty = true;
+            assert!(editor_process_keypress(&mut editor).is_ok());
+            assert!(matches!(editor.status, EditorStatus::InputClosedWithUnsavedChanges));
+        }
+
+        #[test]
+        fn read_only_editor_ignores_edits() {
+            let input = b"ixyz\x1bxddpvld\x1b\x0b\x15\x1b[200~abc\x1b[201~";
+            let mut editor = editor_with_input("one\ntwo", input);
+            editor.read_only = true;
+            editor.register = String::from("pasted");
+            while editor_process_keypress(&mut editor).unwrap() {}
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo");
+            assert!(!editor.dirty);
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert_eq!(editor.message, READ_ONLY_MESSAGE);
+
+            // Navigation still works
+            process_input(&mut editor, b"jl");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+        }
+
+        #[test]
+        fn read_only_editor_refuses_applied_actions() {
+            // As a keymap or macro would apply them, without a key
+            let mut editor = editor_from_str("one\ntwo");
+            editor.read_only = true;
+            for action in [Action::DeleteChar, Action::KillLine, Action::EnterInsertMode] {
+                editor_apply_action(&mut editor, action).unwrap();
+            }
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert_eq!(editor.message, READ_ONLY_MESSAGE);
+        }
+
+        #[test]
+        fn read_only_editor_does_not_save() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_read_only_save.txt", std::process::id()));
+            let path = path.to_str().unwrap();
+            let _ = std::fs::remove_file(path);
+            let mut editor = editor_with_input("abc", format!(":w {}\r", path).as_bytes());
+            editor.read_only = true;
+            editor_process_keypress(&mut editor).unwrap();
+            editor_save(&mut editor).unwrap();
+            assert!(!std::path::Path::new(path).exists());
+            assert_eq!(editor.message, READ_ONLY_MESSAGE);
+        }
+
+        #[test]
+        fn first_save_makes_backup() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_backup.txt", std::process::id()));
+            let filename = path.to_string_lossy().into_owned();
+            let backup = format!("{}.bak", filename);
+            std::fs::write(&path, "original").unwrap();
+
+            let mut editor = editor_from_str("original");
+            editor.filename = Some(filename.clone());
+            editor.config.make_backup = true;
+            editor.insert_str("first ").unwrap();
+            editor.save().unwrap();
+            assert_eq!(std::fs::read_to_string(&backup).unwrap(), "original");
+
+            // The backup keeps the file as it was before the session
+            editor.insert_str("second ").unwrap();
+            editor.save().unwrap();
+            let backed_up = std::fs::read_to_string(&backup).unwrap();
+            let saved = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&backup).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(backed_up, "original");
+            assert_eq!(saved, "first second original");
+        }
+
+        #[test]
+        fn new_file_gets_no_backup() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_new_backup.txt", std::process::id()));
+            let filename = path.to_string_lossy().into_owned();
+            let mut editor = editor_from_str("new");
+            editor.filename = Some(filename.clone());
+            editor.config.make_backup = true;
+            editor.save().unwrap();
+            editor.save().unwrap();
+            let backup_exists = Path::new(&format!("{}.bak", filename)).exists();
+            std::fs::remove_file(&path).unwrap();
+            assert!(!backup_exists);
+        }
+
+        #[test]
+        fn auto_save_fires_after_interval() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_auto_save", std::process::id()));
+            let mut editor = editor_from_str("abc");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor.config.auto_save_interval = Some(Duration::from_secs(10));
+            editor.insert_char('!').unwrap();
+            let start = editor.keypress_time;
+
+            assert!(!editor_auto_save(&mut editor, start + Duration::from_secs(9)));
+            assert!(editor.dirty);
+            assert!(!path.exists());
+
+            assert!(editor_auto_save(&mut editor, start + Duration::from_secs(10)));
+            assert!(!editor.dirty);
+            let saved = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(saved, "!abc");
+
+            // Nothing to save until the buffer changes again
+            assert!(!editor_auto_save(&mut editor, start + Duration::from_secs(20)));
+        }
+
+        #[test]
+        fn auto_save_skips_buffer_without_filename() {
+            let mut editor = editor_from_str("abc");
+            editor.insert_char('!').unwrap();
+            let later = editor.keypress_time + Duration::from_secs(60);
+            assert!(!editor_auto_save(&mut editor, later));
+
+            // Disabled by default
+            editor.filename = Some("unused".to_string());
+            assert!(!editor.auto_save_due(later));
+            editor.config.auto_save_interval = Some(Duration::from_secs(10));
+            assert!(editor.auto_save_due(later));
+        }
+
+        #[test]
+        fn expire_message_clears_old_messages() {
+            let mut editor = editor_from_str("");
+            editor.set_message("hello");
+            assert!(!editor.expire_message());
+            editor.message_time -= MESSAGE_DURATION;
+            assert!(editor.expire_message());
+            assert_eq!(editor.message, "");
+            assert!(!editor.expire_message());
+        }
+
+        #[test]
+        fn overwrite_mode_replaces_characters() {
+            let mut editor = editor_from_str("abcd\nef");
+            process_input(&mut editor, b"i\x1b[2~xy");
+            assert_eq!(editor.mode, EditorMode::Replace);
+            assert_eq!(editor.buffer.write_contents_to_string(), "xycd\nef");
+            assert_eq!(editor.buffer.len(), 7);
+
+            // Multi-byte characters replace whole characters
+            process_input(&mut editor, b"\xc3\xa9z");
+            assert_eq!(editor.buffer.write_contents_to_string(), "xy\u{e9}z\nef");
+            assert_eq!(editor.buffer.write_contents_to_string().chars().count(), 7);
+
+            // At the end of the line characters are inserted
+            process_input(&mut editor, b"!");
+            assert_eq!(editor.buffer.write_contents_to_string(), "xy\u{e9}z!\nef");
+
+            process_input(&mut editor, b"\x1b[2~w");
+            assert_eq!(editor.mode, EditorMode::Insert);
+            assert_eq!(editor.buffer.write_contents_to_string(), "xy\u{e9}z!w\nef");
+        }
+
+        #[test]
+        fn enter_copies_indent_to_new_line() {
+            let mut editor = editor_from_str("    foo");
+            editor.cursor_col = 7;
+            process_input(&mut editor, b"i\rbar");
+            assert_eq!(editor.buffer.write_contents_to_string(), "    foo\n    bar");
+
+            // Backspace after the indent removes a whole indent level
+            let mut editor = editor_from_str("    foo");
+            editor.config.tab_width = 4;
+            editor.cursor_col = 7;
+            process_input(&mut editor, b"i\r\x7fbar");
+            assert_eq!(editor.buffer.write_contents_to_string(), "    foo\nbar");
+        }
+
+        #[test]
+        fn enter_indent_respects_use_spaces_for_tabs() {
+            let mut editor = editor_from_str("\t  foo");
+            editor.config.tab_width = 4;
+            editor.cursor_col = 6;
+            editor.insert_newline_and_indent().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "\t  foo\n\t  ");
+
+            let mut editor = editor_from_str("\t  foo");
+            editor.config.tab_width = 4;
+            editor.config.use_spaces_for_tabs = true;
+            editor.cursor_col = 6;
+            editor.insert_newline_and_indent().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "\t  foo\n      ");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 6));
+        }
+
+        #[test]
+        fn backspace_deletes_character_or_joins_lines() {
+            let mut editor = editor_from_str("ab\ncd");
+            editor.cursor_row = 1;
+            editor.cursor_col = 1;
+            process_input(&mut editor, b"i\x7f\x7f\x7f");
+            assert_eq!(editor.buffer.write_contents_to_string(), "ad");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 1));
+
+            // Spaces after text are deleted one at a time
+            let mut editor = editor_from_str("a    ");
+            editor.config.tab_width = 4;
+            editor.cursor_col = 5;
+            editor.delete_char_before_cursor().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "a   ");
+        }
+
+        #[test]
+        fn word_count_of_paragraph() {
+            let text = "It was the best of times,\nit was the  worst of times;\n\n\tcaf\u{e9} au lait";
+            let editor = editor_from_str(text);
+            assert_eq!(editor.word_count(), WordCount { lines: 4, words: 15, chars: 68 });
+
+            let mut editor = editor_with_input(text, b":wc\r");
+            editor_process_keypress(&mut editor).unwrap();
+            assert_eq!(editor.message, "4 lines, 15 words, 68 characters");
+        }
+
+        #[test]
+        fn expand_and_unexpand_tabs_round_trip() {
+            let text = "\tif x {\n\t\ty = 1;\t// set y\n  \t}\nab\tc";
+            let mut editor = editor_with_input(text, b":expandtabs\r");
+            editor.config.tab_width = 4;
+            editor_process_keypress(&mut editor).unwrap();
+            let expanded = "    if x {\n        y = 1;  // set y\n    }\nab  c";
+            assert_eq!(editor.buffer.write_contents_to_string(), expanded);
+            assert_eq!(editor.message, "Expanded 6 tabs");
+
+            // Only indentation becomes tabs again
+            editor.unexpand_tabs().unwrap();
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "\tif x {\n\t\ty = 1;  // set y\n\t}\nab  c"
+            );
+            editor.expand_tabs().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), expanded);
+
+            // Each retab is undone in one step
+            editor.buffer.undo().unwrap();
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "\tif x {\n\t\ty = 1;  // set y\n\t}\nab  c"
+            );
+            editor.buffer.undo().unwrap();
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), text);
+        }
+
+        #[test]
+        fn retab_keeps_cursor_on_its_text() {
+            let mut editor = editor_from_str("\tif x {\n\t\ty = 1;\n\t}");
+            editor.config.tab_width = 4;
+            // On the `y`
+            editor.cursor_row = 1;
+            editor.cursor_col = 2;
+            editor.expand_tabs().unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 8));
+            editor.unexpand_tabs().unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+
+            // Inside the indentation, as far in as the new one allows
+            editor.expand_tabs().unwrap();
+            editor.cursor_col = 6;
+            editor.unexpand_tabs().unwrap();
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 2));
+        }
+
+        #[test]
+        fn unexpand_tabs_with_zero_tab_width() {
+            let mut editor = editor_from_str("  a\n\tb");
+            editor.config.tab_width = 0;
+            editor.unexpand_tabs().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "\t\ta\n\tb");
+        }
+
+        #[test]
+        fn indent_and_dedent_selection() {
+            let mut editor = editor_from_str("a\n  b\n\tc\nd");
+            editor.config.tab_width = 4;
+            editor.config.use_spaces_for_tabs = true;
+            editor.cursor_col = 1;
+            process_input(&mut editor, b"vjj>");
+            assert_eq!(editor.buffer.write_contents_to_string(), "    a\n      b\n    \tc\nd");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            // The cursor moved down onto the tab drawn under its column
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 4));
+
+            process_input(&mut editor, b"vkk<");
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\n  b\n\tc\nd");
+
+            // Each is undone in one step
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "    a\n      b\n    \tc\nd");
+            editor.buffer.undo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\n  b\n\tc\nd");
+            editor.buffer.redo().unwrap();
+            editor.buffer.redo().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\n  b\n\tc\nd");
+
+            // Dedenting flush lines does nothing
+            assert_eq!(editor.cursor_row, 0);
+            process_input(&mut editor, b"<");
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\n  b\n\tc\nd");
+            process_input(&mut editor, b"jj<<");
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\n  b\nc\nd");
+        }
+
+        #[test]
+        fn toggle_comment_on_rust_selection() {
+            let mut editor = editor_from_str("fn f() {\n    let x = 1;\n\n}\nend");
+            editor.filename = Some(String::from("x.rs"));
+            process_input(&mut editor, b"vjjjgc");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "// fn f() {\n    // let x = 1;\n\n// }\nend"
+            );
+            assert_eq!(editor.mode, EditorMode::Normal);
+            assert!(editor.dirty);
+
+            // Every non-blank line is commented, so they are uncommented
+            process_input(&mut editor, b"kkkvjjjgc");
+            assert_eq!(editor.buffer.write_contents_to_string(), "fn f() {\n    let x = 1;\n\n}\nend");
+
+            // A partly commented selection is commented
+            process_input(&mut editor, b"kkgckvjgc");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "// fn f() {\n    // // let x = 1;\n\n}\nend"
+            );
+        }
+
+        #[test]
+        fn toggle_comment_uses_file_type() {
+            let mut editor = editor_from_str("a\nb");
+            process_input(&mut editor, b"gc");
+            assert_eq!(editor.buffer.write_contents_to_string(), "a\nb");
+            assert_eq!(editor.message, "No comment prefix for this file type");
+
+            editor.filename = Some(String::from("x.py"));
+            process_input(&mut editor, b"gcj.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "# a\n# b");
+            editor.filename = Some(String::from("x.sql"));
+            process_input(&mut editor, b"gc");
+            assert_eq!(editor.buffer.write_contents_to_string(), "# a\n-- # b");
+        }
+
+        #[test]
+        fn indent_current_line_with_tab() {
+            let mut editor = editor_from_str("a\n\nb");
+            process_input(&mut editor, b"\tj\tj\x1b[Z\t");
+            assert_eq!(editor.buffer.write_contents_to_string(), "\ta\n\n\tb");
+        }
+
+        #[test]
+        fn join_lines_collapses_whitespace() {
+            let mut editor = editor_from_str("one  \n\t  two\nthree");
+            editor_process_key(&mut editor, Key::Char('J')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one two\nthree");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 3));
+
+            editor_process_key(&mut editor, Key::Char('J')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one two three");
+
+            // Joining the last line does nothing
+            editor_process_key(&mut editor, Key::Char('J')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one two three");
+        }
+
+        #[test]
+        fn join_lines_without_leading_whitespace() {
+            let mut editor = editor_from_str("one\ntwo\n\nthree");
+            editor_process_key(&mut editor, Key::Char('J')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one two\n\nthree");
+            assert!(editor.dirty);
+
+            // No space is added when joining an empty line
+            editor_process_key(&mut editor, Key::Char('j')).unwrap();
+            editor_process_key(&mut editor, Key::Char('J')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one two\nthree");
+        }
+
+        #[test]
+        fn duplicate_middle_line() {
+            let mut editor = editor_from_str("one\n    two\nthree");
+            editor.set_mode(EditorMode::Insert);
+            editor.cursor_row = 1;
+            editor.cursor_col = 5;
+            editor_process_key(&mut editor, Key::Ctrl('d')).unwrap();
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "one\n    two\n    two\nthree"
+            );
+            assert_eq!((editor.cursor_row, editor.cursor_col), (2, 5));
+            assert!(editor.dirty);
+        }
+
+        #[test]
+        fn duplicate_last_line() {
+            let mut editor = editor_from_str("one\ntwo");
+            editor.cursor_row = 1;
+            editor.duplicate_line().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\ntwo\ntwo");
+            assert_eq!(editor.cursor_row, 2);
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum HookEvent {
+            Insert(usize, String),
+            Delete(usize, usize),
+            Save(String),
+            Reload(String),
+        }
+
+        struct RecordingHook(Rc<RefCell<Vec<HookEvent>>>);
+
+        impl EditorHook for RecordingHook {
+            fn on_insert(&mut self, offset: usize, text: &str) {
+                self.0.borrow_mut().push(HookEvent::Insert(offset, text.to_string()));
+            }
+
+            fn on_delete(&mut self, start: usize, end: usize) {
+                self.0.borrow_mut().push(HookEvent::Delete(start, end));
+            }
+
+            fn on_save(&mut self, path: &str) {
+                self.0.borrow_mut().push(HookEvent::Save(path.to_string()));
+            }
+
+            fn on_reload(&mut self, path: &str) {
+                self.0.borrow_mut().push(HookEvent::Reload(path.to_string()));
+            }
+        }
+
+        struct PanickingHook;
+
+        impl EditorHook for PanickingHook {
+            fn on_insert(&mut self, _offset: usize, _text: &str) {
+                panic!("hook failed");
+            }
+        }
+
+        #[test]
+        fn hooks_are_run_after_edits_and_saves() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut editor = editor_from_str("one\ntwo");
+            editor.hooks.push(Box::new(RecordingHook(Rc::clone(&events))));
+
+            editor.mode = EditorMode::Insert;
+            editor.cursor_col = 3;
+            process_input(&mut editor, b"!\x7f");
+            editor_process_key(&mut editor, Key::Escape).unwrap();
+            editor.cursor_col = 0;
+            process_input(&mut editor, b"x");
+            editor.overwrite_char('O').unwrap();
+
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_hooks", std::process::id()));
+            let path = path.to_string_lossy().into_owned();
+            editor.save_to(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(*events.borrow(), [
+                HookEvent::Insert(3, "!".to_string()),
+                HookEvent::Delete(3, 4),
+                HookEvent::Delete(0, 1),
+                HookEvent::Delete(0, 1),
+                HookEvent::Insert(0, "O".to_string()),
+                HookEvent::Save(path),
+            ]);
+        }
+
+        #[test]
+        fn hooks_are_run_after_reload() {
+            let path = std::env::temp_dir()
+                .join(format!("editor_{}_hooks_reload.txt", std::process::id()));
+            std::fs::write(&path, "one").unwrap();
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut editor = editor_from_str("two");
+            editor.filename = Some(path.to_string_lossy().into_owned());
+            editor.hooks.push(Box::new(RecordingHook(Rc::clone(&events))));
+
+            editor.reload().unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one");
+            assert_eq!(*events.borrow(), [HookEvent::Reload(path.to_string_lossy().into_owned())]);
+        }
+
+        #[test]
+        fn panicking_hook_is_disabled() {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let mut editor = editor_from_str("");
+            editor.hooks.push(Box::new(PanickingHook));
+            editor.hooks.push(Box::new(RecordingHook(Rc::clone(&events))));
+
+            editor.insert_char('a').unwrap();
+            editor.insert_char('b').unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab");
+            assert_eq!(editor.message, format!("{}: hook failed", HOOK_PANICKED_MESSAGE));
+            assert_eq!(editor.hooks.len(), 1);
+            assert_eq!(*events.borrow(), [
+                HookEvent::Insert(0, "a".to_string()),
+                HookEvent::Insert(1, "b".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn record_and_play_macro() {
+            let mut editor = editor_from_str("abc\nabc\nabc\nabc");
+            process_input(&mut editor, b"qaxi!\x1bjq");
+            assert!(editor.recording.is_none());
+            assert_eq!(editor.macros[&'a'].len(), 5);
+            assert_eq!(editor.buffer.write_contents_to_string(), "!bc\nabc\nabc\nabc");
+
+            process_input(&mut editor, b"@a@@");
+            // Each run leaves the cursor one column further right
+            assert_eq!(editor.buffer.write_contents_to_string(), "!bc\na!c\nab!\nabc");
+            assert_eq!(editor.last_macro, Some('a'));
+        }
+
+        #[test]
+        fn recursive_macro_stops() {
+            let mut editor = editor_from_str("aaaa");
+            process_input(&mut editor, b"qbx@bq");
+            assert_eq!(editor.buffer.write_contents_to_string(), "aaa");
+
+            process_input(&mut editor, b"@b");
+            assert_eq!(editor.buffer.write_contents_to_string(), "aa");
+            assert_eq!(editor.message, "Recursive macro @b");
+        }
+
+        #[test]
+        fn jump_to_mark() {
+            let mut editor = editor_from_str("one\ntwo\nthree");
+            process_input(&mut editor, b"jlma");
+            assert_eq!(editor.marks[&'a'], 5);
+            process_input(&mut editor, b"jl");
+            process_input(&mut editor, b"`a");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (1, 1));
+
+            process_input(&mut editor, b"`b");
+            assert_eq!(editor.message, "Mark b not set");
+        }
+
+        #[test]
+        fn mark_is_clamped_when_buffer_shrinks() {
+            let mut editor = editor_from_str("one\ntwo\nthree");
+            editor_go_to_line(&mut editor, 3);
+            process_input(&mut editor, b"llllmz");
+            editor.set_cursor_offset(0);
+            editor.delete_line().unwrap();
+            editor.delete_line().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "three");
+
+            process_input(&mut editor, b"`z");
+            assert_eq!((editor.cursor_row, editor.cursor_col), (0, 5));
+        }
+
+        #[test]
+        fn jump_list_moves_back_and_forward() {
+            let mut editor = editor_with_lines(20, 5);
+            editor_go_to_line(&mut editor, 10);
+            editor_go_to_line(&mut editor, 15);
+            assert_eq!(editor.cursor_row, 14);
+
+            process_input(&mut editor, b"\x0f");
+            assert_eq!(editor.cursor_row, 9);
+            process_input(&mut editor, b"\x0f\x0f");
+            assert_eq!(editor.cursor_row, 0);
+            process_input(&mut editor, b"\x0e");
+            assert_eq!(editor.cursor_row, 9);
+            process_input(&mut editor, b"\x0e\x0e");
+            assert_eq!(editor.cursor_row, 14);
+
+            // A new jump forgets the jumps that were moved back over
+            process_input(&mut editor, b"\x0f\x0f");
+            editor_go_to_line(&mut editor, 5);
+            process_input(&mut editor, b"\x0e");
+            assert_eq!(editor.cursor_row, 4);
+            process_input(&mut editor, b"\x0f");
+            assert_eq!(editor.cursor_row, 0);
+        }
+
+        #[test]
+        fn repeat_deletes_at_new_position() {
+            let mut editor = editor_from_str("abc\ndef");
+            process_input(&mut editor, b"xjl.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "bc\ndf");
+            assert_eq!(editor.last_change, Some((Change::DeleteChar, 1)));
+
+            // Moving the cursor is not a change to repeat
+            process_input(&mut editor, b"k.");
+            assert_eq!(editor.buffer.write_contents_to_string(), "b\ndf");
+        }
+
+        #[test]
+        fn repeat_inserted_text() {
+            let mut editor = editor_from_str("    one\ntwo");
+            editor.cursor_col = 7;
+            process_input(&mut editor, b"i!!\x7f\rx\x1b");
+            assert_eq!(editor.buffer.write_contents_to_string(), "    one!\n    x\ntwo");
+            assert_eq!(editor.last_change, Some((Change::Insert("!\n    x".to_string()), 1)));
+
+            editor_go_to_line(&mut editor, 3);
+            process_input(&mut editor, b".");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "    one!\n    x\n!\n    xtwo"
+            );
+        }
+
+        #[test]
+        fn transpose_chars_mid_line() {
+            let mut editor = editor_from_str("abc\u{e9}d");
+            editor.cursor_col = 1;
+            editor_process_key(&mut editor, Key::Ctrl('t')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "bac\u{e9}d");
+            assert_eq!(editor.cursor_col, 2);
+            editor_process_key(&mut editor, Key::Ctrl('t')).unwrap();
+            editor_process_key(&mut editor, Key::Ctrl('t')).unwrap();
+            // Repeating drags the character forward
+            assert_eq!(editor.buffer.write_contents_to_string(), "bc\u{e9}ad");
+            assert_eq!(editor.cursor_col, 5);
+            assert!(editor.dirty);
+        }
+
+        #[test]
+        fn transpose_chars_at_end_of_line() {
+            let mut editor = editor_from_str("ab\ncd");
+            editor.cursor_col = 2;
+            editor.transpose_chars().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "ba\ncd");
+            assert_eq!(editor.cursor_col, 2);
+
+            // Nothing to swap at the start of a line or on an empty line
+            let mut editor = editor_from_str("ab\n");
+            editor.transpose_chars().unwrap();
+            editor.cursor_row = 1;
+            editor.transpose_chars().unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "ab\n");
+            assert!(!editor.dirty);
+        }
+
+        #[test]
+        fn change_case_of_selection() {
+            let cases = [
+                (b"U", "HELLO, W\u{d6}RLD! stra\u{df}e"),
+                (b"u", "hello, w\u{f6}rld! stra\u{df}e"),
+                (b"~", "hELLO, w\u{d6}rLD! stra\u{df}e"),
+            ];
+            for (key, expected) in cases {
+                let mut editor = editor_from_str("Hello, W\u{f6}Rld! stra\u{df}e");
+                process_input(&mut editor, b"vllllllllllll");
+                process_input(&mut editor, key);
+                assert_eq!(editor.buffer.write_contents_to_string(), expected);
+                assert_eq!(editor.mode, EditorMode::Normal);
+                assert_eq!(editor.cursor_col, 0);
+            }
+        }
+
+        #[test]
+        fn change_case_under_cursor() {
+            let mut editor = editor_from_str("stra\u{df}e aB");
+            editor.cursor_col = 4;
+            process_input(&mut editor, b"gU");
+            assert_eq!(editor.buffer.write_contents_to_string(), "straSSe aB");
+            assert_eq!(editor.cursor_col, 6);
+            process_input(&mut editor, b"ll~~");
+            assert_eq!(editor.buffer.write_contents_to_string(), "straSSe Ab");
+            process_input(&mut editor, b"hhgu");
+            assert_eq!(editor.buffer.write_contents_to_string(), "straSSe ab");
+
+            // At the end of the line there is nothing to change
+            process_input(&mut editor, b"ll~");
+            assert_eq!(editor.buffer.write_contents_to_string(), "straSSe ab");
+        }
+
+        #[test]
+        fn kill_line_then_paste_twice() {
+            let mut editor = editor_from_str("one\ntwo\nthree");
+            editor.cursor_row = 1;
+            process_input(&mut editor, b"dd");
+            assert_eq!(editor.register, "two\n");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\nthree");
+
+            process_input(&mut editor, b"pp");
+            assert_eq!(
+                editor.buffer.write_contents_to_string(), 
+                "one\ntwo\ntwo\nthree"
+            );
+        }
+
+        #[test]
+        fn copy_and_paste_fall_back_to_register_without_clipboard() {
+            let mut editor = editor_from_str("one\ntwo");
+            editor.config.system_clipboard = false;
+            process_input(&mut editor, b"yyjp");
+            assert_eq!(editor.register, "one\n");
+            assert_eq!(editor.buffer.write_contents_to_string(), "one\none\ntwo");
+        }
+
+        #[test]
+        fn consecutive_ctrl_k_appends_to_register() {
+            let mut editor = editor_from_str("one two\nthree four");
+            editor.cursor_col = 3;
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            editor_process_key(&mut editor, Key::ArrowDown).unwrap();
+            editor.cursor_col = 5;
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            assert_eq!(editor.register, " four");
+
+            editor_process_key(&mut editor, Key::ArrowUp).unwrap();
+            editor.set_mode(EditorMode::Insert);
+            editor_process_key(&mut editor, Key::Ctrl('u')).unwrap();
+            assert_eq!(editor.buffer.write_contents_to_string(), "one four\nthree");
+
+            let mut editor = editor_from_str("one two\nthree");
+            editor.cursor_col = 3;
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            editor.cursor_col = 1;
+            editor_process_key(&mut editor, Key::Ctrl('k')).unwrap();
+            assert_eq!(editor.register, " twone");
+        }
+
+        #[test]
+        fn editor_honors_custom_config() {
+            let config = EditorConfig {
+                tab_width: 2,
+                show_line_numbers: true,
+                ..EditorConfig::default()
+            };
+            let size = WindowSize { rows: 5, cols: 20 };
+            let mut editor = Editor::new(size, config.clone(), PieceTable::from_str("\ta\nb"));
+            assert_eq!(editor.config, config);
+
+            let mut frame = String::new();
+            editor_draw_rows(&editor, &mut frame);
+            let rows: Vec<&str> = frame.split("\r\n").collect();
+            assert_eq!(rows[0], "1   a");
+            assert_eq!(rows[1], "2 b");
+            assert_eq!(rows[2], "~");
+
+            editor.cursor_col = 1;
+            assert_eq!(editor.cursor_screen_col(), 4);
+        }
+    }
+}
+
+use editor::*;
+
+fn main() {
+    // Set up terminal and editor 
+    let original_termios = input_stream_editor::activate_stdin_raw_mode();
+    let mut path = None;
+    let mut read_only = false;
+    let mut log_path = std::env::var_os(debug_log::LOG_ENV_VAR).map(std::path::PathBuf::from);
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-R" => read_only = true,
+            _ if arg.starts_with("--log=") => log_path = Some(arg["--log=".len()..].into()),
+            _ => path = Some(arg),
+        }
+    }
+    let mut editor = match Editor::build(path.as_deref()) {
+        Ok(editor) => editor,
+        Err(e) => kill_editor(original_termios, EditorStatus::FailedToBuild(e)),
+    };
+    editor.read_only = read_only;
+    if let Some(log_path) = log_path {
+        editor_start_debug_log(&mut editor, &log_path);
+    }
+    let opened = editor_offer_hex_view(&mut editor).and_then(|()| editor_open_swap(&mut editor));
+    if let Err(e) = opened {
+        kill_editor(original_termios, EditorStatus::FailedToProcessKeypress(e));
+    }
+
+    // Enable bracketed paste so pasted text arrives as one `PasteStart`
+    // key instead of a keypress per character.
+    print!("\x1b[?2004h");
+
+    let mut redraw = true;
+    loop {
+        if redraw {
+            if let Err(e) = editor_refresh_screen(&mut editor) {
+                editor.status = EditorStatus::FailedToRefresh(e);
+            }
+        }
+
+        match editor_process_keypress(&mut editor) {
+            Ok(needs_redraw) => redraw = needs_redraw,
+            Err(e) => editor.status = EditorStatus::FailedToProcessKeypress(e),
+        }
+
+        match editor.status {
+            EditorStatus::RefershScreen => continue,
+            EditorStatus::TerminalExitSuccess => editor.remove_swap(),
+            // Keep the swap file so that the changes can be recovered
+            _ => (),
+        }
+        kill_editor(original_termios, editor.status);
     }
 }
@@ -1,4 +1,12 @@
-use std::io::Write;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{ BufWriter, Read, Write };
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::{ Duration, Instant, SystemTime };
+use memmap2::Mmap;
+use unicode_segmentation::{ GraphemeCursor, GraphemeIncomplete };
 
 mod string_writer {
 
@@ -35,6 +43,30 @@ pub enum PieceTableError {
     GotBadPieceRange,
     GotBadLoc,
     IOError(std::io::Error),
+    /// The file being saved to has been modified since it was loaded or
+    /// last saved.
+    FileChangedOnDisk,
+}
+
+impl std::fmt::Display for PieceTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PieceTableError::GotBadPieceID => write!(f, "bad piece id"),
+            PieceTableError::GotBadPieceRange => write!(f, "bad piece range"),
+            PieceTableError::GotBadLoc => write!(f, "bad location"),
+            PieceTableError::IOError(err) => write!(f, "{}", err),
+            PieceTableError::FileChangedOnDisk => write!(f, "file changed on disk"),
+        }
+    }
+}
+
+impl From<PieceTableError> for std::io::Error {
+    fn from(err: PieceTableError) -> Self {
+        match err {
+            PieceTableError::IOError(err) => err,
+            err => std::io::Error::other(err.to_string()),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -43,7 +75,7 @@ pub enum PieceBuf {
     ADDITION,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Piece {
     pub start: usize,
     pub stop: usize,
@@ -51,250 +83,3929 @@ pub struct Piece {
 }
 
 impl Piece {
+    /// Number of bytes of the buffer the piece covers.
+    ///
+    /// # Panics
+    /// * In debug builds, if the piece ends before it starts.
     pub fn len(&self) -> usize {
+        debug_assert!(
+            self.stop >= self.start, 
+            "piece ends at {} before it starts at {}", self.stop, self.start
+        );
         self.stop - self.start
     }
+
+    /// Whether the piece covers no text.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.stop
+    }
 }
 
 impl PartialEq for Piece {
     fn eq(&self, rhs: &Self) -> bool {
-        if self.start == rhs.start && self.stop == rhs.stop && self.content == rhs.content {
-            true
+        self.start == rhs.start && self.stop == rhs.stop && self.content == rhs.content
+    }
+}
+
+impl Eq for Piece {}
+
+/// Where the last write to a `PieceTable` ended, so that a write 
+/// continuing from it can be appended to the same piece.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteLocation {
+    /// Index of the piece that was written.
+    pub piece_id: usize,
+    /// Offset into the buffer of the end of the write.
+    pub loc: usize,
+}
+
+/// A change made to a `PieceTable`, recorded so that it can be undone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit {
+    /// `text` was inserted at offset `loc`.
+    Insert { loc: usize, text: String },
+    /// `text` was deleted from offset `loc`.
+    Delete { loc: usize, text: String },
+}
+
+impl Edit {
+    /// The edit that reverts `self`.
+    pub fn inverse(&self) -> Edit {
+        match self.clone() {
+            Edit::Insert { loc, text } => Edit::Delete { loc, text },
+            Edit::Delete { loc, text } => Edit::Insert { loc, text },
+        }
+    }
+}
+
+/// A position in a `PieceTable`, either as a byte offset or as the 
+/// number of characters before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    Byte(usize),
+    Char(usize),
+}
+
+/// How long after typing a character the next character typed can join
+/// its undo group.
+const UNDO_GROUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a search term is matched against the buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Match letters regardless of case, folding both the term and the
+    /// buffer to lowercase.
+    pub case_insensitive: bool,
+    /// Only match the term where it is not preceded or followed by a 
+    /// word character.
+    pub whole_word: bool,
+}
+
+/// Lowercase `text`, returning the folded text along with the offset 
+/// into `text` of each byte of it, plus one past the end.
+fn fold_case(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    for (i, c) in text.char_indices() {
+        for lower in c.to_lowercase() {
+            offsets.extend(std::iter::repeat_n(i, lower.len_utf8()));
+            folded.push(lower);
+        }
+    }
+    offsets.push(text.len());
+    (folded, offsets)
+}
+
+/// Whether the text at `range` in `contents` is neither preceded nor 
+/// followed by a word character.
+fn is_whole_word(contents: &str, range: &Range<usize>) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    !contents[..range.start].chars().next_back().is_some_and(is_word)
+        && !contents[range.end..].chars().next().is_some_and(is_word)
+}
+
+/// Return the range of every non-overlapping match of `needle` within
+/// `contents[range]`, in order. `range` must lie on character 
+/// boundaries.
+fn find_matches(
+    contents: &str, 
+    needle: &str, 
+    range: Range<usize>, 
+    options: SearchOptions
+) -> Vec<Range<usize>> {
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    let text = &contents[range.clone()];
+    let (haystack, needle, offsets) = if options.case_insensitive {
+        let (haystack, offsets) = fold_case(text);
+        (Cow::Owned(haystack), Cow::Owned(needle.to_lowercase()), Some(offsets))
+    } else {
+        (Cow::Borrowed(text), Cow::Borrowed(needle), None)
+    };
+    let to_contents = |i: usize| range.start + offsets.as_ref().map_or(i, |offsets| offsets[i]);
+
+    let mut matches = Vec::new();
+    let mut at = 0;
+    while let Some(i) = haystack[at..].find(&*needle) {
+        let start = at + i;
+        let found = to_contents(start)..to_contents(start + needle.len());
+        if options.whole_word && !is_whole_word(contents, &found) {
+            // Try again from the next character, as a match overlapping
+            // this one may still be a whole word
+            at = start + haystack[start..].chars().next().map_or(1, char::len_utf8);
+            continue;
+        }
+        at = start + needle.len();
+        matches.push(found);
+    }
+    matches
+}
+
+/// Style of line ending used by a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    LF,
+    CRLF,
+}
+
+impl LineEnding {
+    /// Detect the line ending used by `s`: `CRLF` if every line ending
+    /// is `\r\n`, otherwise `LF`, including for documents without any 
+    /// line endings and documents that mix the two, which are kept as 
+    /// they are.
+    pub fn detect(s: &str) -> Self {
+        let n_crlf = s.matches("\r\n").count();
+        if n_crlf > 0 && n_crlf == s.matches('\n').count() {
+            LineEnding::CRLF
         } else {
-            false
+            LineEnding::LF
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::LF => "\n",
+            LineEnding::CRLF => "\r\n",
         }
     }
 }
 
-impl Eq for Piece {}
+/// Modification time of the file at `path`, or `None` if it cannot be
+/// read.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The UTF-8 byte order mark some editors write at the start of files.
+const BOM: char = '\u{feff}';
+
+/// Most bytes of a mapped file, or another original buffer that is 
+/// checked as it is read, that are checked to be valid UTF-8 at once,
+/// so that reading the text around an offset does not check the whole
+/// file.
+const MAPPED_CHUNK_LEN: usize = 64 * 1024;
+
+/// As many NULs as the longest chunk of unchecked bytes, read in place
+/// of a chunk that is not valid UTF-8.
+static NULS: [u8; MAPPED_CHUNK_LEN] = [0; MAPPED_CHUNK_LEN];
+
+/// Storage of the original buffer of a `PieceTable`.
+enum Original {
+    Owned(String),
+    /// Bytes that are checked to be valid UTF-8 each time they are read
+    /// rather than once when they are loaded. They are read in chunks of
+    /// at most `MAPPED_CHUNK_LEN` bytes, as `chunk_stop` splits them.
+    Unchecked(UncheckedBytes),
+}
+
+/// The bytes of an `Original::Unchecked` buffer.
+enum UncheckedBytes {
+    /// A file mapped into memory, which other programs can still write 
+    /// to.
+    Mapped(Mmap),
+    /// The contents of a file that is not valid UTF-8.
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for UncheckedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            UncheckedBytes::Mapped(mmap) => mmap,
+            UncheckedBytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Original {
+    fn len(&self) -> usize {
+        self.bytes().len()
+    }
+
+    /// The bytes of the buffer as they are stored.
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Original::Owned(s) => s.as_bytes(),
+            Original::Unchecked(bytes) => bytes,
+        }
+    }
+
+    /// Whether byte `i` starts a character or is the end of the buffer.
+    fn is_char_boundary(&self, i: usize) -> bool {
+        match self {
+            Original::Owned(s) => s.is_char_boundary(i),
+            // Every byte other than a continuation byte starts a 
+            // character
+            Original::Unchecked(bytes) => {
+                i == bytes.len() || bytes.get(i).is_some_and(|&byte| byte & 0xc0 != 0x80)
+            },
+        }
+    }
+
+    /// The text of the bytes `range`. Unchecked bytes that are not valid
+    /// UTF-8, such as those of a mapped file another program has written
+    /// over, are read as the same number of NULs instead, so that the 
+    /// offsets of the text stay the same.
+    ///
+    /// # Panics
+    /// * If `range` is outside of the buffer, or either end falls within
+    ///   a character of an owned buffer.
+    /// * If `range` is longer than a chunk of unchecked bytes that is
+    ///   not valid UTF-8.
+    fn text(&self, range: Range<usize>) -> &str {
+        match self {
+            Original::Owned(s) => &s[range],
+            Original::Unchecked(bytes) => {
+                std::str::from_utf8(&bytes[range.clone()]).unwrap_or_else(|_| {
+                    // Can safely unwrap here since NULs are valid UTF-8
+                    std::str::from_utf8(&NULS[..range.len()]).unwrap()
+                })
+            },
+        }
+    }
+
+    /// Where the first chunk of the bytes `range` ends: at the end of the
+    /// range, unless it is more than `MAPPED_CHUNK_LEN` unchecked bytes,
+    /// when it ends at the last character boundary within that length.
+    fn chunk_stop(&self, range: Range<usize>) -> usize {
+        if matches!(self, Original::Owned(_)) || range.len() <= MAPPED_CHUNK_LEN {
+            return range.end;
+        }
+        // A character is at most 4 bytes long
+        let stop = range.start + MAPPED_CHUNK_LEN;
+        (stop - 3..=stop).rev().find(|&i| self.is_char_boundary(i)).unwrap_or(stop)
+    }
+
+    /// Where the last chunk of the bytes `range` starts, splitting them
+    /// as `chunk_stop` does.
+    fn chunk_start(&self, range: Range<usize>) -> usize {
+        if matches!(self, Original::Owned(_)) || range.len() <= MAPPED_CHUNK_LEN {
+            return range.start;
+        }
+        let start = range.end - MAPPED_CHUNK_LEN;
+        (start..=start + 3).find(|&i| self.is_char_boundary(i)).unwrap_or(start)
+    }
+
+    /// The text of the bytes `range` in chunks, as `chunk_stop` splits 
+    /// them.
+    ///
+    /// # Panics
+    /// * As for `text`.
+    fn chunks(&self, mut range: Range<usize>) -> impl Iterator<Item = &str> {
+        std::iter::from_fn(move || {
+            if range.start >= range.end {
+                return None;
+            }
+            let stop = self.chunk_stop(range.clone());
+            let chunk = self.text(range.start..stop);
+            range.start = stop;
+            Some(chunk)
+        })
+    }
+}
+
+/// Storage of the addition buffer of a `PieceTable`, which is only ever
+/// appended to. It is kept in blocks that snapshots share: text is 
+/// appended to the last block unless a snapshot holds it, in which case
+/// a new block is started, so that taking a snapshot never copies text.
+/// A piece of the addition buffer lies within a single block.
+#[derive(Clone)]
+struct Addition {
+    blocks: Vec<Arc<String>>,
+    /// Offset of the start of each block.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Addition {
+    fn new() -> Self {
+        Self { blocks: vec![Arc::new(String::new())], starts: vec![0], len: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Index of the block that byte `i` is in, taking the end of the 
+    /// buffer to be in the last block.
+    fn block(&self, i: usize) -> usize {
+        self.starts.partition_point(|&start| start <= i) - 1
+    }
+
+    /// Whether byte `i` starts a block other than the first, so that a 
+    /// piece ending at `i` cannot be joined to one starting there.
+    fn starts_block(&self, i: usize) -> bool {
+        i != 0 && self.starts.binary_search(&i).is_ok()
+    }
 
+    /// Whether text appended now would follow straight on from a piece
+    /// ending at `stop` in the same block.
+    fn extends(&self, stop: usize) -> bool {
+        stop == self.len && self.blocks.last().is_some_and(|block| Arc::strong_count(block) == 1)
+    }
+
+    fn is_char_boundary(&self, i: usize) -> bool {
+        let block = self.block(i);
+        self.blocks[block].is_char_boundary(i - self.starts[block])
+    }
+
+    /// # Panics
+    /// * If `range` is not within a single block or either end falls 
+    ///   within a character.
+    fn text(&self, range: Range<usize>) -> &str {
+        let block = self.block(range.start);
+        let start = self.starts[block];
+        &self.blocks[block][range.start - start..range.end - start]
+    }
+
+    /// Append `content` and return the offset it starts at.
+    fn push(&mut self, content: &str) -> usize {
+        let start = self.len;
+        if content.is_empty() {
+            return start;
+        }
+        match self.blocks.last_mut().and_then(Arc::get_mut) {
+            Some(block) => block.push_str(content),
+            None => {
+                self.blocks.push(Arc::new(content.to_string()));
+                self.starts.push(start);
+            },
+        }
+        self.len += content.len();
+        start
+    }
+}
+
+/// A buffer of text stored as pieces of an original and an addition
+/// buffer. Lines within the buffer always end with `\n`; the line
+/// ending of the document on disk is recorded in `line_ending` and
+/// restored when saving.
 pub struct PieceTable {
-    original: String,
-    addition: String,
+    /// Shared with any snapshots of the table.
+    original: Arc<Original>,
+    /// Only ever appended to. Its blocks are shared with any snapshots
+    /// of the table.
+    addition: Addition,
+    /// Offsets of every `\n` in the original and addition buffers, so
+    /// that the lines in a piece can be counted without reading it.
+    original_newlines: Vec<usize>,
+    addition_newlines: Vec<usize>,
     pieces: Vec<Piece>,
+    /// Offset of the end of each piece, so that the piece holding an 
+    /// offset can be found by binary search. Only the first 
+    /// `valid_piece_ends` are up to date; the rest are recomputed when
+    /// they are next needed.
+    piece_ends: Vec<usize>,
+    valid_piece_ends: usize,
     current_piece_id: usize,
+    previous_write: Option<WriteLocation>,
+    line_ending: LineEnding,
+    has_bom: bool,
+    /// Whether the original buffer was loaded as bytes that need not be
+    /// valid UTF-8, so the table should only be read byte by byte.
+    binary: bool,
+    trim_trailing_whitespace: bool,
+    ensure_final_newline: bool,
+    trim_final_newlines: bool,
+    /// The file the buffer was loaded from or saved to, and its 
+    /// modification time at that point.
+    file_modified: Option<(PathBuf, SystemTime)>,
+    /// Groups of edits that are undone together, oldest first, and the
+    /// groups that have been undone and can be redone, most recently 
+    /// undone last.
+    undo_stack: Vec<Vec<Edit>>,
+    redo_stack: Vec<Vec<Edit>>,
+    /// When a single character was inserted, if that was the last edit,
+    /// so that a character typed straight after it joins its group.
+    last_typed: Option<Instant>,
+    /// Edits made since the outermost `begin_undo_group` that has not 
+    /// been ended, and how many groups are open.
+    open_group: Vec<Edit>,
+    open_groups: usize,
+    /// Source of the time that characters are typed at.
+    clock: fn() -> Instant,
 }
 
 impl PieceTable {
     /// Create a `PieceTable` from `s`.
     pub fn from_string(s:String) -> Self {
-        let mut pieces = Vec::new();
-        pieces.push(Piece { start: 0, stop: s.len(), content: PieceBuf::ORIGINAL });
-        Self { original: s, addition: String::new(), pieces, current_piece_id: 0 }
+        let pieces = vec![Piece { start: 0, stop: s.len(), content: PieceBuf::ORIGINAL }];
+        Self { 
+            original_newlines: newline_offsets(s.as_bytes()),
+            addition_newlines: Vec::new(),
+            original: Arc::new(Original::Owned(s)), 
+            addition: Addition::new(), 
+            pieces, 
+            piece_ends: Vec::new(),
+            valid_piece_ends: 0,
+            current_piece_id: 0,
+            previous_write: None,
+            line_ending: LineEnding::LF,
+            has_bom: false,
+            binary: false,
+            trim_trailing_whitespace: false,
+            ensure_final_newline: false,
+            trim_final_newlines: false,
+            file_modified: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            last_typed: None,
+            open_group: Vec::new(),
+            open_groups: 0,
+            clock: Instant::now,
+        }
     }
 
     /// Create a `PieceTable` from `s`.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         let s = String::from(s);
         Self::from_string(s)
     }
 
-    pub fn get_pieces(&self) -> &Vec<Piece> {
-        &self.pieces
+    /// Create a `PieceTable` from the contents of the file at `path`,
+    /// detecting its line ending as `LineEnding::detect` does and, if 
+    /// it is `CRLF`, converting every line ending to `\n`. A leading 
+    /// byte order mark is removed from the buffer and recorded in 
+    /// `has_bom`. The modification time of the file is recorded so that
+    /// saving can detect changes made by others.
+    ///
+    /// # Errors
+    /// * `IOError` if the file cannot be read or is not valid UTF-8.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, PieceTableError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(PieceTableError::IOError)?;
+        let mut piece_table = Self::from_reader(file).map_err(PieceTableError::IOError)?;
+        piece_table.file_modified = modified_time(path).map(|time| (path.to_path_buf(), time));
+        Ok(piece_table)
     }
 
-    /// Insert `content` at `loc` in buffer and return the number of 
-    /// characters written.
+    /// Create a `PieceTable` from everything read from `r`, such as 
+    /// stdin or a decompressor, detecting its line ending as 
+    /// `LineEnding::detect` does and, if it is `CRLF`, converting every
+    /// line ending to `\n`. A leading byte order mark is removed from 
+    /// the buffer and recorded in `has_bom`.
     ///
     /// # Errors
-    /// * `PieceTableError::GotBadLoc` if loc does not exists in the 
-    /// current buffer.
-    pub fn write_to_loc(&mut self, loc: usize, content: &str) ->
-        Result<usize, PieceTableError> {
+    /// * Returns an error if reading from `r` fails, or an error of kind
+    ///   `InvalidData` if what is read is not valid UTF-8.
+    pub fn from_reader<R: Read>(mut r: R) -> std::io::Result<Self> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let s = String::from_utf8(bytes).map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::InvalidData, 
+            "stream did not contain valid UTF-8"
+        ))?;
+        let (s, has_bom) = match s.strip_prefix(BOM) {
+            Some(stripped) => (stripped, true),
+            None => (s.as_str(), false),
+        };
+        let line_ending = LineEnding::detect(s);
+        let s = match line_ending {
+            LineEnding::CRLF => s.replace("\r\n", "\n"),
+            LineEnding::LF => s.to_string(),
+        };
+        let mut piece_table = Self::from_string(s);
+        piece_table.line_ending = line_ending;
+        piece_table.has_bom = has_bom;
+        Ok(piece_table)
+    }
 
-        let mut piece: Option<&Piece> = None;
-        let mut piece_id: Option<usize> = None;
-        let mut piece_start_loc: Option<usize> = None;
-        let mut current_loc = 0;
-        let mut next_loc = 0;
-
-        for (id, _piece) in self.pieces.iter().enumerate() {
-            next_loc += _piece.len();
-            if next_loc >= loc {
-                // This can always be safely unwrapped since id is 
-                // bounded by the lenth of self.pieces
-                piece = Some(&self.pieces.get(id).unwrap());
-                piece_id = Some(id);
-                piece_start_loc = Some(current_loc);
-                break;
-            }
-            current_loc = next_loc;
-        }
-        
-        let piece = piece.ok_or(PieceTableError::GotBadLoc)?;
-        let piece_id = piece_id.ok_or(PieceTableError::GotBadLoc)?;
-        let piece_start_loc = piece_start_loc.ok_or(PieceTableError::GotBadLoc)?;
+    /// Create a `PieceTable` whose original buffer is the file at `path`
+    /// mapped into memory, so that the file is not copied into memory 
+    /// and its pages can be dropped and read again as they are needed.
+    /// The file is still read through once when it is loaded, to check
+    /// that it is valid UTF-8 and to index its lines, so loading takes 
+    /// time in proportion to its size. A file whose line endings are all
+    /// `\r\n` cannot be used as it is, so is loaded with `from_file` 
+    /// instead.
+    ///
+    /// The file should not be changed by other programs while it is 
+    /// mapped, but if it is, text that is no longer valid UTF-8 is read
+    /// as NULs. Saving to it replaces it with a new file rather than 
+    /// writing over the mapping.
+    ///
+    /// # Errors
+    /// * `IOError` if the file cannot be mapped or is not valid UTF-8.
+    pub fn from_file_mmap<P: AsRef<Path>>(path: P) -> Result<Self, PieceTableError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(PieceTableError::IOError)?;
+        // Safety: the mapping is only read from. Another program 
+        // truncating the file while it is mapped could still fault the
+        // editor, as with any memory-mapped file.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(PieceTableError::IOError)?;
+        let Some(newlines) = index_mapped(&mmap).map_err(|err| {
+            PieceTableError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+        })? else {
+            return Self::from_file(path);
+        };
+        let has_bom = mmap.starts_with(BOM.encode_utf8(&mut [0; 4]).as_bytes());
+        let start = if has_bom { BOM.len_utf8() } else { 0 };
 
-        // If loc is in the middle of a piece then split piece before
-        // inputting.
-        if loc != piece_start_loc + piece.len() {
-            let piece_loc = loc - piece_start_loc;
-            self.split_piece(piece_id, piece_loc)
-                .map_err(|_| PieceTableError::GotBadLoc)?;  
-        }       
-        
-        let start = self.addition.len();
-        self.addition.push_str(content);
-        let stop = self.addition.len();
-        let n_chars = stop - start;
+        let mut piece_table = Self::from_str("");
+        piece_table.pieces = vec![Piece { start, stop: mmap.len(), content: PieceBuf::ORIGINAL }];
+        piece_table.invalidate_piece_ends(0);
+        piece_table.original_newlines = newlines;
+        piece_table.original = Arc::new(Original::Unchecked(UncheckedBytes::Mapped(mmap)));
+        piece_table.has_bom = has_bom;
+        piece_table.file_modified = modified_time(path).map(|time| (path.to_path_buf(), time));
+        Ok(piece_table)
+    }
 
-        let new_piece = Piece { start, stop, content: PieceBuf::ADDITION };
-        let new_piece_id = piece_id + 1;
-        self.pieces.insert(new_piece_id, new_piece);
-        self.current_piece_id = new_piece_id;
+    /// Create a `PieceTable` from `bytes` as they are, whether or not 
+    /// they are valid UTF-8, for viewing byte by byte. Line endings are
+    /// not converted and a byte order mark is not removed. Text read 
+    /// from the table reads any part that is not valid UTF-8 as NULs;
+    /// `get_bytes_range` reads the bytes themselves.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let mut piece_table = Self::from_str("");
+        piece_table.pieces = vec![Piece { start: 0, stop: bytes.len(), content: PieceBuf::ORIGINAL }];
+        piece_table.invalidate_piece_ends(0);
+        piece_table.original_newlines = newline_offsets(&bytes);
+        piece_table.original = Arc::new(Original::Unchecked(UncheckedBytes::Owned(bytes)));
+        piece_table.binary = true;
+        piece_table
+    }
+
+    /// Create a `PieceTable` from the bytes of the file at `path` as 
+    /// `from_bytes` does, such as for a file that is not valid UTF-8.
+    /// The modification time of the file is recorded as for `from_file`.
+    ///
+    /// # Errors
+    /// * `IOError` if the file cannot be read.
+    pub fn from_file_bytes<P: AsRef<Path>>(path: P) -> Result<Self, PieceTableError> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(PieceTableError::IOError)?;
+        let mut piece_table = Self::from_bytes(bytes);
+        piece_table.file_modified = modified_time(path).map(|time| (path.to_path_buf(), time));
+        Ok(piece_table)
+    }
 
+    /// Whether the table was loaded with `from_bytes` or 
+    /// `from_file_bytes`, so its text need not be valid UTF-8.
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
 
-        Ok(n_chars)
+    /// Whether the original buffer is a file mapped into memory.
+    pub fn is_mapped(&self) -> bool {
+        matches!(*self.original, Original::Unchecked(UncheckedBytes::Mapped(_)))
     }
 
-    
-    /// Append `content` to the last piece that was written to and 
-    /// returns the number of characters that were written. The last 
-    /// piece written to is usually set by the last call to 
-    /// `self.write_to_loc`.
+    /// Write the contents of `self` to the file at `path` using
+    /// `self.line_ending`, starting with a byte order mark if 
+    /// `self.has_bom` is set, and return the number of bytes written. If
+    /// `self.trim_trailing_whitespace` is set then spaces and tabs at 
+    /// the end of each line are left out of the file, but are kept in 
+    /// the buffer. Likewise, if `self.trim_final_newlines` is set then 
+    /// blank lines, or lines of only spaces and tabs, at the end of the
+    /// buffer are left out, and if 
+    /// `self.ensure_final_newline` is set then a newline is added to the
+    /// end of a buffer that does not end with one.
     ///
     /// # Errors
-    /// * If the last piece written to does not point to the end of 
-    /// `self.addition` then returns `PieceTableError::GotBadPieceID`
-    /// * If the last range of the last piece pointed to does not
-    /// contain the final charcter in `self.addition` the returns
-    /// `PieceTableError::GotBadPieceRange`.
-    pub fn write_to_current_piece(&mut self, content: &str) -> 
-        Result<usize, PieceTableError> 
-    {
-        // Can always unwrap here since any failure indicates that 
-        // the tracking of current_piece_id has failed, which is an 
-        // un-recoverable error.
-        let piece = self.pieces.get_mut(self.current_piece_id).unwrap();
+    /// * `FileChangedOnDisk` if `path` is the file the buffer was loaded
+    ///   from or last saved to and it has been modified since. Call 
+    ///   `forget_file_modified` to overwrite it anyway.
+    /// * `IOError` if the file cannot be written.
+    pub fn save_to_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, PieceTableError> {
+        let path = path.as_ref();
+        let tracked = match &self.file_modified {
+            Some((tracked_path, time)) if tracked_path == path => {
+                // A file that has been deleted can be saved again safely
+                if modified_time(path).is_some_and(|current| current != *time) {
+                    return Err(PieceTableError::FileChangedOnDisk);
+                }
+                true
+            },
+            Some(_) => false,
+            None => true,
+        };
 
-        if piece.content != PieceBuf::ADDITION { 
-            return Err(PieceTableError::GotBadPieceID);
+        let n_bytes = if self.is_mapped() {
+            self.write_mapped_file(path)?
+        } else {
+            self.write_file(path)?
+        };
+        if tracked {
+            self.file_modified = modified_time(path).map(|time| (path.to_path_buf(), time));
         }
-        if piece.stop != self.addition.len() {
-            return Err(PieceTableError::GotBadPieceRange);
+        Ok(n_bytes)
+    }
+
+    /// Stop checking whether the file the buffer was loaded from or last
+    /// saved to has been modified, so that the next save overwrites it.
+    pub fn forget_file_modified(&mut self) {
+        self.file_modified = None;
+    }
+
+    /// Write the contents of `self` to the file at `path` in place, as 
+    /// `write_file` does, when the original buffer is a mapped file that
+    /// `path` may be. The mapping is first copied into memory and 
+    /// dropped, so that the file is not written over while it is being
+    /// read from and keeps its permissions, owner and links. If the file
+    /// is written as the buffer stores it, it is then mapped again.
+    fn write_mapped_file(&mut self, path: &Path) -> Result<usize, PieceTableError> {
+        let bytes = self.original.bytes().to_vec();
+        self.original = Arc::new(Original::Unchecked(UncheckedBytes::Owned(bytes)));
+        let n_bytes = self.write_file(path)?;
+        if self.writes_as_stored() {
+            self.remap(path)?;
         }
+        Ok(n_bytes)
+    }
 
-        let n_chars = content.chars().count();
+    /// Make the file at `path`, which holds the contents of the buffer 
+    /// as `write_file` wrote them, the original buffer, as a single 
+    /// piece. Edits made before stay in the undo history, as they hold 
+    /// their own text.
+    fn remap(&mut self, path: &Path) -> Result<(), PieceTableError> {
+        let file = File::open(path).map_err(PieceTableError::IOError)?;
+        // Safety: as for `from_file_mmap`.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(PieceTableError::IOError)?;
+        let start = if self.has_bom { BOM.len_utf8() } else { 0 };
+        let len = self.len();
+        if mmap.len() != start + len {
+            // Changed by another program since, so keep the copy
+            return Ok(());
+        }
+        self.original_newlines = newline_offsets(&mmap);
+        self.original = Arc::new(Original::Unchecked(UncheckedBytes::Mapped(mmap)));
+        self.pieces = vec![Piece { start, stop: start + len, content: PieceBuf::ORIGINAL }];
+        self.invalidate_piece_ends(0);
+        self.current_piece_id = 0;
+        self.previous_write = None;
+        Ok(())
+    }
 
-        piece.stop += n_chars;
-        self.addition.push_str(content);
-        #[cfg(debug_assertions)]
-        {
-            assert_eq!(self.addition.len(), piece.stop);
+    /// Whether `write_file` writes the buffer as it is stored, without
+    /// changing its line endings or whitespace.
+    fn writes_as_stored(&self) -> bool {
+        !self.trim_trailing_whitespace 
+            && !self.ensure_final_newline
+            && !self.trim_final_newlines
+            && self.line_ending == LineEnding::LF 
+    }
+
+    /// Write the contents of `self` to the file at `path` as described
+    /// by `save_to_file`.
+    fn write_file(&self, path: &Path) -> Result<usize, PieceTableError> {
+        let mut file = File::create(path).map_err(PieceTableError::IOError)?;
+        let mut n_bytes = 0;
+        if self.has_bom {
+            let bom = BOM.encode_utf8(&mut [0; 4]).to_string();
+            file.write_all(bom.as_bytes()).map_err(PieceTableError::IOError)?;
+            n_bytes += bom.len();
         }
 
-        Ok(n_chars)
-        
+        // Without any changes to make the pieces can be written as they 
+        // are.
+        if self.writes_as_stored() {
+            return Ok(n_bytes + self.write_contents_buffered(file)?);
+        }
+
+        let mut contents = self.write_contents_to_string();
+        if self.trim_trailing_whitespace {
+            contents = contents
+                .split('\n')
+                .map(|line| line.trim_end_matches([' ', '\t']))
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        if self.trim_final_newlines && contents.ends_with('\n') {
+            // Keep the whole of the last line that is not blank
+            let end = match contents.trim_end_matches(['\n', ' ', '\t']).len() {
+                0 => 0,
+                end => end + contents[end..].find('\n').unwrap_or(0),
+            };
+            contents.truncate(end);
+            contents.push('\n');
+        }
+        if self.ensure_final_newline && !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        if self.line_ending != LineEnding::LF {
+            contents = contents.replace('\n', self.line_ending.as_str());
+        }
+        file.write_all(contents.as_bytes()).map_err(PieceTableError::IOError)?;
+        Ok(n_bytes + contents.len())
     }
 
-    /// Split a piece at `piece_loc`, the distance from the start of the 
-    /// piece.
+    pub fn get_pieces(&self) -> &Vec<Piece> {
+        &self.pieces
+    }
+
+    /// The text of each piece in order, along with the buffer it is 
+    /// taken from, without copying any of it. A piece of a mapped file 
+    /// is given in chunks, as `chunks` gives it.
     ///
-    /// # Errors
-    /// Each call to `split_piece` may generate the following errors:
-    /// * `GotBadPieceID` if `piece_id` does not exists.
-    /// * `GotBadPieceRange` if `piece_loc` is outside of the range of 
-    /// the piece given by `piece_id`.
-    fn split_piece(&mut self, piece_id: usize, piece_loc: usize) -> 
-        Result<(), PieceTableError> {
-        let piece = self.pieces
-            .get_mut(piece_id)
-            .ok_or(PieceTableError::GotBadPieceID)?;
+    /// # Panics
+    /// * If a piece references a range outside of its buffer, which 
+    ///   `validate` reports as `GotBadPieceRange`.
+    pub fn spans(&self) -> impl Iterator<Item = (&str, &PieceBuf)> {
+        self.pieces.iter().flat_map(|piece| {
+            self.buf_chunks(&piece.content, piece.start..piece.stop)
+                .map(|text| (text, &piece.content))
+        })
+    }
 
-        let true_loc = piece_loc + piece.start;
+    /// The text of each piece that is not empty, in order, without 
+    /// copying any of it. A piece of a mapped file is given in chunks of
+    /// at most `MAPPED_CHUNK_LEN` bytes, so that only the text that is 
+    /// read is checked to be valid UTF-8.
+    ///
+    /// # Panics
+    /// * As for `spans`.
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        self.chunks_in(0..self.len()).map(|(_, chunk)| chunk)
+    }
 
-        if !(true_loc >= piece.start || true_loc <= piece.stop) {
-            return Err(PieceTableError::GotBadPieceRange);
-        }
+    /// The chunks of the text between byte offsets `range.start` and 
+    /// `range.end`, as `chunks` gives them, with the offsets they start
+    /// at.
+    ///
+    /// # Panics
+    /// * If either end of `range` falls within a character, or as for 
+    ///   `spans`.
+    fn chunks_in(&self, range: Range<usize>) -> Chunks<'_> {
+        Chunks::new(self, range)
+    }
 
-        let new_piece_stop = piece.stop;
-        piece.stop = true_loc;
-        let new_piece = Piece { 
-            start: true_loc, 
-            stop: new_piece_stop, 
-            content: piece.content.clone()
-        };
-        self.pieces.insert(piece_id + 1, new_piece);
+    /// The characters of the buffer, in order.
+    ///
+    /// # Panics
+    /// * As for `spans`.
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.chunks().flat_map(str::chars)
+    }
 
-        Ok(())
+    /// The characters of the buffer from byte offset `offset` onwards,
+    /// with their byte offsets, as `str::char_indices` gives them.
+    ///
+    /// # Panics
+    /// * If `offset` falls within a character, or as for `spans`.
+    pub fn char_indices_from(&self, offset: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.chunks_in(offset..self.len()).flat_map(|(chunk_loc, chunk)| {
+            chunk.char_indices().map(move |(i, c)| (chunk_loc + i, c))
+        })
     }
 
-    /// Write contents of `self` to `stream` in correct order 
+    /// The characters of the buffer before byte offset `offset`, with 
+    /// their byte offsets, from the one just before `offset` back to the
+    /// start of the buffer.
     ///
-    /// # Errors
-    /// Each call to `write_contents_to_stream` may generate the following 
-    /// PieceTableError errors:
-    /// * `GotBadPieceID` if a piece trys to reference a non-existant 
-    /// piece number.
-    /// * `IOError` wrapping any errors from calling `write` on `stream`.
-    pub fn write_contents_to_stream<T: Write>(&self, stream: &mut T) -> 
-        Result<usize, PieceTableError> {
-        let mut n_bytes = 0;
+    /// # Panics
+    /// * As for `char_indices_from`.
+    pub fn char_indices_before(&self, offset: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.chunks_in(0..offset).rev().flat_map(|(chunk_loc, chunk)| {
+            chunk.char_indices().rev().map(move |(i, c)| (chunk_loc + i, c))
+        })
+    }
+
+    /// Return the text between byte offsets `range.start` and 
+    /// `range.end`, borrowed from the buffer unless it spans several 
+    /// chunks, or `None` if the range extends past the end of the buffer
+    /// or either end falls within a character.
+    pub fn get_text_range(&self, range: Range<usize>) -> Option<Cow<'_, str>> {
+        if range.start > range.end {
+            return None;
+        }
+        self.byte_offset(Position::Byte(range.start)).ok()?;
+        self.byte_offset(Position::Byte(range.end)).ok()?;
+        let mut text = Cow::Borrowed("");
+        for (_, chunk) in self.chunks_in(range) {
+            if text.is_empty() {
+                text = Cow::Borrowed(chunk);
+            } else {
+                text.to_mut().push_str(chunk);
+            }
+        }
+        Some(text)
+    }
 
+    /// Return the bytes between byte offsets `range.start` and 
+    /// `range.end` as they are stored, even if they are not valid UTF-8,
+    /// or `None` if the range extends past the end of the buffer.
+    pub fn get_bytes_range(&self, range: Range<usize>) -> Option<Vec<u8>> {
+        if range.start > range.end || range.end > self.len() {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(range.len());
+        let mut piece_loc = 0;
         for piece in &self.pieces {
-            let buf = match &piece.content {
-                PieceBuf::ORIGINAL => &self.original,
-                PieceBuf::ADDITION => &self.addition,
-            };
-            let contents = buf
-                .get(piece.start..piece.stop)
-                .ok_or(PieceTableError::GotBadPieceRange)?;
-            n_bytes += stream.write(contents.as_bytes())
-                .or_else(|err| Err(PieceTableError::IOError(err)))?;
+            let piece_stop_loc = piece_loc + piece.len();
+            let start = range.start.clamp(piece_loc, piece_stop_loc);
+            let stop = range.end.clamp(start, piece_stop_loc);
+            let piece_range = piece.start + start - piece_loc..piece.start + stop - piece_loc;
+            bytes.extend_from_slice(self.bytes(&piece.content, piece_range));
+            if piece_stop_loc >= range.end {
+                break;
+            }
+            piece_loc = piece_stop_loc;
         }
+        Some(bytes)
+    }
 
-        Ok(n_bytes)
+    /// The text of line `line`, counting from 0, without its `\n`, or 
+    /// `None` if the buffer does not have that many lines.
+    ///
+    /// # Panics
+    /// * As for `spans`.
+    pub fn line(&self, line: usize) -> Option<Cow<'_, str>> {
+        self.lines_from(line)?.next()
     }
 
-    /// Write contents of `self` to `String` in correct order.
-    pub fn write_contents_to_string(&self) -> String {
-        let mut writer = string_writer::StringWriter::new();
-        // NOTE: Need to hangle unwrap in a more suitable fasion
-        self.write_contents_to_stream(&mut writer).unwrap();
-        writer.contents
+    /// The lines of the buffer without their `\n`s, as `str::split` 
+    /// would give them. Lines are borrowed from the buffer unless they
+    /// span several pieces.
+    ///
+    /// # Panics
+    /// * As for `spans`.
+    pub fn lines(&self) -> Lines<'_> {
+        Lines::new(self, 0)
     }
-}    
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The lines of the buffer as `lines` gives them, starting from line
+    /// `line`, counting from 0, or `None` if the buffer does not have 
+    /// that many lines. Lines before `line` are not read.
+    ///
+    /// # Panics
+    /// * As for `spans`.
+    pub fn lines_from(&self, line: usize) -> Option<Lines<'_>> {
+        self.line_to_offset(line).map(|offset| Lines::new(self, offset))
+    }
 
-    #[test]
-    fn piece_table_split_piece() {
-        let mut piece_table = PieceTable::from_str("hello world!");
-        piece_table.split_piece(0, 5).unwrap();
+    /// Where the last write ended, if the buffer has not been split or
+    /// deleted from since.
+    pub fn previous_write(&self) -> Option<WriteLocation> {
+        self.previous_write
+    }
+
+    /// Length of the contents of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(Piece::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Length of the contents of the buffer in characters, which is less
+    /// than `len` when it holds multibyte characters.
+    pub fn char_len(&self) -> usize {
+        self.chunks().map(|chunk| chunk.chars().count()).sum()
+    }
+
+    /// The byte offset of `position`.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` if `position` is past the end of the buffer or is a
+    ///   byte offset that falls within a character.
+    pub fn byte_offset(&self, position: Position) -> Result<usize, PieceTableError> {
+        let mut piece_start_loc = 0;
+        match position {
+            Position::Byte(offset) => {
+                for piece in &self.pieces {
+                    let piece_stop_loc = piece_start_loc + piece.len();
+                    if offset < piece_stop_loc {
+                        let loc = piece.start + offset - piece_start_loc;
+                        return self.is_char_boundary(&piece.content, loc)
+                            .then_some(offset)
+                            .ok_or(PieceTableError::GotBadLoc);
+                    }
+                    piece_start_loc = piece_stop_loc;
+                }
+                (offset == piece_start_loc).then_some(offset).ok_or(PieceTableError::GotBadLoc)
+            },
+            Position::Char(mut n_chars) => {
+                for (chunk_loc, chunk) in self.chunks_in(0..self.len()) {
+                    if let Some((i, _)) = chunk.char_indices().nth(n_chars) {
+                        return Ok(chunk_loc + i);
+                    }
+                    n_chars -= chunk.chars().count();
+                }
+                (n_chars == 0).then_some(self.len()).ok_or(PieceTableError::GotBadLoc)
+            },
+        }
+    }
+
+    /// The number of characters before `position`.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` as for `byte_offset`.
+    pub fn char_offset(&self, position: Position) -> Result<usize, PieceTableError> {
+        let offset = match position {
+            Position::Byte(offset) => offset,
+            Position::Char(n_chars) => {
+                self.byte_offset(position)?;
+                return Ok(n_chars);
+            },
+        };
+        self.byte_offset(position)?;
+        Ok(self.chunks_in(0..offset).map(|(_, chunk)| chunk.chars().count()).sum())
+    }
+
+    /// Insert `content` at `position` as `write_to_loc` does, and return
+    /// the position of the end of `content`, in the same units as 
+    /// `position`.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` as for `byte_offset`.
+    pub fn write_to_position(&mut self, position: Position, content: &str) -> 
+        Result<Position, PieceTableError> 
+    {
+        let loc = self.byte_offset(position)?;
+        let n_bytes = self.write_to_loc(loc, content)?;
+        Ok(match position {
+            Position::Byte(offset) => Position::Byte(offset + n_bytes),
+            Position::Char(n_chars) => Position::Char(n_chars + content.chars().count()),
+        })
+    }
+
+    /// Remove the contents of the buffer between `start` and `stop` as
+    /// `delete` does.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` as for `byte_offset`, or if `start` is after `stop`.
+    pub fn delete_between(&mut self, start: Position, stop: Position) -> 
+        Result<(), PieceTableError> 
+    {
+        let start = self.byte_offset(start)?;
+        let stop = self.byte_offset(stop)?;
+        self.delete(start, stop)
+    }
+
+    /// Length of the backing store that pieces of kind `content` point
+    /// into.
+    fn buf_len(&self, content: &PieceBuf) -> usize {
+        match content {
+            PieceBuf::ORIGINAL => self.original.len(),
+            PieceBuf::ADDITION => self.addition.len(),
+        }
+    }
+
+    /// Whether byte `i` of the backing store that pieces of kind 
+    /// `content` point into starts a character or is its end.
+    fn is_char_boundary(&self, content: &PieceBuf, i: usize) -> bool {
+        match content {
+            PieceBuf::ORIGINAL => self.original.is_char_boundary(i),
+            PieceBuf::ADDITION => self.addition.is_char_boundary(i),
+        }
+    }
+
+    /// The text of the bytes `range` of the backing store that pieces 
+    /// of kind `content` point into, as `Original::text` gives it.
+    ///
+    /// # Panics
+    /// * As for `Original::text`.
+    fn text(&self, content: &PieceBuf, range: Range<usize>) -> &str {
+        match content {
+            PieceBuf::ORIGINAL => self.original.text(range),
+            PieceBuf::ADDITION => self.addition.text(range),
+        }
+    }
+
+    /// The bytes `range` of the backing store that pieces of kind 
+    /// `content` point into, as they are stored.
+    ///
+    /// # Panics
+    /// * If `range` is outside of the backing store.
+    fn bytes(&self, content: &PieceBuf, range: Range<usize>) -> &[u8] {
+        match content {
+            PieceBuf::ORIGINAL => &self.original.bytes()[range],
+            PieceBuf::ADDITION => self.addition.text(range).as_bytes(),
+        }
+    }
+
+    /// Where the first chunk of the bytes `range` of the backing store 
+    /// of `content` ends, as `chunks` splits them: at the end of the 
+    /// range, unless it is more than `MAPPED_CHUNK_LEN` bytes that are 
+    /// checked as they are read, such as those of a mapped file.
+    fn chunk_stop(&self, content: &PieceBuf, range: Range<usize>) -> usize {
+        match content {
+            PieceBuf::ORIGINAL => self.original.chunk_stop(range),
+            PieceBuf::ADDITION => range.end,
+        }
+    }
+
+    /// Where the last chunk of the bytes `range` of the backing store of
+    /// `content` starts, splitting them as `chunk_stop` does.
+    fn chunk_start(&self, content: &PieceBuf, range: Range<usize>) -> usize {
+        match content {
+            PieceBuf::ORIGINAL => self.original.chunk_start(range),
+            PieceBuf::ADDITION => range.start,
+        }
+    }
+
+    /// The text of the bytes `range` of the backing store of `content` 
+    /// in chunks, as `chunk_stop` splits them.
+    ///
+    /// # Panics
+    /// * As for `text`.
+    fn buf_chunks(&self, content: &PieceBuf, range: Range<usize>) -> impl Iterator<Item = &str> {
+        buf_chunks(&self.original, &self.addition, content, range)
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    pub fn has_bom(&self) -> bool {
+        self.has_bom
+    }
+
+    pub fn set_has_bom(&mut self, has_bom: bool) {
+        self.has_bom = has_bom;
+    }
+
+    /// The contents as the bytes of the file they are loaded from and 
+    /// saved to, with its byte order mark and line endings. Trailing 
+    /// whitespace and final newlines are left as they are rather than 
+    /// changed as saving can change them.
+    pub fn to_stored_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        if self.has_bom {
+            bytes.extend_from_slice(BOM.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        for piece in &self.pieces {
+            let piece_bytes = self.bytes(&piece.content, piece.start..piece.stop);
+            if self.line_ending == LineEnding::LF {
+                bytes.extend_from_slice(piece_bytes);
+                continue;
+            }
+            for (i, line) in piece_bytes.split(|&byte| byte == b'\n').enumerate() {
+                if i > 0 {
+                    bytes.extend_from_slice(self.line_ending.as_str().as_bytes());
+                }
+                bytes.extend_from_slice(line);
+            }
+        }
+        bytes
+    }
+
+    /// Offset in `to_stored_bytes` of the byte at `offset` in the 
+    /// contents.
+    pub fn stored_offset(&self, offset: usize) -> usize {
+        let bom_len = if self.has_bom { BOM.len_utf8() } else { 0 };
+        let newlines = match self.line_ending {
+            LineEnding::LF => 0,
+            LineEnding::CRLF => self.offset_to_line(offset).unwrap_or(self.line_count() - 1),
+        };
+        bom_len + offset + newlines
+    }
+
+    /// Offset in the contents of the byte at `stored` in 
+    /// `to_stored_bytes`, taking a byte of the byte order mark to be the
+    /// start of the contents and a byte of a line ending to be its `\n`.
+    pub fn offset_from_stored(&self, stored: usize) -> usize {
+        let bom_len = if self.has_bom { BOM.len_utf8() } else { 0 };
+        let stored = stored.saturating_sub(bom_len);
+        if self.line_ending == LineEnding::LF {
+            return stored.min(self.len());
+        }
+        // The last line that starts at or before `stored`, each line 
+        // before it taking one more byte to store than it does in the
+        // contents
+        let (mut low, mut high) = (0, self.line_count());
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            match self.line_to_offset(mid) {
+                Some(start) if start + mid <= stored => low = mid,
+                _ => high = mid,
+            }
+        }
+        let line_start = self.line_to_offset(low).unwrap_or(0);
+        let line_end = self.line_to_offset(low + 1).map_or(self.len(), |start| start - 1);
+        (stored - low).clamp(line_start, line_end)
+    }
+
+    pub fn trim_trailing_whitespace(&self) -> bool {
+        self.trim_trailing_whitespace
+    }
+
+    pub fn set_trim_trailing_whitespace(&mut self, trim_trailing_whitespace: bool) {
+        self.trim_trailing_whitespace = trim_trailing_whitespace;
+    }
+
+    pub fn ensure_final_newline(&self) -> bool {
+        self.ensure_final_newline
+    }
+
+    pub fn set_ensure_final_newline(&mut self, ensure_final_newline: bool) {
+        self.ensure_final_newline = ensure_final_newline;
+    }
+
+    pub fn trim_final_newlines(&self) -> bool {
+        self.trim_final_newlines
+    }
+
+    pub fn set_trim_final_newlines(&mut self, trim_final_newlines: bool) {
+        self.trim_final_newlines = trim_final_newlines;
+    }
+
+    /// Insert `content` at `loc` in buffer and return the number of 
+    /// bytes written, so that `loc` plus the result is the offset of the
+    /// end of `content`. If `loc` is where the previous write ended
+    /// then `content` is appended to the piece of the previous write 
+    /// rather than creating a new piece.
+    ///
+    /// # Errors
+    /// * `PieceTableError::GotBadLoc` if loc does not exists in the 
+    ///   current buffer or falls within a character.
+    pub fn write_to_loc(&mut self, loc: usize, content: &str) ->
+        Result<usize, PieceTableError> {
+        let n_bytes = self.insert_text(loc, content)?;
+        self.record_edit(Edit::Insert { loc, text: content.to_string() });
+        Ok(n_bytes)
+    }
+
+    /// Behaves as `write_to_loc` without recording the insertion to be
+    /// undone.
+    fn insert_text(&mut self, loc: usize, content: &str) -> Result<usize, PieceTableError> {
+        if let Some(n_bytes) = self.append_to_previous_write(loc, content) {
+            #[cfg(debug_assertions)]
+            self.validate()?;
+            return Ok(n_bytes);
+        }
+
+        let (piece_id, piece_start_loc) = self.get_piece_from_position(loc)
+            .ok_or(PieceTableError::GotBadLoc)?;
+        let piece = &self.pieces[piece_id];
+
+        if !self.is_char_boundary(&piece.content, piece.start + loc - piece_start_loc) {
+            return Err(PieceTableError::GotBadLoc);
+        }
+
+        // If loc is in the middle of a piece then split piece before
+        // inputting.
+        if loc != piece_start_loc + piece.len() {
+            let piece_loc = loc - piece_start_loc;
+            self.split_piece(piece_id, piece_loc)
+                .map_err(|_| PieceTableError::GotBadLoc)?;  
+        }       
+        
+        let start = push_addition(&mut self.addition, &mut self.addition_newlines, content);
+        let stop = self.addition.len();
+        let n_bytes = stop - start;
+
+        let new_piece = Piece { start, stop, content: PieceBuf::ADDITION };
+        let new_piece_id = piece_id + 1;
+        self.pieces.insert(new_piece_id, new_piece);
+        self.invalidate_piece_ends(new_piece_id);
+        self.current_piece_id = new_piece_id;
+        self.previous_write = Some(WriteLocation { piece_id: new_piece_id, loc: loc + n_bytes });
+        // The new piece, and the halves of the piece split around it
+        self.prune_pieces(piece_id..new_piece_id + 2);
+
+        #[cfg(debug_assertions)]
+        self.validate()?;
+
+        Ok(n_bytes)
+    }
+
+    /// Insert `c` at `loc` and return its length in bytes, which is how 
+    /// far a cursor at `loc` should move to be after it.
+    ///
+    /// # Errors
+    /// * `PieceTableError::GotBadLoc` if loc does not exists in the 
+    ///   current buffer or falls within a character.
+    pub fn insert_char(&mut self, loc: usize, c: char) -> Result<usize, PieceTableError> {
+        self.write_to_loc(loc, c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Append `content` to the piece of the previous write if `loc` is
+    /// where it ended and the piece can still be extended at the end of
+    /// `self.addition`, returning the number of bytes written.
+    fn append_to_previous_write(&mut self, loc: usize, content: &str) -> Option<usize> {
+        let previous_write = self.previous_write.as_mut().filter(|write| write.loc == loc)?;
+        let piece = self.pieces
+            .get_mut(previous_write.piece_id)
+            .filter(|piece| {
+                piece.content == PieceBuf::ADDITION && self.addition.extends(piece.stop)
+            })?;
+
+        push_addition(&mut self.addition, &mut self.addition_newlines, content);
+        piece.stop += content.len();
+        previous_write.loc += content.len();
+        let piece_id = previous_write.piece_id;
+        self.invalidate_piece_ends(piece_id);
+        Some(content.len())
+    }
+
+    /// Return the index of the first piece that ends at or after `loc`
+    /// and the offset that it starts at, or `None` if `loc` is past the
+    /// end of the buffer.
+    fn get_piece_from_position(&mut self, loc: usize) -> Option<(usize, usize)> {
+        self.update_piece_ends();
+        let piece_id = self.piece_ends.partition_point(|&end| end < loc);
+        if piece_id == self.pieces.len() {
+            return None;
+        }
+        let piece_start_loc = piece_id.checked_sub(1).map_or(0, |id| self.piece_ends[id]);
+        Some((piece_id, piece_start_loc))
+    }
+
+    /// Recompute the ends of the pieces from the first one that has
+    /// changed.
+    fn update_piece_ends(&mut self) {
+        self.piece_ends.truncate(self.valid_piece_ends);
+        let mut end = self.piece_ends.last().copied().unwrap_or(0);
+        for piece in &self.pieces[self.valid_piece_ends..] {
+            end += piece.len();
+            self.piece_ends.push(end);
+        }
+        self.valid_piece_ends = self.pieces.len();
+    }
+
+    /// Mark the ends of the pieces from `piece_id` onwards as needing to
+    /// be recomputed.
+    fn invalidate_piece_ends(&mut self, piece_id: usize) {
+        self.valid_piece_ends = self.valid_piece_ends.min(piece_id);
+    }
+
+    
+    /// Append `content` to the last piece that was written to and 
+    /// returns the number of bytes that were written. The last 
+    /// piece written to is usually set by the last call to 
+    /// `self.write_to_loc`.
+    ///
+    /// # Errors
+    /// * If the last piece written to does not point to the end of 
+    ///   `self.addition` then returns `PieceTableError::GotBadPieceID`
+    /// * If the last range of the last piece pointed to does not
+    ///   contain the final charcter in `self.addition` the returns
+    ///   `PieceTableError::GotBadPieceRange`.
+    pub fn write_to_current_piece(&mut self, content: &str) -> 
+        Result<usize, PieceTableError> 
+    {
+        self.update_piece_ends();
+        let loc = self.piece_ends
+            .get(self.current_piece_id)
+            .copied()
+            .ok_or(PieceTableError::GotBadPieceID)?;
+        let piece = self.pieces
+            .get_mut(self.current_piece_id)
+            .ok_or(PieceTableError::GotBadPieceID)?;
+
+        if piece.content != PieceBuf::ADDITION { 
+            return Err(PieceTableError::GotBadPieceID);
+        }
+        if !self.addition.extends(piece.stop) {
+            return Err(PieceTableError::GotBadPieceRange);
+        }
+
+        let n_bytes = content.len();
+
+        piece.stop += n_bytes;
+        push_addition(&mut self.addition, &mut self.addition_newlines, content);
+        if let Some(previous_write) = self.previous_write
+            .as_mut()
+            .filter(|write| write.piece_id == self.current_piece_id) 
+        {
+            previous_write.loc += n_bytes;
+        }
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(self.addition.len(), piece.stop);
+            self.validate()?;
+        }
+        self.invalidate_piece_ends(self.current_piece_id);
+        self.record_edit(Edit::Insert { loc, text: content.to_string() });
+
+        Ok(n_bytes)
+        
+    }
+
+    /// Remove the contents of the buffer in the range `start..stop`.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` if `start` is after `stop`, `stop` is past the end
+    ///   of the buffer, or either falls within a character.
+    pub fn delete(&mut self, start: usize, stop: usize) -> Result<(), PieceTableError> {
+        let text = self.remove_text(start, stop)?;
+        self.record_edit(Edit::Delete { loc: start, text });
+        Ok(())
+    }
+
+    /// Behaves as `delete` without recording the deletion to be undone,
+    /// returning the text that was deleted.
+    fn remove_text(&mut self, start: usize, stop: usize) -> Result<String, PieceTableError> {
+        if start > stop || stop > self.len() {
+            return Err(PieceTableError::GotBadLoc);
+        }
+        if start == stop {
+            return Ok(String::new());
+        }
+
+        let mut pieces = Vec::with_capacity(self.pieces.len() + 1);
+        let mut current_piece_id = None;
+        let mut piece_start_loc = 0;
+        let mut removed = String::new();
+        // Ids of the pieces left by those that the deletion touched
+        let mut touched = None;
+
+        for (id, piece) in self.pieces.iter().enumerate() {
+            let piece_stop_loc = piece_start_loc + piece.len();
+            let touches = piece_stop_loc >= start && piece_start_loc <= stop;
+            if touches && touched.is_none() {
+                touched = Some(pieces.len()..pieces.len());
+            }
+
+            // Take the part of the piece within `start..stop`
+            let removed_start = start.clamp(piece_start_loc, piece_stop_loc) - piece_start_loc;
+            let removed_stop = stop.clamp(piece_start_loc, piece_stop_loc) - piece_start_loc;
+            let removed_range = piece.start + removed_start..piece.start + removed_stop;
+            if !self.is_char_boundary(&piece.content, removed_range.start) 
+                || !self.is_char_boundary(&piece.content, removed_range.end) 
+            {
+                return Err(PieceTableError::GotBadLoc);
+            }
+            removed.extend(self.buf_chunks(&piece.content, removed_range));
+
+            // Keep the part of the piece before `start`
+            if piece_start_loc < start {
+                let kept_len = start.min(piece_stop_loc) - piece_start_loc;
+                pieces.push(Piece {
+                    start: piece.start,
+                    stop: piece.start + kept_len,
+                    content: piece.content.clone(),
+                });
+            }
+
+            // Keep the part of the piece after `stop`
+            if piece_stop_loc > stop {
+                let removed_len = stop.max(piece_start_loc) - piece_start_loc;
+                pieces.push(Piece {
+                    start: piece.start + removed_len,
+                    stop: piece.stop,
+                    content: piece.content.clone(),
+                });
+            }
+
+            // The current piece can only still be appended to if its end
+            // was not deleted.
+            if id == self.current_piece_id && (piece_stop_loc <= start || piece_stop_loc > stop) {
+                current_piece_id = pieces.len().checked_sub(1);
+            }
+            if let Some(touched) = touched.as_mut().filter(|_| touches) {
+                touched.end = pieces.len();
+            }
+
+            piece_start_loc = piece_stop_loc;
+        }
+
+        self.pieces = pieces;
+        self.invalidate_piece_ends(0);
+        self.current_piece_id = current_piece_id.unwrap_or(self.pieces.len());
+        self.previous_write = None;
+        self.prune_pieces(touched.unwrap_or_default());
+
+        #[cfg(debug_assertions)]
+        self.validate()?;
+
+        Ok(removed)
+    }
+
+    /// Record `edit` so that it can be undone, as `record_edits` does.
+    fn record_edit(&mut self, edit: Edit) {
+        self.record_edits(vec![edit]);
+    }
+
+    /// Record `edits` as a group that is undone in one step, forgetting
+    /// any groups that were undone as they can no longer be redone. A 
+    /// character typed straight after the end of the last insertion, 
+    /// when that was also a single character, joins the same group, so
+    /// that typing a word is undone in one step.
+    fn record_edits(&mut self, mut edits: Vec<Edit>) {
+        edits.retain(|edit| {
+            let (Edit::Insert { text, .. } | Edit::Delete { text, .. }) = edit;
+            !text.is_empty()
+        });
+        if edits.is_empty() {
+            return;
+        }
+        self.redo_stack.clear();
+        if self.open_groups > 0 {
+            self.open_group.append(&mut edits);
+            self.last_typed = None;
+            return;
+        }
+
+        let now = (self.clock)();
+        let is_typed = matches!(
+            edits.as_slice(), 
+            [Edit::Insert { text, .. }] if text.chars().count() == 1
+        );
+        let last_insert = self.undo_stack
+            .last_mut()
+            .and_then(|group| group.last_mut())
+            .filter(|_| {
+                self.last_typed.is_some_and(|time| now.duration_since(time) < UNDO_GROUP_TIMEOUT)
+            });
+        let joined = match (last_insert, edits.as_slice()) {
+            (
+                Some(Edit::Insert { loc, text }), 
+                [Edit::Insert { loc: next_loc, text: next }]
+            ) if is_typed && *next_loc == *loc + text.len() => {
+                text.push_str(next);
+                true
+            },
+            _ => false,
+        };
+        if !joined {
+            self.undo_stack.push(edits);
+        }
+        self.last_typed = is_typed.then_some(now);
+    }
+
+    /// Start a new undo group with the next edit, so that a character 
+    /// typed next does not join the group of the last one.
+    pub fn push_undo_boundary(&mut self) {
+        self.last_typed = None;
+    }
+
+    /// Record the edits made until the matching `end_undo_group` as a 
+    /// single group that is undone in one step. Groups can be nested, in
+    /// which case the edits are recorded when the outermost one ends.
+    pub fn begin_undo_group(&mut self) {
+        self.open_groups += 1;
+    }
+
+    /// End the group started by the last `begin_undo_group`, recording 
+    /// its edits if it was the outermost one.
+    pub fn end_undo_group(&mut self) {
+        self.open_groups = self.open_groups.saturating_sub(1);
+        if self.open_groups == 0 && !self.open_group.is_empty() {
+            self.undo_stack.push(std::mem::take(&mut self.open_group));
+            self.last_typed = None;
+        }
+    }
+
+    /// Use `clock` for the time that characters are typed at in place of
+    /// `Instant::now`.
+    pub fn set_clock(&mut self, clock: fn() -> Instant) {
+        self.clock = clock;
+    }
+
+    /// Undo the most recent group of edits that has not been undone and
+    /// return the edits made to revert it, in the order they were made,
+    /// or nothing if there is no group to undo.
+    ///
+    /// # Errors
+    /// * Any error from reverting the edits, which can only fail if the
+    ///   buffer was changed in a way that was not recorded.
+    pub fn undo(&mut self) -> Result<Vec<Edit>, PieceTableError> {
+        let Some(group) = self.undo_stack.pop() else {
+            return Ok(Vec::new());
+        };
+        let reverted: Vec<Edit> = group.iter().rev().map(Edit::inverse).collect();
+        for edit in &reverted {
+            self.apply_edit(edit)?;
+        }
+        self.redo_stack.push(group);
+        self.last_typed = None;
+        Ok(reverted)
+    }
+
+    /// Redo the most recently undone group of edits and return them, or
+    /// nothing if there is no group to redo.
+    ///
+    /// # Errors
+    /// * Any error from making the edits, as for `undo`.
+    pub fn redo(&mut self) -> Result<Vec<Edit>, PieceTableError> {
+        let Some(group) = self.redo_stack.pop() else {
+            return Ok(Vec::new());
+        };
+        for edit in &group {
+            self.apply_edit(edit)?;
+        }
+        self.undo_stack.push(group.clone());
+        self.last_typed = None;
+        Ok(group)
+    }
+
+    /// Make `edit` without recording it.
+    fn apply_edit(&mut self, edit: &Edit) -> Result<(), PieceTableError> {
+        match edit {
+            Edit::Insert { loc, text } => self.insert_text(*loc, text).map(drop),
+            Edit::Delete { loc, text } => self.remove_text(*loc, loc + text.len()).map(drop),
+        }
+    }
+
+    /// Remove pieces that hold no text and merge neighbouring pieces 
+    /// that are contiguous in the same buffer, keeping the current piece
+    /// and the previous write pointing at the same text. An empty buffer
+    /// keeps a single empty piece so that there is a piece to write to.
+    pub fn prune_empty_pieces(&mut self) {
+        self.prune_pieces(0..self.pieces.len());
+    }
+
+    /// Behaves as `prune_empty_pieces` for only the pieces with ids in 
+    /// `ids`, which an edit has just made, and the pieces on either side
+    /// of them that they may be merged into.
+    fn prune_pieces(&mut self, ids: Range<usize>) {
+        let n_pieces = self.pieces.len();
+        let start = ids.start.saturating_sub(1);
+        let end = (ids.end + 1).min(n_pieces);
+        let mut pieces: Vec<Piece> = Vec::with_capacity(end - start);
+        let mut current_piece_id = None;
+        let mut previous_write_id = None;
+
+        let pruned: Vec<Piece> = self.pieces.drain(start..end).collect();
+        for (id, piece) in (start..end).zip(pruned) {
+            let is_empty = piece.is_empty();
+            match pieces.last_mut() {
+                _ if is_empty => (),
+                Some(last) if self.joins(last, &piece) => last.stop = piece.stop,
+                _ => pieces.push(piece),
+            }
+
+            let new_id = pieces.len().checked_sub(1).filter(|_| !is_empty).map(|i| start + i);
+            if id == self.current_piece_id {
+                current_piece_id = new_id;
+            }
+            if self.previous_write.is_some_and(|write| write.piece_id == id) {
+                previous_write_id = new_id;
+            }
+        }
+
+        if pieces.is_empty() && start == 0 && end == n_pieces {
+            pieces.push(Piece { start: 0, stop: 0, content: PieceBuf::ORIGINAL });
+        }
+
+        // Pieces after the range move back by the number removed from it
+        let n_kept = pieces.len();
+        self.pieces.splice(start..start, pieces);
+        self.invalidate_piece_ends(start);
+        let moved_id = |id: usize| match id {
+            id if id < start => Some(id),
+            id if id < end => None,
+            id => Some(id + n_kept - (end - start)),
+        };
+        self.current_piece_id = moved_id(self.current_piece_id)
+            .or(current_piece_id)
+            .unwrap_or(self.pieces.len());
+        self.previous_write = self.previous_write.and_then(|write| {
+            let piece_id = moved_id(write.piece_id).or(previous_write_id)?;
+            Some(WriteLocation { piece_id, ..write })
+        });
+    }
+
+    /// Whether `next` starts where `piece` ends in the same backing 
+    /// store, so that the two can be merged into one piece.
+    fn joins(&self, piece: &Piece, next: &Piece) -> bool {
+        piece.content == next.content 
+            && piece.stop == next.start 
+            && !(next.content == PieceBuf::ADDITION && self.addition.starts_block(next.start))
+    }
+
+    /// Delete the character starting at `offset` and return its length
+    /// in bytes, or 0 if `offset` is the end of the buffer.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` if `offset` is past the end of the buffer or falls
+    ///   within a character.
+    pub fn delete_char_at(&mut self, offset: usize) -> Result<usize, PieceTableError> {
+        self.byte_offset(Position::Byte(offset))?;
+        let Some((_, c)) = self.char_indices_from(offset).next() else {
+            return Ok(0);
+        };
+        let n_bytes = c.len_utf8();
+        self.delete(offset, offset + n_bytes)?;
+        Ok(n_bytes)
+    }
+
+    /// Replace the contents of the buffer in the range `start..stop` 
+    /// with `content` and return the text that was replaced. The 
+    /// replacement is a single edit that is undone in one step, and the
+    /// buffer is left unchanged if it fails.
+    ///
+    /// # Errors
+    /// * `GotBadLoc` if `start` is after `stop`, `stop` is past the end
+    ///   of the buffer, or either falls within a character.
+    pub fn replace_range(&mut self, start: usize, stop: usize, content: &str) -> 
+        Result<String, PieceTableError> 
+    {
+        let replaced = self.remove_text(start, stop)?;
+        if !content.is_empty() {
+            if let Err(err) = self.insert_text(start, content) {
+                self.insert_text(start, &replaced)?;
+                return Err(err);
+            }
+        }
+        self.record_edits(vec![
+            Edit::Delete { loc: start, text: replaced.clone() },
+            Edit::Insert { loc: start, text: content.to_string() },
+        ]);
+        Ok(replaced)
+    }
+
+    /// Split a piece at `piece_loc`, the distance from the start of the 
+    /// piece.
+    ///
+    /// # Errors
+    /// Each call to `split_piece` may generate the following errors:
+    /// * `GotBadPieceID` if `piece_id` does not exists.
+    /// * `GotBadPieceRange` if `piece_loc` is outside of the range of 
+    ///   the piece given by `piece_id`.
+    /// * `GotBadLoc` if `piece_loc` falls within a character.
+    fn split_piece(&mut self, piece_id: usize, piece_loc: usize) -> 
+        Result<(), PieceTableError> {
+        let piece = self.pieces
+            .get(piece_id)
+            .ok_or(PieceTableError::GotBadPieceID)?;
+
+        if piece_loc > piece.len() {
+            return Err(PieceTableError::GotBadPieceRange);
+        }
+        let true_loc = piece_loc + piece.start;
+        if !self.is_char_boundary(&piece.content, true_loc) {
+            return Err(PieceTableError::GotBadLoc);
+        }
+
+        let piece = &mut self.pieces[piece_id];
+
+        let new_piece_stop = piece.stop;
+        piece.stop = true_loc;
+        let new_piece = Piece { 
+            start: true_loc, 
+            stop: new_piece_stop, 
+            content: piece.content.clone()
+        };
+        self.pieces.insert(piece_id + 1, new_piece);
+        self.invalidate_piece_ends(piece_id);
+        self.previous_write = None;
+
+        #[cfg(debug_assertions)]
+        self.validate()?;
+
+        Ok(())
+    }
+
+    /// Check that every piece refers to a range that lies within its 
+    /// buffer. Only available in debug builds, where it is run after 
+    /// every change to the pieces.
+    ///
+    /// # Errors
+    /// * `GotBadPieceRange` if a piece ends before it starts or extends
+    ///   past the end of its buffer.
+    #[cfg(debug_assertions)]
+    pub fn validate(&self) -> Result<(), PieceTableError> {
+        for piece in &self.pieces {
+            if piece.start > piece.stop || piece.stop > self.buf_len(&piece.content) {
+                return Err(PieceTableError::GotBadPieceRange);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write contents of `self` to `stream` in correct order 
+    ///
+    /// # Errors
+    /// Each call to `write_contents_to_stream` may generate the following 
+    /// PieceTableError errors:
+    /// * `GotBadPieceID` if a piece trys to reference a non-existant 
+    ///   piece number.
+    /// * `IOError` wrapping any errors from calling `write_all` on 
+    ///   `stream`.
+    pub fn write_contents_to_stream<T: Write>(&self, stream: &mut T) -> 
+        Result<usize, PieceTableError> {
+        let mut n_bytes = 0;
+
+        for piece in self.pieces.iter().filter(|piece| !piece.is_empty()) {
+            if piece.stop > self.buf_len(&piece.content) 
+                || !self.is_char_boundary(&piece.content, piece.start) 
+                || !self.is_char_boundary(&piece.content, piece.stop) 
+            {
+                return Err(PieceTableError::GotBadPieceRange);
+            }
+            for contents in self.buf_chunks(&piece.content, piece.start..piece.stop) {
+                stream.write_all(contents.as_bytes())
+                    .map_err(PieceTableError::IOError)?;
+                n_bytes += contents.len();
+            }
+        }
+
+        Ok(n_bytes)
+    }
+
+    /// Write contents of `self` to `stream` through a `BufWriter`, so 
+    /// that a buffer of many small pieces is written in few calls to 
+    /// `stream`.
+    ///
+    /// # Errors
+    /// * `GotBadPieceRange` if a piece references a range outside of 
+    ///   its buffer.
+    /// * `IOError` wrapping any errors from writing to or flushing 
+    ///   `stream`.
+    pub fn write_contents_buffered<T: Write>(&self, stream: T) -> 
+        Result<usize, PieceTableError> {
+        let mut writer = BufWriter::new(stream);
+        let n_bytes = self.write_contents_to_stream(&mut writer)?;
+        writer.flush().map_err(PieceTableError::IOError)?;
+        Ok(n_bytes)
+    }
+
+    /// Return the offset of the first grapheme cluster boundary after
+    /// `offset`, or `None` if `offset` is at or past the end of the 
+    /// contents.
+    pub fn next_grapheme_boundary(&self, offset: usize) -> Option<usize> {
+        let len = self.len();
+        if offset >= len || self.byte_offset(Position::Byte(offset)).is_err() {
+            return None;
+        }
+        let mut chunks = self.chunks_in(offset..len);
+        let (mut chunk_loc, mut chunk) = chunks.next()?;
+        let mut cursor = GraphemeCursor::new(offset, len, true);
+        loop {
+            match cursor.next_boundary(chunk, chunk_loc) {
+                Ok(boundary) => return boundary,
+                Err(GraphemeIncomplete::NextChunk) => (chunk_loc, chunk) = chunks.next()?,
+                Err(GraphemeIncomplete::PreContext(end)) => {
+                    provide_grapheme_context(&mut cursor, self, end);
+                },
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Return the offset of the last grapheme cluster boundary before
+    /// `offset`, or `None` if `offset` is at the start of the contents
+    /// or past the end.
+    pub fn prev_grapheme_boundary(&self, offset: usize) -> Option<usize> {
+        let len = self.len();
+        if offset == 0 || offset > len || self.byte_offset(Position::Byte(offset)).is_err() {
+            return None;
+        }
+        let mut chunks = self.chunks_in(0..offset).rev();
+        let (mut chunk_loc, mut chunk) = chunks.next()?;
+        let mut cursor = GraphemeCursor::new(offset, len, true);
+        loop {
+            match cursor.prev_boundary(chunk, chunk_loc) {
+                Ok(boundary) => return boundary,
+                Err(GraphemeIncomplete::PrevChunk) => (chunk_loc, chunk) = chunks.next()?,
+                Err(GraphemeIncomplete::PreContext(end)) => {
+                    provide_grapheme_context(&mut cursor, self, end);
+                },
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Offsets of the `\n`s within `piece`, as offsets into its buffer.
+    fn piece_newlines(&self, piece: &Piece) -> &[usize] {
+        let newlines = match piece.content {
+            PieceBuf::ORIGINAL => &self.original_newlines,
+            PieceBuf::ADDITION => &self.addition_newlines,
+        };
+        let start = newlines.partition_point(|&i| i < piece.start);
+        let stop = newlines.partition_point(|&i| i < piece.stop);
+        &newlines[start..stop]
+    }
+
+    /// Number of lines in the buffer. A buffer always has at least one
+    /// line, and a trailing `\n` starts a new empty line.
+    pub fn line_count(&self) -> usize {
+        self.pieces.iter().map(|piece| self.piece_newlines(piece).len()).sum::<usize>() + 1
+    }
+
+    /// Return the offset of the start of line `line`, counting from 0,
+    /// or `None` if the buffer does not have that many lines.
+    pub fn line_to_offset(&self, line: usize) -> Option<usize> {
+        let Some(mut newline) = line.checked_sub(1) else {
+            return Some(0);
+        };
+        let mut piece_start_loc = 0;
+        for piece in &self.pieces {
+            let newlines = self.piece_newlines(piece);
+            if let Some(&i) = newlines.get(newline) {
+                return Some(piece_start_loc + i - piece.start + 1);
+            }
+            newline -= newlines.len();
+            piece_start_loc += piece.len();
+        }
+        None
+    }
+
+    /// Return the line, counting from 0, that `offset` is on, or `None`
+    /// if `offset` is past the end of the buffer. The `\n` ending a line
+    /// is on that line.
+    pub fn offset_to_line(&self, offset: usize) -> Option<usize> {
+        if offset > self.len() {
+            return None;
+        }
+        let mut line = 0;
+        let mut piece_start_loc = 0;
+        for piece in &self.pieces {
+            if piece_start_loc >= offset {
+                break;
+            }
+            let stop = piece.start + piece.len().min(offset - piece_start_loc);
+            line += self.piece_newlines(piece).partition_point(|&i| i < stop);
+            piece_start_loc += piece.len();
+        }
+        Some(line)
+    }
+
+    /// Return the range of every non-overlapping occurrence of `needle`
+    /// in the buffer, in order.
+    pub fn find_all(&self, needle: &str, options: SearchOptions) -> Vec<Range<usize>> {
+        self.find_all_in(needle, 0..usize::MAX, options)
+    }
+
+    /// Return the range of every non-overlapping occurrence of `needle`
+    /// that lies within the byte offsets `range`, in order. The range is
+    /// clamped to the buffer and widened to character boundaries. Only
+    /// the text of the range is copied out of the buffer.
+    pub fn find_all_in(
+        &self, 
+        needle: &str, 
+        range: Range<usize>, 
+        options: SearchOptions
+    ) -> Vec<Range<usize>> {
+        let len = self.len();
+        let mut start = range.start.min(len);
+        while self.byte_offset(Position::Byte(start)).is_err() {
+            start -= 1;
+        }
+        let mut end = range.end.clamp(start, len);
+        while self.byte_offset(Position::Byte(end)).is_err() {
+            end += 1;
+        }
+        // The characters either side of the range tell whether a match
+        // at its edge is a whole word.
+        let text_start = self.char_indices_before(start).next().map_or(start, |(i, _)| i);
+        let text_end = self.char_indices_from(end).next().map_or(end, |(i, c)| i + c.len_utf8());
+        let Some(text) = self.get_text_range(text_start..text_end) else {
+            return Vec::new();
+        };
+        find_matches(&text, needle, start - text_start..end - text_start, options)
+            .into_iter()
+            .map(|found| text_start + found.start..text_start + found.end)
+            .collect()
+    }
+
+    /// Return the range of every non-overlapping match of the regular
+    /// expression `pattern` in the buffer, in order.
+    ///
+    /// # Errors
+    /// * Returns an error if `pattern` is not a valid regular expression.
+    #[cfg(feature = "regex")]
+    pub fn find_all_regex(&self, pattern: &str) -> Result<Vec<Range<usize>>, regex::Error> {
+        let regex = regex::Regex::new(pattern)?;
+        Ok(regex.find_iter(&self.write_contents_to_string())
+            .map(|found| found.range())
+            .collect())
+    }
+
+    /// Return an iterator over the range of every non-overlapping match
+    /// of the regular expression `pattern` in the buffer, in order, 
+    /// giving the same matches as `find_all_regex`. The pieces are read
+    /// in place as each match is searched for, so matches can be taken
+    /// from the start of a large buffer without reading all of it.
+    ///
+    /// # Errors
+    /// * Returns an error if `pattern` is not a valid regular expression.
+    #[cfg(feature = "regex")]
+    pub fn search_regex(&self, pattern: &str) -> Result<RegexMatches<'_>, regex::Error> {
+        Ok(self.search_regex_from(&SearchRegex::new(pattern)?, 0))
+    }
+
+    /// Return an iterator over the range of every non-overlapping match
+    /// of `regex` that starts at or after `from`, in order, as 
+    /// `search_regex` finds them but starting the search at `from`. The
+    /// text before `from` is only read to see whether a match can start
+    /// there, so a match that starts before `from` and runs past it is 
+    /// not found, nor does it hide the matches that it overlaps.
+    #[cfg(feature = "regex")]
+    pub fn search_regex_from(&self, regex: &SearchRegex, from: usize) -> RegexMatches<'_> {
+        RegexMatches::new(self, regex.clone(), from)
+    }
+
+    /// Return the offset of the first occurrence of `needle` that starts
+    /// at or after `from`.
+    pub fn find_from(&self, needle: &str, from: usize, options: SearchOptions) -> Option<usize> {
+        let len = self.len();
+        let mut from = from;
+        while from < len && self.byte_offset(Position::Byte(from)).is_err() {
+            from += 1;
+        }
+        if from > len {
+            return None;
+        }
+        if options == SearchOptions::default() {
+            return self.find(needle, Position::Byte(from))
+                .and_then(|found| self.byte_offset(found).ok());
+        }
+        self.find_all_in(needle, from..len, options)
+            .first()
+            .map(|found| found.start)
+    }
+
+    /// Return the offset of the last occurrence of `needle` that starts
+    /// before `before`.
+    pub fn rfind_before(&self, needle: &str, before: usize) -> Option<usize> {
+        if needle.is_empty() || before == 0 {
+            return None;
+        }
+        // A match cannot start within a character, so one that starts
+        // before `before` starts before the end of its character.
+        let mut before = before.min(self.len());
+        while self.byte_offset(Position::Byte(before)).is_err() {
+            before += 1;
+        }
+        self.rfind(needle, Position::Byte(before))
+            .and_then(|found| self.byte_offset(found).ok())
+    }
+
+    /// Return the position of the first occurrence of `needle` that 
+    /// starts at or after `from`, in the same units as `from`. The 
+    /// pieces are searched in turn, only copying the text around the
+    /// boundaries between them that a match could span.
+    pub fn find(&self, needle: &str, from: Position) -> Option<Position> {
+        let from_loc = self.byte_offset(from).ok()?;
+        if needle.is_empty() {
+            return None;
+        }
+        let overlap = needle.len() - 1;
+        // The last `overlap` bytes searched and the offset they start at
+        let mut carry: Vec<u8> = Vec::new();
+        let mut carry_loc = from_loc;
+
+        for (text_loc, text) in self.chunks_in(from_loc..self.len()) {
+
+            // A match starting in the carried bytes that ends in `text`
+            if !carry.is_empty() {
+                let mut window = carry.clone();
+                window.extend_from_slice(&text.as_bytes()[..overlap.min(text.len())]);
+                let found = find_bytes(&window, needle.as_bytes()).filter(|&i| i < carry.len());
+                if let Some(i) = found {
+                    return self.position_like(from, carry_loc + i);
+                }
+            }
+            if let Some(i) = text.find(needle) {
+                return self.position_like(from, text_loc + i);
+            }
+
+            carry.extend_from_slice(text.as_bytes());
+            let excess = carry.len().saturating_sub(overlap);
+            carry.drain(..excess);
+            carry_loc = text_loc + text.len() - carry.len();
+        }
+        None
+    }
+
+    /// Return the position of the last occurrence of `needle` that 
+    /// starts before `before`, in the same units as `before`, searching
+    /// the pieces in turn as `find` does.
+    pub fn rfind(&self, needle: &str, before: Position) -> Option<Position> {
+        let before_loc = self.byte_offset(before).ok()?;
+        if needle.is_empty() {
+            return None;
+        }
+        let overlap = needle.len() - 1;
+        // A match starting before `before_loc` ends by `stop_loc`
+        let mut stop_loc = (before_loc + overlap).min(self.len());
+        while self.byte_offset(Position::Byte(stop_loc)).is_err() {
+            stop_loc -= 1;
+        }
+        // The first `overlap` bytes searched after the current chunk
+        let mut carry: Vec<u8> = Vec::new();
+
+        for (chunk_loc, text) in self.chunks_in(0..stop_loc).rev() {
+
+            // A match starting in `text` that ends in the carried bytes
+            if !carry.is_empty() {
+                let tail_start = text.len().saturating_sub(overlap);
+                let mut window = text.as_bytes()[tail_start..].to_vec();
+                let tail_len = window.len();
+                window.extend_from_slice(&carry);
+                let found = rfind_bytes(&window, needle.as_bytes()).filter(|&i| i < tail_len);
+                if let Some(i) = found {
+                    return self.position_like(before, chunk_loc + tail_start + i);
+                }
+            }
+            if let Some(i) = text.rfind(needle) {
+                return self.position_like(before, chunk_loc + i);
+            }
+
+            let mut next_carry = text.as_bytes().to_vec();
+            next_carry.append(&mut carry);
+            next_carry.truncate(overlap);
+            carry = next_carry;
+        }
+        None
+    }
+
+    /// The position of the byte offset `loc`, in the same units as 
+    /// `like`.
+    fn position_like(&self, like: Position, loc: usize) -> Option<Position> {
+        match like {
+            Position::Byte(_) => Some(Position::Byte(loc)),
+            Position::Char(_) => self.char_offset(Position::Byte(loc)).ok().map(Position::Char),
+        }
+    }
+
+    /// Write contents of `self` to `String` in correct order.
+    pub fn write_contents_to_string(&self) -> String {
+        // NOTE: Need to hangle unwrap in a more suitable fasion
+        self.try_write_contents_to_string().unwrap()
+    }
+
+    /// Write contents of `self` to `String` in correct order.
+    ///
+    /// # Errors
+    /// * `GotBadPieceRange` if a piece references a range outside of 
+    ///   its buffer.
+    pub fn try_write_contents_to_string(&self) -> Result<String, PieceTableError> {
+        let mut writer = string_writer::StringWriter::new();
+        self.write_contents_to_stream(&mut writer)?;
+        Ok(writer.contents)
+    }
+
+    /// Take a read-only view of the current contents, which later edits
+    /// to `self` do not change. Only the list of pieces is copied; the
+    /// buffers they refer to are shared.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            original: Arc::clone(&self.original),
+            addition: self.addition.clone(),
+            pieces: self.pieces.clone(),
+        }
+    }
+}    
+
+/// Index of the first occurrence of `needle` in `haystack`.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Index of the last occurrence of `needle` in `haystack`.
+fn rfind_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window == needle)
+}
+
+/// The text of the bytes `range` of `original` or `addition`, as 
+/// `content` says, in chunks as `Original::chunks` splits them.
+///
+/// # Panics
+/// * As for `Original::text`.
+fn buf_chunks<'a>(
+    original: &'a Original, 
+    addition: &'a Addition, 
+    content: &PieceBuf, 
+    range: Range<usize>,
+) -> impl Iterator<Item = &'a str> {
+    let (original, addition) = match content {
+        PieceBuf::ORIGINAL => (Some(original.chunks(range)), None),
+        PieceBuf::ADDITION => (None, Some(addition.text(range))),
+    };
+    original.into_iter().flatten().chain(addition)
+}
+
+/// Offsets of every `\n` in `bytes`, in order.
+fn newline_offsets(bytes: &[u8]) -> Vec<usize> {
+    bytes.iter()
+        .enumerate()
+        .filter(|&(_, &byte)| byte == b'\n')
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Check that `bytes` are valid UTF-8 and find the offsets of every 
+/// `\n` in them, in order, reading them once in chunks of 
+/// `MAPPED_CHUNK_LEN` bytes. Returns `None` if every `\n` follows a 
+/// `\r`, as `LineEnding::detect` would detect `CRLF`.
+///
+/// # Errors
+/// * Returns the error of the first chunk that is not valid UTF-8, 
+///   with offsets from the start of the chunk.
+fn index_mapped(bytes: &[u8]) -> Result<Option<Vec<usize>>, std::str::Utf8Error> {
+    let mut newlines = Vec::new();
+    let mut has_lf = false;
+    let mut start = 0;
+    while start < bytes.len() {
+        let stop = (start + MAPPED_CHUNK_LEN).min(bytes.len());
+        // A character cut off by the end of the chunk is read with the
+        // next one
+        let stop = match std::str::from_utf8(&bytes[start..stop]) {
+            Ok(_) => stop,
+            Err(err) if err.error_len().is_none() && stop < bytes.len() => {
+                start + err.valid_up_to()
+            },
+            Err(err) => return Err(err),
+        };
+        for (i, &byte) in bytes[start..stop].iter().enumerate() {
+            if byte != b'\n' {
+                continue;
+            }
+            let i = start + i;
+            has_lf |= i == 0 || bytes[i - 1] != b'\r';
+            newlines.push(i);
+        }
+        start = stop;
+    }
+    if !has_lf && !newlines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(newlines))
+}
+
+/// Append `content` to the addition buffer `addition`, recording the 
+/// offsets of its `\n`s in `newlines`, and return the offset it starts
+/// at.
+fn push_addition(addition: &mut Addition, newlines: &mut Vec<usize>, content: &str) -> usize {
+    let start = addition.push(content);
+    newlines.extend(newline_offsets(content.as_bytes()).into_iter().map(|i| start + i));
+    start
+}
+
+impl From<String> for PieceTable {
+    fn from(s: String) -> Self {
+        Self::from_string(s)
+    }
+}
+
+impl From<&str> for PieceTable {
+    fn from(s: &str) -> Self {
+        Self::from_str(s)
+    }
+}
+
+impl std::fmt::Display for PieceTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let contents = self.try_write_contents_to_string().map_err(|_| std::fmt::Error)?;
+        f.write_str(&contents)
+    }
+}
+
+/// Iterator over the chunks of the text of a `PieceTable` between two 
+/// offsets, with the offsets they start at, returned by 
+/// `PieceTable::chunks_in`.
+struct Chunks<'a> {
+    piece_table: &'a PieceTable,
+    range: Range<usize>,
+    /// The pieces that have not been started from either end, and the 
+    /// offsets that the first starts at and the last ends at.
+    pieces: std::slice::Iter<'a, Piece>,
+    front_loc: usize,
+    back_loc: usize,
+    /// The parts of the pieces that have been started from the front 
+    /// and the back but not finished, with the offsets they start at.
+    front: Option<(usize, &'a PieceBuf, Range<usize>)>,
+    back: Option<(usize, &'a PieceBuf, Range<usize>)>,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(piece_table: &'a PieceTable, range: Range<usize>) -> Self {
+        Self {
+            piece_table,
+            range,
+            pieces: piece_table.pieces.iter(),
+            front_loc: 0,
+            back_loc: piece_table.len(),
+            front: None,
+            back: None,
+        }
+    }
+
+    /// The part of `piece`, which starts at offset `piece_loc`, that is
+    /// within `self.range`, as a range of its buffer, with the offset it
+    /// starts at.
+    fn clip(&self, piece: &'a Piece, piece_loc: usize) -> (usize, &'a PieceBuf, Range<usize>) {
+        let piece_stop_loc = piece_loc + piece.len();
+        let start = self.range.start.clamp(piece_loc, piece_stop_loc);
+        let stop = self.range.end.clamp(start, piece_stop_loc);
+        (start, &piece.content, piece.start + start - piece_loc..piece.start + stop - piece_loc)
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((loc, content, range)) = &mut self.front {
+                if range.start < range.end {
+                    let stop = self.piece_table.chunk_stop(content, range.clone());
+                    let chunk = (*loc, self.piece_table.text(content, range.start..stop));
+                    *loc += stop - range.start;
+                    range.start = stop;
+                    return Some(chunk);
+                }
+            }
+            self.front = match self.pieces.next() {
+                Some(piece) => {
+                    self.front_loc += piece.len();
+                    Some(self.clip(piece, self.front_loc - piece.len()))
+                },
+                None => Some(self.back.take()?),
+            };
+        }
+    }
+}
+
+impl DoubleEndedIterator for Chunks<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((loc, content, range)) = &mut self.back {
+                if range.start < range.end {
+                    let start = self.piece_table.chunk_start(content, range.clone());
+                    let chunk_loc = *loc + start - range.start;
+                    let chunk = (chunk_loc, self.piece_table.text(content, start..range.end));
+                    range.end = start;
+                    return Some(chunk);
+                }
+            }
+            self.back = match self.pieces.next_back() {
+                Some(piece) => {
+                    self.back_loc -= piece.len();
+                    Some(self.clip(piece, self.back_loc))
+                },
+                None => Some(self.front.take()?),
+            };
+        }
+    }
+}
+
+/// Iterator over the lines of a `PieceTable`, returned by 
+/// `PieceTable::lines`.
+pub struct Lines<'a> {
+    chunks: Chunks<'a>,
+    /// The text of the current chunk that has not been read yet.
+    rest: &'a str,
+    done: bool,
+}
+
+impl<'a> Lines<'a> {
+    /// Lines of `piece_table` starting from `offset`, which must be the
+    /// start of a line.
+    fn new(piece_table: &'a PieceTable, offset: usize) -> Self {
+        let chunks = piece_table.chunks_in(offset..piece_table.len());
+        Self { chunks, rest: "", done: false }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut line = Cow::Borrowed("");
+        loop {
+            let (text, newline) = match self.rest.split_once('\n') {
+                Some((text, rest)) => {
+                    self.rest = rest;
+                    (text, true)
+                },
+                None => (std::mem::take(&mut self.rest), false),
+            };
+            if line.is_empty() {
+                line = Cow::Borrowed(text);
+            } else if !text.is_empty() {
+                line.to_mut().push_str(text);
+            }
+            if newline {
+                return Some(line);
+            }
+            match self.chunks.next() {
+                Some((_, chunk)) => self.rest = chunk,
+                None => {
+                    self.done = true;
+                    return Some(line);
+                },
+            }
+        }
+    }
+}
+
+/// A regular expression compiled to search a `PieceTable` with 
+/// `PieceTable::search_regex_from`, so that it can be searched for 
+/// again without compiling it again. Cloning it is cheap, as its 
+/// compiled parts are shared.
+#[cfg(feature = "regex")]
+#[derive(Clone)]
+pub struct SearchRegex {
+    regex: regex::Regex,
+    /// A lazy DFA that finds where a match ends and a reversed one that
+    /// finds where it starts, or `None` if they could not be built.
+    dfas: Option<(regex_automata::hybrid::dfa::DFA, regex_automata::hybrid::dfa::DFA)>,
+    /// Whether a match can contain a `\n`, and so span lines.
+    spans_lines: bool,
+}
+
+#[cfg(feature = "regex")]
+impl SearchRegex {
+    /// Compile `pattern`.
+    ///
+    /// # Errors
+    /// * Returns an error if `pattern` is not a valid regular expression.
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        use regex_automata::{ hybrid::dfa::DFA, nfa::thompson, MatchKind };
+
+        let regex = regex::Regex::new(pattern)?;
+        let forward = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true))
+            .build(pattern);
+        let reverse = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true).match_kind(MatchKind::All))
+            .thompson(thompson::Config::new().reverse(true))
+            .build(pattern);
+        let spans_lines = regex_syntax::parse(pattern).map_or(true, |hir| can_match_newline(&hir));
+        Ok(Self { regex, dfas: forward.ok().zip(reverse.ok()), spans_lines })
+    }
+}
+
+/// Whether `hir` can match a `\n`.
+#[cfg(feature = "regex")]
+fn can_match_newline(hir: &regex_syntax::hir::Hir) -> bool {
+    use regex_syntax::hir::{ Class, HirKind };
+
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => false,
+        HirKind::Literal(literal) => literal.0.contains(&b'\n'),
+        HirKind::Class(Class::Unicode(class)) => class.ranges()
+            .iter()
+            .any(|range| range.start() <= '\n' && '\n' <= range.end()),
+        HirKind::Class(Class::Bytes(class)) => class.ranges()
+            .iter()
+            .any(|range| range.start() <= b'\n' && b'\n' <= range.end()),
+        HirKind::Repetition(repetition) => can_match_newline(&repetition.sub),
+        HirKind::Capture(capture) => can_match_newline(&capture.sub),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => hirs.iter().any(can_match_newline),
+    }
+}
+
+/// Iterator over the matches of a regular expression in a `PieceTable`,
+/// returned by `PieceTable::search_regex`. Each match is found by 
+/// feeding the bytes of the pieces through a lazy DFA that finds where
+/// the match ends, then back through a reversed one that finds where it
+/// starts. Should either DFA give up, as one does when a Unicode word 
+/// boundary meets text that is not ASCII, the matches up to where it 
+/// gave up are found with `regex::Regex` on the text of one line at a
+/// time. If a match of the expression can span lines they are instead
+/// found on the contents written out in full, which copies the whole 
+/// buffer once.
+#[cfg(feature = "regex")]
+pub struct RegexMatches<'a> {
+    piece_table: &'a PieceTable,
+    regex: SearchRegex,
+    /// Caches of the forward and reverse DFAs.
+    caches: Option<(regex_automata::hybrid::dfa::Cache, regex_automata::hybrid::dfa::Cache)>,
+    /// Where the DFAs last gave up, up to which matches are found with
+    /// `regex` instead.
+    gave_up_at: Option<usize>,
+    /// The contents written out in full, once the DFAs have given up on
+    /// an expression whose matches can span lines.
+    contents: Option<String>,
+    /// Where the search for the next match starts.
+    at: usize,
+    /// Where the previous match ended, as an empty match cannot start 
+    /// there.
+    last_end: Option<usize>,
+}
+
+#[cfg(feature = "regex")]
+impl<'a> RegexMatches<'a> {
+    fn new(piece_table: &'a PieceTable, regex: SearchRegex, from: usize) -> Self {
+        let caches = regex.dfas
+            .as_ref()
+            .map(|(forward, reverse)| (forward.create_cache(), reverse.create_cache()));
+        Self {
+            piece_table,
+            regex,
+            caches,
+            gave_up_at: None,
+            contents: None,
+            at: from,
+            last_end: None,
+        }
+    }
+
+    /// The byte at offset `loc` in the buffer, if there is one.
+    fn byte_at(&self, loc: usize) -> Option<u8> {
+        bytes_from(self.piece_table, loc).next().map(|(_, byte)| byte)
+    }
+
+    /// The leftmost match starting at or after `self.at`, `Ok(None)` if
+    /// there is none, or where the DFAs gave up.
+    fn find_with_dfas(&mut self) -> Result<Option<Range<usize>>, usize> {
+        let Some(end) = self.find_end()? else {
+            return Ok(None);
+        };
+        let start = self.find_start(end).ok_or(end)?;
+        Ok(Some(start..end))
+    }
+
+    /// Where the leftmost match starting at or after `self.at` ends, 
+    /// `Ok(None)` if there is no match, or where the DFA gave up.
+    fn find_end(&mut self) -> Result<Option<usize>, usize> {
+        use regex_automata::{ util::start, Anchored };
+
+        let at = self.at;
+        let look_behind = at.checked_sub(1).and_then(|loc| self.byte_at(loc));
+        let bytes = bytes_from(self.piece_table, at);
+        let ((dfa, _), (cache, _)) = self.regex.dfas.as_ref().zip(self.caches.as_mut()).ok_or(at)?;
+        let config = start::Config::new().anchored(Anchored::No).look_behind(look_behind);
+        let mut state = dfa.start_state(cache, &config).map_err(|_| at)?;
+        let mut end = None;
+        for (loc, byte) in bytes {
+            state = dfa.next_state(cache, state, byte).map_err(|_| loc)?;
+            // Matches are reported one byte after they end
+            if state.is_match() {
+                end = Some(loc);
+            } else if state.is_dead() {
+                return Ok(end);
+            } else if state.is_quit() {
+                return Err(loc);
+            }
+        }
+        let len = self.piece_table.len();
+        state = dfa.next_eoi_state(cache, state).map_err(|_| len)?;
+        if state.is_match() {
+            end = Some(len);
+        }
+        Ok(end)
+    }
+
+    /// Where the match ending at `end` that starts at or after `self.at`
+    /// starts, or `None` if the DFA gave up.
+    fn find_start(&mut self, end: usize) -> Option<usize> {
+        use regex_automata::{ util::start, Anchored };
+
+        let look_behind = self.byte_at(end);
+        let before_at = self.at.checked_sub(1).and_then(|loc| self.byte_at(loc));
+        let bytes = bytes_before(self.piece_table, end);
+        let at = self.at;
+        let ((_, dfa), (_, cache)) = self.regex.dfas.as_ref().zip(self.caches.as_mut())?;
+        let config = start::Config::new().anchored(Anchored::Yes).look_behind(look_behind);
+        let mut state = dfa.start_state(cache, &config).ok()?;
+        let mut start = None;
+        for (loc, byte) in bytes.take_while(|&(loc, _)| loc >= at) {
+            state = dfa.next_state(cache, state, byte).ok()?;
+            if state.is_match() {
+                start = Some(loc + 1);
+            } else if state.is_dead() {
+                return start;
+            } else if state.is_quit() {
+                return None;
+            }
+        }
+        state = match before_at {
+            Some(byte) => dfa.next_state(cache, state, byte).ok()?,
+            None => dfa.next_eoi_state(cache, state).ok()?,
+        };
+        if state.is_match() {
+            start = Some(at);
+        }
+        start
+    }
+
+    /// The leftmost match starting at or after `self.at` found with 
+    /// `self.regex` rather than the DFAs, reading the text a line at a 
+    /// time up to where the DFAs gave up. Returns `Some(None)` if there 
+    /// is none before then, so the DFAs can be tried again, or `None` if
+    /// there are no more matches.
+    fn fall_back(&mut self) -> Option<Option<Range<usize>>> {
+        let len = self.piece_table.len();
+        if self.regex.spans_lines {
+            let contents = self.contents.get_or_insert_with(|| self.piece_table.write_contents_to_string());
+            return self.regex.regex.find_at(contents, self.at).map(|found| Some(found.range()));
+        }
+        let gave_up_at = self.gave_up_at.unwrap_or(len);
+        while self.at <= gave_up_at {
+            let line = self.piece_table.offset_to_line(self.at)?;
+            let line_start = self.piece_table.line_to_offset(line)?;
+            let next_line_start = self.piece_table.line_to_offset(line + 1).unwrap_or(len);
+            // The `\n`s either side of the line are searched as context
+            // for the start and end of a match, which cannot contain them
+            let window_start = line_start.saturating_sub(1);
+            let text = self.piece_table.get_text_range(window_start..next_line_start)?;
+            let found = self.regex.regex
+                .find_at(&text, self.at - window_start)
+                .map(|found| window_start + found.start()..window_start + found.end())
+                .filter(|found| found.start < next_line_start || next_line_start == len);
+            if found.is_some() {
+                return Some(found);
+            }
+            if next_line_start == len {
+                return None;
+            }
+            self.at = next_line_start;
+        }
+        self.gave_up_at = None;
+        Some(None)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Iterator for RegexMatches<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.at > self.piece_table.len() {
+                return None;
+            }
+            let found = if self.contents.is_some() || self.gave_up_at.is_some_and(|loc| self.at <= loc) {
+                self.fall_back()?
+            } else {
+                match self.find_with_dfas() {
+                    Ok(found) => Some(found?),
+                    Err(loc) => {
+                        self.gave_up_at = Some(loc);
+                        self.fall_back()?
+                    },
+                }
+            };
+            // The DFAs are tried again past where they gave up
+            let Some(found) = found else {
+                continue;
+            };
+            let (start, end) = (found.start, found.end);
+
+            // Move past an empty match by a character so that the search
+            // goes on
+            let next_at = if start == end {
+                end + self.byte_at(end).map_or(1, utf8_char_len)
+            } else {
+                end
+            };
+            if start == end && self.last_end == Some(end) {
+                self.at = next_at;
+                continue;
+            }
+            self.at = next_at;
+            self.last_end = Some(end);
+            return Some(start..end);
+        }
+    }
+}
+
+/// The bytes of `piece_table` from offset `from` onwards along with 
+/// their offsets. Chunks start between characters, so the chunks are
+/// read from the start of the character `from` is in.
+#[cfg(feature = "regex")]
+fn bytes_from(piece_table: &PieceTable, from: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let len = piece_table.len();
+    let mut start = from.min(len);
+    while piece_table.byte_offset(Position::Byte(start)).is_err() {
+        start -= 1;
+    }
+    piece_table.chunks_in(start..len)
+        .flat_map(|(chunk_loc, chunk)| {
+            chunk.bytes().enumerate().map(move |(i, byte)| (chunk_loc + i, byte))
+        })
+        .skip_while(move |&(loc, _)| loc < from)
+}
+
+/// The bytes of `piece_table` before offset `before` along with their 
+/// offsets, last first.
+#[cfg(feature = "regex")]
+fn bytes_before(piece_table: &PieceTable, before: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let mut stop = before.min(piece_table.len());
+    while piece_table.byte_offset(Position::Byte(stop)).is_err() {
+        stop += 1;
+    }
+    piece_table.chunks_in(0..stop)
+        .rev()
+        .flat_map(|(chunk_loc, chunk)| {
+            chunk.bytes().enumerate().rev().map(move |(i, byte)| (chunk_loc + i, byte))
+        })
+        .skip_while(move |&(loc, _)| loc >= before)
+}
+
+/// Give `cursor` the text of the chunk of `piece_table` that ends at or
+/// runs past `end`, up to `end`, as the context it asked for before 
+/// `end`.
+fn provide_grapheme_context(cursor: &mut GraphemeCursor, piece_table: &PieceTable, end: usize) {
+    if let Some((chunk_loc, chunk)) = piece_table.chunks_in(0..end).next_back() {
+        cursor.provide_context(chunk, chunk_loc);
+    }
+}
+
+/// Length of the UTF-8 encoded character that starts with `byte`.
+#[cfg(feature = "regex")]
+fn utf8_char_len(byte: u8) -> usize {
+    match byte {
+        0..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        _ => 4,
+    }
+}
+
+
+/// The contents of a `PieceTable` at the time `PieceTable::snapshot` 
+/// was called. A snapshot can be sent to another thread to be read 
+/// while the table goes on being edited.
+#[derive(Clone)]
+pub struct Snapshot {
+    original: Arc<Original>,
+    addition: Addition,
+    pieces: Vec<Piece>,
+}
+
+impl Snapshot {
+    /// Length of the contents in bytes.
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(Piece::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The text of each piece, in order, with a piece of a mapped file
+    /// given in chunks.
+    fn slices(&self) -> impl Iterator<Item = &str> {
+        self.pieces.iter().filter(|piece| !piece.is_empty()).flat_map(|piece| {
+            buf_chunks(&self.original, &self.addition, &piece.content, piece.start..piece.stop)
+        })
+    }
+
+    /// Return the text between byte offsets `range.start` and 
+    /// `range.end`, or `None` if the range extends past the end of the
+    /// contents or either end falls within a character.
+    pub fn get_text_range(&self, range: Range<usize>) -> Option<String> {
+        if range.start > range.end || range.end > self.len() {
+            return None;
+        }
+        let mut text = String::with_capacity(range.end - range.start);
+        let mut slice_start = 0;
+        for slice in self.slices() {
+            let slice_end = slice_start + slice.len();
+            if slice_end > range.start && slice_start < range.end {
+                let start = range.start.saturating_sub(slice_start);
+                let end = range.end.min(slice_end) - slice_start;
+                text.push_str(slice.get(start..end)?);
+            }
+            slice_start = slice_end;
+        }
+        Some(text)
+    }
+
+    /// The characters of the contents, in order.
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.slices().flat_map(str::chars)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::get_first)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// A clock that always gives the same time, so that every character
+    /// typed is within the undo group timeout of the last.
+    fn fixed_clock() -> Instant {
+        static NOW: OnceLock<Instant> = OnceLock::new();
+        *NOW.get_or_init(Instant::now)
+    }
+
+    /// A clock that gives the time the undo group timeout after 
+    /// `fixed_clock`.
+    fn later_clock() -> Instant {
+        fixed_clock() + UNDO_GROUP_TIMEOUT
+    }
+
+    #[test]
+    fn piece_table_split_piece() {
+        let mut piece_table = PieceTable::from_str("hello world!");
+        piece_table.split_piece(0, 5).unwrap();
         piece_table.split_piece(1, 2).unwrap();
         let pieces = piece_table.get_pieces();
         assert_eq!(
-            pieces.get(0).unwrap(),
-            &Piece { start: 0, stop: 5, content: PieceBuf::ORIGINAL }
+            pieces.get(0).unwrap(),
+            &Piece { start: 0, stop: 5, content: PieceBuf::ORIGINAL }
+        );
+        assert_eq!(
+            pieces.get(1).unwrap(),
+            &Piece { start: 5, stop: 7, content: PieceBuf::ORIGINAL }
+        );
+        assert_eq!(
+            pieces.get(2).unwrap(), 
+            &Piece { start: 7, stop: 12, content: PieceBuf::ORIGINAL }
+        )
+    }
+
+    #[test]
+    fn piece_table_write_to_current_piece() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        piece_table.write_to_loc(5, "123").unwrap();
+        piece_table.write_to_current_piece("new").unwrap();
+        piece_table.write_to_loc(1, "22").unwrap();
+        piece_table.write_to_current_piece("test").unwrap();
+        let contents = piece_table.write_contents_to_string();
+        assert_eq!(contents, "h22testello123new world");
+    }
+
+    #[test]
+    fn piece_table_writes_return_byte_counts() {
+        let mut piece_table = PieceTable::from_str("caf");
+        assert_eq!(piece_table.write_to_loc(3, "\u{e9}").unwrap(), 2);
+        assert_eq!(piece_table.write_to_loc(0, "\u{1f600} ").unwrap(), 5);
+        assert_eq!(piece_table.write_to_current_piece("\u{e8}\u{e8}").unwrap(), 4);
+        assert_eq!(piece_table.write_contents_to_string(), "\u{1f600} \u{e8}\u{e8}caf\u{e9}");
+        assert_eq!(piece_table.len(), 14);
+        assert_eq!(piece_table.char_len(), 8);
+    }
+
+    #[test]
+    fn piece_table_insert_char() {
+        let mut piece_table = PieceTable::from_str("ab");
+        let mut loc = 1;
+        for c in ['x', '\u{e9}', '\u{1f600}', 'y'] {
+            loc += piece_table.insert_char(loc, c).unwrap();
+        }
+        assert_eq!(loc, 1 + 1 + 2 + 4 + 1);
+        assert_eq!(piece_table.write_contents_to_string(), "ax\u{e9}\u{1f600}yb");
+
+        // Inserting within a multibyte character is refused
+        assert!(matches!(piece_table.insert_char(3, 'z'), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.insert_char(6, 'z'), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.write_contents_to_string(), "ax\u{e9}\u{1f600}yb");
+    }
+
+    #[test]
+    fn piece_table_delete_char_at() {
+        let mut piece_table = PieceTable::from_str("a\u{e9}b");
+        piece_table.write_to_loc(4, "\u{1f600}").unwrap();
+        assert_eq!(piece_table.delete_char_at(1).unwrap(), 2);
+        assert_eq!(piece_table.write_contents_to_string(), "ab\u{1f600}");
+        assert_eq!(piece_table.delete_char_at(0).unwrap(), 1);
+        assert_eq!(piece_table.write_contents_to_string(), "b\u{1f600}");
+        assert!(matches!(piece_table.delete_char_at(2), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.delete_char_at(1).unwrap(), 4);
+        assert_eq!(piece_table.write_contents_to_string(), "b");
+
+        // Nothing is deleted at the end of the buffer
+        assert_eq!(piece_table.delete_char_at(1).unwrap(), 0);
+        assert_eq!(piece_table.write_contents_to_string(), "b");
+        assert!(matches!(piece_table.delete_char_at(2), Err(PieceTableError::GotBadLoc)));
+    }
+
+    #[test]
+    fn piece_table_from_and_display() {
+        let mut piece_table: PieceTable = "hi".into();
+        piece_table.write_to_loc(2, " there").unwrap();
+        assert_eq!(format!("{piece_table}"), "hi there");
+        assert_eq!(piece_table.to_string(), piece_table.write_contents_to_string());
+
+        let piece_table = PieceTable::from(String::from("caf\u{e9}"));
+        assert_eq!(format!("{piece_table}"), "caf\u{e9}");
+    }
+
+    #[test]
+    fn piece_table_from_reader() {
+        let reader = std::io::Cursor::new(b"\xef\xbb\xbfone\r\ntwo\r\n".as_slice());
+        let piece_table = PieceTable::from_reader(reader).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "one\ntwo\n");
+        assert_eq!(piece_table.line_ending(), LineEnding::CRLF);
+        assert!(piece_table.has_bom());
+
+        let reader = std::io::Cursor::new(b"ab\xffc".as_slice());
+        let err = PieceTable::from_reader(reader).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn piece_table_write_to_loc_bad_loc() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        let output = piece_table.write_to_loc(20, "test");
+        if let Err(PieceTableError::GotBadLoc) = output {} else { panic!() }
+    }
+
+    #[test]
+    fn piece_table_grapheme_boundary_combining_character() {
+        let piece_table = PieceTable::from_str("e\u{301}x");
+        assert_eq!(piece_table.next_grapheme_boundary(0), Some(3));
+        assert_eq!(piece_table.next_grapheme_boundary(3), Some(4));
+        assert_eq!(piece_table.next_grapheme_boundary(4), None);
+        assert_eq!(piece_table.prev_grapheme_boundary(3), Some(0));
+        assert_eq!(piece_table.prev_grapheme_boundary(0), None);
+    }
+
+    #[test]
+    fn piece_table_grapheme_boundary_flag_emoji() {
+        let piece_table = PieceTable::from_str("a\u{1f1ec}\u{1f1e7}b");
+        assert_eq!(piece_table.next_grapheme_boundary(1), Some(9));
+        assert_eq!(piece_table.prev_grapheme_boundary(9), Some(1));
+    }
+
+    #[test]
+    fn piece_table_grapheme_boundary_across_pieces() {
+        let mut piece_table = PieceTable::from_str("a\u{1f1e7}b");
+        piece_table.write_to_loc(1, "\u{1f1ec}").unwrap();
+        piece_table.write_to_loc(9, "\u{1f1ec}").unwrap();
+        piece_table.write_to_loc(13, "\u{1f1e7}").unwrap();
+        piece_table.write_to_loc(18, "e").unwrap();
+        piece_table.write_to_loc(19, "\u{301}").unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 6);
+        assert_eq!(
+            piece_table.write_contents_to_string(), 
+            "a\u{1f1ec}\u{1f1e7}\u{1f1ec}\u{1f1e7}be\u{301}"
+        );
+
+        assert_eq!(piece_table.next_grapheme_boundary(1), Some(9));
+        assert_eq!(piece_table.next_grapheme_boundary(9), Some(17));
+        assert_eq!(piece_table.next_grapheme_boundary(18), Some(21));
+        assert_eq!(piece_table.next_grapheme_boundary(21), None);
+        assert_eq!(piece_table.prev_grapheme_boundary(21), Some(18));
+        assert_eq!(piece_table.prev_grapheme_boundary(17), Some(9));
+        assert_eq!(piece_table.prev_grapheme_boundary(9), Some(1));
+        assert_eq!(piece_table.next_grapheme_boundary(2), None);
+    }
+
+    #[test]
+    fn piece_table_text_around_offsets() {
+        let mut piece_table = PieceTable::from_str("one\ntw\u{f6}\nthree");
+        piece_table.write_to_loc(5, "X").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "one\ntXw\u{f6}\nthree");
+
+        let after: Vec<(usize, char)> = piece_table.char_indices_from(5).take(4).collect();
+        assert_eq!(after, [(5, 'X'), (6, 'w'), (7, '\u{f6}'), (9, '\n')]);
+        let before: Vec<(usize, char)> = piece_table.char_indices_before(9).take(4).collect();
+        assert_eq!(before, [(7, '\u{f6}'), (6, 'w'), (5, 'X'), (4, 't')]);
+        assert_eq!(piece_table.char_indices_from(15).next(), None);
+        assert_eq!(piece_table.char_indices_before(0).next(), None);
+
+        assert_eq!(piece_table.get_text_range(4..9).as_deref(), Some("tXw\u{f6}"));
+        assert!(matches!(piece_table.get_text_range(6..9), Some(Cow::Borrowed("w\u{f6}"))));
+        assert_eq!(piece_table.get_text_range(15..15).as_deref(), Some(""));
+        assert_eq!(piece_table.get_text_range(4..8), None);
+        assert_eq!(piece_table.get_text_range(8..8), None);
+        assert_eq!(piece_table.get_text_range(10..16), None);
+
+        assert_eq!(piece_table.line(1).as_deref(), Some("tXw\u{f6}"));
+        assert_eq!(piece_table.line(2).as_deref(), Some("three"));
+        assert_eq!(piece_table.line(3), None);
+    }
+
+    fn temp_file_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("piece_table_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn line_ending_detect() {
+        assert_eq!(LineEnding::detect(""), LineEnding::LF);
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::LF);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::CRLF);
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::LF);
+        assert_eq!(LineEnding::detect("a\r\nb\nc\n"), LineEnding::LF);
+    }
+
+    #[test]
+    fn piece_table_preserves_crlf() {
+        let path = temp_file_path("crlf");
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        assert_eq!(piece_table.line_ending(), LineEnding::CRLF);
+        assert_eq!(piece_table.write_contents_to_string(), "one\ntwo\n");
+
+        piece_table.write_to_loc(4, "new\n").unwrap();
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one\r\nnew\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn piece_table_keeps_mixed_line_endings() {
+        let path = temp_file_path("mixed");
+        std::fs::write(&path, "one\r\ntwo\nthree\r\n").unwrap();
+
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        assert_eq!(piece_table.line_ending(), LineEnding::LF);
+        assert_eq!(piece_table.write_contents_to_string(), "one\r\ntwo\nthree\r\n");
+
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one\r\ntwo\nthree\r\n");
+    }
+
+    #[test]
+    fn piece_table_preserves_bom() {
+        let path = temp_file_path("bom");
+        std::fs::write(&path, b"\xef\xbb\xbfab\r\n").unwrap();
+
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        assert!(piece_table.has_bom());
+        assert_eq!(piece_table.line_ending(), LineEnding::CRLF);
+        assert_eq!(piece_table.write_contents_to_string().chars().next(), Some('a'));
+        assert_eq!(piece_table.len(), 3);
+
+        piece_table.write_to_loc(0, "x").unwrap();
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(saved, b"\xef\xbb\xbfxab\r\n");
+
+        // Files without a BOM are not given one
+        std::fs::write(&path, "ab").unwrap();
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        assert!(!piece_table.has_bom());
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, b"ab");
+    }
+
+    #[test]
+    fn piece_table_saves_unchanged_file() {
+        let path = temp_file_path("unchanged");
+        std::fs::write(&path, "one").unwrap();
+
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        piece_table.write_to_loc(3, " two").unwrap();
+        piece_table.save_to_file(&path).unwrap();
+        // The new modification time is recorded by the first save
+        piece_table.write_to_loc(7, " three").unwrap();
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one two three");
+    }
+
+    #[test]
+    fn piece_table_refuses_to_overwrite_changed_file() {
+        let path = temp_file_path("changed");
+        std::fs::write(&path, "one").unwrap();
+
+        let mut piece_table = PieceTable::from_file(&path).unwrap();
+        std::fs::write(&path, "changed").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+
+        let result = piece_table.save_to_file(&path);
+        assert!(matches!(result, Err(PieceTableError::FileChangedOnDisk)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "changed");
+
+        // Other files can still be written
+        let other_path = temp_file_path("changed_other");
+        piece_table.save_to_file(&other_path).unwrap();
+        std::fs::remove_file(&other_path).unwrap();
+
+        piece_table.forget_file_modified();
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one");
+    }
+
+    #[test]
+    fn piece_table_from_file_mmap() {
+        let path = temp_file_path("mmap");
+        let line = "0123456789 caf\u{e9}\n";
+        let contents = line.repeat(100_000);
+        std::fs::write(&path, &contents).unwrap();
+
+        let mut piece_table = PieceTable::from_file_mmap(&path).unwrap();
+        assert!(piece_table.is_mapped());
+        assert_eq!(piece_table.len(), contents.len());
+        assert_eq!(piece_table.line_count(), 100_001);
+        assert_eq!(piece_table.line_to_offset(50_000), Some(50_000 * line.len()));
+
+        // The mapped file is read in chunks, from either end
+        assert!(piece_table.chunks().all(|chunk| chunk.len() <= MAPPED_CHUNK_LEN));
+        assert_eq!(piece_table.chunks().collect::<String>(), contents);
+        let mut chunks: Vec<&str> = piece_table.chunks_in(0..contents.len())
+            .rev()
+            .map(|(_, chunk)| chunk)
+            .collect();
+        chunks.reverse();
+        assert_eq!(chunks.concat(), contents);
+        assert_eq!(
+            piece_table.find_from("caf\u{e9}", line.len(), SearchOptions::default()),
+            Some(line.len() + 11)
+        );
+
+        let middle = 50_000 * line.len();
+        piece_table.write_to_loc(middle, "inserted\n").unwrap();
+        piece_table.delete(0, line.len()).unwrap();
+        let mut expected = contents.clone();
+        expected.insert_str(middle, "inserted\n");
+        expected.replace_range(0..line.len(), "");
+        assert_eq!(piece_table.write_contents_to_string(), expected);
+
+        // Saving writes over the mapped file in place, keeping its 
+        // links, and maps it again
+        #[cfg(unix)]
+        let link = {
+            let link = temp_file_path("mmap_link");
+            std::fs::hard_link(&path, &link).unwrap();
+            link
+        };
+        piece_table.save_to_file(&path).unwrap();
+        assert!(piece_table.is_mapped());
+        assert_eq!(piece_table.get_pieces().len(), 1);
+        assert_eq!(piece_table.write_contents_to_string(), expected);
+        assert_eq!(piece_table.line_count(), 100_001);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(saved == expected);
+        #[cfg(unix)]
+        {
+            let linked = std::fs::read_to_string(&link).unwrap();
+            std::fs::remove_file(&link).unwrap();
+            assert!(linked == expected);
+        }
+
+        // Edits made before the save can still be undone
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string().len(), contents.len() + "inserted\n".len());
+    }
+
+    #[test]
+    fn piece_table_from_bytes() {
+        let mut piece_table = PieceTable::from_bytes(b"ab\xff\r\ncd".to_vec());
+        assert!(piece_table.is_binary());
+        assert_eq!(piece_table.len(), 7);
+        assert_eq!(piece_table.line_count(), 2);
+        assert_eq!(piece_table.get_bytes_range(1..5).unwrap(), b"b\xff\r\n");
+        assert_eq!(piece_table.get_bytes_range(5..8), None);
+
+        // Text that is not valid UTF-8 reads as NULs
+        assert_eq!(piece_table.write_contents_to_string(), "\0\0\0\0\0\0\0");
+        piece_table.insert_char(7, 'x').unwrap();
+        assert_eq!(piece_table.get_bytes_range(4..8).unwrap(), b"\ncdx");
+    }
+
+    #[test]
+    fn piece_table_to_stored_bytes() {
+        let mut piece_table = PieceTable::from_str("ab\ncd\n");
+        assert_eq!(piece_table.to_stored_bytes(), b"ab\ncd\n");
+        assert_eq!(piece_table.stored_offset(4), 4);
+        assert_eq!(piece_table.offset_from_stored(4), 4);
+
+        piece_table.set_has_bom(true);
+        piece_table.set_line_ending(LineEnding::CRLF);
+        piece_table.write_to_loc(4, "\nx").unwrap();
+        assert_eq!(piece_table.to_stored_bytes(), b"\xef\xbb\xbfab\r\nc\r\nxd\r\n");
+        for (offset, stored) in [(0, 3), (2, 5), (3, 7), (4, 8), (5, 10), (8, 14)] {
+            assert_eq!(piece_table.stored_offset(offset), stored);
+            assert_eq!(piece_table.offset_from_stored(stored), offset);
+        }
+        // Bytes of the byte order mark and line endings
+        assert_eq!(piece_table.offset_from_stored(1), 0);
+        assert_eq!(piece_table.offset_from_stored(6), 2);
+        assert_eq!(piece_table.offset_from_stored(9), 4);
+    }
+
+    #[test]
+    fn index_mapped_across_chunks() {
+        // A character cut off by the end of the first chunk
+        let mut bytes = "a".repeat(MAPPED_CHUNK_LEN - 1).into_bytes();
+        bytes.extend_from_slice("\u{e9}\n".as_bytes());
+        assert_eq!(index_mapped(&bytes).unwrap(), Some(vec![MAPPED_CHUNK_LEN + 1]));
+
+        // Mixed line endings are kept as they are
+        bytes.extend_from_slice(b"\r\n");
+        assert_eq!(index_mapped(&bytes).unwrap(), Some(vec![MAPPED_CHUNK_LEN + 1, MAPPED_CHUNK_LEN + 3]));
+        assert_eq!(index_mapped(b"a\r\nb\r\n").unwrap(), None);
+
+        bytes.truncate(MAPPED_CHUNK_LEN + 2);
+        bytes.push(0xff);
+        assert!(index_mapped(&bytes).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn piece_table_from_file_mmap_written_over() {
+        use std::os::unix::fs::FileExt;
+
+        let path = temp_file_path("mmap_written_over");
+        std::fs::write(&path, "caf\u{e9}\nline two").unwrap();
+        let piece_table = PieceTable::from_file_mmap(&path).unwrap();
+        assert_eq!(piece_table.line(0).as_deref(), Some("caf\u{e9}"));
+
+        // Bytes that another program makes invalid UTF-8 are read as 
+        // NULs, while the text around them is still read as it is
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"\xff", 3).unwrap();
+        assert_eq!(piece_table.line(1).as_deref(), Some("line two"));
+        assert_eq!(piece_table.write_contents_to_string(), "\0".repeat(14));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn piece_table_from_file_mmap_bom_and_crlf() {
+        let path = temp_file_path("mmap_bom");
+        std::fs::write(&path, b"\xef\xbb\xbfab\ncd").unwrap();
+        let piece_table = PieceTable::from_file_mmap(&path).unwrap();
+        assert!(piece_table.is_mapped());
+        assert!(piece_table.has_bom());
+        assert_eq!(piece_table.write_contents_to_string(), "ab\ncd");
+
+        // Line endings that need converting are read into memory
+        std::fs::write(&path, "ab\r\ncd\r\n").unwrap();
+        let piece_table = PieceTable::from_file_mmap(&path).unwrap();
+        assert!(!piece_table.is_mapped());
+        assert_eq!(piece_table.line_ending(), LineEnding::CRLF);
+        assert_eq!(piece_table.write_contents_to_string(), "ab\ncd\n");
+
+        std::fs::write(&path, b"\xff\xfe").unwrap();
+        assert!(PieceTable::from_file_mmap(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Writer that accepts at most two bytes per call to `write`.
+    struct ShortWriter {
+        contents: Vec<u8>,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(2);
+            self.contents.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn piece_table_validate_catches_broken_pieces() {
+        let mut piece_table = PieceTable::from_str("abc");
+        piece_table.write_to_loc(1, "xy").unwrap();
+        assert!(piece_table.validate().is_ok());
+
+        piece_table.pieces.push(Piece { start: 2, stop: 1, content: PieceBuf::ORIGINAL });
+        assert!(matches!(piece_table.validate(), Err(PieceTableError::GotBadPieceRange)));
+        piece_table.pieces.pop();
+
+        piece_table.pieces.push(Piece { start: 0, stop: 3, content: PieceBuf::ADDITION });
+        assert!(matches!(piece_table.validate(), Err(PieceTableError::GotBadPieceRange)));
+        piece_table.pieces.pop();
+
+        piece_table.pieces.push(Piece { start: 3, stop: 4, content: PieceBuf::ORIGINAL });
+        assert!(matches!(piece_table.validate(), Err(PieceTableError::GotBadPieceRange)));
+    }
+
+    #[test]
+    fn piece_is_empty() {
+        let piece = Piece { start: 3, stop: 3, content: PieceBuf::ADDITION };
+        assert!(piece.is_empty());
+        assert_eq!(piece.len(), 0);
+
+        let piece = Piece { start: 3, stop: 5, content: PieceBuf::ORIGINAL };
+        assert!(!piece.is_empty());
+        assert_eq!(piece.len(), 2);
+
+        // An empty piece left in the table writes nothing
+        let mut piece_table = PieceTable::from_str("abc");
+        piece_table.pieces.insert(1, Piece { start: 0, stop: 0, content: PieceBuf::ADDITION });
+        assert_eq!(piece_table.write_contents_to_string(), "abc");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "before it starts")]
+    fn piece_len_catches_inverted_range() {
+        let piece = Piece { start: 2, stop: 1, content: PieceBuf::ORIGINAL };
+        piece.len();
+    }
+
+    #[test]
+    fn piece_table_split_rejects_loc_past_piece() {
+        let mut piece_table = PieceTable::from_str("abc");
+        assert!(matches!(
+            piece_table.split_piece(0, 4), 
+            Err(PieceTableError::GotBadPieceRange)
+        ));
+        assert!(piece_table.split_piece(0, 3).is_ok());
+        assert_eq!(piece_table.write_contents_to_string(), "abc");
+    }
+
+    #[test]
+    fn piece_table_sequential_writes_extend_one_piece() {
+        let mut piece_table = PieceTable::from_str("ab");
+        piece_table.write_to_loc(1, "x").unwrap();
+        let n_pieces = piece_table.get_pieces().len();
+        assert_eq!(n_pieces, 3);
+
+        // Typing continues from the end of the previous write
+        for (i, c) in ["y", "\u{e9}", "z"].iter().enumerate() {
+            let loc = piece_table.previous_write().unwrap().loc;
+            assert_eq!(loc, [2, 3, 5][i]);
+            piece_table.write_to_loc(loc, c).unwrap();
+            assert_eq!(piece_table.get_pieces().len(), n_pieces);
+        }
+        assert_eq!(piece_table.write_contents_to_string(), "axy\u{e9}zb");
+
+        // Jumping elsewhere starts a new piece
+        piece_table.write_to_loc(7, ">").unwrap();
+        assert_eq!(piece_table.get_pieces().len(), n_pieces + 1);
+        assert_eq!(piece_table.previous_write().unwrap().loc, 8);
+        assert_eq!(piece_table.write_contents_to_string(), "axy\u{e9}zb>");
+    }
+
+    #[test]
+    fn piece_table_delete_resets_previous_write() {
+        let mut piece_table = PieceTable::from_str("abc");
+        piece_table.write_to_loc(3, "de").unwrap();
+        piece_table.delete(4, 5).unwrap();
+        assert_eq!(piece_table.previous_write(), None);
+
+        piece_table.write_to_loc(4, "f").unwrap();
+        piece_table.write_to_loc(5, "g").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "abcdfg");
+        assert_eq!(piece_table.get_pieces().len(), 3);
+    }
+
+    #[test]
+    fn piece_table_write_contents_survives_short_writes() {
+        let mut piece_table = PieceTable::from_str("hello");
+        piece_table.write_to_loc(5, " world").unwrap();
+        let mut writer = ShortWriter { contents: vec![] };
+        assert_eq!(piece_table.write_contents_to_stream(&mut writer).unwrap(), 11);
+        assert_eq!(writer.contents, b"hello world");
+    }
+
+    #[test]
+    fn piece_table_saves_many_pieces() {
+        let mut piece_table = PieceTable::from_str("");
+        let mut expected = String::new();
+        for i in 0..2000 {
+            // Alternate between the start and end so that every write 
+            // makes a new piece, apart from the second which continues 
+            // the first in the addition buffer and so is merged with it.
+            let line = format!("{}\n", i);
+            if i % 2 == 0 {
+                piece_table.write_to_loc(0, &line).unwrap();
+                expected.insert_str(0, &line);
+            } else {
+                piece_table.write_to_loc(piece_table.len(), &line).unwrap();
+                expected.push_str(&line);
+            }
+        }
+        assert_eq!(piece_table.get_pieces().len(), 1999);
+
+        let mut writer = ShortWriter { contents: vec![] };
+        let n_bytes = piece_table.write_contents_buffered(&mut writer).unwrap();
+        assert_eq!(n_bytes, expected.len());
+        assert_eq!(writer.contents, expected.as_bytes());
+
+        let path = temp_file_path("many_pieces");
+        assert_eq!(piece_table.save_to_file(&path).unwrap(), expected.len());
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn piece_table_save_trims_trailing_whitespace() {
+        let path = temp_file_path("trim");
+        let mut piece_table = PieceTable::from_str("one  \ntwo\t \n  \nthree\n");
+        piece_table.set_line_ending(LineEnding::CRLF);
+        piece_table.set_trim_trailing_whitespace(true);
+
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one\r\ntwo\r\n\r\nthree\r\n");
+        // The buffer itself is left alone
+        assert_eq!(piece_table.write_contents_to_string(), "one  \ntwo\t \n  \nthree\n");
+    }
+
+    #[test]
+    fn piece_table_save_adds_final_newline() {
+        let path = temp_file_path("final_newline");
+        let mut piece_table = PieceTable::from_str("one\ntwo");
+        piece_table.set_ensure_final_newline(true);
+
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved, "one\ntwo\n");
+        assert_eq!(piece_table.write_contents_to_string(), "one\ntwo");
+
+        let mut piece_table = PieceTable::from_str("");
+        piece_table.set_ensure_final_newline(true);
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "");
+    }
+
+    #[test]
+    fn piece_table_save_trims_final_newlines() {
+        let path = temp_file_path("trim_final_newlines");
+        let mut piece_table = PieceTable::from_str("one\ntwo\n  \n\n\n");
+        piece_table.set_line_ending(LineEnding::CRLF);
+        piece_table.set_trim_trailing_whitespace(true);
+        piece_table.set_trim_final_newlines(true);
+
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "one\r\ntwo\r\n");
+        assert_eq!(piece_table.write_contents_to_string(), "one\ntwo\n  \n\n\n");
+
+        // Lines of whitespace at the end are blank even when trailing 
+        // whitespace is kept elsewhere
+        let mut piece_table = PieceTable::from_str("one \ntwo\t\n  \n\t\n\n");
+        piece_table.set_trim_final_newlines(true);
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved, "one \ntwo\t\n");
+
+        let mut piece_table = PieceTable::from_str(" \n\n");
+        piece_table.set_trim_final_newlines(true);
+        piece_table.save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "\n");
+    }
+
+    #[test]
+    fn piece_table_find_from() {
+        let mut piece_table = PieceTable::from_str("ab ab");
+        piece_table.write_to_loc(5, " a").unwrap();
+        piece_table.write_to_current_piece("b").unwrap();
+        assert_eq!(piece_table.find_from("ab", 0, SearchOptions::default()), Some(0));
+        assert_eq!(piece_table.find_from("ab", 1, SearchOptions::default()), Some(3));
+        assert_eq!(piece_table.find_from("ab", 4, SearchOptions::default()), Some(6));
+        assert_eq!(piece_table.find_from("ab", 7, SearchOptions::default()), None);
+        assert_eq!(piece_table.find_from("", 0, SearchOptions::default()), None);
+    }
+
+    #[test]
+    fn piece_table_rfind_before() {
+        let piece_table = PieceTable::from_str("ab ab ab");
+        assert_eq!(piece_table.rfind_before("ab", usize::MAX), Some(6));
+        assert_eq!(piece_table.rfind_before("ab", 6), Some(3));
+        assert_eq!(piece_table.rfind_before("ab", 4), Some(3));
+        assert_eq!(piece_table.rfind_before("ab", 3), Some(0));
+        assert_eq!(piece_table.rfind_before("ab", 0), None);
+    }
+
+    #[test]
+    fn piece_table_delete_within_piece() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        piece_table.delete(2, 4).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "heo world");
+    }
+
+    #[test]
+    fn piece_table_delete_across_pieces() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        piece_table.write_to_loc(5, "123").unwrap();
+        piece_table.delete(3, 10).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "helorld");
+        piece_table.delete(0, 7).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "");
+        piece_table.write_to_loc(0, "new").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "new");
+    }
+
+    #[test]
+    fn piece_table_prunes_empty_pieces() {
+        let mut piece_table = PieceTable::from_str("hello");
+
+        // Writing at the start splits off an empty piece before it
+        piece_table.write_to_loc(0, ">").unwrap();
+        assert!(piece_table.get_pieces().iter().all(|piece| !piece.is_empty()));
+        assert_eq!(piece_table.get_pieces().len(), 2);
+        piece_table.write_to_current_piece(">").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), ">>hello");
+
+        // Deleting an insertion leaves the pieces on either side of it
+        // contiguous, so they are merged back together
+        piece_table.write_to_loc(4, "123").unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 4);
+        piece_table.delete(4, 7).unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 2);
+        assert_eq!(piece_table.write_contents_to_string(), ">>hello");
+
+        piece_table.delete(0, 7).unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 1);
+        assert!(piece_table.is_empty());
+        piece_table.write_to_loc(0, "new").unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 1);
+        assert_eq!(piece_table.write_contents_to_string(), "new");
+    }
+
+    #[test]
+    fn piece_table_prunes_only_around_edit() {
+        // Pieces away from an edit are left as they are, even where they
+        // could be pruned
+        let mut piece_table = PieceTable::from_str("one two three");
+        piece_table.pieces = vec![
+            Piece { start: 0, stop: 0, content: PieceBuf::ORIGINAL },
+            Piece { start: 0, stop: 4, content: PieceBuf::ORIGINAL },
+            Piece { start: 4, stop: 8, content: PieceBuf::ORIGINAL },
+            Piece { start: 8, stop: 13, content: PieceBuf::ORIGINAL },
+        ];
+        piece_table.invalidate_piece_ends(0);
+        piece_table.write_to_loc(13, "!").unwrap();
+        piece_table.delete(10, 11).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "one two thee!");
+        assert!(piece_table.get_pieces()[0].is_empty());
+        assert_eq!(piece_table.get_pieces().len(), 4);
+
+        piece_table.prune_empty_pieces();
+        assert_eq!(piece_table.get_pieces().len(), 3);
+        assert_eq!(piece_table.write_contents_to_string(), "one two thee!");
+    }
+
+    #[test]
+    fn piece_table_random_inserts_match_string() {
+        // A simple linear congruential generator, so that the test is 
+        // repeatable without depending on a random number crate
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next_random = |bound: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % bound
+        };
+
+        let mut piece_table = PieceTable::from_str("start\n");
+        let mut expected = String::from("start\n");
+        for i in 0..5000 {
+            let text = ["a", "\u{e9}", "xyz\n", "\u{1f600}"][i % 4];
+            let mut loc = next_random(expected.len() + 1);
+            while !expected.is_char_boundary(loc) {
+                loc -= 1;
+            }
+            assert_eq!(piece_table.write_to_loc(loc, text).unwrap(), text.len());
+            expected.insert_str(loc, text);
+
+            if i % 7 == 0 {
+                let mut start = next_random(expected.len());
+                while !expected.is_char_boundary(start) {
+                    start -= 1;
+                }
+                let n_bytes = piece_table.delete_char_at(start).unwrap();
+                expected.replace_range(start..start + n_bytes, "");
+            }
+        }
+        assert_eq!(piece_table.len(), expected.len());
+        assert_eq!(piece_table.write_contents_to_string(), expected);
+        assert!(matches!(
+            piece_table.write_to_loc(expected.len() + 1, "x"), 
+            Err(PieceTableError::GotBadLoc)
+        ));
+    }
+
+    #[test]
+    fn piece_table_delete_at_piece_boundaries() {
+        let split = || {
+            let mut piece_table = PieceTable::from_str("hello world");
+            piece_table.write_to_loc(5, "123").unwrap();
+            assert_eq!(piece_table.get_pieces().len(), 3);
+            piece_table
+        };
+        for (start, stop, expected, n_pieces) in [
+            (0, 5, "123 world", 2),
+            (5, 8, "hello world", 1),
+            (8, 11, "hello123rld", 3),
+            (3, 5, "hel123 world", 3),
+            (4, 9, "hellworld", 2),
+            (0, 14, "", 1),
+        ] {
+            let mut piece_table = split();
+            piece_table.delete(start, stop).unwrap();
+            assert_eq!(piece_table.write_contents_to_string(), expected);
+            assert_eq!(piece_table.get_pieces().len(), n_pieces);
+            assert_eq!(piece_table.previous_write(), None);
+        }
+
+        // Deleting nothing leaves the pieces and previous write alone
+        let mut piece_table = split();
+        piece_table.delete(8, 8).unwrap();
+        assert_eq!(piece_table.get_pieces().len(), 3);
+        assert_eq!(piece_table.previous_write().unwrap().loc, 8);
+    }
+
+    #[test]
+    fn piece_table_undo_and_redo() {
+        let mut piece_table = PieceTable::from_str("hello");
+        piece_table.write_to_loc(5, " world").unwrap();
+        piece_table.delete(0, 1).unwrap();
+        piece_table.write_to_current_piece("!").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "ello world!");
+
+        assert_eq!(
+            piece_table.undo().unwrap(),
+            vec![Edit::Delete { loc: 10, text: String::from("!") }]
         );
         assert_eq!(
-            pieces.get(1).unwrap(),
-            &Piece { start: 5, stop: 7, content: PieceBuf::ORIGINAL }
+            piece_table.undo().unwrap(),
+            vec![Edit::Insert { loc: 0, text: String::from("h") }]
         );
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "hello");
+        assert!(piece_table.undo().unwrap().is_empty());
+
+        piece_table.redo().unwrap();
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "ello world");
+
+        // A new edit forgets the groups that could have been redone
+        piece_table.write_to_loc(0, "j").unwrap();
+        assert!(piece_table.redo().unwrap().is_empty());
+        assert_eq!(piece_table.write_contents_to_string(), "jello world");
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "ello world");
+    }
+
+    #[test]
+    fn piece_table_undo_groups_typed_characters() {
+        let mut piece_table = PieceTable::from_str("x");
+        piece_table.set_clock(fixed_clock);
+        for (loc, c) in "abc".chars().enumerate() {
+            piece_table.insert_char(loc, c).unwrap();
+        }
+        piece_table.push_undo_boundary();
+        piece_table.insert_char(3, 'd').unwrap();
+        piece_table.insert_char(4, 'e').unwrap();
+
+        // Characters not typed where the last one ended start a group,
+        // as does text inserted in one go
+        piece_table.insert_char(0, '>').unwrap();
+        piece_table.write_to_loc(7, "fg").unwrap();
+        piece_table.insert_char(9, 'h').unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), ">abcdexfgh");
+
+        for expected in [">abcdexfg", ">abcdex", "abcdex", "abcx", "x"] {
+            piece_table.undo().unwrap();
+            assert_eq!(piece_table.write_contents_to_string(), expected);
+        }
         assert_eq!(
-            pieces.get(2).unwrap(), 
-            &Piece { start: 7, stop: 12, content: PieceBuf::ORIGINAL }
-        )
+            piece_table.redo().unwrap(),
+            vec![Edit::Insert { loc: 0, text: String::from("abc") }]
+        );
+
+        // A character typed after the timeout starts a group
+        piece_table.set_clock(later_clock);
+        piece_table.insert_char(3, 'd').unwrap();
+        assert_eq!(piece_table.undo().unwrap().len(), 1);
+        assert_eq!(piece_table.write_contents_to_string(), "abcx");
     }
 
     #[test]
-    fn piece_table_write_to_current_piece() {
+    fn piece_table_undo_groups_of_edits() {
+        let mut piece_table = PieceTable::from_str("one two");
+        piece_table.set_clock(fixed_clock);
+        piece_table.insert_char(7, '!').unwrap();
+        piece_table.begin_undo_group();
+        piece_table.insert_char(8, '?').unwrap();
+        piece_table.delete(0, 4).unwrap();
+        piece_table.begin_undo_group();
+        piece_table.write_to_loc(0, "three ").unwrap();
+        piece_table.end_undo_group();
+        assert!(piece_table.redo().unwrap().is_empty());
+        piece_table.end_undo_group();
+        // Nothing was recorded in the group, so there is nothing to undo
+        piece_table.begin_undo_group();
+        piece_table.end_undo_group();
+        assert_eq!(piece_table.write_contents_to_string(), "three two!?");
+
+        assert_eq!(piece_table.undo().unwrap().len(), 3);
+        assert_eq!(piece_table.write_contents_to_string(), "one two!");
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "three two!?");
+        piece_table.undo().unwrap();
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "one two");
+    }
+
+    #[test]
+    fn piece_table_char_positions() {
+        let mut piece_table = PieceTable::from_str("h\u{e9}llo");
+        piece_table.write_to_loc(6, " w\u{f6}rld").unwrap();
+        assert_eq!(piece_table.byte_offset(Position::Char(2)).unwrap(), 3);
+        assert_eq!(piece_table.byte_offset(Position::Char(8)).unwrap(), 10);
+        assert_eq!(piece_table.byte_offset(Position::Char(11)).unwrap(), 13);
+        assert!(matches!(piece_table.byte_offset(Position::Char(12)), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.byte_offset(Position::Byte(10)).unwrap(), 10);
+        assert!(matches!(piece_table.byte_offset(Position::Byte(9)), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.byte_offset(Position::Byte(14)), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.char_offset(Position::Byte(10)).unwrap(), 8);
+        assert_eq!(piece_table.char_offset(Position::Byte(13)).unwrap(), 11);
+        assert!(matches!(piece_table.char_offset(Position::Byte(2)), Err(PieceTableError::GotBadLoc)));
+
+        let end = piece_table.write_to_position(Position::Char(2), "\u{1f600}!").unwrap();
+        assert_eq!(end, Position::Char(4));
+        assert_eq!(piece_table.write_contents_to_string(), "h\u{e9}\u{1f600}!llo w\u{f6}rld");
+        piece_table.delete_between(Position::Char(1), end).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "hllo w\u{f6}rld");
+    }
+
+    #[test]
+    fn piece_table_refuses_offsets_within_characters() {
+        let mut piece_table = PieceTable::from_str("\u{e9}t\u{e9}");
+        assert!(matches!(piece_table.write_to_loc(1, "x"), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.delete(1, 3), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.delete(0, 4), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.split_piece(0, 1), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.write_contents_to_string(), "\u{e9}t\u{e9}");
+    }
+
+    #[test]
+    fn piece_table_delete_bad_range() {
+        let mut piece_table = PieceTable::from_str("hello");
+        assert!(matches!(piece_table.delete(3, 2), Err(PieceTableError::GotBadLoc)));
+        assert!(matches!(piece_table.delete(0, 6), Err(PieceTableError::GotBadLoc)));
+    }
+
+    #[test]
+    fn piece_table_delete_keeps_current_piece() {
         let mut piece_table = PieceTable::from_str("hello world");
-        piece_table.write_to_loc(5, "123").unwrap();
-        piece_table.write_to_current_piece("new").unwrap();
-        piece_table.write_to_loc(1, "22").unwrap();
-        piece_table.write_to_current_piece("test").unwrap();
+        piece_table.write_to_loc(5, "abc").unwrap();
+        piece_table.delete(0, 2).unwrap();
+        piece_table.write_to_current_piece("d").unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "lloabcd world");
+
+        piece_table.delete(5, 7).unwrap();
+        assert!(piece_table.write_to_current_piece("e").is_err());
+        assert_eq!(piece_table.write_contents_to_string(), "lloab world");
+    }
+
+    #[test]
+    fn piece_table_replace_range() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        let replaced = piece_table.replace_range(6, 11, "there").unwrap();
+        assert_eq!(replaced, "world");
+        assert_eq!(piece_table.write_contents_to_string(), "hello there");
+    }
+
+    #[test]
+    fn piece_table_replace_range_is_undone_in_one_step() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        piece_table.write_to_loc(5, ",").unwrap();
+        assert_eq!(piece_table.replace_range(3, 8, "p! W").unwrap(), "lo, w");
+        assert_eq!(piece_table.write_contents_to_string(), "help! World");
+        assert_eq!(piece_table.replace_range(0, 0, "").unwrap(), "");
+
+        assert_eq!(
+            piece_table.undo().unwrap(),
+            vec![
+                Edit::Delete { loc: 3, text: String::from("p! W") },
+                Edit::Insert { loc: 3, text: String::from("lo, w") },
+            ]
+        );
+        assert_eq!(piece_table.write_contents_to_string(), "hello, world");
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "help! World");
+
+        // Typing after a replacement starts a group of its own
+        piece_table.insert_char(11, '!').unwrap();
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "help! World");
+
+        assert!(matches!(piece_table.replace_range(4, 2, "x"), Err(PieceTableError::GotBadLoc)));
+        let mut piece_table = PieceTable::from_str("caf\u{e9}");
+        assert!(matches!(piece_table.replace_range(4, 5, "e"), Err(PieceTableError::GotBadLoc)));
+        assert_eq!(piece_table.replace_range(3, 5, "e").unwrap(), "\u{e9}");
+    }
+
+    #[test]
+    fn piece_table_find_all() {
+        let piece_table = PieceTable::from_str("foo bar foo\nfoo");
+        assert_eq!(piece_table.find_all("foo", SearchOptions::default()), vec![0..3, 8..11, 12..15]);
+        assert!(piece_table.find_all("baz", SearchOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn piece_table_find_all_in() {
+        let piece_table = PieceTable::from_str("foo bar foo\nfoo");
+        assert_eq!(piece_table.find_all_in("foo", 1..15, SearchOptions::default()), vec![8..11, 12..15]);
+        assert_eq!(piece_table.find_all_in("foo", 4..14, SearchOptions::default()), vec![8..11]);
+        assert!(piece_table.find_all_in("foo", 20..30, SearchOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn piece_table_find_case_insensitive() {
+        let piece_table = PieceTable::from_str("Foo FOO foo \u{c9}t\u{e9}");
+        let options = SearchOptions { case_insensitive: true, ..Default::default() };
+        assert_eq!(piece_table.find_all("foo", options), vec![0..3, 4..7, 8..11]);
+        assert_eq!(piece_table.find_all("Foo", SearchOptions::default()), vec![0..3]);
+        assert_eq!(piece_table.find_from("foo", 1, options), Some(4));
+        assert_eq!(piece_table.find_all("\u{e9}T\u{c9}", options), vec![12..17]);
+    }
+
+    #[test]
+    fn piece_table_find_whole_word() {
+        let piece_table = PieceTable::from_str("category cat _cat cat.");
+        let options = SearchOptions { whole_word: true, ..Default::default() };
+        assert_eq!(piece_table.find_all("cat", options), vec![9..12, 18..21]);
+        assert_eq!(piece_table.find_from("cat", 0, options), Some(9));
+        assert_eq!(piece_table.find_all("cat", SearchOptions::default()).len(), 4);
+
+        let piece_table = PieceTable::from_str("aaa aa");
+        assert_eq!(piece_table.find_all("aa", options), vec![4..6]);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn piece_table_find_all_regex() {
+        let piece_table = PieceTable::from_str("a1 b22\nc333 4\nd");
+        assert_eq!(
+            piece_table.find_all_regex(r"\d+").unwrap(),
+            vec![1..2, 4..6, 8..11, 12..13]
+        );
+        assert_eq!(
+            piece_table.find_all_regex(r"(?m)^\w\d*").unwrap(),
+            vec![0..2, 7..11, 14..15]
+        );
+        assert!(piece_table.find_all_regex("x+").unwrap().is_empty());
+        assert!(piece_table.find_all_regex("(").is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn piece_table_search_regex_matches_find_all_regex() {
+        let mut piece_table = PieceTable::from_str("cat a\u{e9}b\n\nbb c");
+        for (loc, text) in [(2, "t\nca"), (0, "a"), (13, "\u{e9}\u{e9}"), (20, " cat\n")] {
+            piece_table.write_to_loc(loc, text).unwrap();
+            piece_table.push_undo_boundary();
+        }
+        assert!(piece_table.get_pieces().len() > 4);
+
+        for pattern in [
+            "a+", "a*", "", "ca", r"t\sc", r"\w+", r"(?m)^\w*$", "^", "$", r"(?m)$", 
+            ".*", "(?s).+", "x?", "\u{e9}+", r"(?-u)\bcat\b", r"\b\w+\b", "b\n*b",
+            r"\b", r"(?m)\b\w$", r"\bc", r"\w\s\w", r"\Aca", r"b\z",
+        ] {
+            assert_eq!(
+                piece_table.search_regex(pattern).unwrap().collect::<Vec<_>>(),
+                piece_table.find_all_regex(pattern).unwrap(),
+                "{pattern}",
+            );
+        }
+        assert!(piece_table.search_regex("(").is_err());
+
+        // Matches of a pattern that cannot span lines are found a line at
+        // a time when the DFAs give up, without writing out the contents
+        let mut matches = piece_table.search_regex(r"\b\w+\b").unwrap();
+        assert_eq!(matches.by_ref().count(), 7);
+        assert!(matches.contents.is_none());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn piece_table_search_regex_from() {
+        let piece_table = PieceTable::from_str("ab cd\n\u{e9}f gh\nij");
+        let regex = SearchRegex::new(r"\b\w+\b").unwrap();
+        let matches = |from| piece_table.search_regex_from(&regex, from).collect::<Vec<_>>();
+        assert_eq!(matches(0), vec![0..2, 3..5, 6..9, 10..12, 13..15]);
+        assert_eq!(matches(3), vec![3..5, 6..9, 10..12, 13..15]);
+        // A match is not found starting within a word, as `\b` looks 
+        // behind `from`
+        assert_eq!(matches(8), vec![10..12, 13..15]);
+        assert!(matches(15).is_empty());
+        assert!(matches(16).is_empty());
+    }
+
+    #[test]
+    fn piece_table_line_to_offset() {
+        let mut piece_table = PieceTable::from_str("one\ntwo");
+        piece_table.write_to_loc(7, "\nthree\n").unwrap();
+        assert_eq!(piece_table.line_count(), 4);
+        assert_eq!(piece_table.line_to_offset(0), Some(0));
+        assert_eq!(piece_table.line_to_offset(1), Some(4));
+        assert_eq!(piece_table.line_to_offset(2), Some(8));
+        assert_eq!(piece_table.line_to_offset(3), Some(14));
+        assert_eq!(piece_table.line_to_offset(4), None);
+    }
+
+    #[test]
+    fn piece_table_offset_to_line() {
+        let mut piece_table = PieceTable::from_str("one\ntwo");
+        piece_table.write_to_loc(7, "\nthree\n").unwrap();
+        let lines: Vec<Option<usize>> = (0..16).map(|i| piece_table.offset_to_line(i)).collect();
+        assert_eq!(lines[..4], [Some(0); 4]);
+        assert_eq!(lines[4..8], [Some(1); 4]);
+        assert_eq!(lines[8..14], [Some(2); 6]);
+        assert_eq!(lines[14..], [Some(3), None]);
+    }
+
+    #[test]
+    fn piece_table_line_index_follows_edits() {
+        let mut piece_table = PieceTable::from_str("a\nbc\n\nd");
+        piece_table.write_to_loc(3, "x\ny\n").unwrap();
+        piece_table.write_to_current_piece("\n").unwrap();
+        piece_table.delete(1, 4).unwrap();
+        piece_table.write_to_loc(0, "\n").unwrap();
+        piece_table.undo().unwrap();
+        piece_table.insert_char(2, '\n').unwrap();
+
         let contents = piece_table.write_contents_to_string();
-        assert_eq!(contents, "h22testello123new world");
+        assert_eq!(contents, "a\n\ny\n\nc\n\nd");
+        assert_eq!(piece_table.line_count(), contents.split('\n').count());
+        let starts: Vec<usize> = std::iter::once(0)
+            .chain(contents.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        for (line, &start) in starts.iter().enumerate() {
+            assert_eq!(piece_table.line_to_offset(line), Some(start));
+        }
+        for offset in 0..=contents.len() {
+            let line = contents[..offset].matches('\n').count();
+            assert_eq!(piece_table.offset_to_line(offset), Some(line));
+        }
     }
 
     #[test]
-    fn piece_table_write_to_loc_bad_loc() {
+    fn piece_table_spans_join_to_contents() {
         let mut piece_table = PieceTable::from_str("hello world");
-        let output = piece_table.write_to_loc(20, "test");
-        if let Err(PieceTableError::GotBadLoc) = output {} else { panic!() }
+        piece_table.write_to_loc(5, ", dear").unwrap();
+        piece_table.delete(0, 2).unwrap();
+        let spans: Vec<(&str, &PieceBuf)> = piece_table.spans().collect();
+        assert_eq!(spans.first(), Some(&("llo", &PieceBuf::ORIGINAL)));
+        assert!(spans.contains(&(", dear", &PieceBuf::ADDITION)));
+        assert_eq!(
+            spans.iter().map(|(text, _)| *text).collect::<String>(),
+            piece_table.write_contents_to_string()
+        );
+    }
+
+    #[test]
+    fn piece_table_iterates_without_copying() {
+        let mut piece_table = PieceTable::from_str("one\ntwo\nthree");
+        piece_table.write_to_loc(5, "X\nY").unwrap();
+        piece_table.write_to_loc(0, "\u{e9}").unwrap();
+        let contents = piece_table.write_contents_to_string();
+        assert_eq!(contents, "\u{e9}one\ntX\nYwo\nthree");
+
+        assert_eq!(piece_table.chunks().collect::<String>(), contents);
+        assert!(piece_table.chunks().all(|chunk| !chunk.is_empty()));
+        assert!(piece_table.chars().eq(contents.chars()));
+
+        let lines: Vec<Cow<str>> = piece_table.lines().collect();
+        assert_eq!(lines, contents.split('\n').collect::<Vec<&str>>());
+        assert!(matches!(lines[3], Cow::Borrowed("three")));
+        assert!(matches!(lines[0], Cow::Owned(_)));
+        assert_eq!(piece_table.lines_from(2).unwrap().collect::<Vec<_>>(), ["Ywo", "three"]);
+        assert_eq!(piece_table.lines_from(3).unwrap().collect::<Vec<_>>(), ["three"]);
+        assert!(piece_table.lines_from(4).is_none());
+
+        piece_table.write_to_loc(contents.len(), "\n").unwrap();
+        assert_eq!(piece_table.lines_from(4).unwrap().collect::<Vec<_>>(), [""]);
+
+        assert_eq!(PieceTable::from_str("").lines().collect::<Vec<_>>(), [""]);
+    }
+
+    #[test]
+    fn piece_table_find_across_pieces() {
+        let mut piece_table = PieceTable::from_str("c\u{e9}ab");
+        for (loc, text) in [(0, "a"), (1, "b"), (0, "ca"), (7, "c"), (4, "b"), (3, "\u{e9}")] {
+            piece_table.write_to_loc(loc, text).unwrap();
+            piece_table.push_undo_boundary();
+        }
+        let contents = piece_table.write_contents_to_string();
+        assert!(piece_table.get_pieces().len() > 4);
+
+        for needle in ["a", "ab", "bc", "cab", "c\u{e9}a", "\u{e9}b", "bcab", "zz", ""] {
+            for offset in (0..=contents.len()).filter(|&i| contents.is_char_boundary(i)) {
+                let expected = contents[offset..]
+                    .find(needle)
+                    .filter(|_| !needle.is_empty())
+                    .map(|i| Position::Byte(offset + i));
+                assert_eq!(piece_table.find(needle, Position::Byte(offset)), expected);
+                let expected = piece_table.rfind_before(needle, offset).map(Position::Byte);
+                assert_eq!(piece_table.rfind(needle, Position::Byte(offset)), expected);
+            }
+        }
+
+        assert_eq!(contents, "caa\u{e9}bbc\u{e9}cab");
+        assert_eq!(piece_table.find("ca", Position::Char(1)), Some(Position::Char(8)));
+        assert_eq!(piece_table.rfind("\u{e9}", Position::Char(6)), Some(Position::Char(3)));
+        assert_eq!(piece_table.find("a", Position::Byte(2)), Some(Position::Byte(2)));
+        assert_eq!(piece_table.find("a", Position::Byte(12)), None);
+        assert_eq!(piece_table.find("a", Position::Byte(4)), None);
     }
 
     #[test]
@@ -305,4 +4016,59 @@ mod tests {
         assert_eq!(&new_string, "hello123 world");
     }
 
+    #[test]
+    fn piece_table_snapshot_keeps_contents_before_edit() {
+        let mut piece_table = PieceTable::from_str("hello world");
+        piece_table.write_to_loc(5, ", dear").unwrap();
+        let snapshot = piece_table.snapshot();
+
+        piece_table.write_to_loc(11, " old").unwrap();
+        piece_table.delete(0, 7).unwrap();
+        assert_eq!(piece_table.write_contents_to_string(), "dear old world");
+
+        assert_eq!(snapshot.len(), 17);
+        assert_eq!(snapshot.chars().collect::<String>(), "hello, dear world");
+        assert_eq!(snapshot.get_text_range(3..9).as_deref(), Some("lo, de"));
+        assert_eq!(snapshot.get_text_range(0..0).as_deref(), Some(""));
+        assert_eq!(snapshot.get_text_range(10..18), None);
+    }
+
+    #[test]
+    fn piece_table_snapshot_shares_addition() {
+        let mut piece_table = PieceTable::from_str("ab");
+        piece_table.write_to_loc(1, "x").unwrap();
+        let snapshot = piece_table.snapshot();
+
+        // Typing after the snapshot starts a new block rather than 
+        // copying the one the snapshot holds, without joining the pieces
+        // of the two blocks
+        piece_table.write_to_loc(2, "y").unwrap();
+        piece_table.write_to_loc(3, "z").unwrap();
+        piece_table.write_to_current_piece("\n").unwrap();
+        assert!(Arc::ptr_eq(&snapshot.addition.blocks[0], &piece_table.addition.blocks[0]));
+        assert_eq!(piece_table.addition.blocks.len(), 2);
+        assert_eq!(piece_table.pieces.len(), 4);
+        assert_eq!(piece_table.write_contents_to_string(), "axyz\nb");
+        assert_eq!(piece_table.line_count(), 2);
+        assert_eq!(snapshot.chars().collect::<String>(), "axb");
+
+        // Once the snapshot is dropped the last block is appended to
+        drop(snapshot);
+        piece_table.write_to_loc(5, "w").unwrap();
+        assert_eq!(piece_table.addition.blocks.len(), 2);
+        assert_eq!(piece_table.write_contents_to_string(), "axyz\nwb");
+    }
+
+    #[test]
+    fn piece_table_snapshot_reads_on_another_thread() {
+        let mut piece_table = PieceTable::from_str("caf\u{e9}");
+        let snapshot = piece_table.snapshot();
+        piece_table.write_to_loc(5, "!").unwrap();
+
+        let handle = std::thread::spawn(move || {
+            (snapshot.get_text_range(3..4), snapshot.chars().count())
+        });
+        assert_eq!(handle.join().unwrap(), (None, 4));
+    }
+
 }